@@ -8,7 +8,7 @@ use ic_base_types::PrincipalId;
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use std::borrow::Cow;
 
-use crate::{UserCanisterConfig, CanisterRecord, TokenConfig, state};
+use crate::{UserCanisterConfig, CanisterRecord, FeeRevenue, PlatformFeeReport, state};
 
 // Include the compiled user payment canister WASM
 include!(concat!(env!("OUT_DIR"), "/user_payment_canister_wasm.rs"));
@@ -162,43 +162,25 @@ pub async fn upgrade_user_canister(
         return Err("User canister WASM not available".to_string());
     }
     
-    // For upgrade, we pass empty candid-encoded args since we're preserving existing state
-    // Using proper candid encoding for empty arguments
-    let arg = Encode!().map_err(|e| format!("Failed to encode empty arguments: {:?}", e))?;
+    // State is preserved, the only upgrade argument is this factory so canisters
+    // deployed before fee collection know who may register the platform treasury
+    let arg = Encode!(&Some(ic_cdk::id()))
+        .map_err(|e| format!("Failed to encode upgrade arguments: {:?}", e))?;
 
     // Upgrade the user payment canister code
     upgrade_code(canister_id, wasm.to_vec(), arg)
         .await
         .map_err(|e| format!("{} - {:?}", e.method, e.reason))?;
 
+    // Not fatal: fees keep accruing in the canister until the treasury is registered
+    if let Err(err) = register_treasury(canister_id).await {
+        ic_cdk::println!("Failed to register treasury on {}: {}", canister_id, err);
+    }
+
     Ok(())
 }
 
 
-/// Create a default user canister configuration
-fn create_default_user_config() -> UserCanisterConfig {
-    let default_token = TokenConfig {
-        symbol: "ckBTC".to_string(),
-        name: "Chain Key Bitcoin".to_string(),
-        decimals: 8,
-        canister_id: Principal::from_text("mxzaz-hqaaa-aaaar-qaada-cai").unwrap(),
-        fee: 10,
-        logo: None,
-        is_active: true,
-    };
-    
-    UserCanisterConfig {
-        name: "Default Payment System".to_string(),
-        description: "Default payment system configuration".to_string(),
-        supported_tokens: vec![default_token],
-        webhook: None,
-        merchant_fee: 250, // 2.5%
-        auto_withdraw: false,
-        withdraw_threshold: None,
-        custom_settings: vec![],
-    }
-}
-
 /// Deploy a new user payment canister (following BOB's spawn_miner pattern)
 pub async fn deploy_user_canister(
     config: UserCanisterConfig,
@@ -214,11 +196,8 @@ pub async fn deploy_user_canister(
         return Err("User canister WASM not available".to_string());
     }
     
-    // Create hardcoded default configuration for user canister initialization
-    let hardcoded_config = create_default_user_config();
-    
-    // Prepare the initialization argument with hardcoded values
-    let arg = Encode!(&hardcoded_config, &owner)
+    // The merchant's validated configuration becomes the init argument
+    let arg = Encode!(&config, &owner)
         .map_err(|e| format!("Failed to encode canister arguments: {:?}", e))?;
 
     // Create the canister
@@ -231,9 +210,27 @@ pub async fn deploy_user_canister(
         .await
         .map_err(|e| format!("{} - {:?}", e.method, e.reason))?;
 
+    // Not fatal: fees keep accruing in the canister until the treasury is registered
+    if let Err(err) = register_treasury(canister_id).await {
+        ic_cdk::println!("Failed to register treasury on {}: {}", canister_id, err);
+    }
+
     Ok(canister_id)
 }
 
+/// Register the platform treasury on a user canister so it can sweep merchant fees
+pub async fn register_treasury(canister_id: Principal) -> Result<(), String> {
+    let treasury = state::get_platform_treasury();
+
+    let result: Result<(Result<(), String>,), _> =
+        ic_cdk::call(canister_id, "register_platform_treasury", (treasury,)).await;
+
+    match result {
+        Ok((inner,)) => inner,
+        Err((code, msg)) => Err(format!("Failed to register treasury: {:?} - {}", code, msg)),
+    }
+}
+
 /// Create a canister record for tracking
 pub fn create_canister_record(
     canister_id: Principal,
@@ -288,6 +285,105 @@ pub async fn complete_canister_deployment(
     Ok(canister_id)
 }
 
+/// Copy the user-editable parts of a canister's live configuration into its record
+fn apply_config_to_record(record: &mut CanisterRecord, config: &UserCanisterConfig, now: u64) {
+    record.name = config.name.clone();
+    record.description = config.description.clone();
+    record.supported_tokens = config.supported_tokens.clone();
+    record.last_updated = now;
+}
+
+/// Re-sync a registry record from the user canister's `get_configuration()`
+pub async fn sync_canister_record(
+    canister_id: Principal,
+    caller: Principal,
+) -> Result<CanisterRecord, String> {
+    let mut record = state::get_user_canister(&canister_id)
+        .ok_or("Canister not found in factory records")?;
+
+    if caller != record.owner && !is_admin(caller) {
+        return Err("Only the canister owner or admin can sync this record".to_string());
+    }
+
+    let result: Result<(UserCanisterConfig,), _> =
+        ic_cdk::call(canister_id, "get_configuration", ()).await;
+
+    let (config,) = result.map_err(|(code, msg)| {
+        format!("Failed to fetch configuration: {:?} - {}", code, msg)
+    })?;
+
+    apply_config_to_record(&mut record, &config, ic_cdk::api::time());
+    state::add_user_canister(canister_id, record.clone());
+
+    Ok(record)
+}
+
+async fn fetch_fee_summary(canister_id: Principal) -> Result<Vec<FeeRevenue>, String> {
+    let result: Result<(Result<Vec<FeeRevenue>, String>,), _> =
+        ic_cdk::call(canister_id, "get_platform_fee_summary", ()).await;
+
+    match result {
+        Ok((inner,)) => inner,
+        Err((code, msg)) => Err(format!("Failed to fetch fee summary: {:?} - {}", code, msg)),
+    }
+}
+
+/// Fee revenue of a single user canister
+pub async fn get_canister_fee_revenue(
+    canister_id: Principal,
+    caller: Principal,
+) -> Result<Vec<FeeRevenue>, String> {
+    let record = state::get_user_canister(&canister_id)
+        .ok_or("Canister not found in factory records")?;
+
+    if caller != record.owner && !is_admin(caller) {
+        return Err("Only the canister owner or admin can view fee revenue".to_string());
+    }
+
+    fetch_fee_summary(canister_id).await
+}
+
+/// Fee revenue across all active user canisters, summed per token
+pub async fn get_platform_fee_revenue() -> PlatformFeeReport {
+    let mut summaries = Vec::new();
+    let mut unreachable_canisters = Vec::new();
+
+    for record in state::get_active_canisters() {
+        match fetch_fee_summary(record.id).await {
+            Ok(summary) => summaries.push(summary),
+            Err(err) => {
+                ic_cdk::println!("Fee summary unavailable for {}: {}", record.id, err);
+                unreachable_canisters.push(record.id);
+            }
+        }
+    }
+
+    PlatformFeeReport {
+        canisters_reporting: summaries.len() as u64,
+        tokens: aggregate_fee_revenue(summaries),
+        unreachable_canisters,
+    }
+}
+
+/// Sum per-canister fee summaries by token symbol
+fn aggregate_fee_revenue(summaries: Vec<Vec<FeeRevenue>>) -> Vec<FeeRevenue> {
+    let mut totals: Vec<FeeRevenue> = Vec::new();
+
+    for revenue in summaries.into_iter().flatten() {
+        match totals.iter_mut().find(|total| total.token_symbol == revenue.token_symbol) {
+            Some(total) => {
+                total.accrued += revenue.accrued;
+                total.pending += revenue.pending;
+                total.swept += revenue.swept;
+            }
+            None => totals.push(revenue),
+        }
+    }
+
+    totals.sort_by(|a, b| a.token_symbol.cmp(&b.token_symbol));
+    totals
+}
+
 /// Validate user canister configuration
 fn validate_canister_config(config: &UserCanisterConfig) -> Result<(), String> {
     if config.name.is_empty() || config.name.len() > 50 {
@@ -429,4 +525,49 @@ mod tests {
         config.merchant_fee = 1001; // Over 10%
         assert!(validate_canister_config(&config).is_err());
     }
+
+    #[test]
+    fn test_aggregate_fee_revenue_sums_by_token() {
+        let revenue = |symbol: &str, accrued: u64, pending: u64, swept: u64| FeeRevenue {
+            token_symbol: symbol.to_string(),
+            accrued,
+            pending,
+            swept,
+        };
+
+        let totals = aggregate_fee_revenue(vec![
+            vec![revenue("ckBTC", 100, 40, 60), revenue("ICP", 10, 10, 0)],
+            vec![revenue("ckBTC", 50, 50, 0)],
+        ]);
+
+        assert_eq!(totals, vec![
+            revenue("ICP", 10, 10, 0),
+            revenue("ckBTC", 150, 90, 60),
+        ]);
+    }
+
+    #[test]
+    fn test_apply_config_to_record_replaces_tokens() {
+        let config = create_test_config();
+        let mut record = CanisterRecord {
+            id: Principal::anonymous(),
+            owner: Principal::anonymous(),
+            name: "Old name".to_string(),
+            description: "Old description".to_string(),
+            version: 1,
+            created_at: 0,
+            last_updated: 0,
+            is_active: true,
+            supported_tokens: vec![],
+        };
+
+        apply_config_to_record(&mut record, &config, 42);
+
+        assert_eq!(record.name, config.name);
+        assert_eq!(record.description, config.description);
+        assert_eq!(record.supported_tokens.len(), 1);
+        assert_eq!(record.supported_tokens[0].symbol, "ckBTC");
+        assert_eq!(record.last_updated, 42);
+        assert_eq!(record.version, 1);
+    }
 }
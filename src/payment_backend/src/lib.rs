@@ -113,6 +113,40 @@ impl Storable for FactoryStats {
     const BOUND: Bound = Bound::Unbounded;
 }
 
+// Account that receives the merchant fees collected by user canisters
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct PlatformTreasury {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+impl Storable for PlatformTreasury {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct FeeRevenue {
+    pub token_symbol: String,
+    pub accrued: u64,
+    pub pending: u64,
+    pub swept: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct PlatformFeeReport {
+    pub tokens: Vec<FeeRevenue>,
+    pub canisters_reporting: u64,
+    pub unreachable_canisters: Vec<Principal>,
+}
+
 // Vector wrapper for stable storage
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
 pub struct StableVecPrincipal(pub Vec<Principal>);
@@ -238,6 +272,17 @@ fn find_canisters_by_token(token_symbol: String) -> Vec<CanisterRecord> {
     })
 }
 
+#[ic_cdk::update]
+async fn sync_canister_record(canister_id: Principal) -> Result<CanisterRecord, String> {
+    // Refresh the registry record when it drifts from the canister's live configuration
+    factory::sync_canister_record(canister_id, ic_cdk::caller()).await
+}
+
+#[ic_cdk::update]
+async fn get_canister_fee_revenue(canister_id: Principal) -> Result<Vec<FeeRevenue>, String> {
+    factory::get_canister_fee_revenue(canister_id, ic_cdk::caller()).await
+}
+
 #[ic_cdk::query]
 fn get_factory_stats() -> FactoryStats {
     FACTORY_STATS.with(|s| s.borrow().get().clone())
@@ -259,6 +304,67 @@ fn set_user_canister_wasm(wasm: Vec<u8>) -> Result<(), String> {
 }
 
 
+#[ic_cdk::query]
+fn get_platform_treasury() -> PlatformTreasury {
+    state::get_platform_treasury()
+}
+
+#[ic_cdk::update]
+async fn set_platform_treasury(treasury: PlatformTreasury) -> Result<Vec<Principal>, String> {
+    if !is_admin(ic_cdk::caller()) {
+        return Err("Only admin can set the platform treasury".to_string());
+    }
+
+    if treasury.owner == Principal::anonymous() {
+        return Err("Treasury cannot be the anonymous principal".to_string());
+    }
+    if matches!(&treasury.subaccount, Some(subaccount) if subaccount.len() != 32) {
+        return Err("Subaccount must be exactly 32 bytes".to_string());
+    }
+
+    state::set_platform_treasury(treasury);
+
+    // Push the new treasury to every deployed canister, returns the ones that could not be updated
+    let mut failed = Vec::new();
+    for record in state::get_active_canisters() {
+        if let Err(err) = factory::register_treasury(record.id).await {
+            ic_cdk::println!("Failed to register treasury on {}: {}", record.id, err);
+            failed.push(record.id);
+        }
+    }
+    Ok(failed)
+}
+
+// Merchants cannot change their own fee rate, the platform sets it per canister
+#[ic_cdk::update]
+async fn set_canister_merchant_fee(canister_id: Principal, merchant_fee: u32) -> Result<(), String> {
+    if !is_admin(ic_cdk::caller()) {
+        return Err("Only admin can set merchant fees".to_string());
+    }
+
+    // Same limit as at deployment (max 10% = 1000 basis points)
+    if merchant_fee > 1000 {
+        return Err("Merchant fee cannot exceed 10% (1000 basis points)".to_string());
+    }
+
+    let result: Result<(Result<(), String>,), _> =
+        ic_cdk::call(canister_id, "set_merchant_fee", (merchant_fee,)).await;
+
+    match result {
+        Ok((inner,)) => inner,
+        Err((code, msg)) => Err(format!("Failed to set merchant fee: {:?} - {}", code, msg)),
+    }
+}
+
+#[ic_cdk::update]
+async fn get_platform_fee_revenue() -> Result<PlatformFeeReport, String> {
+    if !is_admin(ic_cdk::caller()) {
+        return Err("Only admin can view platform fee revenue".to_string());
+    }
+
+    Ok(factory::get_platform_fee_revenue().await)
+}
+
 #[ic_cdk::update]
 async fn admin_upgrade_user_canister(canister_id: Principal) -> Result<String, String> {
     if !is_admin(ic_cdk::caller()) {
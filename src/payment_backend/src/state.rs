@@ -11,7 +11,7 @@ use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::cell::RefCell;
 
-use crate::{CanisterRecord, FactoryStats, StableVecPrincipal};
+use crate::{CanisterRecord, FactoryStats, PlatformTreasury, StableVecPrincipal};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
@@ -54,6 +54,11 @@ thread_local! {
     pub static USER_CANISTER_WASM: RefCell<Cell<Option<Vec<u8>>, Memory>> = RefCell::new(
         Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))), None).unwrap()
     );
+
+    // Platform treasury for merchant fees, unset means the factory's own account
+    pub static PLATFORM_TREASURY: RefCell<Cell<Option<PlatformTreasury>, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))), None).unwrap()
+    );
 }
 
 // ============================================================================
@@ -134,3 +139,13 @@ pub fn get_factory_statistics() -> FactoryStats {
     FACTORY_STATS.with(|s| s.borrow().get().clone())
 }
 
+pub fn get_platform_treasury() -> PlatformTreasury {
+    PLATFORM_TREASURY.with(|t| t.borrow().get().clone()).unwrap_or(PlatformTreasury {
+        owner: ic_cdk::id(),
+        subaccount: None,
+    })
+}
+
+pub fn set_platform_treasury(treasury: PlatformTreasury) {
+    PLATFORM_TREASURY.with(|t| t.borrow_mut().set(Some(treasury)).unwrap());
+}
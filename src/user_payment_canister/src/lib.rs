@@ -1,4 +1,4 @@
-use candid::{CandidType, Decode, Encode, Principal};
+use candid::{CandidType, Decode, Encode, Nat, Principal};
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     storable::Bound,
@@ -7,10 +7,18 @@ use ic_stable_structures::{
     DefaultMemoryImpl,
     Storable,
 };
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
 use std::borrow::Cow;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
@@ -76,9 +84,77 @@ pub struct PaymentInvoice {
     pub expires_at: Option<u64>,
     pub created_at: u64,
     pub status: InvoiceStatus,
+    pub deposit_subaccount: Option<Vec<u8>>, // Subaccount of this canister for push-style payments
+    pub fiat_amount: Option<FiatAmount>, // Set for invoices priced in a fiat currency
+    pub quote: Option<ExchangeQuote>, // Locked conversion of the fiat price into `amount` of `token`
+    pub line_items: Option<Vec<LineItem>>,
+    pub tax_rate_bps: Option<u32>, // Invoice-level tax rate for lines without their own rate
+    pub shipping: Option<u64>,
+    pub breakdown: Option<InvoiceBreakdown>, // Computed from the line items, `amount` equals its total
+    pub payer: Option<Principal>, // Expected payer, the only principal credited for deposits
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct LineItem {
+    pub product_id: Option<String>, // Product lines are priced from the catalog
+    pub variant_sku: Option<String>,
+    pub description: String,
+    pub quantity: u32,
+    pub unit_price: u64,
+    pub discount: u64, // Absolute discount on the whole line
+    pub tax_rate_bps: Option<u32>, // Basis points, overrides the invoice-level rate
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct LineBreakdown {
+    pub description: String,
+    pub quantity: u32,
+    pub gross_amount: u64,
+    pub discount: u64,
+    pub net_amount: u64,
+    pub tax_rate_bps: u32,
+    pub tax_amount: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct InvoiceBreakdown {
+    pub lines: Vec<LineBreakdown>,
+    pub subtotal: u64, // Sum of line net amounts
+    pub discount_total: u64,
+    pub tax_total: u64,
+    pub shipping: u64, // Not taxed
+    pub total: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ItemizedInvoiceRequest {
+    pub token_symbol: String,
+    pub description: String,
+    pub line_items: Vec<LineItem>,
+    pub tax_rate_bps: Option<u32>,
+    pub shipping: Option<u64>,
+    pub metadata: Vec<(String, String)>,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct FiatAmount {
+    pub currency: String, // ISO 4217 code, e.g. "USD"
+    pub amount: u64, // Minor units (cents)
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ExchangeQuote {
+    pub token_symbol: String,
+    pub token_amount: u64,
+    pub rate: u64, // Price of one token in the fiat currency, scaled by 10^rate_decimals
+    pub rate_decimals: u32,
+    pub rate_timestamp: u64, // Seconds, as reported by the exchange rate canister
+    pub quoted_at: u64,
+    pub expires_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
 pub enum InvoiceStatus {
     Created,
     Paid,
@@ -112,6 +188,7 @@ pub struct PaymentTransaction {
     pub metadata: Vec<(String, String)>,
     pub payment_method: PaymentMethod,
     pub block_index: Option<u64>, // Block index from ledger transaction
+    pub idempotency_key: Option<String>, // Client-provided key for safe retries
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
@@ -128,6 +205,15 @@ pub struct PaymentRequest {
     pub token_symbol: String,
     pub coupon_code: Option<String>,
     pub metadata: Vec<(String, String)>,
+    pub idempotency_key: Option<String>, // Retries with the same key never charge twice
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct InvoiceDepositAccount {
+    pub invoice_id: String,
+    pub owner: Principal,
+    pub subaccount: Vec<u8>,
+    pub amount_due: u64, // Invoice amount plus the ledger fee needed to sweep the deposit
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
@@ -160,6 +246,48 @@ impl Storable for PaymentTransaction {
     const BOUND: Bound = Bound::Unbounded;
 }
 
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum TransactionStatusKind {
+    Pending,
+    Completed,
+    Failed,
+    Refunded,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct TransactionFilter {
+    pub status: Option<TransactionStatusKind>,
+    pub token_symbol: Option<String>,
+    pub payer: Option<Principal>, // Ignored for non-owners, who only see their own payments
+    pub from_time: Option<u64>,
+    pub to_time: Option<u64>,
+    pub metadata_key: Option<String>,
+    pub metadata_value: Option<String>, // Only checked together with `metadata_key`
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct InvoiceFilter {
+    pub status: Option<InvoiceStatus>,
+    pub token_symbol: Option<String>,
+    pub payer: Option<Principal>, // Ignored for non-owners, who only see their own invoices
+    pub from_time: Option<u64>,
+    pub to_time: Option<u64>,
+    pub metadata_key: Option<String>,
+    pub metadata_value: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct TransactionPage {
+    pub items: Vec<PaymentTransaction>,
+    pub next_cursor: Option<u64>, // Pass back to fetch the next (older) page
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct InvoicePage {
+    pub items: Vec<PaymentInvoice>,
+    pub next_cursor: Option<u64>,
+}
+
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub struct PaymentAnalytics {
     pub total_transactions: u64,
@@ -169,6 +297,262 @@ pub struct PaymentAnalytics {
     pub top_tokens: Vec<String>,
 }
 
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum WithdrawalStatus {
+    Pending,
+    Completed,
+    Failed(String),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct WithdrawalRecord {
+    pub id: String,
+    pub token_symbol: String,
+    pub amount: u64, // Amount deducted from the internal balance
+    pub fee: u64, // Ledger fee paid out of `amount`
+    pub to: Principal,
+    pub to_subaccount: Option<Vec<u8>>,
+    pub status: WithdrawalStatus,
+    pub block_index: Option<u64>, // Block index of the ledger transfer, once completed
+    pub requested_by: Principal, // This canister's own id for automatic payouts
+    pub created_at: u64,
+    pub completed_at: Option<u64>,
+}
+
+impl Storable for WithdrawalRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct PayoutSettings {
+    pub destination: Option<Principal>, // Defaults to the owner
+    pub destination_subaccount: Option<Vec<u8>>,
+    pub cadence_seconds: Option<u64>, // Pay out on a schedule in addition to the threshold
+    pub paused: bool,
+    pub paused_reason: Option<String>,
+    pub consecutive_failures: u32,
+    pub last_payout_at: Option<u64>,
+}
+
+impl Storable for PayoutSettings {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum RefundStatus {
+    Pending,
+    Completed,
+    Failed(String),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct RefundRecord {
+    pub refund_id: String,
+    pub transaction_id: String,
+    pub invoice_id: Option<String>,
+    pub token_symbol: String,
+    pub amount: u64, // Amount returned to the payer
+    pub fee: u64, // Ledger fee, paid by the merchant on top of `amount`
+    pub to: Principal,
+    pub reason: Option<String>,
+    pub is_full_refund: bool, // True when this refund settles the rest of the transaction
+    pub status: RefundStatus,
+    pub block_index: Option<u64>,
+    pub created_at: u64,
+    pub completed_at: Option<u64>,
+}
+
+impl Storable for RefundRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct PlatformTreasury {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+impl Storable for PlatformTreasury {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum FeeSweepStatus {
+    Pending,
+    Completed,
+    Failed(String),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct FeeSweep {
+    pub id: String,
+    pub token_symbol: String,
+    pub amount: u64, // Accrued fees taken out of the pending pool
+    pub fee: u64, // Ledger fee paid out of `amount`
+    pub treasury: PlatformTreasury,
+    pub status: FeeSweepStatus,
+    pub block_index: Option<u64>,
+    pub created_at: u64,
+    pub completed_at: Option<u64>,
+}
+
+impl Storable for FeeSweep {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct FeeRevenue {
+    pub token_symbol: String,
+    pub accrued: u64, // All fees charged so far
+    pub pending: u64, // Waiting for the next sweep
+    pub swept: u64, // Delivered to the treasury, ledger fees included
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct TokenReconciliation {
+    pub token_symbol: String,
+    pub ledger_balance: Option<u64>, // None when the ledger could not be queried
+    pub internal_balance: u64,
+    pub pending_withdrawals: u64,
+    pub pending_refunds: u64,
+    pub retained_fees: u64, // Merchant fees kept in the canister account
+    pub difference: i64, // Ledger balance minus everything the canister accounts for
+    pub error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct BlockDiscrepancy {
+    pub transaction_id: String,
+    pub block_index: u64,
+    pub issue: String,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ReconciliationReport {
+    pub generated_at: u64,
+    pub tokens: Vec<TokenReconciliation>,
+    pub block_discrepancies: Vec<BlockDiscrepancy>,
+    pub transactions_checked: u32,
+}
+
+impl Storable for ReconciliationReport {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum WebhookEventType {
+    InvoicePaid,
+    PaymentFailed,
+    Refunded,
+    SubscriptionUpdated,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct WebhookEvent {
+    pub event_id: String,
+    pub event_type: WebhookEventType,
+    pub payload: String, // JSON body sent to every endpoint
+    pub created_at: u64,
+}
+
+impl Storable for WebhookEvent {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum WebhookDeliveryStatus {
+    Pending,
+    Delivered,
+    Failed, // Gave up after the maximum number of attempts
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct WebhookAttempt {
+    pub attempted_at: u64,
+    pub status_code: Option<u16>, // None when the outcall itself failed
+    pub error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct WebhookDelivery {
+    pub delivery_id: String,
+    pub event_id: String,
+    pub url: String,
+    pub status: WebhookDeliveryStatus,
+    pub attempts: Vec<WebhookAttempt>,
+    pub next_attempt_at: u64,
+    pub created_at: u64,
+    pub delivered_at: Option<u64>,
+}
+
+impl Storable for WebhookDelivery {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
 // ============================================================================
 // MODAL BUILDER FEATURE STRUCTURES
 // ============================================================================
@@ -279,6 +663,34 @@ pub struct DiscountCoupon {
     pub is_active: bool,
     pub created_at: u64,
     pub updated_at: u64,
+    pub per_customer_limit: Option<u32>, // Max uses per principal, None = unlimited
+    pub first_purchase_only: Option<bool>, // Only for principals without a completed payment
+    pub applicable_products: Option<Vec<String>>, // Restricts the discount to these products
+    pub applicable_categories: Option<Vec<String>>, // ... and to products in these categories
+    pub batch_id: Option<String>, // Set on codes generated in bulk from a template coupon
+}
+
+// Single-use codes generated from a template coupon
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct CouponBatch {
+    pub batch_id: String,
+    pub template_coupon_id: String,
+    pub prefix: String,
+    pub coupon_ids: Vec<String>,
+    pub created_at: u64,
+    pub deactivated_at: Option<u64>,
+}
+
+impl Storable for CouponBatch {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
 }
 
 impl Storable for DiscountCoupon {
@@ -301,6 +713,13 @@ pub struct CouponUsage {
     pub invoice_id: String,
     pub discount_applied: u64,
     pub used_at: u64,
+    pub status: Option<CouponUsageStatus>, // None for uses recorded before reservations, counted as committed
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum CouponUsageStatus {
+    Reserved, // Attached to an unpaid invoice
+    Committed, // The invoice was paid
 }
 
 impl Storable for CouponUsage {
@@ -340,10 +759,48 @@ pub struct Product {
     pub metadata: Vec<(String, String)>, // Custom metadata key-value pairs
     pub created_at: u64,
     pub updated_at: u64,
+    pub reserved_count: Option<u32>, // Units held by unpaid invoices, maintained by the canister
+    pub prices: Option<Vec<TokenPrice>>, // Prices in other tokens, `price` stays the price in `token_symbol`
+    pub variants: Option<Vec<ProductVariant>>, // Stock is tracked per variant when set
 }
 
-impl Storable for Product {
-    fn to_bytes(&self) -> Cow<[u8]> {
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct TokenPrice {
+    pub token_symbol: String,
+    pub amount: u64, // In the token's smallest unit
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ProductVariant {
+    pub sku: String,
+    pub name: String, // e.g. "Large / Blue"
+    pub options: Vec<(String, String)>, // e.g. ("size", "L")
+    pub prices: Vec<TokenPrice>, // Empty means the product's prices apply
+    pub inventory_count: Option<u32>, // None = unlimited inventory
+    pub reserved_count: Option<u32>, // Maintained by the canister
+    pub image_url: Option<String>,
+    pub status: ProductStatus,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ReservedItem {
+    pub product_id: String,
+    pub variant_sku: Option<String>,
+    pub quantity: u32,
+}
+
+// Units held for an unpaid invoice until it is paid, cancelled or the hold runs out
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct InventoryReservation {
+    pub invoice_id: String,
+    pub items: Vec<ReservedItem>,
+    pub created_at: u64,
+    pub holder: Option<Principal>, // Counted against this principal's cap on held units
+    pub expires_at: Option<u64>, // Independent of the invoice expiry, paying renews the hold
+}
+
+impl Storable for InventoryReservation {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -355,16 +812,24 @@ impl Storable for Product {
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
-pub struct ProductSalesStats {
+pub enum DeliverableKind {
+    LicenseKeys, // One key from the product's pool per unit sold
+    DownloadToken { base_url: String, valid_for_seconds: u64 },
+    EncryptedContent { content_ref: String, key_ref: String }, // Same reference for every buyer
+}
+
+// What a buyer receives once an invoice for the product is paid
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ProductDeliverable {
     pub product_id: String,
-    pub total_sales: u64,
-    pub total_revenue: u64,
-    pub units_sold: u32,
-    pub last_sale_at: Option<u64>,
+    pub kind: DeliverableKind,
+    pub instructions: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
 }
 
-impl Storable for ProductSalesStats {
-    fn to_bytes(&self) -> Cow<[u8]> {
+impl Storable for ProductDeliverable {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -375,12 +840,196 @@ impl Storable for ProductSalesStats {
     const BOUND: Bound = Bound::Unbounded;
 }
 
-// ============================================================================
-// SUBSCRIPTION MANAGEMENT SYSTEM STRUCTURES
-// ============================================================================
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct LicenseKeyPool {
+    pub available: Vec<String>,
+    pub issued_count: u64,
+}
 
-#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
-pub enum SubscriptionStatus {
+impl Storable for LicenseKeyPool {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum DeliveryContent {
+    LicenseKey(String),
+    DownloadToken { url: String, token: String, expires_at: u64 },
+    EncryptedContent { content_ref: String, key_ref: String },
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum DeliveryStatus {
+    Pending, // Waiting for license keys to be added or for the download token seed
+    Issued,
+    Revoked,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Delivery {
+    pub delivery_id: String,
+    pub invoice_id: String,
+    pub product_id: String,
+    pub variant_sku: Option<String>,
+    pub buyer: Principal,
+    pub status: DeliveryStatus,
+    pub content: Option<DeliveryContent>, // Only returned to the buyer while issued
+    pub instructions: Option<String>,
+    pub created_at: u64,
+    pub issued_at: Option<u64>,
+    pub revoked_at: Option<u64>,
+}
+
+impl Storable for Delivery {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Product {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ProductSalesStats {
+    pub product_id: String,
+    pub total_sales: u64,
+    pub total_revenue: u64, // In the product's own token only, see `revenue_by_token`
+    pub units_sold: u32,
+    pub last_sale_at: Option<u64>,
+    pub revenue_by_token: Option<Vec<(String, u64)>>,
+    pub variant_stats: Option<Vec<VariantSalesStats>>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct VariantSalesStats {
+    pub variant_sku: String,
+    pub total_sales: u64,
+    pub units_sold: u32,
+    pub revenue_by_token: Vec<(String, u64)>,
+}
+
+impl Storable for ProductSalesStats {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum OrderStatus {
+    Pending, // Waiting for the invoice to be paid
+    Paid,
+    Shipped,
+    Delivered,
+    Cancelled,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct OrderItem {
+    pub product_id: String,
+    pub variant_sku: Option<String>,
+    pub product_name: String,
+    pub quantity: u32,
+    pub unit_price: u64, // Catalog price when the order was placed
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Order {
+    pub order_id: String,
+    pub customer: Principal,
+    pub items: Vec<OrderItem>,
+    pub invoice_id: String,
+    pub token_symbol: String,
+    pub coupon_id: Option<String>,
+    pub discount_applied: u64,
+    pub total_amount: u64,
+    pub status: OrderStatus,
+    pub tracking_reference: Option<String>,
+    pub metadata: Vec<(String, String)>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Storable for Order {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct OrderItemRequest {
+    pub product_id: String,
+    pub variant_sku: Option<String>,
+    pub quantity: u32,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct CreateOrderRequest {
+    pub items: Vec<OrderItemRequest>,
+    pub token_symbol: Option<String>, // Defaults to the first product's token
+    pub coupon_code: Option<String>,
+    pub metadata: Vec<(String, String)>,
+    pub expires_at: Option<u64>,
+}
+
+// Merchant-controlled charges added to every order
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct OrderSettings {
+    pub tax_rate_bps: Option<u32>,
+    pub shipping_fees: Vec<(String, u64)>, // Flat shipping charge per token symbol
+}
+
+impl Storable for OrderSettings {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// ============================================================================
+// SUBSCRIPTION MANAGEMENT SYSTEM STRUCTURES
+// ============================================================================
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum SubscriptionStatus {
     Active,
     Paused,
     Cancelled,
@@ -412,6 +1061,47 @@ pub struct SubscriptionPlan {
     pub is_active: bool,
     pub created_at: u64,
     pub updated_at: u64,
+    pub dunning_policy: Option<DunningPolicy>, // Retry schedule for failed charges, None = default policy
+    pub billing_day: Option<u8>, // Fixed day of month (1-31) new subscriptions renew on, clamped to month end
+    pub usage_pricing: Option<UsagePricing>, // Metered usage billed in arrears on top of `price`
+    pub self_service: Option<SelfServicePolicy>, // What subscribers may do themselves, None = default policy
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct SelfServicePolicy {
+    pub allow_immediate_cancel: bool, // Otherwise subscribers can only cancel at the end of the period
+    pub allow_pause: bool,
+    pub max_pause_days: Option<u32>, // Longest pause a subscriber can request, None = until resumed
+    pub max_pauses: Option<u32>, // Pauses a subscriber can request over the subscription's lifetime
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct UsageTier {
+    pub up_to: Option<u64>, // Inclusive upper bound in units, None = no bound (last tier only)
+    pub unit_price: u64,
+    pub flat_fee: u64, // Charged once when usage reaches the tier
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum UsagePricing {
+    PerUnit { unit_price: u64 },
+    Graduated(Vec<UsageTier>), // Each tier prices the units that fall inside it
+    Volume(Vec<UsageTier>), // The tier the period total falls in prices every unit
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum DunningFinalAction {
+    Pause,
+    Cancel,
+    Expire,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct DunningPolicy {
+    pub retry_offsets_seconds: Vec<u64>, // Retry delays after the missed billing date, one per retry
+    pub max_attempts: u32, // Total charge attempts, including the first one
+    pub grace_period_seconds: u64, // How long an active subscription stays active after a missed charge
+    pub final_action: DunningFinalAction, // Applied once all attempts have failed
 }
 
 impl Storable for SubscriptionPlan {
@@ -440,9 +1130,53 @@ pub struct Subscription {
     pub cancel_at_period_end: bool, // If true, cancel at end of current period
     pub total_payments: u64, // Total amount paid over lifetime
     pub payment_failures: u32, // Number of consecutive payment failures
+    pub next_retry_at: Option<u64>, // Scheduled retry after a failed charge
     pub metadata: Vec<(String, String)>, // Custom metadata key-value pairs
     pub created_at: u64,
     pub updated_at: u64,
+    pub proration_credit: Option<u64>, // Left over from downgrades, deducted from upcoming charges
+    pub scheduled_plan_change: Option<PlanChange>, // Applied with the next renewal charge
+    pub plan_changes: Option<Vec<PlanChange>>, // Applied plan changes, oldest first
+    pub billing_anchor_day: Option<u8>, // Day of month calendar intervals renew on, None = day of the previous renewal
+    pub paused_at: Option<u64>,
+    pub resume_at: Option<u64>, // Automatic resume date of a pause
+    pub paused_by: Option<Principal>, // None when the dunning policy paused the subscription
+    pub pause_count: Option<u32>, // Pauses requested by the subscriber
+}
+
+// Breakdown of the next charge, as the billing engine will make it
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct UpcomingCharge {
+    pub subscription_id: String,
+    pub plan_id: String, // Plan the charge is billed under, includes scheduled plan changes
+    pub token: String,
+    pub charge_at: u64,
+    pub period_start: u64,
+    pub period_end: u64,
+    pub base_amount: u64, // Plan price for the period
+    pub usage_quantity: Option<u64>, // Unbilled metered units
+    pub usage_amount: Option<u64>,
+    pub credit_applied: u64,
+    pub amount: u64, // Total that will be pulled from the subscriber
+    pub final_charge: bool, // The subscription ends at period end, only usage is still owed
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum ProrationMode {
+    Immediate, // Switch now and settle the prorated difference right away
+    NextRenewal, // Switch when the current period ends, nothing is prorated
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct PlanChange {
+    pub from_plan_id: String,
+    pub to_plan_id: String,
+    pub mode: ProrationMode,
+    pub credit: u64, // Unused value of the old plan for the rest of the period
+    pub charge: u64, // Cost of the new plan for the rest of the period
+    pub payment_id: Option<String>, // Subscription payment that settled a positive difference
+    pub requested_at: u64,
+    pub effective_at: u64,
 }
 
 impl Storable for Subscription {
@@ -469,6 +1203,41 @@ pub struct SubscriptionPayment {
     pub status: String, // "paid", "failed", "pending"
     pub transaction_id: Option<String>, // Link to actual payment transaction
     pub failure_reason: Option<String>, // If payment failed, why?
+    pub attempt: Option<u32>, // Charge attempt number for the billing period, starting at 1
+    pub usage_quantity: Option<u64>, // Metered units billed with this payment
+    pub usage_amount: Option<u64>, // Part of `amount` charged for metered units
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct UsageRecord {
+    pub record_id: String,
+    pub subscription_id: String,
+    pub quantity: u64,
+    pub timestamp: u64,
+    pub reported_by: Principal,
+    pub idempotency_key: Option<String>, // Repeated reports with the same key are recorded once
+    pub payment_id: Option<String>, // Subscription payment that billed this record
+    pub usage_pricing: Option<UsagePricing>, // Pricing of the plan the usage was recorded under
+}
+
+impl Storable for UsageRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct UsageSummary {
+    pub subscription_id: String,
+    pub quantity: u64, // Unbilled units
+    pub estimated_amount: u64, // Usage charge for those units at the current plan's pricing
+    pub billed_at: u64, // Next billing date, when the usage will be charged
 }
 
 impl Storable for SubscriptionPayment {
@@ -653,6 +1422,9 @@ thread_local! {
                 ],
                 created_at: 0,
                 updated_at: 0,
+                reserved_count: None,
+                prices: None,
+                variants: None,
             };
             
             map.insert("product_1".to_string(), default_product);
@@ -671,6 +1443,8 @@ thread_local! {
                 total_revenue: 0,
                 units_sold: 0,
                 last_sale_at: None,
+                revenue_by_token: None,
+                variant_stats: None,
             };
             
             map.insert("product_1".to_string(), default_stats);
@@ -681,100 +1455,436 @@ thread_local! {
     static NEXT_PRODUCT_ID: RefCell<Cell<u64, Memory>> = RefCell::new(
         Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))), 2u64).unwrap()
     );
-}
 
-// ============================================================================
-// INITIALIZATION
-// ============================================================================
+    // Withdrawal history storage (MemoryId 20, 21)
+    static WITHDRAWALS: RefCell<StableBTreeMap<String, WithdrawalRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))))
+    );
 
-#[ic_cdk::init]
-fn init(config: UserCanisterConfig, owner: Principal) {
-    CONFIG.with(|c| c.borrow_mut().set(config).unwrap());
-    OWNER.with(|o| o.borrow_mut().set(owner).unwrap());
-}
+    static NEXT_WITHDRAWAL_ID: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))), 1u64).unwrap()
+    );
 
-#[ic_cdk::post_upgrade]
-fn post_upgrade() {
-    // After upgrade, stable storage is automatically restored
-    // This hook ensures all memory managers and storage are properly initialized
-}
+    // Refund history storage (MemoryId 22, 23)
+    static REFUNDS: RefCell<StableBTreeMap<String, RefundRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))))
+    );
 
-// ============================================================================
-// CONFIGURATION MANAGEMENT
-// ============================================================================
+    static NEXT_REFUND_ID: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))), 1u64).unwrap()
+    );
 
-#[ic_cdk::update]
-fn update_configuration(new_config: UserCanisterConfig) -> Result<(), String> {
-    let caller = ic_cdk::caller();
-    let owner = OWNER.with(|o| *o.borrow().get());
-    
-    if caller != owner {
-        return Err("Only the owner can update configuration".to_string());
-    }
+    // Webhook delivery storage (MemoryId 24, 25, 26, 27)
+    static WEBHOOK_EVENTS: RefCell<StableBTreeMap<String, WebhookEvent, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))))
+    );
 
-    CONFIG.with(|c| c.borrow_mut().set(new_config).unwrap());
-    Ok(())
-}
+    static WEBHOOK_DELIVERIES: RefCell<StableBTreeMap<String, WebhookDelivery, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))))
+    );
 
-#[ic_cdk::query]
-fn get_configuration() -> UserCanisterConfig {
-    CONFIG.with(|c| c.borrow().get().clone())
-}
+    static NEXT_WEBHOOK_EVENT_ID: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))), 1u64).unwrap()
+    );
 
-#[ic_cdk::query]
-fn get_supported_tokens() -> Vec<TokenConfig> {
-    CONFIG.with(|c| c.borrow().get().supported_tokens.clone())
-}
+    static WEBHOOK_SECRET: RefCell<Cell<String, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))), String::new()).unwrap()
+    );
 
-#[ic_cdk::update]
-fn add_supported_token(token: TokenConfig) -> Result<(), String> {
-    let caller = ic_cdk::caller();
-    let owner = OWNER.with(|o| *o.borrow().get());
-    
-    if caller != owner {
-        return Err("Only the owner can add tokens".to_string());
-    }
+    // Exchange rate source for fiat invoices (MemoryId 28), replaceable with a local mock
+    static EXCHANGE_RATE_CANISTER: RefCell<Cell<Principal, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))),
+            Principal::from_text(DEFAULT_EXCHANGE_RATE_CANISTER).unwrap()
+        ).unwrap()
+    );
 
-    // Validate token configuration
-    if token.symbol.is_empty() {
-        return Err("Token symbol cannot be empty".to_string());
-    }
-    if token.name.is_empty() {
-        return Err("Token name cannot be empty".to_string());
-    }
-    if token.canister_id == Principal::anonymous() {
-        return Err("Token canister ID cannot be anonymous".to_string());
-    }
+    // Idempotency key index, "<caller>:<key>" -> transaction id (MemoryId 29)
+    static IDEMPOTENCY_KEYS: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))))
+    );
 
-    CONFIG.with(|c| {
-        let mut config = c.borrow().get().clone();
-        
-        // Check if token already exists (by symbol or canister_id)
-        if config.supported_tokens.iter().any(|t| t.symbol == token.symbol || t.canister_id == token.canister_id) {
-            return Err("Token with this symbol or canister ID already exists".to_string());
-        }
-        
-        // Add the new token
-        config.supported_tokens.push(token);
-        c.borrow_mut().set(config).unwrap();
-        Ok(())
-    })
-}
+    // Reconciliation reports keyed by generation time (MemoryId 30)
+    static RECONCILIATION_REPORTS: RefCell<StableBTreeMap<u64, ReconciliationReport, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))))
+    );
 
-#[ic_cdk::update]
-fn remove_supported_token(token_symbol: String) -> Result<(), String> {
-    let caller = ic_cdk::caller();
-    let owner = OWNER.with(|o| *o.borrow().get());
-    
-    if caller != owner {
-        return Err("Only the owner can remove tokens".to_string());
-    }
+    // Automatic payout settings and failure tracking (MemoryId 31)
+    static PAYOUT_SETTINGS: RefCell<Cell<PayoutSettings, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31))), PayoutSettings::default()).unwrap()
+    );
 
-    CONFIG.with(|c| {
-        let mut config = c.borrow().get().clone();
-        
-        let initial_len = config.supported_tokens.len();
-        config.supported_tokens.retain(|t| t.symbol != token_symbol);
+    // Platform fee collection (MemoryId 32, 33, 34, 35, 36)
+    static FACTORY: RefCell<Cell<Principal, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32))), Principal::anonymous()).unwrap()
+    );
+
+    static PLATFORM_TREASURY: RefCell<Cell<Option<PlatformTreasury>, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33))), None).unwrap()
+    );
+
+    static PENDING_FEES: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34))))
+    );
+
+    static FEE_SWEEPS: RefCell<StableBTreeMap<String, FeeSweep, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35))))
+    );
+
+    static NEXT_FEE_SWEEP_ID: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36))), 1u64).unwrap()
+    );
+
+    // Default lifetime of new invoices in seconds (MemoryId 37)
+    static INVOICE_TTL_SECONDS: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37))), DEFAULT_INVOICE_TTL_SECONDS).unwrap()
+    );
+
+    // Listing indexes, the numeric part of "inv_N" / "tx_N" -> id (MemoryId 38, 39, 40)
+    static INVOICE_INDEX: RefCell<StableBTreeMap<u64, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38))))
+    );
+
+    static TRANSACTION_INDEX: RefCell<StableBTreeMap<u64, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39))))
+    );
+
+    static TRANSACTIONS_BY_PAYER: RefCell<StableBTreeMap<(Principal, u64), String, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40))))
+    );
+
+    // Orders (MemoryId 41, 42, 43)
+    static ORDERS: RefCell<StableBTreeMap<String, Order, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41))))
+    );
+
+    static NEXT_ORDER_ID: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42))), 1u64).unwrap()
+    );
+
+    static ORDER_SETTINGS: RefCell<Cell<OrderSettings, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43))), OrderSettings::default()).unwrap()
+    );
+
+    // Inventory held by unpaid invoices, keyed by invoice id (MemoryId 44)
+    static INVENTORY_RESERVATIONS: RefCell<StableBTreeMap<String, InventoryReservation, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44))))
+    );
+
+    // Digital goods delivery (MemoryId 45, 46, 47, 48, 49)
+    static DELIVERABLES: RefCell<StableBTreeMap<String, ProductDeliverable, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45))))
+    );
+
+    static LICENSE_KEY_POOLS: RefCell<StableBTreeMap<String, LicenseKeyPool, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46))))
+    );
+
+    static DELIVERIES: RefCell<StableBTreeMap<String, Delivery, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47))))
+    );
+
+    static NEXT_DELIVERY_ID: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48))), 1u64).unwrap()
+    );
+
+    // Random bytes from the management canister, download tokens are derived from them
+    static DELIVERY_SEED: RefCell<Cell<Vec<u8>, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49))), Vec::new()).unwrap()
+    );
+
+    // Uppercase coupon code -> coupon id, enforces unique codes (MemoryId 50)
+    static COUPON_CODE_INDEX: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50))))
+    );
+
+    // Bulk generated coupon codes (MemoryId 51, 52)
+    static COUPON_BATCHES: RefCell<StableBTreeMap<String, CouponBatch, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51))))
+    );
+
+    static NEXT_COUPON_BATCH_ID: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52))), 1u64).unwrap()
+    );
+
+    // Metered usage (MemoryId 53 - 55)
+    static USAGE_REPORTERS: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53))))
+    );
+
+    // Keyed "<subscription_id>:<timestamp>:<record_id>" so a subscription's usage is read in order
+    static USAGE_RECORDS: RefCell<StableBTreeMap<String, UsageRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54))))
+    );
+
+    static NEXT_USAGE_RECORD_ID: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(55))), 1u64).unwrap()
+    );
+
+    // Owner-set dev flag allowing plain http webhook endpoints for local testing (MemoryId 56)
+    static WEBHOOK_ALLOW_HTTP: RefCell<Cell<bool, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56))), false).unwrap()
+    );
+
+    // Secondary listing indexes, (value, sequence) -> id (MemoryId 57 - 63). Token
+    // indexes use "<symbol>:<zero-padded sequence>" keys
+    static INVOICES_BY_PAYER: RefCell<StableBTreeMap<(Principal, u64), String, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57))))
+    );
+
+    static INVOICES_BY_STATUS: RefCell<StableBTreeMap<(u8, u64), String, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(58))))
+    );
+
+    static INVOICES_BY_TOKEN: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(59))))
+    );
+
+    static INVOICES_BY_TIME: RefCell<StableBTreeMap<(u64, u64), String, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(60))))
+    );
+
+    static TRANSACTIONS_BY_STATUS: RefCell<StableBTreeMap<(u8, u64), String, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(61))))
+    );
+
+    static TRANSACTIONS_BY_TOKEN: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(62))))
+    );
+
+    static TRANSACTIONS_BY_TIME: RefCell<StableBTreeMap<(u64, u64), String, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(63))))
+    );
+
+    // Download token -> delivery id (MemoryId 64)
+    static DOWNLOAD_TOKEN_INDEX: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(64))))
+    );
+
+    // Coupon usage indexes (MemoryId 65 - 67): "<coupon_id>:<principal>" -> number of uses,
+    // invoice id -> usage id, reserved usage id -> end of its hold
+    static COUPON_CUSTOMER_USES: RefCell<StableBTreeMap<String, u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(65))))
+    );
+
+    static INVOICE_COUPON_USAGE: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(66))))
+    );
+
+    static COUPON_HOLDS: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(67))))
+    );
+
+    // "<subscription_id>:<idempotency_key>" -> usage record key (MemoryId 68)
+    static USAGE_IDEMPOTENCY_INDEX: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(68))))
+    );
+
+    // Pending webhook deliveries keyed "<next_attempt_at>:<delivery_id>" (MemoryId 69)
+    static WEBHOOK_QUEUE: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(69))))
+    );
+
+    // Number of the oldest webhook event not pruned yet (MemoryId 70)
+    static WEBHOOK_PRUNE_CURSOR: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(70))), 1u64).unwrap()
+    );
+}
+
+// Heap-only state, starts empty again after every upgrade
+thread_local! {
+    // Keys of operations currently awaiting a ledger call
+    static IN_FLIGHT: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+
+    // Recently fetched rates per (base asset, fiat currency) with their fetch time
+    static EXCHANGE_RATE_CACHE: RefCell<HashMap<(String, String), (u64, ExchangeRate)>> = RefCell::new(HashMap::new());
+
+    // Times of uncached rate fetches triggered by non-owner callers
+    static PUBLIC_RATE_FETCHES: RefCell<Vec<u64>> = RefCell::new(Vec::new());
+
+    // Sequence of the open invoice the next expiry run starts from
+    static EXPIRY_SCAN_CURSOR: RefCell<u64> = RefCell::new(0);
+
+    // Times of payment.failed events queued recently
+    static PAYMENT_FAILED_EVENTS: RefCell<Vec<u64>> = RefCell::new(Vec::new());
+}
+
+// Marks an operation as in flight until the guard is dropped, on every return path
+struct InFlightGuard {
+    key: String,
+}
+
+impl InFlightGuard {
+    fn acquire(key: String) -> Result<Self, String> {
+        let inserted = IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(key.clone()));
+        if !inserted {
+            return Err("Another operation for this item is already in progress".to_string());
+        }
+        Ok(InFlightGuard { key })
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&self.key));
+    }
+}
+
+fn is_in_flight(key: &str) -> bool {
+    IN_FLIGHT.with(|in_flight| in_flight.borrow().contains(key))
+}
+
+// ============================================================================
+// INITIALIZATION
+// ============================================================================
+
+#[ic_cdk::init]
+fn init(config: UserCanisterConfig, owner: Principal) {
+    CONFIG.with(|c| c.borrow_mut().set(config).unwrap());
+    OWNER.with(|o| o.borrow_mut().set(owner).unwrap());
+    // The installer is the factory, it registers the platform treasury after install
+    FACTORY.with(|f| f.borrow_mut().set(ic_cdk::caller()).unwrap());
+    start_timers();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade(factory: Option<Principal>) {
+    // After upgrade, stable storage is automatically restored
+    // This hook ensures all memory managers and storage are properly initialized
+    // Canisters deployed before fee collection learn their factory from the upgrade
+    // argument, upgrades without one leave the recorded factory unchanged
+    if let Some(factory) = factory {
+        FACTORY.with(|f| f.borrow_mut().set(factory).unwrap());
+    }
+    // Fee rates set before the factory controlled them are brought back into range
+    CONFIG.with(|c| {
+        let mut config = c.borrow().get().clone();
+        if config.merchant_fee > MAX_MERCHANT_FEE_BPS {
+            config.merchant_fee = MAX_MERCHANT_FEE_BPS;
+            c.borrow_mut().set(config).unwrap();
+        }
+    });
+    rebuild_list_indexes();
+    rebuild_coupon_code_index();
+    rebuild_coupon_usage_indexes();
+    rebuild_download_token_index();
+    rebuild_webhook_queue();
+    backfill_billing_anchors();
+    rekey_usage_records();
+    // Timers do not survive upgrades and have to be registered again
+    start_timers();
+}
+
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(BILLING_CHECK_INTERVAL, || {
+        ic_cdk::spawn(run_billing_cycle())
+    });
+    ic_cdk_timers::set_timer_interval(WEBHOOK_RETRY_INTERVAL, || {
+        ic_cdk::spawn(process_webhook_queue())
+    });
+    ic_cdk_timers::set_timer_interval(PAYOUT_CHECK_INTERVAL, || {
+        ic_cdk::spawn(run_auto_payouts())
+    });
+    ic_cdk_timers::set_timer_interval(FEE_SWEEP_INTERVAL, || {
+        ic_cdk::spawn(sweep_platform_fees())
+    });
+    ic_cdk_timers::set_timer_interval(INVOICE_EXPIRY_INTERVAL, || expire_overdue_invoices(ic_cdk::api::time()));
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(init_delivery_seed()));
+    ic_cdk_timers::set_timer_interval(RECONCILIATION_INTERVAL, || {
+        ic_cdk::spawn(async {
+            if let Err(err) = reconcile().await {
+                ic_cdk::println!("Reconciliation skipped: {}", err);
+            }
+        })
+    });
+}
+
+// ============================================================================
+// CONFIGURATION MANAGEMENT
+// ============================================================================
+
+#[ic_cdk::update]
+fn update_configuration(new_config: UserCanisterConfig) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can update configuration".to_string());
+    }
+
+    if let Some(url) = &new_config.webhook {
+        validate_webhook_url(url)?;
+    }
+
+    // The platform fee rate is the factory's to set
+    let merchant_fee = CONFIG.with(|c| c.borrow().get().merchant_fee);
+    if new_config.merchant_fee != merchant_fee {
+        return Err("The merchant fee can only be changed by the platform".to_string());
+    }
+
+    CONFIG.with(|c| c.borrow_mut().set(new_config).unwrap());
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_configuration() -> UserCanisterConfig {
+    CONFIG.with(|c| c.borrow().get().clone())
+}
+
+#[ic_cdk::query]
+fn get_supported_tokens() -> Vec<TokenConfig> {
+    CONFIG.with(|c| c.borrow().get().supported_tokens.clone())
+}
+
+#[ic_cdk::update]
+fn add_supported_token(token: TokenConfig) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can add tokens".to_string());
+    }
+
+    // Validate token configuration
+    if token.symbol.is_empty() {
+        return Err("Token symbol cannot be empty".to_string());
+    }
+    if token.name.is_empty() {
+        return Err("Token name cannot be empty".to_string());
+    }
+    if token.canister_id == Principal::anonymous() {
+        return Err("Token canister ID cannot be anonymous".to_string());
+    }
+
+    CONFIG.with(|c| {
+        let mut config = c.borrow().get().clone();
+        
+        // Check if token already exists (by symbol or canister_id)
+        if config.supported_tokens.iter().any(|t| t.symbol == token.symbol || t.canister_id == token.canister_id) {
+            return Err("Token with this symbol or canister ID already exists".to_string());
+        }
+        
+        // Add the new token
+        config.supported_tokens.push(token);
+        c.borrow_mut().set(config).unwrap();
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn remove_supported_token(token_symbol: String) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can remove tokens".to_string());
+    }
+
+    CONFIG.with(|c| {
+        let mut config = c.borrow().get().clone();
+        
+        let initial_len = config.supported_tokens.len();
+        config.supported_tokens.retain(|t| t.symbol != token_symbol);
         
         if config.supported_tokens.len() == initial_len {
             return Err("Token not found".to_string());
@@ -869,13 +1979,27 @@ fn toggle_token_status(token_symbol: String) -> Result<bool, String> {
 // PAYMENT PROCESSING
 // ============================================================================
 
+// The creator is recorded as the expected payer, anonymous invoices have none
+// and can only be confirmed by the owner
+fn expected_invoice_payer() -> Option<Principal> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        None
+    } else {
+        Some(caller)
+    }
+}
+
 #[ic_cdk::update]
 fn create_invoice(
     amount: u64,
     token_symbol: String,
     description: String,
-    metadata: Vec<(String, String)>
+    metadata: Vec<(String, String)>,
+    expires_at: Option<u64>,
 ) -> Result<PaymentInvoice, String> {
+    let expires_at = invoice_expiry(expires_at)?;
+    validate_caller_metadata(&metadata)?;
     let config = CONFIG.with(|c| c.borrow().get().clone());
     
     // Find the token configuration
@@ -899,12 +2023,20 @@ fn create_invoice(
         token,
         description,
         metadata,
-        expires_at: Some(ic_cdk::api::time() + (24 * 60 * 60 * 1_000_000_000)), // 24 hours
+        expires_at: Some(expires_at),
         created_at: ic_cdk::api::time(),
         status: InvoiceStatus::Created,
+        deposit_subaccount: Some(invoice_deposit_subaccount(&invoice_id).to_vec()),
+        fiat_amount: None,
+        quote: None,
+        line_items: None,
+        tax_rate_bps: None,
+        shipping: None,
+        breakdown: None,
+        payer: expected_invoice_payer(),
     };
 
-    INVOICES.with(|invoices| invoices.borrow_mut().insert(invoice_id, invoice.clone()));
+    store_invoice(&invoice);
     Ok(invoice)
 }
 
@@ -913,6 +2045,32 @@ fn create_invoice(
 async fn process_payment_request(payment_request: PaymentRequest) -> Result<PaymentResult, String> {
     let caller = ic_cdk::caller();
     let current_time = ic_cdk::api::time();
+    validate_caller_metadata(&payment_request.metadata)?;
+
+    // Held until this call returns, so concurrent payments for the same invoice
+    // cannot both reach the ledger
+    let _guard = InFlightGuard::acquire(format!("invoice:{}", payment_request.invoice_id))?;
+
+    // A retried request with a known idempotency key replays the original result,
+    // or re-submits the same ledger transfer if the outcome was never recorded
+    let previous_transaction = match &payment_request.idempotency_key {
+        Some(key) => {
+            if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LENGTH {
+                return Err(format!("Idempotency key must be 1-{} characters", MAX_IDEMPOTENCY_KEY_LENGTH));
+            }
+            find_idempotent_transaction(caller, key)
+        },
+        None => None,
+    };
+
+    if let Some(previous) = &previous_transaction {
+        if metadata_value(&previous.metadata, "invoice_id").as_deref() != Some(payment_request.invoice_id.as_str()) {
+            return Err("Idempotency key already used for another invoice".to_string());
+        }
+        if matches!(previous.status, TransactionStatus::Completed | TransactionStatus::Refunded) {
+            return Ok(payment_result_from_transaction(previous));
+        }
+    }
     
     // Get the invoice
     let mut invoice = INVOICES.with(|invoices| {
@@ -920,8 +2078,11 @@ async fn process_payment_request(payment_request: PaymentRequest) -> Result<Paym
     }).ok_or("Invoice not found")?;
 
     // Check if invoice is still valid
-    if let InvoiceStatus::Paid = invoice.status {
-        return Err("Invoice already paid".to_string());
+    match invoice.status {
+        InvoiceStatus::Paid => return Err("Invoice already paid".to_string()),
+        InvoiceStatus::Cancelled => return Err("Invoice cancelled".to_string()),
+        InvoiceStatus::Expired => return Err("Invoice expired".to_string()),
+        InvoiceStatus::Created => {},
     }
 
     if let Some(expires_at) = invoice.expires_at {
@@ -930,41 +2091,94 @@ async fn process_payment_request(payment_request: PaymentRequest) -> Result<Paym
         }
     }
 
+    check_invoice_quote(&invoice, current_time)?;
+    hold_invoice_inventory(&invoice, invoice.payer.unwrap_or(caller), current_time)?;
+
     // Validate token
     if invoice.token.symbol != payment_request.token_symbol {
         return Err("Token mismatch".to_string());
     }
 
-    let mut final_amount = payment_request.amount;
-    let mut discount_applied = 0u64;
-    let mut coupon_id: Option<String> = None;
-
-    // Process coupon if provided
-    if let Some(coupon_code) = payment_request.coupon_code {
-        match validate_and_use_coupon(coupon_code, payment_request.amount, payment_request.token_symbol.clone()) {
-            Ok((used_coupon_id, discount)) => {
-                discount_applied = discount;
-                final_amount = payment_request.amount.saturating_sub(discount);
-                coupon_id = Some(used_coupon_id);
+    // The payer submits the invoice amount, a coupon lowers what is actually charged
+    if payment_request.amount < invoice.amount {
+        return Err("Payment amount insufficient".to_string());
+    }
+
+    // Coupons are reserved on the invoice and only committed once the transfer succeeds.
+    // Order discounts are already part of the invoice amount.
+    let discount_in_invoice = metadata_value(&invoice.metadata, "coupon_id").is_some();
+    let mut reservation = if discount_in_invoice { None } else { invoice_coupon_usage(&invoice.id) };
+    let mut reserved_here = false;
+
+    if let Some(previous) = &previous_transaction {
+        // A retry is charged on the earlier attempt's terms, with the coupon it recorded
+        match metadata_value(&previous.metadata, "coupon_id") {
+            Some(coupon_id) if reservation.as_ref().map(|usage| &usage.coupon_id) != Some(&coupon_id) => {
+                if reservation.is_some() {
+                    return Err("Invoice has a different coupon than the earlier attempt".to_string());
+                }
+                let coupon = DISCOUNT_COUPONS.with(|coupons| coupons.borrow().get(&coupon_id))
+                    .ok_or("The coupon of the earlier attempt no longer exists")?;
+                let discount = metadata_value(&previous.metadata, "discount_applied")
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or(0);
+                reservation = Some(reserve_coupon(&coupon, &invoice.id, previous.from, discount));
+                reserved_here = true;
             },
-            Err(err) => {
-                // Log coupon error but don't fail the payment
-                ic_cdk::println!("Coupon validation failed: {}", err);
+            Some(_) => {},
+            None => {
+                // Applied after the earlier attempt, it would be redeemed without a discount
+                if reservation.take().is_some() {
+                    release_invoice_coupon(&invoice.id);
+                }
+            },
+        }
+    } else if let Some(coupon_code) = payment_request.coupon_code {
+        let reserved_code = reservation.as_ref()
+            .and_then(|usage| DISCOUNT_COUPONS.with(|coupons| coupons.borrow().get(&usage.coupon_id)))
+            .map(|coupon| coupon.code);
+
+        if reserved_code.as_deref() != Some(coupon_code.to_uppercase().as_str()) {
+            if discount_in_invoice || reservation.is_some() {
+                return Err("Invoice already has a coupon applied".to_string());
             }
+            let (coupon, discount) = evaluate_invoice_coupon(&coupon_code, &invoice, caller)?;
+            reservation = Some(reserve_coupon(&coupon, &invoice.id, caller, discount));
+            reserved_here = true;
         }
     }
 
-    // Validate final amount
-    if final_amount < invoice.amount {
-        return Err("Payment amount insufficient after discount".to_string());
-    }
-
-    // Generate transaction ID
-    let transaction_id = NEXT_TRANSACTION_ID.with(|id| {
-        let current = *id.borrow().get();
-        id.borrow_mut().set(current + 1).unwrap();
-        format!("tx_{}", current)
-    });
+    let (amount_paid, discount_applied, final_amount) = match &previous_transaction {
+        Some(previous) => {
+            let recorded = |key: &str| metadata_value(&previous.metadata, key).and_then(|value| value.parse().ok());
+            (recorded("original_amount").unwrap_or(previous.amount), recorded("discount_applied").unwrap_or(0), previous.amount)
+        },
+        None => {
+            let discount_applied = reservation.as_ref().map_or(0, |usage| usage.discount_applied.min(invoice.amount));
+            (payment_request.amount, discount_applied, payment_request.amount.saturating_sub(discount_applied))
+        },
+    };
+    let coupon_id = reservation.map(|usage| usage.coupon_id);
+
+    // Retries reuse the transaction id and ledger timestamp of the earlier attempt,
+    // so the ledger rejects a second transfer as a duplicate of the first
+    let (transaction_id, ledger_created_at) = match &previous_transaction {
+        Some(previous) => (
+            previous.id.clone(),
+            metadata_value(&previous.metadata, "ledger_created_at")
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(current_time),
+        ),
+        None => {
+            // Generate transaction ID
+            let transaction_id = NEXT_TRANSACTION_ID.with(|id| {
+                let current = *id.borrow().get();
+                id.borrow_mut().set(current + 1).unwrap();
+                format!("tx_{}", current)
+            });
+            (transaction_id, current_time)
+        },
+    };
 
     let owner = OWNER.with(|o| *o.borrow().get());
     let config = CONFIG.with(|c| c.borrow().get().clone());
@@ -973,32 +2187,24 @@ async fn process_payment_request(payment_request: PaymentRequest) -> Result<Paym
     let merchant_fee = (final_amount * config.merchant_fee as u64) / 10000;
     let net_amount = final_amount.saturating_sub(merchant_fee);
 
-    // Attempt transferFrom call
-    let transfer_result = transfer_from_token(
-        invoice.token.canister_id,
-        caller,
-        owner,
-        final_amount + invoice.token.fee, // Include token transfer fee
-    ).await;
-
-    let (status, block_index) = match transfer_result {
-        Ok(block_idx) => (TransactionStatus::Completed, Some(block_idx)),
-        Err(err) => {
-            ic_cdk::println!("Transfer failed: {}", err);
-            (TransactionStatus::Failed(err), None)
-        }
+    // Create transaction record with enhanced metadata, retries keep the earlier record's
+    let metadata = match &previous_transaction {
+        Some(previous) => previous.metadata.clone(),
+        None => {
+            let mut metadata = payment_request.metadata;
+            metadata.push(("invoice_id".to_string(), payment_request.invoice_id.clone()));
+            if let Some(cid) = coupon_id {
+                metadata.push(("coupon_id".to_string(), cid));
+                metadata.push(("discount_applied".to_string(), discount_applied.to_string()));
+            }
+            metadata.push(("original_amount".to_string(), amount_paid.to_string()));
+            metadata.push(("final_amount".to_string(), final_amount.to_string()));
+            metadata.push(("ledger_created_at".to_string(), ledger_created_at.to_string()));
+            metadata
+        },
     };
 
-    // Create transaction record with enhanced metadata
-    let mut metadata = payment_request.metadata;
-    if let Some(cid) = coupon_id {
-        metadata.push(("coupon_id".to_string(), cid));
-        metadata.push(("discount_applied".to_string(), discount_applied.to_string()));
-    }
-    metadata.push(("original_amount".to_string(), payment_request.amount.to_string()));
-    metadata.push(("final_amount".to_string(), final_amount.to_string()));
-
-    let transaction = PaymentTransaction {
+    let mut transaction = PaymentTransaction {
         id: transaction_id.clone(),
         from: caller,
         to: owner,
@@ -1007,43 +2213,80 @@ async fn process_payment_request(payment_request: PaymentRequest) -> Result<Paym
         fee: invoice.token.fee,
         merchant_fee,
         timestamp: current_time,
-        status: status.clone(),
+        status: TransactionStatus::Pending,
         metadata,
         payment_method: PaymentMethod::TransferFrom,
-        block_index,
+        block_index: None,
+        idempotency_key: payment_request.idempotency_key.clone(),
     };
 
-    // Only update invoice and balances if payment succeeded
-    if matches!(status, TransactionStatus::Completed) {
-        // Update invoice status
-        invoice.status = InvoiceStatus::Paid;
-        INVOICES.with(|invoices| invoices.borrow_mut().insert(payment_request.invoice_id, invoice.clone()));
-
-        // Update balance for successful payment
-        BALANCES.with(|balances| {
-            let mut map = balances.borrow_mut();
-            let current_balance = map.get(&invoice.token.symbol).unwrap_or(0);
-            map.insert(invoice.token.symbol.clone(), current_balance + net_amount);
+    // Record the pending transaction before the ledger call, so a retry after a
+    // lost response finds it and re-submits with the same memo and timestamp
+    store_transaction(&transaction);
+    if let Some(key) = &payment_request.idempotency_key {
+        IDEMPOTENCY_KEYS.with(|keys| {
+            keys.borrow_mut().insert(idempotency_index_key(caller, key), transaction_id.clone())
         });
+    }
+
+    // Attempt transferFrom call into this canister's account, the payer's
+    // allowance has to cover the amount plus the ledger fee
+    let transfer_result = transfer_from_token(
+        invoice.token.canister_id,
+        caller,
+        ic_cdk::id(),
+        final_amount,
+        Some(payment_memo(&transaction_id)),
+        ledger_created_at,
+    ).await;
 
-        // Track product sales if this is a product-based payment
-        if let Some(product_id) = invoice.metadata.iter().find(|(key, _)| key == "product_id").map(|(_, value)| value) {
-            update_product_sales_stats(product_id, final_amount);
+    let (status, block_index) = match transfer_result {
+        Ok(block_idx) => (TransactionStatus::Completed, Some(block_idx)),
+        Err(err) => {
+            ic_cdk::println!("Transfer failed: {}", err);
+            (TransactionStatus::Failed(err), None)
         }
+    };
+    transaction.status = status.clone();
+    transaction.block_index = block_index;
+
+    // A coupon entered with this payment is given back when the payment fails,
+    // one applied to the invoice beforehand stays reserved
+    if reserved_here && !matches!(status, TransactionStatus::Completed) {
+        release_invoice_coupon(&invoice.id);
+    }
 
-        // Track modal analytics if successful
-        track_payment_analytics(&invoice.token.symbol, final_amount);
+    // Only update invoice and balances if payment succeeded
+    if matches!(status, TransactionStatus::Completed) {
+        mark_invoice_paid(&mut invoice, final_amount, net_amount, transaction.from);
     }
 
     // Store transaction regardless of status for analytics
-    TRANSACTIONS.with(|transactions| {
-        transactions.borrow_mut().insert(transaction_id.clone(), transaction.clone())
-    });
+    store_transaction(&transaction);
+
+    match &transaction.status {
+        TransactionStatus::Completed => enqueue_webhook_event(
+            WebhookEventType::InvoicePaid,
+            json!({ "invoice": invoice, "transaction": transaction }),
+        ),
+        TransactionStatus::Failed(err) => {
+            // Anyone can fail a payment without an allowance, only failures that tell
+            // the merchant something are sent, within a budget per window
+            let reportable = invoice.payer == Some(caller) || !is_payer_side_failure(err);
+            if reportable && reserve_payment_failed_event(current_time).is_ok() {
+                enqueue_webhook_event(
+                    WebhookEventType::PaymentFailed,
+                    json!({ "invoice_id": invoice.id, "transaction": transaction }),
+                );
+            }
+        },
+        _ => {},
+    }
 
     // Return payment result
     Ok(PaymentResult {
         transaction_id,
-        amount_paid: payment_request.amount,
+        amount_paid,
         discount_applied,
         final_amount,
         block_index,
@@ -1051,6 +2294,97 @@ async fn process_payment_request(payment_request: PaymentRequest) -> Result<Paym
     })
 }
 
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 64;
+
+// Keys are scoped per caller so two payers cannot collide on the same key
+fn idempotency_index_key(caller: Principal, key: &str) -> String {
+    format!("{}:{}", caller.to_text(), key)
+}
+
+fn find_idempotent_transaction(caller: Principal, key: &str) -> Option<PaymentTransaction> {
+    let transaction_id = IDEMPOTENCY_KEYS.with(|keys| keys.borrow().get(&idempotency_index_key(caller, key)))?;
+    TRANSACTIONS.with(|transactions| transactions.borrow().get(&transaction_id))
+}
+
+// Ledger memo identifying the payment, fits the 32 byte memo limit of ICRC-1 ledgers
+fn payment_memo(transaction_id: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"payment:");
+    hasher.update(transaction_id.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+fn payment_result_from_transaction(transaction: &PaymentTransaction) -> PaymentResult {
+    let amount_paid = metadata_value(&transaction.metadata, "original_amount")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(transaction.amount);
+    let discount_applied = metadata_value(&transaction.metadata, "discount_applied")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
+
+    PaymentResult {
+        transaction_id: transaction.id.clone(),
+        amount_paid,
+        discount_applied,
+        final_amount: transaction.amount,
+        block_index: transaction.block_index,
+        payment_method: transaction.payment_method.clone(),
+    }
+}
+
+// A product line of a paid invoice and the amount booked for it in the sales stats
+struct SoldProductLine {
+    product_id: String,
+    variant_sku: Option<String>,
+    amount: u64,
+    units: u32,
+}
+
+// Every catalog line of an invoice is booked, caller metadata never counts as a sale
+fn sold_product_lines(invoice: &PaymentInvoice) -> Vec<SoldProductLine> {
+    invoice.line_items.iter()
+        .flatten()
+        .filter_map(|line| {
+            Some(SoldProductLine {
+                product_id: line.product_id.clone()?,
+                variant_sku: line.variant_sku.clone(),
+                amount: line_net_amount(line).unwrap_or(0),
+                units: line.quantity,
+            })
+        })
+        .collect()
+}
+
+// Mark the invoice paid and book the payment against balances and analytics
+fn mark_invoice_paid(invoice: &mut PaymentInvoice, final_amount: u64, net_amount: u64, payer: Principal) {
+    invoice.status = InvoiceStatus::Paid;
+    store_invoice(invoice);
+
+    // Update balance for successful payment, the merchant fee belongs to the platform
+    credit_balance(&invoice.token.symbol, net_amount);
+    accrue_platform_fee(&invoice.token.symbol, final_amount.saturating_sub(net_amount));
+
+    // Track product sales if this is a product-based payment
+    for line in sold_product_lines(invoice) {
+        update_product_sales_stats(
+            &line.product_id,
+            line.variant_sku.as_deref(),
+            &invoice.token.symbol,
+            line.amount,
+            line.units,
+        );
+    }
+
+    commit_inventory(invoice);
+    commit_invoice_coupon(&invoice.id);
+    issue_deliveries(invoice, payer);
+
+    update_order_for_invoice(invoice, OrderStatus::Paid);
+
+    // Track modal analytics if successful
+    track_payment_analytics(&invoice.token.symbol, final_amount);
+}
+
 // Legacy process_payment method for backwards compatibility
 #[ic_cdk::update]
 async fn process_payment(invoice_id: String, _from: Principal) -> Result<PaymentTransaction, String> {
@@ -1066,6 +2400,7 @@ async fn process_payment(invoice_id: String, _from: Principal) -> Result<Payment
         token_symbol: invoice.token.symbol,
         coupon_code: None,
         metadata: vec![],
+        idempotency_key: None,
     };
 
     // Process the payment
@@ -1089,1299 +2424,6921 @@ fn get_invoice(invoice_id: String) -> Option<PaymentInvoice> {
 }
 
 // ============================================================================
-// BALANCE AND WITHDRAWAL MANAGEMENT
+// ITEMIZED INVOICES
 // ============================================================================
 
-#[ic_cdk::query]
-fn get_balance(token_symbol: String) -> u64 {
-    BALANCES.with(|balances| balances.borrow().get(&token_symbol).unwrap_or(0))
+const MAX_LINE_ITEMS: usize = 100;
+const MAX_TAX_RATE_BPS: u32 = 10_000;
+
+fn line_net_amount(line: &LineItem) -> Result<u64, String> {
+    let gross = line.unit_price.checked_mul(line.quantity as u64)
+        .ok_or("Line amount overflow")?;
+    gross.checked_sub(line.discount)
+        .ok_or_else(|| format!("Discount on '{}' exceeds the line amount", line.description))
 }
 
-#[ic_cdk::query]
-fn get_all_balances() -> Vec<(String, u64)> {
-    BALANCES.with(|balances| balances.borrow().iter().collect())
+// Tax rounds half up to the token's smallest unit
+fn tax_amount(net_amount: u64, rate_bps: u32) -> u64 {
+    ((net_amount as u128 * rate_bps as u128 + 5_000) / 10_000) as u64
 }
 
-#[ic_cdk::update]
-async fn withdraw(token_symbol: String, amount: u64, _to: Principal) -> Result<u64, String> {
-    let caller = ic_cdk::caller();
-    let owner = OWNER.with(|o| *o.borrow().get());
-    
-    if caller != owner {
-        return Err("Only the owner can withdraw".to_string());
+fn compute_invoice_breakdown(
+    line_items: &[LineItem],
+    tax_rate_bps: Option<u32>,
+    shipping: u64,
+) -> Result<InvoiceBreakdown, String> {
+    if line_items.is_empty() {
+        return Err("At least one line item is required".to_string());
+    }
+    if line_items.len() > MAX_LINE_ITEMS {
+        return Err(format!("An invoice can have at most {} line items", MAX_LINE_ITEMS));
     }
 
-    let current_balance = BALANCES.with(|balances| {
-        balances.borrow().get(&token_symbol).unwrap_or(0)
-    });
+    let overflow = || "Invoice amount overflow".to_string();
+    let mut lines = Vec::with_capacity(line_items.len());
+    let (mut subtotal, mut discount_total, mut tax_total) = (0u64, 0u64, 0u64);
 
-    if current_balance < amount {
-        return Err("Insufficient balance".to_string());
-    }
+    for line in line_items {
+        if line.quantity == 0 {
+            return Err("Line item quantity must be greater than 0".to_string());
+        }
 
-    // Here you would integrate with actual token transfer logic
-    // For now, we'll simulate the withdrawal
+        let rate_bps = line.tax_rate_bps.or(tax_rate_bps).unwrap_or(0);
+        if rate_bps > MAX_TAX_RATE_BPS {
+            return Err("Tax rate cannot exceed 100%".to_string());
+        }
 
-    BALANCES.with(|balances| {
-        let mut map = balances.borrow_mut();
-        map.insert(token_symbol, current_balance - amount);
-    });
+        let net_amount = line_net_amount(line)?;
+        let line_tax = tax_amount(net_amount, rate_bps);
+
+        subtotal = subtotal.checked_add(net_amount).ok_or_else(overflow)?;
+        discount_total = discount_total.checked_add(line.discount).ok_or_else(overflow)?;
+        tax_total = tax_total.checked_add(line_tax).ok_or_else(overflow)?;
+
+        lines.push(LineBreakdown {
+            description: line.description.clone(),
+            quantity: line.quantity,
+            gross_amount: net_amount + line.discount,
+            discount: line.discount,
+            net_amount,
+            tax_rate_bps: rate_bps,
+            tax_amount: line_tax,
+        });
+    }
+
+    let total = subtotal.checked_add(tax_total)
+        .and_then(|amount| amount.checked_add(shipping))
+        .ok_or_else(overflow)?;
 
-    Ok(amount)
+    Ok(InvoiceBreakdown {
+        lines,
+        subtotal,
+        discount_total,
+        tax_total,
+        shipping,
+        total,
+    })
 }
 
-// ============================================================================
-// ANALYTICS
-// ============================================================================
+// Product lines take their price from the catalog, callers cannot reprice them
+fn price_product_line(line: &mut LineItem, token_symbol: &str) -> Result<Product, String> {
+    let product_id = line.product_id.clone().ok_or("Line item has no product")?;
+    let product = PRODUCTS.with(|products| products.borrow().get(&product_id))
+        .ok_or_else(|| format!("Product {} not found", product_id))?;
 
-#[ic_cdk::query]
-fn get_analytics(_from_date: Option<String>, _to_date: Option<String>) -> PaymentAnalytics {
-    let transactions: Vec<PaymentTransaction> = TRANSACTIONS.with(|t| {
-        t.borrow().iter().map(|(_, tx)| tx).collect()
-    });
+    if !matches!(product.status, ProductStatus::Active) {
+        return Err(format!("Product {} is not available for purchase", product.name));
+    }
 
-    let total_transactions = transactions.len() as u64;
-    let completed_transactions = transactions.iter()
-        .filter(|tx| matches!(tx.status, TransactionStatus::Completed))
-        .count() as u64;
+    let variant = find_variant(&product, line.variant_sku.as_deref())?;
+    if let Some(variant) = variant {
+        if !matches!(variant.status, ProductStatus::Active) {
+            return Err(format!("{} of {} is not available for purchase", variant.name, product.name));
+        }
+    }
 
-    let success_rate = if total_transactions > 0 {
-        completed_transactions as f64 / total_transactions as f64
-    } else {
-        0.0
-    };
+    let unit_price = resolve_price(&product, variant, token_symbol)
+        .ok_or_else(|| format!("Product {} is not priced in {}", product.name, token_symbol))?;
 
-    // Calculate volume per token
-    let mut volume_per_token: HashMap<String, u64> = HashMap::new();
-    for tx in &transactions {
-        if matches!(tx.status, TransactionStatus::Completed) {
-            *volume_per_token.entry(tx.token.symbol.clone()).or_insert(0) += tx.amount;
+    if let Some(available) = stock_available(&product, variant) {
+        if available < line.quantity {
+            return Err(format!(
+                "Insufficient inventory for {}. Available: {}, Requested: {}",
+                product.name, available, line.quantity
+            ));
         }
     }
 
-    let total_volume: Vec<(String, u64)> = volume_per_token.into_iter().collect();
+    // Catalog lines are priced by the merchant only, callers cannot discount them
+    line.unit_price = unit_price;
+    line.discount = 0;
+    line.tax_rate_bps = None;
+    if line.description.is_empty() {
+        line.description = match variant {
+            Some(variant) => format!("{} ({})", product.name, variant.name),
+            None => product.name.clone(),
+        };
+    }
+    Ok(product)
+}
 
-    PaymentAnalytics {
-        total_transactions,
-        total_volume,
-        success_rate,
-        average_amount: vec![], // Calculate as needed
-        top_tokens: vec![],     // Calculate as needed
+fn insert_itemized_invoice(
+    token: TokenConfig,
+    description: String,
+    line_items: Vec<LineItem>,
+    tax_rate_bps: Option<u32>,
+    shipping: Option<u64>,
+    metadata: Vec<(String, String)>,
+    expires_at: u64,
+) -> Result<PaymentInvoice, String> {
+    let breakdown = compute_invoice_breakdown(&line_items, tax_rate_bps, shipping.unwrap_or(0))?;
+    if breakdown.total == 0 {
+        return Err("Invoice total must be greater than zero".to_string());
+    }
+    check_invoice_units(&line_items)?;
+
+    // Generate invoice ID
+    let invoice_id = NEXT_INVOICE_ID.with(|id| {
+        let current = *id.borrow().get();
+        id.borrow_mut().set(current + 1).unwrap();
+        format!("inv_{}", current)
+    });
+    reserve_inventory(&invoice_id, inventory_items(&line_items), ic_cdk::caller(), ic_cdk::api::time())?;
+
+    let invoice = PaymentInvoice {
+        id: invoice_id.clone(),
+        merchant: OWNER.with(|o| *o.borrow().get()),
+        amount: breakdown.total,
+        token,
+        description,
+        metadata,
+        expires_at: Some(expires_at),
+        created_at: ic_cdk::api::time(),
+        status: InvoiceStatus::Created,
+        deposit_subaccount: Some(invoice_deposit_subaccount(&invoice_id).to_vec()),
+        fiat_amount: None,
+        quote: None,
+        line_items: Some(line_items),
+        tax_rate_bps,
+        shipping,
+        breakdown: Some(breakdown),
+        payer: expected_invoice_payer(),
+    };
+
+    store_invoice(&invoice);
+    Ok(invoice)
+}
+
+#[ic_cdk::update]
+fn create_itemized_invoice(request: ItemizedInvoiceRequest) -> Result<PaymentInvoice, String> {
+    let expires_at = invoice_expiry(request.expires_at)?;
+    validate_caller_metadata(&request.metadata)?;
+    let token = find_active_token(&request.token_symbol)?;
+
+    let mut line_items = request.line_items;
+    let has_products = line_items.iter().any(|line| line.product_id.is_some());
+    for line in line_items.iter_mut().filter(|line| line.product_id.is_some()) {
+        price_product_line(line, &token.symbol)?;
     }
+
+    // Tax and shipping would change what catalog products cost, only the merchant sets them
+    let owner = OWNER.with(|o| *o.borrow().get());
+    if has_products && ic_cdk::caller() != owner && (request.tax_rate_bps.is_some() || request.shipping.is_some()) {
+        return Err("Only the owner can set tax or shipping on invoices with catalog products".to_string());
+    }
+
+    insert_itemized_invoice(
+        token,
+        request.description,
+        line_items,
+        request.tax_rate_bps,
+        request.shipping,
+        request.metadata,
+        expires_at,
+    )
 }
 
+// Breakdown for display when `PaymentOptions.show_amount_breakdown` is set,
+// single-amount invoices are presented as one line
 #[ic_cdk::query]
-fn get_transaction_history(limit: u64, offset: u64) -> Vec<PaymentTransaction> {
-    TRANSACTIONS.with(|transactions| {
-        transactions.borrow()
-            .iter()
-            .skip(offset as usize)
-            .take(limit as usize)
-            .map(|(_, tx)| tx)
-            .collect()
+fn get_invoice_breakdown(invoice_id: String) -> Result<InvoiceBreakdown, String> {
+    let invoice = INVOICES.with(|invoices| invoices.borrow().get(&invoice_id))
+        .ok_or("Invoice not found")?;
+
+    if let Some(breakdown) = invoice.breakdown {
+        return Ok(breakdown);
+    }
+
+    Ok(InvoiceBreakdown {
+        lines: vec![LineBreakdown {
+            description: invoice.description,
+            quantity: 1,
+            gross_amount: invoice.amount,
+            discount: 0,
+            net_amount: invoice.amount,
+            tax_rate_bps: 0,
+            tax_amount: 0,
+        }],
+        subtotal: invoice.amount,
+        discount_total: 0,
+        tax_total: 0,
+        shipping: 0,
+        total: invoice.amount,
     })
 }
 
 // ============================================================================
-// TOKEN TRANSFER INTEGRATION
+// ORDERS
 // ============================================================================
 
-// Transfer arguments for ICRC-1 tokens
-#[derive(CandidType, Deserialize, Clone, Debug)]
-pub struct TransferArg {
-    pub from_subaccount: Option<[u8; 32]>,
-    pub to: Account,
-    pub amount: u64,
-    pub fee: Option<u64>,
-    pub memo: Option<Vec<u8>>,
-    pub created_at_time: Option<u64>,
-}
+// Spreads an order-level discount over the lines in order, each line absorbing
+// at most its own amount. Returns the part of the discount that was applied.
+fn allocate_discount(line_items: &mut [LineItem], discount: u64) -> u64 {
+    let mut remaining = discount;
+    for line in line_items.iter_mut() {
+        if remaining == 0 {
+            break;
+        }
+        let available = line_net_amount(line).unwrap_or(0);
+        let share = remaining.min(available);
+        line.discount += share;
+        remaining -= share;
+    }
+    discount - remaining
+}
+
+fn order_transition_allowed(from: &OrderStatus, to: &OrderStatus) -> bool {
+    matches!(
+        (from, to),
+        (OrderStatus::Pending, OrderStatus::Paid) |
+        (OrderStatus::Pending, OrderStatus::Cancelled) |
+        (OrderStatus::Paid, OrderStatus::Shipped) |
+        (OrderStatus::Shipped, OrderStatus::Delivered)
+    )
+}
+
+// Moves a pending order along with its invoice, only the order the invoice was created for
+fn update_order_for_invoice(invoice: &PaymentInvoice, status: OrderStatus) {
+    let order = metadata_value(&invoice.metadata, "order_id")
+        .and_then(|order_id| ORDERS.with(|orders| orders.borrow().get(&order_id)))
+        .filter(|order| order.invoice_id == invoice.id);
+    let mut order = match order {
+        Some(order) => order,
+        None => return,
+    };
+    if !order_transition_allowed(&order.status, &status) {
+        return;
+    }
 
-#[derive(CandidType, Deserialize, Clone, Debug)]
-pub struct Account {
-    pub owner: Principal,
-    pub subaccount: Option<[u8; 32]>,
+    order.status = status;
+    order.updated_at = ic_cdk::api::time();
+    ORDERS.with(|orders| orders.borrow_mut().insert(order.order_id.clone(), order));
 }
 
-#[derive(CandidType, Deserialize, Clone, Debug)]
-pub struct TransferFromArg {
-    pub spender_subaccount: Option<[u8; 32]>,
-    pub from: Account,
-    pub to: Account,
-    pub amount: u64,
-    pub fee: Option<u64>,
-    pub memo: Option<Vec<u8>>,
-    pub created_at_time: Option<u64>,
-}
+#[ic_cdk::update]
+fn create_order(request: CreateOrderRequest) -> Result<Order, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("Anonymous callers cannot place orders".to_string());
+    }
+    validate_caller_metadata(&request.metadata)?;
 
-#[derive(CandidType, Deserialize, Clone, Debug)]
-pub enum TransferError {
-    BadFee { expected_fee: u64 },
-    BadBurn { min_burn_amount: u64 },
-    InsufficientFunds { balance: u64 },
-    InsufficientAllowance { allowance: u64 },
-    TooOld,
-    CreatedInFuture { ledger_time: u64 },
-    Duplicate { duplicate_of: u64 },
-    TemporarilyUnavailable,
-    GenericError { error_code: u64, message: String },
-}
+    let expires_at = invoice_expiry(request.expires_at)?;
+    if request.items.is_empty() {
+        return Err("An order needs at least one item".to_string());
+    }
 
-type TransferResult = Result<u64, TransferError>; // Block index or error
+    // Repeated products are merged into a single line per variant
+    let mut quantities: Vec<OrderItemRequest> = Vec::new();
+    for item in request.items {
+        if item.quantity == 0 {
+            return Err("Quantity must be greater than 0".to_string());
+        }
+        match quantities.iter_mut().find(|merged| merged.product_id == item.product_id && merged.variant_sku == item.variant_sku) {
+            Some(merged) => {
+                merged.quantity = merged.quantity.checked_add(item.quantity).ok_or("Quantity overflow")?;
+            },
+            None => quantities.push(item),
+        }
+    }
 
-// Function to perform transferFrom call to token canister
-async fn transfer_from_token(
-    token_canister_id: Principal,
-    from: Principal,
-    to: Principal,
-    amount: u64,
-) -> Result<u64, String> {
-    let transfer_from_arg = TransferFromArg {
-        spender_subaccount: None,
-        from: Account {
-            owner: from,
-            subaccount: None,
-        },
-        to: Account {
-            owner: to,
-            subaccount: None,
+    // Every product in the order is paid in one token, by default the first product's
+    let token_symbol = match request.token_symbol {
+        Some(token_symbol) => token_symbol,
+        None => {
+            let first_product_id = &quantities[0].product_id;
+            PRODUCTS.with(|products| products.borrow().get(first_product_id))
+                .ok_or_else(|| format!("Product {} not found", first_product_id))?
+                .token_symbol
         },
-        amount,
-        fee: None, // Let the token canister determine the fee
-        memo: None,
-        created_at_time: Some(ic_cdk::api::time()),
     };
+    let token = find_active_token(&token_symbol)?;
+
+    let mut line_items = Vec::with_capacity(quantities.len());
+    let mut items = Vec::with_capacity(quantities.len());
+    for item in quantities {
+        let mut line = LineItem {
+            product_id: Some(item.product_id.clone()),
+            variant_sku: item.variant_sku.clone(),
+            description: String::new(),
+            quantity: item.quantity,
+            unit_price: 0,
+            discount: 0,
+            tax_rate_bps: None,
+        };
+        let product = price_product_line(&mut line, &token.symbol)?;
+
+        items.push(OrderItem {
+            product_id: item.product_id,
+            variant_sku: item.variant_sku,
+            product_name: product.name,
+            quantity: item.quantity,
+            unit_price: line.unit_price,
+        });
+        line_items.push(line);
+    }
 
-    // Call the token canister's icrc2_transfer_from method
-    let result: Result<(TransferResult,), _> = ic_cdk::call(
-        token_canister_id,
-        "icrc2_transfer_from",
-        (transfer_from_arg,),
-    ).await;
+    let settings = ORDER_SETTINGS.with(|s| s.borrow().get().clone());
+    let mut shipping = settings.shipping_fees.iter()
+        .find(|(symbol, _)| *symbol == token.symbol)
+        .map(|(_, fee)| *fee);
 
-    match result {
-        Ok((transfer_result,)) => match transfer_result {
-            Ok(block_index) => Ok(block_index),
-            Err(transfer_error) => {
-                let error_msg = match transfer_error {
-                    TransferError::BadFee { expected_fee } => {
-                        format!("Bad fee: expected {}", expected_fee)
-                    },
-                    TransferError::InsufficientFunds { balance } => {
-                        format!("Insufficient funds: balance {}", balance)
-                    },
-                    TransferError::InsufficientAllowance { allowance } => {
-                        format!("Insufficient allowance: {}", allowance)
-                    },
-                    TransferError::TooOld => "Transaction too old".to_string(),
-                    TransferError::CreatedInFuture { ledger_time } => {
-                        format!("Transaction created in future: ledger time {}", ledger_time)
-                    },
-                    TransferError::Duplicate { duplicate_of } => {
-                        format!("Duplicate transaction: {}", duplicate_of)
-                    },
-                    TransferError::TemporarilyUnavailable => {
-                        "Service temporarily unavailable".to_string()
-                    },
-                    TransferError::GenericError { error_code, message } => {
-                        format!("Generic error {}: {}", error_code, message)
-                    },
-                    _ => "Unknown transfer error".to_string(),
-                };
-                Err(error_msg)
-            }
-        },
-        Err((rejection_code, msg)) => {
-            Err(format!(
-                "Transfer call failed: {:?} - {}",
-                rejection_code, msg
-            ))
-        }
-    }
-}
+    // Validate the order before a coupon use gets recorded
+    let undiscounted = compute_invoice_breakdown(&line_items, settings.tax_rate_bps, shipping.unwrap_or(0))?;
 
-// ============================================================================
-// ENHANCED ANALYTICS
-// ============================================================================
+    let current_time = ic_cdk::api::time();
+    let order_id = NEXT_ORDER_ID.with(|id| {
+        let current = *id.borrow().get();
+        id.borrow_mut().set(current + 1).unwrap();
+        format!("ord_{}", current)
+    });
 
-// Track payment analytics for modal conversion
-fn track_payment_analytics(token_symbol: &str, amount: u64) {
-    // Update modal analytics for successful payment
-    MODAL_ANALYTICS.with(|analytics| {
-        let mut map = analytics.borrow_mut();
-        if let Some(mut modal_analytics) = map.get(&"modal_1".to_string()) {
-            modal_analytics.successful_payments += 1;
-            modal_analytics.revenue_generated += amount;
-            
-            // Recalculate conversion rate
-            if modal_analytics.total_views > 0 {
-                modal_analytics.conversion_rate = 
-                    modal_analytics.successful_payments as f64 / modal_analytics.total_views as f64;
-            }
-            
-            map.insert("modal_1".to_string(), modal_analytics);
-        }
-    });
-}
+    // The discount is built into the invoice, the coupon use is reserved on it below
+    let mut coupon: Option<DiscountCoupon> = None;
+    let mut discount_applied = 0u64;
+    if let Some(coupon_code) = request.coupon_code {
+        let (evaluated, discount) = evaluate_coupon(
+            &coupon_code,
+            caller,
+            undiscounted.subtotal,
+            &token.symbol,
+            undiscounted.shipping,
+            coupon_lines(&line_items),
+        )?;
+
+        discount_applied = match evaluated.coupon_type {
+            CouponType::FreeShipping => {
+                shipping = Some(undiscounted.shipping - discount);
+                discount
+            },
+            _ => allocate_discount(&mut line_items, discount),
+        };
+        coupon = Some(evaluated);
+    }
+
+    let mut invoice_metadata = request.metadata.clone();
+    invoice_metadata.push(("order_id".to_string(), order_id.clone()));
+    if let Some(coupon) = &coupon {
+        invoice_metadata.push(("coupon_id".to_string(), coupon.coupon_id.clone()));
+        invoice_metadata.push(("discount_applied".to_string(), discount_applied.to_string()));
+    }
+
+    let invoice = insert_itemized_invoice(
+        token.clone(),
+        format!("Order {}", order_id),
+        line_items,
+        settings.tax_rate_bps,
+        shipping,
+        invoice_metadata,
+        expires_at,
+    )?;
+
+    if let Some(coupon) = &coupon {
+        reserve_coupon(coupon, &invoice.id, caller, discount_applied);
+    }
+
+    let order = Order {
+        order_id: order_id.clone(),
+        customer: caller,
+        items,
+        invoice_id: invoice.id,
+        token_symbol: token.symbol,
+        coupon_id: coupon.map(|coupon| coupon.coupon_id),
+        discount_applied,
+        total_amount: invoice.amount,
+        status: OrderStatus::Pending,
+        tracking_reference: None,
+        metadata: request.metadata,
+        created_at: current_time,
+        updated_at: current_time,
+    };
 
-// Get enhanced analytics with payment method breakdown
-#[ic_cdk::query]
-fn get_enhanced_analytics() -> PaymentAnalytics {
-    let transactions: Vec<PaymentTransaction> = TRANSACTIONS.with(|t| {
-        t.borrow().iter().map(|(_, tx)| tx).collect()
-    });
+    ORDERS.with(|orders| orders.borrow_mut().insert(order_id, order.clone()));
+    Ok(order)
+}
 
-    let total_transactions = transactions.len() as u64;
-    let completed_transactions = transactions.iter()
-        .filter(|tx| matches!(tx.status, TransactionStatus::Completed))
-        .count() as u64;
+#[ic_cdk::update]
+fn cancel_order(order_id: String) -> Result<Order, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
 
-    let success_rate = if total_transactions > 0 {
-        completed_transactions as f64 / total_transactions as f64
-    } else {
-        0.0
-    };
+    let order = ORDERS.with(|orders| orders.borrow().get(&order_id))
+        .ok_or("Order not found")?;
 
-    // Calculate volume per token
-    let mut volume_per_token: HashMap<String, u64> = HashMap::new();
-    let mut amount_per_token: HashMap<String, Vec<u64>> = HashMap::new();
-    
-    for tx in &transactions {
-        if matches!(tx.status, TransactionStatus::Completed) {
-            *volume_per_token.entry(tx.token.symbol.clone()).or_insert(0) += tx.amount;
-            amount_per_token.entry(tx.token.symbol.clone())
-                .or_insert_with(Vec::new)
-                .push(tx.amount);
-        }
+    if caller != owner && caller != order.customer {
+        return Err("Only the owner or the customer can cancel this order".to_string());
+    }
+    if order.status != OrderStatus::Pending {
+        return Err("Only pending orders can be cancelled".to_string());
     }
 
-    let total_volume: Vec<(String, u64)> = volume_per_token.clone().into_iter().collect();
-    
-    // Calculate average amounts
-    let average_amount: Vec<(String, u64)> = amount_per_token.into_iter()
-        .map(|(token, amounts)| {
-            let avg = if !amounts.is_empty() {
-                amounts.iter().sum::<u64>() / amounts.len() as u64
-            } else {
-                0
-            };
-            (token, avg)
-        })
-        .collect();
+    // Refuse while a payment for the order's invoice is being processed
+    let _guard = InFlightGuard::acquire(format!("invoice:{}", order.invoice_id))?;
 
-    // Get top tokens by volume
-    let mut top_tokens: Vec<(String, u64)> = volume_per_token.into_iter().collect();
-    top_tokens.sort_by(|a, b| b.1.cmp(&a.1));
-    let top_tokens: Vec<String> = top_tokens.into_iter().take(5).map(|(token, _)| token).collect();
+    let mut invoice = INVOICES.with(|invoices| invoices.borrow().get(&order.invoice_id))
+        .ok_or("Invoice not found")?;
 
-    PaymentAnalytics {
-        total_transactions,
-        total_volume,
-        success_rate,
-        average_amount,
-        top_tokens,
+    match invoice.status {
+        InvoiceStatus::Paid => return Err("Order has already been paid".to_string()),
+        InvoiceStatus::Created => {
+            invoice.status = InvoiceStatus::Cancelled;
+            store_invoice(&invoice);
+        },
+        InvoiceStatus::Cancelled | InvoiceStatus::Expired => {},
     }
-}
 
-// Get payment method analytics
-#[ic_cdk::query]
-fn get_payment_method_analytics() -> Vec<(String, u64)> {
-    let transactions: Vec<PaymentTransaction> = TRANSACTIONS.with(|t| {
-        t.borrow().iter().map(|(_, tx)| tx).collect()
-    });
+    // Also cancels the order and releases its coupon
+    release_invoice_reservations(&invoice);
 
-    let mut method_counts: HashMap<String, u64> = HashMap::new();
+    ORDERS.with(|orders| orders.borrow().get(&order_id)).ok_or("Order not found".to_string())
+}
+
+#[ic_cdk::update]
+fn update_order_status(
+    order_id: String,
+    status: OrderStatus,
+    tracking_reference: Option<String>,
+) -> Result<Order, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
     
-    for tx in &transactions {
-        if matches!(tx.status, TransactionStatus::Completed) {
-            let method_name = match tx.payment_method {
-                PaymentMethod::Direct => "Direct",
-                PaymentMethod::TransferFrom => "TransferFrom",
-                PaymentMethod::Subscription => "Subscription",
-            };
-            *method_counts.entry(method_name.to_string()).or_insert(0) += 1;
-        }
+    if caller != owner {
+        return Err("Only the owner can update orders".to_string());
     }
 
-    method_counts.into_iter().collect()
-}
+    if !matches!(status, OrderStatus::Shipped | OrderStatus::Delivered) {
+        return Err("Orders are marked paid by their payment and cancelled with cancel_order".to_string());
+    }
 
-// ============================================================================
-// HEALTH CHECK
-// ============================================================================
+    let mut order = ORDERS.with(|orders| orders.borrow().get(&order_id))
+        .ok_or("Order not found")?;
 
-#[ic_cdk::query]
-fn health() -> (String, u64, u64) {
-    let status = "healthy".to_string();
-    let version = 1u64;
-    let uptime = ic_cdk::api::time();
-    (status, version, uptime)
-}
+    if !order_transition_allowed(&order.status, &status) {
+        return Err(format!("Cannot move order from {:?} to {:?}", order.status, status));
+    }
 
-// ============================================================================
-// UTILITY METHODS
-// ============================================================================
+    order.status = status;
+    if tracking_reference.is_some() {
+        order.tracking_reference = tracking_reference;
+    }
+    order.updated_at = ic_cdk::api::time();
+
+    ORDERS.with(|orders| orders.borrow_mut().insert(order_id, order.clone()));
+    Ok(order)
+}
 
 #[ic_cdk::query]
-fn whoami() -> Principal {
-    ic_cdk::caller()
+fn get_order(order_id: String) -> Result<Order, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+
+    let order = ORDERS.with(|orders| orders.borrow().get(&order_id))
+        .ok_or("Order not found")?;
+
+    if caller != owner && caller != order.customer {
+        return Err("Order not found".to_string());
+    }
+    Ok(order)
 }
 
+// The owner sees every order, customers only their own. Newest first.
 #[ic_cdk::query]
-fn get_owner() -> Principal {
-    OWNER.with(|o| *o.borrow().get())
+fn list_orders(status: Option<OrderStatus>) -> Vec<Order> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+
+    let mut orders: Vec<Order> = ORDERS.with(|orders| {
+        orders.borrow().iter()
+            .map(|(_, order)| order)
+            .filter(|order| caller == owner || order.customer == caller)
+            .filter(|order| status.as_ref().is_none_or(|status| order.status == *status))
+            .collect()
+    });
+
+    orders.sort_by_key(|order| std::cmp::Reverse(order.created_at));
+    orders
 }
 
 #[ic_cdk::update]
-fn admin_update_owner(new_owner: Principal) -> Result<(), String> {
+fn set_order_settings(settings: OrderSettings) -> Result<(), String> {
     let caller = ic_cdk::caller();
-    let current_owner = OWNER.with(|o| *o.borrow().get());
+    let owner = OWNER.with(|o| *o.borrow().get());
     
-    // Only current owner or controllers can change ownership
-    if caller != current_owner {
-        // Check if caller is a controller by attempting to call a management canister function
-        // This is a simple way to verify controller status
-        // In practice, you might want a more sophisticated check
-        
-        // For now, we'll also allow the admin principal (factory owner) to update ownership
-        let admin_principal = "ouuvn-c7hpi-46km4-ywlnr-j2ten-wldfi-xu53v-vth6u-3qtqr-cmbxu-gqe";
-        if caller.to_text() != admin_principal {
-            return Err("Only the current owner or admin can update ownership".to_string());
-        }
+    if caller != owner {
+        return Err("Only the owner can change order settings".to_string());
     }
-    
-    if new_owner == Principal::anonymous() {
-        return Err("Cannot set owner to anonymous principal".to_string());
+
+    if settings.tax_rate_bps.is_some_and(|rate| rate > MAX_TAX_RATE_BPS) {
+        return Err("Tax rate cannot exceed 100%".to_string());
     }
-    
-    OWNER.with(|o| o.borrow_mut().set(new_owner).unwrap());
+
+    ORDER_SETTINGS.with(|s| s.borrow_mut().set(settings).unwrap());
     Ok(())
 }
 
 #[ic_cdk::query]
-fn canister_id() -> Principal {
-    ic_cdk::id()
+fn get_order_settings() -> OrderSettings {
+    ORDER_SETTINGS.with(|s| s.borrow().get().clone())
 }
 
 // ============================================================================
-// MODAL BUILDER METHODS
+// INVOICE LIFECYCLE
 // ============================================================================
 
+const DEFAULT_INVOICE_TTL_SECONDS: u64 = 24 * 60 * 60;
+const MAX_INVOICE_TTL_SECONDS: u64 = 365 * 24 * 60 * 60;
+const INVOICE_EXPIRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+fn default_invoice_ttl_nanos() -> u64 {
+    INVOICE_TTL_SECONDS.with(|ttl| *ttl.borrow().get()) * 1_000_000_000
+}
+
+// Resolve the expiry of a new invoice, falling back to the configured default TTL
+fn invoice_expiry(expires_at: Option<u64>) -> Result<u64, String> {
+    resolve_invoice_expiry(expires_at, ic_cdk::api::time())
+}
+
+fn resolve_invoice_expiry(expires_at: Option<u64>, current_time: u64) -> Result<u64, String> {
+    match expires_at {
+        Some(expires_at) if expires_at <= current_time => {
+            Err("Invoice expiry must be in the future".to_string())
+        },
+        Some(expires_at) if expires_at - current_time > MAX_INVOICE_TTL_SECONDS * 1_000_000_000 => {
+            Err("Invoice expiry cannot be more than a year ahead".to_string())
+        },
+        Some(expires_at) => Ok(expires_at),
+        None => Ok(current_time + default_invoice_ttl_nanos()),
+    }
+}
+
+fn is_invoice_overdue(invoice: &PaymentInvoice, current_time: u64) -> bool {
+    matches!(invoice.status, InvoiceStatus::Created) &&
+        invoice.expires_at.is_some_and(|expires_at| current_time > expires_at)
+}
+
+// Give back what an unpaid invoice holds: its inventory and its coupon reservation
+fn release_invoice_reservations(invoice: &PaymentInvoice) {
+    release_inventory_reservation(&invoice.id);
+    release_invoice_coupon(&invoice.id);
+
+    update_order_for_invoice(invoice, OrderStatus::Cancelled);
+}
+
+const MAX_EXPIRY_SCAN_PER_RUN: usize = 500;
+
+// Each run looks at the next batch of open invoices, continuing where the last run stopped
+fn expire_overdue_invoices(current_time: u64) {
+    let created = invoice_status_code(&InvoiceStatus::Created);
+    let cursor = EXPIRY_SCAN_CURSOR.with(|cursor| *cursor.borrow());
+    let batch: Vec<(u64, String)> = INVOICES_BY_STATUS.with(|index| {
+        index.borrow().range((created, cursor)..(created + 1, 0))
+            .take(MAX_EXPIRY_SCAN_PER_RUN)
+            .map(|((_, sequence), invoice_id)| (sequence, invoice_id))
+            .collect()
+    });
+    let next_cursor = match batch.last() {
+        Some((sequence, _)) if batch.len() == MAX_EXPIRY_SCAN_PER_RUN => sequence + 1,
+        _ => 0,
+    };
+    EXPIRY_SCAN_CURSOR.with(|cursor| *cursor.borrow_mut() = next_cursor);
+
+    // Invoices with a payment in flight are left for the next run
+    let overdue: Vec<PaymentInvoice> = batch.into_iter()
+        .filter(|(_, invoice_id)| !is_in_flight(&format!("invoice:{}", invoice_id)))
+        .filter_map(|(_, invoice_id)| INVOICES.with(|invoices| invoices.borrow().get(&invoice_id)))
+        .filter(|invoice| is_invoice_overdue(invoice, current_time))
+        .collect();
+
+    for mut invoice in overdue {
+        invoice.status = InvoiceStatus::Expired;
+        store_invoice(&invoice);
+        release_invoice_reservations(&invoice);
+    }
+
+    release_expired_reservations(current_time);
+    release_lapsed_coupon_holds(current_time);
+}
+
 #[ic_cdk::update]
-fn create_modal_config(config: ModalConfig) -> Result<String, String> {
+fn cancel_invoice(invoice_id: String) -> Result<PaymentInvoice, String> {
     let caller = ic_cdk::caller();
     let owner = OWNER.with(|o| *o.borrow().get());
     
     if caller != owner {
-        return Err("Only the owner can create modal configurations".to_string());
-    }
-
-    // Validate modal configuration
-    if config.name.is_empty() {
-        return Err("Modal name cannot be empty".to_string());
-    }
-    if config.branding.company_name.is_empty() {
-        return Err("Company name cannot be empty".to_string());
-    }
-    if config.redirect_urls.success_url.is_empty() {
-        return Err("Success URL cannot be empty".to_string());
-    }
-    if config.redirect_urls.cancel_url.is_empty() {
-        return Err("Cancel URL cannot be empty".to_string());
+        return Err("Only the owner can cancel invoices".to_string());
     }
 
-    // Generate modal ID
-    let modal_id = NEXT_MODAL_ID.with(|id| {
-        let current = *id.borrow().get();
-        id.borrow_mut().set(current + 1).unwrap();
-        format!("modal_{}", current)
-    });
+    // Refuse while a payment for this invoice is being processed
+    let _guard = InFlightGuard::acquire(format!("invoice:{}", invoice_id))?;
 
-    let mut new_config = config;
-    new_config.modal_id = modal_id.clone();
-    new_config.created_at = ic_cdk::api::time();
-    new_config.updated_at = ic_cdk::api::time();
+    let mut invoice = INVOICES.with(|invoices| invoices.borrow().get(&invoice_id))
+        .ok_or("Invoice not found")?;
 
-    MODAL_CONFIGS.with(|configs| {
-        configs.borrow_mut().insert(modal_id.clone(), new_config)
-    });
+    if !matches!(invoice.status, InvoiceStatus::Created) {
+        return Err("Only unpaid invoices can be cancelled".to_string());
+    }
 
-    // Initialize analytics for this modal
-    let analytics = ModalAnalytics {
-        modal_id: modal_id.clone(),
-        total_views: 0,
-        successful_payments: 0,
-        conversion_rate: 0.0,
-        revenue_generated: 0,
-    };
-    
-    MODAL_ANALYTICS.with(|analytics_map| {
-        analytics_map.borrow_mut().insert(modal_id.clone(), analytics)
-    });
+    invoice.status = InvoiceStatus::Cancelled;
+    store_invoice(&invoice);
+    release_invoice_reservations(&invoice);
 
-    Ok(modal_id)
+    Ok(invoice)
 }
 
 #[ic_cdk::update]
-fn update_modal_config(modal_id: String, mut config: ModalConfig) -> Result<(), String> {
+fn set_default_invoice_ttl(ttl_seconds: u64) -> Result<(), String> {
     let caller = ic_cdk::caller();
     let owner = OWNER.with(|o| *o.borrow().get());
     
     if caller != owner {
-        return Err("Only the owner can update modal configurations".to_string());
+        return Err("Only the owner can change the invoice TTL".to_string());
     }
 
-    // Validate modal configuration
-    if config.name.is_empty() {
-        return Err("Modal name cannot be empty".to_string());
-    }
-    if config.branding.company_name.is_empty() {
-        return Err("Company name cannot be empty".to_string());
-    }
-    if config.redirect_urls.success_url.is_empty() {
-        return Err("Success URL cannot be empty".to_string());
-    }
-    if config.redirect_urls.cancel_url.is_empty() {
-        return Err("Cancel URL cannot be empty".to_string());
+    if !(60..=MAX_INVOICE_TTL_SECONDS).contains(&ttl_seconds) {
+        return Err("Invoice TTL must be between one minute and one year".to_string());
     }
 
-    MODAL_CONFIGS.with(|configs| {
-        let mut map = configs.borrow_mut();
-        let existing_config = map.get(&modal_id)
-            .ok_or("Modal configuration not found")?;
-        
-        // Preserve original creation data and ID
-        config.modal_id = modal_id.clone();
-        config.created_at = existing_config.created_at;
-        config.updated_at = ic_cdk::api::time();
-        
-        map.insert(modal_id, config);
-        Ok(())
-    })
+    INVOICE_TTL_SECONDS.with(|ttl| ttl.borrow_mut().set(ttl_seconds).unwrap());
+    Ok(())
 }
 
 #[ic_cdk::query]
-fn get_modal_config(modal_id: String) -> Result<ModalConfig, String> {
-    MODAL_CONFIGS.with(|configs| {
-        configs.borrow().get(&modal_id)
-            .ok_or("Modal configuration not found".to_string())
-    })
+fn get_default_invoice_ttl() -> u64 {
+    INVOICE_TTL_SECONDS.with(|ttl| *ttl.borrow().get())
 }
 
 #[ic_cdk::query]
-fn list_my_modals() -> Vec<ModalConfig> {
-    MODAL_CONFIGS.with(|configs| {
-        configs.borrow().iter().map(|(_, config)| config).collect()
-    })
-}
-
-#[ic_cdk::update]
-fn delete_modal_config(modal_id: String) -> Result<(), String> {
+fn get_invoices_by_status(status: InvoiceStatus) -> Vec<PaymentInvoice> {
     let caller = ic_cdk::caller();
     let owner = OWNER.with(|o| *o.borrow().get());
     
     if caller != owner {
-        return Err("Only the owner can delete modal configurations".to_string());
+        return vec![];
     }
 
-    MODAL_CONFIGS.with(|configs| {
-        let mut map = configs.borrow_mut();
-        if map.remove(&modal_id).is_none() {
-            return Err("Modal configuration not found".to_string());
+    let current_time = ic_cdk::api::time();
+    let filter = InvoiceFilter {
+        status: Some(status),
+        ..Default::default()
+    };
+
+    let mut matching = Vec::new();
+    let mut cursor = None;
+    loop {
+        let candidates = invoice_candidates(&filter, cursor);
+        let scanned_everything = candidates.len() < MAX_PAGE_SCAN;
+        cursor = candidates.last().map(|(sequence, _)| *sequence);
+        INVOICES.with(|invoices| {
+            let map = invoices.borrow();
+            matching.extend(
+                candidates.iter()
+                    .filter_map(|(_, id)| map.get(id))
+                    .filter(|invoice| invoice_matches(invoice, &filter, current_time))
+            );
+        });
+        if scanned_everything || cursor.is_none() {
+            return matching;
         }
-        Ok(())
-    })?;
+    }
+}
 
-    // Also remove analytics data
-    MODAL_ANALYTICS.with(|analytics| {
-        analytics.borrow_mut().remove(&modal_id)
+// ============================================================================
+// INVOICE AND TRANSACTION LISTING
+// ============================================================================
+
+const MAX_PAGE_SIZE: u32 = 100;
+// Upper bound on index entries examined per page, filtered queries may return
+// short pages and continue from `next_cursor`
+const MAX_PAGE_SCAN: usize = 2_000;
+
+// Numeric part of generated ids such as "inv_12" or "tx_7"
+fn id_sequence(id: &str) -> Option<u64> {
+    id.rsplit_once('_').and_then(|(_, number)| number.parse().ok())
+}
+
+fn token_index_key(token_symbol: &str, sequence: u64) -> String {
+    format!("{}:{:020}", token_symbol, sequence)
+}
+
+fn invoice_status_code(status: &InvoiceStatus) -> u8 {
+    match status {
+        InvoiceStatus::Created => 0,
+        InvoiceStatus::Paid => 1,
+        InvoiceStatus::Expired => 2,
+        InvoiceStatus::Cancelled => 3,
+    }
+}
+
+fn transaction_status_code(status: &TransactionStatusKind) -> u8 {
+    match status {
+        TransactionStatusKind::Pending => 0,
+        TransactionStatusKind::Completed => 1,
+        TransactionStatusKind::Failed => 2,
+        TransactionStatusKind::Refunded => 3,
+    }
+}
+
+fn transaction_status_kind(status: &TransactionStatus) -> TransactionStatusKind {
+    match status {
+        TransactionStatus::Pending => TransactionStatusKind::Pending,
+        TransactionStatus::Completed => TransactionStatusKind::Completed,
+        TransactionStatus::Failed(_) => TransactionStatusKind::Failed,
+        TransactionStatus::Refunded => TransactionStatusKind::Refunded,
+    }
+}
+
+fn index_invoice(invoice: &PaymentInvoice) {
+    if let Some(sequence) = id_sequence(&invoice.id) {
+        INVOICE_INDEX.with(|index| index.borrow_mut().insert(sequence, invoice.id.clone()));
+        if let Some(payer) = invoice.payer {
+            INVOICES_BY_PAYER.with(|index| index.borrow_mut().insert((payer, sequence), invoice.id.clone()));
+        }
+        INVOICES_BY_STATUS.with(|index| {
+            index.borrow_mut().insert((invoice_status_code(&invoice.status), sequence), invoice.id.clone())
+        });
+        INVOICES_BY_TOKEN.with(|index| {
+            index.borrow_mut().insert(token_index_key(&invoice.token.symbol, sequence), invoice.id.clone())
+        });
+        INVOICES_BY_TIME.with(|index| index.borrow_mut().insert((invoice.created_at, sequence), invoice.id.clone()));
+    }
+}
+
+// Drop the entries of a previous version whose status or token may have changed
+fn unindex_invoice(invoice: &PaymentInvoice) {
+    if let Some(sequence) = id_sequence(&invoice.id) {
+        if let Some(payer) = invoice.payer {
+            INVOICES_BY_PAYER.with(|index| index.borrow_mut().remove(&(payer, sequence)));
+        }
+        INVOICES_BY_STATUS.with(|index| index.borrow_mut().remove(&(invoice_status_code(&invoice.status), sequence)));
+        INVOICES_BY_TOKEN.with(|index| index.borrow_mut().remove(&token_index_key(&invoice.token.symbol, sequence)));
+        INVOICES_BY_TIME.with(|index| index.borrow_mut().remove(&(invoice.created_at, sequence)));
+    }
+}
+
+// Every invoice write goes through here so the listing indexes stay in step
+fn store_invoice(invoice: &PaymentInvoice) {
+    let previous = INVOICES.with(|invoices| invoices.borrow_mut().insert(invoice.id.clone(), invoice.clone()));
+    if let Some(previous) = previous {
+        unindex_invoice(&previous);
+    }
+    index_invoice(invoice);
+}
+
+fn index_transaction(transaction: &PaymentTransaction) {
+    if let Some(sequence) = id_sequence(&transaction.id) {
+        TRANSACTION_INDEX.with(|index| index.borrow_mut().insert(sequence, transaction.id.clone()));
+        TRANSACTIONS_BY_PAYER.with(|index| {
+            index.borrow_mut().insert((transaction.from, sequence), transaction.id.clone())
+        });
+        let status = transaction_status_code(&transaction_status_kind(&transaction.status));
+        TRANSACTIONS_BY_STATUS.with(|index| index.borrow_mut().insert((status, sequence), transaction.id.clone()));
+        TRANSACTIONS_BY_TOKEN.with(|index| {
+            index.borrow_mut().insert(token_index_key(&transaction.token.symbol, sequence), transaction.id.clone())
+        });
+        TRANSACTIONS_BY_TIME.with(|index| {
+            index.borrow_mut().insert((transaction.timestamp, sequence), transaction.id.clone())
+        });
+    }
+}
+
+fn unindex_transaction(transaction: &PaymentTransaction) {
+    if let Some(sequence) = id_sequence(&transaction.id) {
+        TRANSACTIONS_BY_PAYER.with(|index| index.borrow_mut().remove(&(transaction.from, sequence)));
+        let status = transaction_status_code(&transaction_status_kind(&transaction.status));
+        TRANSACTIONS_BY_STATUS.with(|index| index.borrow_mut().remove(&(status, sequence)));
+        TRANSACTIONS_BY_TOKEN.with(|index| {
+            index.borrow_mut().remove(&token_index_key(&transaction.token.symbol, sequence))
+        });
+        TRANSACTIONS_BY_TIME.with(|index| index.borrow_mut().remove(&(transaction.timestamp, sequence)));
+    }
+}
+
+// Every transaction write goes through here so the listing indexes stay in step
+fn store_transaction(transaction: &PaymentTransaction) {
+    let previous = TRANSACTIONS.with(|transactions| {
+        transactions.borrow_mut().insert(transaction.id.clone(), transaction.clone())
     });
+    if let Some(previous) = previous {
+        unindex_transaction(&previous);
+    }
+    index_transaction(transaction);
+}
 
-    Ok(())
+// Index invoices and transactions created before the indexes existed, each
+// record has exactly one status entry once fully indexed
+fn rebuild_list_indexes() {
+    let invoices_indexed = INVOICES_BY_STATUS.with(|index| index.borrow().len()) ==
+        INVOICES.with(|invoices| invoices.borrow().len());
+    if !invoices_indexed {
+        INVOICES.with(|invoices| {
+            for (_, invoice) in invoices.borrow().iter() {
+                index_invoice(&invoice);
+            }
+        });
+    }
+
+    let transactions_indexed = TRANSACTIONS_BY_STATUS.with(|index| index.borrow().len()) ==
+        TRANSACTIONS.with(|transactions| transactions.borrow().len());
+    if !transactions_indexed {
+        TRANSACTIONS.with(|transactions| {
+            for (_, transaction) in transactions.borrow().iter() {
+                index_transaction(&transaction);
+            }
+        });
+    }
 }
 
-#[ic_cdk::update]
-fn track_modal_view(modal_id: String) -> Result<(), String> {
-    MODAL_ANALYTICS.with(|analytics| {
-        let mut map = analytics.borrow_mut();
-        if let Some(mut modal_analytics) = map.get(&modal_id) {
-            modal_analytics.total_views += 1;
-            
-            // Recalculate conversion rate
-            if modal_analytics.total_views > 0 {
-                modal_analytics.conversion_rate = 
-                    modal_analytics.successful_payments as f64 / modal_analytics.total_views as f64;
+fn metadata_matches(metadata: &[(String, String)], key: &Option<String>, value: &Option<String>) -> bool {
+    match key {
+        None => true,
+        Some(key) => match (metadata_value(metadata, key), value) {
+            (Some(found), Some(expected)) => &found == expected,
+            (Some(_), None) => true,
+            (None, _) => false,
+        },
+    }
+}
+
+fn in_time_range(timestamp: u64, from_time: Option<u64>, to_time: Option<u64>) -> bool {
+    from_time.is_none_or(|from| timestamp >= from) && to_time.is_none_or(|to| timestamp <= to)
+}
+
+fn transaction_matches(transaction: &PaymentTransaction, filter: &TransactionFilter) -> bool {
+    let status_matches = match &filter.status {
+        None => true,
+        Some(kind) => matches!(
+            (kind, &transaction.status),
+            (TransactionStatusKind::Pending, TransactionStatus::Pending) |
+            (TransactionStatusKind::Completed, TransactionStatus::Completed) |
+            (TransactionStatusKind::Failed, TransactionStatus::Failed(_)) |
+            (TransactionStatusKind::Refunded, TransactionStatus::Refunded)
+        ),
+    };
+
+    status_matches &&
+        filter.token_symbol.as_ref().is_none_or(|symbol| &transaction.token.symbol == symbol) &&
+        filter.payer.is_none_or(|payer| transaction.from == payer) &&
+        in_time_range(transaction.timestamp, filter.from_time, filter.to_time) &&
+        metadata_matches(&transaction.metadata, &filter.metadata_key, &filter.metadata_value)
+}
+
+fn invoice_matches(invoice: &PaymentInvoice, filter: &InvoiceFilter, current_time: u64) -> bool {
+    let status_matches = match &filter.status {
+        None => true,
+        Some(status) => {
+            // Overdue invoices count as expired even before the sweep has run
+            let effective_status = if is_invoice_overdue(invoice, current_time) {
+                InvoiceStatus::Expired
+            } else {
+                invoice.status.clone()
+            };
+            &effective_status == status
+        },
+    };
+
+    status_matches &&
+        filter.token_symbol.as_ref().is_none_or(|symbol| &invoice.token.symbol == symbol) &&
+        filter.payer.is_none_or(|payer| invoice.payer == Some(payer)) &&
+        in_time_range(invoice.created_at, filter.from_time, filter.to_time) &&
+        metadata_matches(&invoice.metadata, &filter.metadata_key, &filter.metadata_value)
+}
+
+// Newest first: walk index entries below `cursor`, collecting up to `limit` matches
+fn collect_page<T>(
+    candidates: Vec<(u64, String)>,
+    limit: u32,
+    load: impl Fn(&str) -> Option<T>,
+    matches: impl Fn(&T) -> bool,
+) -> (Vec<T>, Option<u64>) {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let scanned_everything = candidates.len() < MAX_PAGE_SCAN;
+    let mut items = Vec::new();
+    let mut last_sequence = None;
+
+    for (sequence, id) in candidates {
+        last_sequence = Some(sequence);
+        if let Some(item) = load(&id) {
+            if matches(&item) {
+                items.push(item);
+                if items.len() == limit {
+                    return (items, Some(sequence));
+                }
             }
-            
-            map.insert(modal_id, modal_analytics);
-            Ok(())
-        } else {
-            Err("Modal analytics not found".to_string())
         }
+    }
+
+    let next_cursor = if scanned_everything { None } else { last_sequence };
+    (items, next_cursor)
+}
+
+// Newest first entries of a (value, sequence) index below `upper`
+fn keyed_candidates<K: Storable + Ord + Clone>(
+    index: &StableBTreeMap<(K, u64), String, Memory>,
+    key: K,
+    upper: u64,
+) -> Vec<(u64, String)> {
+    index.range((key.clone(), 0)..(key, upper))
+        .rev()
+        .take(MAX_PAGE_SCAN)
+        .map(|((_, sequence), id)| (sequence, id))
+        .collect()
+}
+
+fn token_candidates(index: &StableBTreeMap<String, String, Memory>, token_symbol: &str, upper: u64) -> Vec<(u64, String)> {
+    index.range(token_index_key(token_symbol, 0)..token_index_key(token_symbol, upper))
+        .rev()
+        .take(MAX_PAGE_SCAN)
+        .filter_map(|(_, id)| id_sequence(&id).map(|sequence| (sequence, id)))
+        .collect()
+}
+
+// Entries created within the time range, continuing below the cursor entry's
+// (timestamp, sequence) position
+fn time_candidates(
+    index: &StableBTreeMap<(u64, u64), String, Memory>,
+    from_time: Option<u64>,
+    to_time: Option<u64>,
+    cursor: Option<(u64, u64)>,
+) -> Vec<(u64, String)> {
+    let start = (from_time.unwrap_or(0), 0);
+    let mut end = (to_time.map_or(u64::MAX, |to| to.saturating_add(1)), 0);
+    if let Some(cursor) = cursor {
+        end = end.min(cursor);
+    }
+    if start >= end {
+        return vec![];
+    }
+
+    let upper = cursor.map_or(u64::MAX, |(_, sequence)| sequence);
+    index.range(start..end)
+        .rev()
+        .filter(|((_, sequence), _)| *sequence < upper)
+        .take(MAX_PAGE_SCAN)
+        .map(|((_, sequence), id)| (sequence, id))
+        .collect()
+}
+
+// Pick the most selective index for the filter, the remaining criteria are
+// checked on the loaded records
+fn transaction_candidates(filter: &TransactionFilter, cursor: Option<u64>) -> Vec<(u64, String)> {
+    let upper = cursor.unwrap_or(u64::MAX);
+    if let Some(payer) = filter.payer {
+        return TRANSACTIONS_BY_PAYER.with(|index| keyed_candidates(&index.borrow(), payer, upper));
+    }
+    if let Some(status) = &filter.status {
+        return TRANSACTIONS_BY_STATUS.with(|index| keyed_candidates(&index.borrow(), transaction_status_code(status), upper));
+    }
+    if let Some(symbol) = &filter.token_symbol {
+        return TRANSACTIONS_BY_TOKEN.with(|index| token_candidates(&index.borrow(), symbol, upper));
+    }
+    if filter.from_time.is_some() || filter.to_time.is_some() {
+        let cursor = cursor.map(|sequence| {
+            let timestamp = TRANSACTION_INDEX.with(|index| index.borrow().get(&sequence))
+                .and_then(|id| TRANSACTIONS.with(|transactions| transactions.borrow().get(&id)))
+                .map_or(u64::MAX, |transaction| transaction.timestamp);
+            (timestamp, sequence)
+        });
+        return TRANSACTIONS_BY_TIME.with(|index| time_candidates(&index.borrow(), filter.from_time, filter.to_time, cursor));
+    }
+
+    TRANSACTION_INDEX.with(|index| {
+        index.borrow()
+            .range(..upper)
+            .rev()
+            .take(MAX_PAGE_SCAN)
+            .collect()
     })
 }
 
-#[ic_cdk::query]
-fn get_modal_analytics(modal_id: String) -> Result<ModalAnalytics, String> {
-    MODAL_ANALYTICS.with(|analytics| {
-        analytics.borrow().get(&modal_id)
-            .ok_or("Modal analytics not found".to_string())
+fn invoice_candidates(filter: &InvoiceFilter, cursor: Option<u64>) -> Vec<(u64, String)> {
+    let upper = cursor.unwrap_or(u64::MAX);
+    if let Some(payer) = filter.payer {
+        return INVOICES_BY_PAYER.with(|index| keyed_candidates(&index.borrow(), payer, upper));
+    }
+    if let Some(status) = &filter.status {
+        let mut candidates = INVOICES_BY_STATUS.with(|index| {
+            keyed_candidates(&index.borrow(), invoice_status_code(status), upper)
+        });
+        // Overdue invoices the sweep has not reached yet are still indexed as created
+        if matches!(status, InvoiceStatus::Expired) {
+            candidates.extend(INVOICES_BY_STATUS.with(|index| {
+                keyed_candidates(&index.borrow(), invoice_status_code(&InvoiceStatus::Created), upper)
+            }));
+            candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.0));
+            candidates.truncate(MAX_PAGE_SCAN);
+        }
+        return candidates;
+    }
+    if let Some(symbol) = &filter.token_symbol {
+        return INVOICES_BY_TOKEN.with(|index| token_candidates(&index.borrow(), symbol, upper));
+    }
+    if filter.from_time.is_some() || filter.to_time.is_some() {
+        let cursor = cursor.map(|sequence| {
+            let created_at = INVOICE_INDEX.with(|index| index.borrow().get(&sequence))
+                .and_then(|id| INVOICES.with(|invoices| invoices.borrow().get(&id)))
+                .map_or(u64::MAX, |invoice| invoice.created_at);
+            (created_at, sequence)
+        });
+        return INVOICES_BY_TIME.with(|index| time_candidates(&index.borrow(), filter.from_time, filter.to_time, cursor));
+    }
+
+    INVOICE_INDEX.with(|index| {
+        index.borrow()
+            .range(..upper)
+            .rev()
+            .take(MAX_PAGE_SCAN)
+            .collect()
     })
 }
 
-#[ic_cdk::update]
-fn generate_modal_embed_code(modal_id: String) -> Result<String, String> {
+#[ic_cdk::query]
+fn list_transactions(filter: TransactionFilter, cursor: Option<u64>, limit: u32) -> TransactionPage {
     let caller = ic_cdk::caller();
     let owner = OWNER.with(|o| *o.borrow().get());
-    
+
+    let mut filter = filter;
     if caller != owner {
-        return Err("Only the owner can generate embed codes".to_string());
+        filter.payer = Some(caller);
+    }
+
+    let candidates = transaction_candidates(&filter, cursor);
+    let (items, next_cursor) = TRANSACTIONS.with(|transactions| {
+        let map = transactions.borrow();
+        collect_page(
+            candidates,
+            limit,
+            |id| map.get(&id.to_string()),
+            |transaction| transaction_matches(transaction, &filter),
+        )
+    });
+
+    TransactionPage { items, next_cursor }
+}
+
+#[ic_cdk::query]
+fn list_invoices(filter: InvoiceFilter, cursor: Option<u64>, limit: u32) -> InvoicePage {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+
+    let mut filter = filter;
+    if caller != owner {
+        filter.payer = Some(caller);
     }
 
-    let config = MODAL_CONFIGS.with(|configs| {
-        configs.borrow().get(&modal_id)
-    }).ok_or("Modal configuration not found")?;
+    let candidates = invoice_candidates(&filter, cursor);
 
-    if !config.is_active {
-        return Err("Cannot generate embed code for inactive modal".to_string());
+    let current_time = ic_cdk::api::time();
+    let (items, next_cursor) = INVOICES.with(|invoices| {
+        let map = invoices.borrow();
+        collect_page(
+            candidates,
+            limit,
+            |id| map.get(&id.to_string()),
+            |invoice| invoice_matches(invoice, &filter, current_time),
+        )
+    });
+
+    InvoicePage { items, next_cursor }
+}
+
+// ============================================================================
+// DEPOSIT PAYMENTS
+// ============================================================================
+
+// Deterministic per-invoice subaccount, so invoices created before deposit
+// accounts existed resolve to the same account as new ones
+fn invoice_deposit_subaccount(invoice_id: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"invoice-deposit:");
+    hasher.update(invoice_id.as_bytes());
+    hasher.finalize().into()
+}
+
+#[ic_cdk::query]
+fn get_invoice_deposit_account(invoice_id: String) -> Result<InvoiceDepositAccount, String> {
+    let invoice = INVOICES.with(|invoices| invoices.borrow().get(&invoice_id))
+        .ok_or("Invoice not found")?;
+
+    Ok(InvoiceDepositAccount {
+        invoice_id: invoice.id.clone(),
+        owner: ic_cdk::id(),
+        subaccount: invoice_deposit_subaccount(&invoice.id).to_vec(),
+        amount_due: invoice_amount_due(&invoice).saturating_add(invoice.token.fee),
+    })
+}
+
+// Only the expected payer (or the owner on their behalf) may confirm a deposit.
+// Invoices without a recorded payer are confirmed by the owner, who then
+// becomes the payer of record
+fn deposit_payer(invoice: &PaymentInvoice, caller: Principal) -> Result<Principal, String> {
+    let owner = OWNER.with(|o| *o.borrow().get());
+    match invoice.payer {
+        Some(payer) if caller == payer || caller == owner => Ok(payer),
+        None if caller == owner => Ok(owner),
+        _ => Err("Only the invoice payer can confirm this payment".to_string()),
     }
+}
 
-    let canister_id = ic_cdk::id().to_text();
-    
-    let embed_code = format!(
-        r#"<script src="https://sdk.ckpayment.io/embed.js"></script>
-<script>
-  ckPayment.createModal({{
-    canisterId: "{}",
-    modalId: "{}",
-    theme: {{
-      primaryColor: "{}",
-      backgroundColor: "{}",
-      textColor: "{}",
-      borderRadius: {},
-      fontFamily: "{}"
-    }},
-    branding: {{
-      companyName: "{}",
-      logoUrl: "{}"
-    }},
-    redirectUrls: {{
-      successUrl: "{}",
-      cancelUrl: "{}"
-    }}
-  }});
-</script>"#,
-        canister_id,
-        modal_id,
-        config.theme.primary_color,
-        config.theme.background_color,
-        config.theme.text_color,
-        config.theme.border_radius,
-        config.theme.font_family,
-        config.branding.company_name,
-        config.branding.logo_url.as_deref().unwrap_or(""),
-        config.redirect_urls.success_url,
-        config.redirect_urls.cancel_url
+// Check the invoice's deposit account and, once it covers the amount due,
+// sweep it into the canister's main account and mark the invoice paid
+#[ic_cdk::update]
+async fn check_invoice_payment(invoice_id: String) -> Result<PaymentResult, String> {
+    let caller = ic_cdk::caller();
+    let _guard = InFlightGuard::acquire(format!("invoice:{}", invoice_id))?;
+
+    let invoice = INVOICES.with(|invoices| invoices.borrow().get(&invoice_id))
+        .ok_or("Invoice not found")?;
+
+    let payer = deposit_payer(&invoice, caller)?;
+    match invoice.status {
+        InvoiceStatus::Created => {},
+        InvoiceStatus::Paid => return Err("Invoice already paid".to_string()),
+        InvoiceStatus::Expired => return Err("Invoice expired".to_string()),
+        InvoiceStatus::Cancelled => return Err("Invoice cancelled".to_string()),
+    }
+    check_invoice_quote(&invoice, ic_cdk::api::time())?;
+
+    let subaccount = invoice_deposit_subaccount(&invoice.id);
+    let deposited = token_balance_of(
+        invoice.token.canister_id,
+        Account {
+            owner: ic_cdk::id(),
+            subaccount: Some(subaccount),
+        },
+    ).await?;
+
+    let amount_due = invoice_amount_due(&invoice).saturating_add(invoice.token.fee);
+    if deposited < amount_due {
+        return Err(format!("Payment not received: deposited {} of {}", deposited, amount_due));
+    }
+
+    // Sweep the whole deposit, overpayments are credited to the merchant
+    let final_amount = deposited - invoice.token.fee;
+    let block_index = transfer_token(
+        invoice.token.canister_id,
+        Some(subaccount),
+        Account {
+            owner: ic_cdk::id(),
+            subaccount: None,
+        },
+        final_amount,
+        invoice.token.fee,
+    ).await?;
+
+    let current_time = ic_cdk::api::time();
+    let transaction_id = NEXT_TRANSACTION_ID.with(|id| {
+        let current = *id.borrow().get();
+        id.borrow_mut().set(current + 1).unwrap();
+        format!("tx_{}", current)
+    });
+
+    let config = CONFIG.with(|c| c.borrow().get().clone());
+    let merchant_fee = (final_amount * config.merchant_fee as u64) / 10000;
+    let net_amount = final_amount.saturating_sub(merchant_fee);
+
+    // The ledger does not tell us who sent the deposit, so the payer recorded
+    // at creation is credited and receives any refunds
+    let transaction = PaymentTransaction {
+        id: transaction_id.clone(),
+        from: payer,
+        to: OWNER.with(|o| *o.borrow().get()),
+        token: invoice.token.clone(),
+        amount: final_amount,
+        fee: invoice.token.fee,
+        merchant_fee,
+        timestamp: current_time,
+        status: TransactionStatus::Completed,
+        metadata: vec![
+            ("invoice_id".to_string(), invoice.id.clone()),
+            ("deposit_subaccount".to_string(), to_hex(&subaccount)),
+            ("final_amount".to_string(), final_amount.to_string()),
+        ],
+        payment_method: PaymentMethod::Direct,
+        block_index: Some(block_index),
+        idempotency_key: None,
+    };
+
+    store_transaction(&transaction);
+
+    // Re-read the invoice, the sweep awaited and metadata may have changed
+    let mut invoice = INVOICES.with(|invoices| invoices.borrow().get(&invoice_id))
+        .unwrap_or(invoice);
+    mark_invoice_paid(&mut invoice, final_amount, net_amount, payer);
+
+    enqueue_webhook_event(
+        WebhookEventType::InvoicePaid,
+        json!({ "invoice": invoice, "transaction": transaction }),
+    );
+
+    Ok(PaymentResult {
+        transaction_id,
+        amount_paid: deposited,
+        discount_applied: 0,
+        final_amount,
+        block_index: Some(block_index),
+        payment_method: PaymentMethod::Direct,
+    })
+}
+
+// Return what is left in the deposit account of a closed invoice, e.g. a deposit that
+// arrived after the invoice expired or was cancelled, to the payer of record
+#[ic_cdk::update]
+async fn refund_invoice_deposit(invoice_id: String) -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    let _guard = InFlightGuard::acquire(format!("invoice:{}", invoice_id))?;
+
+    let invoice = INVOICES.with(|invoices| invoices.borrow().get(&invoice_id))
+        .ok_or("Invoice not found")?;
+
+    let payer = deposit_payer(&invoice, caller)?;
+    if matches!(invoice.status, InvoiceStatus::Created) {
+        return Err("Deposits of open invoices are confirmed with check_invoice_payment".to_string());
+    }
+
+    let subaccount = invoice_deposit_subaccount(&invoice.id);
+    let deposited = token_balance_of(
+        invoice.token.canister_id,
+        Account {
+            owner: ic_cdk::id(),
+            subaccount: Some(subaccount),
+        },
+    ).await?;
+    if deposited <= invoice.token.fee {
+        return Err("Nothing to refund".to_string());
+    }
+
+    transfer_token(
+        invoice.token.canister_id,
+        Some(subaccount),
+        Account {
+            owner: payer,
+            subaccount: None,
+        },
+        deposited - invoice.token.fee,
+        invoice.token.fee,
+    ).await
+}
+
+// ============================================================================
+// FIAT PRICING
+// ============================================================================
+
+const DEFAULT_EXCHANGE_RATE_CANISTER: &str = "uf6dk-hyaaa-aaaaq-qaaaq-cai";
+const EXCHANGE_RATE_CALL_CYCLES: u128 = 1_000_000_000;
+const QUOTE_TTL_NANOS: u64 = 15 * 60 * 1_000_000_000; // 15 minutes
+const EXCHANGE_RATE_CACHE_TTL_NANOS: u64 = 5 * 60 * 1_000_000_000; // 5 minutes
+const PUBLIC_RATE_FETCH_WINDOW_NANOS: u64 = 60 * 60 * 1_000_000_000; // 1 hour
+const MAX_PUBLIC_RATE_FETCHES_PER_WINDOW: usize = 20;
+const FIAT_DECIMALS: u32 = 2;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum AssetClass {
+    Cryptocurrency,
+    FiatCurrency,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Asset {
+    pub symbol: String,
+    pub class: AssetClass,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetExchangeRateRequest {
+    pub base_asset: Asset,
+    pub quote_asset: Asset,
+    pub timestamp: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ExchangeRateMetadata {
+    pub decimals: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ExchangeRate {
+    pub rate: u64,
+    pub timestamp: u64,
+    pub metadata: ExchangeRateMetadata,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ExchangeRateError {
+    AnonymousPrincipalNotAllowed,
+    Pending,
+    CryptoBaseAssetNotFound,
+    CryptoQuoteAssetNotFound,
+    StablecoinRateNotFound,
+    StablecoinRateTooFewRates,
+    StablecoinRateZeroRate,
+    ForexInvalidTimestamp,
+    ForexBaseAssetNotFound,
+    ForexQuoteAssetNotFound,
+    ForexAssetsNotFound,
+    RateLimited,
+    NotEnoughCycles,
+    FailedToAcceptCycles,
+    InconsistentRatesReceived,
+    Other { code: u32, description: String },
+}
+
+// The exchange rate canister knows the underlying asset, not the chain-key wrapper
+fn exchange_asset_symbol(token_symbol: &str) -> String {
+    token_symbol.strip_prefix("ck").unwrap_or(token_symbol).to_uppercase()
+}
+
+// Convert a fiat amount in minor units into token base units, rounding up so
+// the merchant never receives less than the fiat price
+fn fiat_to_token_amount(
+    fiat_amount: u64,
+    rate: u64,
+    rate_decimals: u32,
+    token_decimals: u8,
+) -> Result<u64, String> {
+    if rate == 0 {
+        return Err("Exchange rate is zero".to_string());
+    }
+
+    let overflow = || "Fiat amount too large to convert".to_string();
+    let numerator = (fiat_amount as u128)
+        .checked_mul(10u128.checked_pow(token_decimals as u32 + rate_decimals).ok_or_else(overflow)?)
+        .ok_or_else(overflow)?;
+    let denominator = (rate as u128) * 10u128.pow(FIAT_DECIMALS);
+
+    u64::try_from(numerator.div_ceil(denominator)).map_err(|_| overflow())
+}
+
+fn cached_exchange_rate(pair: &(String, String), current_time: u64) -> Option<ExchangeRate> {
+    EXCHANGE_RATE_CACHE.with(|cache| {
+        cache.borrow().get(pair)
+            .filter(|(fetched_at, _)| current_time < fetched_at.saturating_add(EXCHANGE_RATE_CACHE_TTL_NANOS))
+            .map(|(_, rate)| rate.clone())
+    })
+}
+
+// Every uncached fetch costs cycles, so non-owner callers share a budget per window
+fn reserve_public_rate_fetch(current_time: u64) -> Result<(), String> {
+    PUBLIC_RATE_FETCHES.with(|fetches| {
+        let mut fetches = fetches.borrow_mut();
+        fetches.retain(|fetched_at| current_time < fetched_at.saturating_add(PUBLIC_RATE_FETCH_WINDOW_NANOS));
+        if fetches.len() >= MAX_PUBLIC_RATE_FETCHES_PER_WINDOW {
+            return Err("Too many exchange rate requests, try again later".to_string());
+        }
+        fetches.push(current_time);
+        Ok(())
+    })
+}
+
+async fn fetch_exchange_rate(token_symbol: &str, fiat_currency: &str) -> Result<ExchangeRate, String> {
+    let current_time = ic_cdk::api::time();
+    let pair = (exchange_asset_symbol(token_symbol), fiat_currency.to_string());
+    if let Some(rate) = cached_exchange_rate(&pair, current_time) {
+        return Ok(rate);
+    }
+
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    if caller != owner && caller == Principal::anonymous() {
+        return Err("Anonymous callers cannot request a fresh exchange rate".to_string());
+    }
+    let _guard = InFlightGuard::acquire(format!("exchange_rate:{}/{}", pair.0, pair.1))?;
+    if caller != owner {
+        reserve_public_rate_fetch(current_time)?;
+    }
+
+    let request = GetExchangeRateRequest {
+        base_asset: Asset {
+            symbol: pair.0.clone(),
+            class: AssetClass::Cryptocurrency,
+        },
+        quote_asset: Asset {
+            symbol: pair.1.clone(),
+            class: AssetClass::FiatCurrency,
+        },
+        timestamp: None,
+    };
+
+    let exchange_rate_canister = EXCHANGE_RATE_CANISTER.with(|c| *c.borrow().get());
+    let result: Result<(Result<ExchangeRate, ExchangeRateError>,), _> = ic_cdk::api::call::call_with_payment128(
+        exchange_rate_canister,
+        "get_exchange_rate",
+        (request,),
+        EXCHANGE_RATE_CALL_CYCLES,
+    ).await;
+
+    match result {
+        Ok((Ok(rate),)) => {
+            EXCHANGE_RATE_CACHE.with(|cache| cache.borrow_mut().insert(pair, (current_time, rate.clone())));
+            Ok(rate)
+        },
+        Ok((Err(err),)) => Err(format!("Exchange rate unavailable: {:?}", err)),
+        Err((rejection_code, msg)) => {
+            Err(format!(
+                "Exchange rate call failed: {:?} - {}",
+                rejection_code, msg
+            ))
+        }
+    }
+}
+
+async fn quote_fiat_amount(fiat_amount: &FiatAmount, token: &TokenConfig) -> Result<ExchangeQuote, String> {
+    let exchange_rate = fetch_exchange_rate(&token.symbol, &fiat_amount.currency).await?;
+    let token_amount = fiat_to_token_amount(
+        fiat_amount.amount,
+        exchange_rate.rate,
+        exchange_rate.metadata.decimals,
+        token.decimals,
+    )?;
+
+    let current_time = ic_cdk::api::time();
+    Ok(ExchangeQuote {
+        token_symbol: token.symbol.clone(),
+        token_amount,
+        rate: exchange_rate.rate,
+        rate_decimals: exchange_rate.metadata.decimals,
+        rate_timestamp: exchange_rate.timestamp,
+        quoted_at: current_time,
+        expires_at: current_time + QUOTE_TTL_NANOS,
+    })
+}
+
+fn check_invoice_quote(invoice: &PaymentInvoice, current_time: u64) -> Result<(), String> {
+    if invoice.fiat_amount.is_none() {
+        return Ok(());
+    }
+
+    match &invoice.quote {
+        Some(quote) if current_time <= quote.expires_at => Ok(()),
+        Some(_) => Err("Price quote expired, refresh the invoice quote".to_string()),
+        None => Err("Invoice has no price quote".to_string()),
+    }
+}
+
+fn find_active_token(token_symbol: &str) -> Result<TokenConfig, String> {
+    let config = CONFIG.with(|c| c.borrow().get().clone());
+    config.supported_tokens
+        .into_iter()
+        .find(|t| t.symbol == token_symbol && t.is_active)
+        .ok_or("Token not supported or inactive".to_string())
+}
+
+#[ic_cdk::update]
+async fn create_fiat_invoice(
+    fiat_amount: FiatAmount,
+    token_symbol: String,
+    description: String,
+    metadata: Vec<(String, String)>,
+    expires_at: Option<u64>,
+) -> Result<PaymentInvoice, String> {
+    let expires_at = invoice_expiry(expires_at)?;
+    validate_caller_metadata(&metadata)?;
+    let currency = fiat_amount.currency.to_uppercase();
+    if currency.len() != 3 || !currency.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err("Fiat currency must be a three-letter ISO 4217 code".to_string());
+    }
+    if fiat_amount.amount == 0 {
+        return Err("Fiat amount must be greater than zero".to_string());
+    }
+    let fiat_amount = FiatAmount { currency, amount: fiat_amount.amount };
+
+    let token = find_active_token(&token_symbol)?;
+    let quote = quote_fiat_amount(&fiat_amount, &token).await?;
+
+    // Generate invoice ID
+    let invoice_id = NEXT_INVOICE_ID.with(|id| {
+        let current = *id.borrow().get();
+        id.borrow_mut().set(current + 1).unwrap();
+        format!("inv_{}", current)
+    });
+
+    let invoice = PaymentInvoice {
+        id: invoice_id.clone(),
+        merchant: OWNER.with(|o| *o.borrow().get()),
+        amount: quote.token_amount,
+        token,
+        description,
+        metadata,
+        expires_at: Some(expires_at),
+        created_at: ic_cdk::api::time(),
+        status: InvoiceStatus::Created,
+        deposit_subaccount: Some(invoice_deposit_subaccount(&invoice_id).to_vec()),
+        fiat_amount: Some(fiat_amount),
+        quote: Some(quote),
+        line_items: None,
+        tax_rate_bps: None,
+        shipping: None,
+        breakdown: None,
+        payer: expected_invoice_payer(),
+    };
+
+    store_invoice(&invoice);
+    Ok(invoice)
+}
+
+// Lock a fresh quote, optionally switching the invoice to another supported token.
+// A still valid quote for the same token is kept to avoid paying for needless rate calls.
+#[ic_cdk::update]
+async fn refresh_invoice_quote(invoice_id: String, token_symbol: Option<String>) -> Result<PaymentInvoice, String> {
+    let caller = ic_cdk::caller();
+    let _guard = InFlightGuard::acquire(format!("invoice:{}", invoice_id))?;
+
+    let invoice = INVOICES.with(|invoices| invoices.borrow().get(&invoice_id))
+        .ok_or("Invoice not found")?;
+
+    let owner = OWNER.with(|o| *o.borrow().get());
+    if caller != owner && invoice.payer != Some(caller) {
+        return Err("Only the invoice's customer or the owner can re-quote it".to_string());
+    }
+
+    let fiat_amount = invoice.fiat_amount.clone()
+        .ok_or("Invoice is not priced in a fiat currency")?;
+
+    if !matches!(invoice.status, InvoiceStatus::Created) {
+        return Err("Only unpaid invoices can be re-quoted".to_string());
+    }
+
+    let token_symbol = token_symbol.unwrap_or_else(|| invoice.token.symbol.clone());
+    if token_symbol == invoice.token.symbol && check_invoice_quote(&invoice, ic_cdk::api::time()).is_ok() {
+        return Ok(invoice);
+    }
+
+    // A deposit already made in the current token would be stranded by a switch
+    let switching_token = token_symbol != invoice.token.symbol;
+    if switching_token {
+        let deposited = token_balance_of(
+            invoice.token.canister_id,
+            Account {
+                owner: ic_cdk::id(),
+                subaccount: Some(invoice_deposit_subaccount(&invoice.id)),
+            },
+        ).await?;
+        if deposited > 0 {
+            return Err("The invoice has a deposit in its current token, confirm or refund it first".to_string());
+        }
+    }
+
+    let token = find_active_token(&token_symbol)?;
+    let quote = quote_fiat_amount(&fiat_amount, &token).await?;
+
+    let mut invoice = INVOICES.with(|invoices| invoices.borrow().get(&invoice_id))
+        .ok_or("Invoice not found")?;
+    if !matches!(invoice.status, InvoiceStatus::Created) {
+        return Err("Only unpaid invoices can be re-quoted".to_string());
+    }
+    invoice.amount = quote.token_amount;
+    invoice.token = token;
+    invoice.quote = Some(quote);
+    store_invoice(&invoice);
+
+    if switching_token {
+        requote_invoice_coupon(&invoice);
+    }
+    Ok(invoice)
+}
+
+// A reserved discount is an amount in the invoice's old token. The reservation is given
+// back and the coupon evaluated again for the new token, it is dropped if it no longer applies.
+fn requote_invoice_coupon(invoice: &PaymentInvoice) {
+    let usage = match invoice_coupon_usage(&invoice.id) {
+        Some(usage) if usage.status == Some(CouponUsageStatus::Reserved) => usage,
+        _ => return,
+    };
+    let coupon_code = DISCOUNT_COUPONS.with(|coupons| coupons.borrow().get(&usage.coupon_id))
+        .map(|coupon| coupon.code);
+    release_invoice_coupon(&invoice.id);
+
+    let evaluated = coupon_code.ok_or_else(|| "Coupon not found".to_string())
+        .and_then(|code| evaluate_invoice_coupon(&code, invoice, usage.user_principal));
+    match evaluated {
+        Ok((coupon, discount)) => {
+            reserve_coupon(&coupon, &invoice.id, usage.user_principal, discount);
+        },
+        Err(err) => ic_cdk::println!("Coupon dropped from re-quoted invoice {}: {}", invoice.id, err),
+    }
+}
+
+#[ic_cdk::update]
+fn set_exchange_rate_canister(canister_id: Principal) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can set the exchange rate canister".to_string());
+    }
+
+    EXCHANGE_RATE_CANISTER.with(|c| c.borrow_mut().set(canister_id).unwrap());
+    Ok(())
+}
+
+// ============================================================================
+// BALANCE AND WITHDRAWAL MANAGEMENT
+// ============================================================================
+
+#[ic_cdk::query]
+fn get_balance(token_symbol: String) -> u64 {
+    BALANCES.with(|balances| balances.borrow().get(&token_symbol).unwrap_or(0))
+}
+
+#[ic_cdk::query]
+fn get_all_balances() -> Vec<(String, u64)> {
+    BALANCES.with(|balances| balances.borrow().iter().collect())
+}
+
+fn credit_balance(token_symbol: &str, amount: u64) {
+    BALANCES.with(|balances| {
+        let mut map = balances.borrow_mut();
+        let current_balance = map.get(&token_symbol.to_string()).unwrap_or(0);
+        map.insert(token_symbol.to_string(), current_balance + amount);
+    });
+}
+
+fn debit_balance(token_symbol: &str, amount: u64) -> Result<(), String> {
+    BALANCES.with(|balances| {
+        let mut map = balances.borrow_mut();
+        let current_balance = map.get(&token_symbol.to_string()).unwrap_or(0);
+        if current_balance < amount {
+            return Err("Insufficient balance".to_string());
+        }
+        map.insert(token_symbol.to_string(), current_balance - amount);
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+async fn withdraw(
+    token_symbol: String,
+    amount: u64,
+    to: Principal,
+    to_subaccount: Option<Vec<u8>>,
+) -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can withdraw".to_string());
+    }
+
+    if to == Principal::anonymous() {
+        return Err("Cannot withdraw to the anonymous principal".to_string());
+    }
+
+    execute_withdrawal(&token_symbol, amount, to, to_subaccount, caller).await
+}
+
+// Reserve, transfer and record a withdrawal out of the canister's main account
+async fn execute_withdrawal(
+    token_symbol: &str,
+    amount: u64,
+    to: Principal,
+    to_subaccount: Option<Vec<u8>>,
+    requested_by: Principal,
+) -> Result<u64, String> {
+    let subaccount = parse_subaccount(to_subaccount.clone())?;
+
+    // Inactive tokens can still be withdrawn, they only stop accepting payments
+    let config = CONFIG.with(|c| c.borrow().get().clone());
+    let token = config.supported_tokens
+        .iter()
+        .find(|t| t.symbol == token_symbol)
+        .ok_or("Token not supported")?
+        .clone();
+
+    // The ledger fee is paid out of the withdrawn amount
+    if amount <= token.fee {
+        return Err(format!("Withdrawal amount must exceed the ledger fee of {}", token.fee));
+    }
+
+    // Reserve the funds before calling the ledger so concurrent withdrawals cannot overdraw
+    debit_balance(token_symbol, amount)?;
+
+    let withdrawal_id = NEXT_WITHDRAWAL_ID.with(|id| {
+        let current = *id.borrow().get();
+        id.borrow_mut().set(current + 1).unwrap();
+        format!("wd_{}", current)
+    });
+
+    let mut record = WithdrawalRecord {
+        id: withdrawal_id.clone(),
+        token_symbol: token_symbol.to_string(),
+        amount,
+        fee: token.fee,
+        to,
+        to_subaccount,
+        status: WithdrawalStatus::Pending,
+        block_index: None,
+        requested_by,
+        created_at: ic_cdk::api::time(),
+        completed_at: None,
+    };
+
+    WITHDRAWALS.with(|withdrawals| {
+        withdrawals.borrow_mut().insert(withdrawal_id.clone(), record.clone())
+    });
+
+    let transfer_result = transfer_token(
+        token.canister_id,
+        None,
+        Account {
+            owner: to,
+            subaccount,
+        },
+        amount - token.fee,
+        token.fee,
+    ).await;
+
+    let result = match transfer_result {
+        Ok(block_index) => {
+            record.status = WithdrawalStatus::Completed;
+            record.block_index = Some(block_index);
+            record.completed_at = Some(ic_cdk::api::time());
+            Ok(block_index)
+        },
+        Err(err) => {
+            ic_cdk::println!("Withdrawal {} failed: {}", withdrawal_id, err);
+            // Give the reserved funds back so the balance matches the ledger again
+            credit_balance(token_symbol, amount);
+            record.status = WithdrawalStatus::Failed(err.clone());
+            Err(format!("Withdrawal failed: {}", err))
+        }
+    };
+
+    WITHDRAWALS.with(|withdrawals| {
+        withdrawals.borrow_mut().insert(withdrawal_id, record)
+    });
+
+    result
+}
+
+#[ic_cdk::query]
+fn get_withdrawal(withdrawal_id: String) -> Result<WithdrawalRecord, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can view withdrawals".to_string());
+    }
+
+    WITHDRAWALS.with(|withdrawals| {
+        withdrawals.borrow().get(&withdrawal_id)
+            .ok_or("Withdrawal not found".to_string())
+    })
+}
+
+#[ic_cdk::query]
+fn list_withdrawals() -> Vec<WithdrawalRecord> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return vec![];
+    }
+
+    WITHDRAWALS.with(|withdrawals| {
+        withdrawals.borrow().iter().map(|(_, withdrawal)| withdrawal).collect()
+    })
+}
+
+// ============================================================================
+// AUTOMATIC PAYOUTS
+// ============================================================================
+
+const PAYOUT_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MAX_PAYOUT_FAILURES: u32 = 3;
+
+// A token is paid out once its balance reaches the configured threshold, or on
+// every tick of the cadence as long as there is more than the ledger fee to send
+fn payout_due(
+    settings: &PayoutSettings,
+    withdraw_threshold: Option<u64>,
+    balance: u64,
+    fee: u64,
+    current_time: u64,
+) -> bool {
+    if balance <= fee {
+        return false;
+    }
+
+    let threshold_reached = withdraw_threshold.is_some_and(|threshold| balance >= threshold);
+    let cadence_reached = settings.cadence_seconds.is_some_and(|cadence| {
+        let last_payout = settings.last_payout_at.unwrap_or(0);
+        current_time >= last_payout.saturating_add(cadence.saturating_mul(SECONDS_TO_NANOS))
+    });
+
+    threshold_reached || cadence_reached
+}
+
+async fn run_auto_payouts() {
+    let config = CONFIG.with(|c| c.borrow().get().clone());
+    let settings = PAYOUT_SETTINGS.with(|s| s.borrow().get().clone());
+
+    if !config.auto_withdraw || settings.paused {
+        return;
+    }
+
+    let _guard = match InFlightGuard::acquire("auto_payout".to_string()) {
+        Ok(guard) => guard,
+        Err(_) => return,
+    };
+
+    let destination = settings.destination.unwrap_or_else(|| OWNER.with(|o| *o.borrow().get()));
+    let current_time = ic_cdk::api::time();
+
+    for token in config.supported_tokens.iter() {
+        let balance = get_balance(token.symbol.clone());
+        if !payout_due(&settings, config.withdraw_threshold, balance, token.fee, current_time) {
+            continue;
+        }
+
+        let result = execute_withdrawal(
+            &token.symbol,
+            balance,
+            destination,
+            settings.destination_subaccount.clone(),
+            ic_cdk::id(),
+        ).await;
+
+        let paused = PAYOUT_SETTINGS.with(|s| {
+            let mut settings = s.borrow().get().clone();
+            match &result {
+                Ok(_) => {
+                    settings.consecutive_failures = 0;
+                    settings.last_payout_at = Some(ic_cdk::api::time());
+                },
+                Err(err) => {
+                    settings.consecutive_failures += 1;
+                    if settings.consecutive_failures >= MAX_PAYOUT_FAILURES {
+                        settings.paused = true;
+                        settings.paused_reason = Some(err.clone());
+                    }
+                },
+            }
+            let paused = settings.paused;
+            s.borrow_mut().set(settings).unwrap();
+            paused
+        });
+
+        if paused {
+            ic_cdk::println!("Automatic payouts paused after {} failures", MAX_PAYOUT_FAILURES);
+            break;
+        }
+    }
+}
+
+#[ic_cdk::update]
+fn set_payout_settings(
+    destination: Option<Principal>,
+    destination_subaccount: Option<Vec<u8>>,
+    cadence_seconds: Option<u64>,
+) -> Result<PayoutSettings, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can change payout settings".to_string());
+    }
+
+    if destination == Some(Principal::anonymous()) {
+        return Err("Cannot pay out to the anonymous principal".to_string());
+    }
+    parse_subaccount(destination_subaccount.clone())?;
+
+    if cadence_seconds == Some(0) {
+        return Err("Payout cadence must be greater than zero".to_string());
+    }
+
+    PAYOUT_SETTINGS.with(|s| {
+        let mut settings = s.borrow().get().clone();
+        settings.destination = destination;
+        settings.destination_subaccount = destination_subaccount;
+        settings.cadence_seconds = cadence_seconds;
+        s.borrow_mut().set(settings.clone()).unwrap();
+        Ok(settings)
+    })
+}
+
+#[ic_cdk::query]
+fn get_payout_settings() -> Result<PayoutSettings, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can view payout settings".to_string());
+    }
+
+    Ok(PAYOUT_SETTINGS.with(|s| s.borrow().get().clone()))
+}
+
+// Re-enable automatic payouts after they were paused by repeated ledger failures
+#[ic_cdk::update]
+fn resume_auto_payouts() -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can resume payouts".to_string());
+    }
+
+    PAYOUT_SETTINGS.with(|s| {
+        let mut settings = s.borrow().get().clone();
+        settings.paused = false;
+        settings.paused_reason = None;
+        settings.consecutive_failures = 0;
+        s.borrow_mut().set(settings).unwrap();
+    });
+    Ok(())
+}
+
+// ============================================================================
+// PLATFORM FEES
+// ============================================================================
+
+const FEE_SWEEP_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+// Only sweep once the pool is worth this many ledger fees, so transfer costs stay below 1%
+const FEE_SWEEP_MIN_LEDGER_FEES: u64 = 100;
+
+fn accrue_platform_fee(token_symbol: &str, amount: u64) {
+    if amount == 0 {
+        return;
+    }
+    PENDING_FEES.with(|fees| {
+        let mut map = fees.borrow_mut();
+        let current = map.get(&token_symbol.to_string()).unwrap_or(0);
+        map.insert(token_symbol.to_string(), current + amount);
+    });
+}
+
+fn pending_platform_fees(token_symbol: &str) -> u64 {
+    PENDING_FEES.with(|fees| fees.borrow().get(&token_symbol.to_string()).unwrap_or(0))
+}
+
+fn fee_sweep_due(pending: u64, ledger_fee: u64) -> bool {
+    pending > ledger_fee && pending >= ledger_fee.max(1).saturating_mul(FEE_SWEEP_MIN_LEDGER_FEES)
+}
+
+async fn sweep_platform_fees() {
+    let treasury = match PLATFORM_TREASURY.with(|t| t.borrow().get().clone()) {
+        Some(treasury) => treasury,
+        None => return, // Fees keep accruing until the factory registers a treasury
+    };
+
+    let _guard = match InFlightGuard::acquire("fee_sweep".to_string()) {
+        Ok(guard) => guard,
+        Err(_) => return,
+    };
+
+    let treasury_subaccount = match parse_subaccount(treasury.subaccount.clone()) {
+        Ok(subaccount) => subaccount,
+        Err(err) => {
+            ic_cdk::println!("Invalid treasury subaccount: {}", err);
+            return;
+        }
+    };
+
+    let config = CONFIG.with(|c| c.borrow().get().clone());
+    for token in config.supported_tokens.iter() {
+        let pending = pending_platform_fees(&token.symbol);
+        if !fee_sweep_due(pending, token.fee) {
+            continue;
+        }
+
+        // Take the whole pool out before the ledger call, fees accrued meanwhile stay pending
+        PENDING_FEES.with(|fees| fees.borrow_mut().insert(token.symbol.clone(), 0));
+
+        let sweep_id = NEXT_FEE_SWEEP_ID.with(|id| {
+            let current = *id.borrow().get();
+            id.borrow_mut().set(current + 1).unwrap();
+            format!("fee_{}", current)
+        });
+
+        let mut sweep = FeeSweep {
+            id: sweep_id.clone(),
+            token_symbol: token.symbol.clone(),
+            amount: pending,
+            fee: token.fee,
+            treasury: treasury.clone(),
+            status: FeeSweepStatus::Pending,
+            block_index: None,
+            created_at: ic_cdk::api::time(),
+            completed_at: None,
+        };
+        FEE_SWEEPS.with(|sweeps| sweeps.borrow_mut().insert(sweep_id.clone(), sweep.clone()));
+
+        let transfer_result = transfer_token(
+            token.canister_id,
+            None,
+            Account {
+                owner: treasury.owner,
+                subaccount: treasury_subaccount,
+            },
+            pending - token.fee,
+            token.fee,
+        ).await;
+
+        match transfer_result {
+            Ok(block_index) => {
+                sweep.status = FeeSweepStatus::Completed;
+                sweep.block_index = Some(block_index);
+                sweep.completed_at = Some(ic_cdk::api::time());
+            },
+            Err(err) => {
+                ic_cdk::println!("Fee sweep {} failed: {}", sweep_id, err);
+                accrue_platform_fee(&token.symbol, pending);
+                sweep.status = FeeSweepStatus::Failed(err);
+            },
+        }
+        FEE_SWEEPS.with(|sweeps| sweeps.borrow_mut().insert(sweep_id, sweep));
+    }
+}
+
+fn is_factory(caller: Principal) -> bool {
+    caller != Principal::anonymous() && caller == FACTORY.with(|f| *f.borrow().get())
+}
+
+#[ic_cdk::update]
+fn register_platform_treasury(treasury: PlatformTreasury) -> Result<(), String> {
+    if !is_factory(ic_cdk::caller()) {
+        return Err("Only the factory can register the platform treasury".to_string());
+    }
+
+    if treasury.owner == Principal::anonymous() {
+        return Err("Treasury cannot be the anonymous principal".to_string());
+    }
+    parse_subaccount(treasury.subaccount.clone())?;
+
+    PLATFORM_TREASURY.with(|t| t.borrow_mut().set(Some(treasury)).unwrap());
+    Ok(())
+}
+
+const MAX_MERCHANT_FEE_BPS: u32 = 1_000; // Same 10% ceiling the factory enforces at deployment
+
+#[ic_cdk::update]
+fn set_merchant_fee(merchant_fee: u32) -> Result<(), String> {
+    if !is_factory(ic_cdk::caller()) {
+        return Err("Only the factory can set the merchant fee".to_string());
+    }
+    if merchant_fee > MAX_MERCHANT_FEE_BPS {
+        return Err(format!("Merchant fee cannot exceed {} basis points", MAX_MERCHANT_FEE_BPS));
+    }
+
+    CONFIG.with(|c| {
+        let mut config = c.borrow().get().clone();
+        config.merchant_fee = merchant_fee;
+        c.borrow_mut().set(config).unwrap();
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_platform_fee_summary() -> Result<Vec<FeeRevenue>, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner && !is_factory(caller) {
+        return Err("Only the owner or the factory can view platform fees".to_string());
+    }
+
+    let config = CONFIG.with(|c| c.borrow().get().clone());
+    let summary = config.supported_tokens.iter()
+        .map(|token| {
+            let pending = pending_platform_fees(&token.symbol);
+            let (swept, in_flight) = FEE_SWEEPS.with(|sweeps| {
+                sweeps.borrow().iter()
+                    .filter(|(_, sweep)| sweep.token_symbol == token.symbol)
+                    .fold((0u64, 0u64), |(swept, in_flight), (_, sweep)| match sweep.status {
+                        FeeSweepStatus::Completed => (swept + sweep.amount, in_flight),
+                        FeeSweepStatus::Pending => (swept, in_flight + sweep.amount),
+                        FeeSweepStatus::Failed(_) => (swept, in_flight),
+                    })
+            });
+
+            FeeRevenue {
+                token_symbol: token.symbol.clone(),
+                accrued: pending + in_flight + swept,
+                pending,
+                swept,
+            }
+        })
+        .collect();
+
+    Ok(summary)
+}
+
+#[ic_cdk::query]
+fn list_fee_sweeps() -> Vec<FeeSweep> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner && !is_factory(caller) {
+        return vec![];
+    }
+
+    FEE_SWEEPS.with(|sweeps| {
+        sweeps.borrow().iter().map(|(_, sweep)| sweep).collect()
+    })
+}
+
+// ============================================================================
+// REFUND MANAGEMENT
+// ============================================================================
+
+// Sum of refunds that are completed or still in flight for a transaction
+fn refunded_amount(transaction_id: &str) -> u64 {
+    REFUNDS.with(|refunds| {
+        refunds.borrow().iter()
+            .filter(|(_, refund)| {
+                refund.transaction_id == transaction_id &&
+                matches!(refund.status, RefundStatus::Pending | RefundStatus::Completed)
+            })
+            .map(|(_, refund)| refund.amount)
+            .sum()
+    })
+}
+
+fn metadata_value(metadata: &[(String, String)], key: &str) -> Option<String> {
+    metadata.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone())
+}
+
+// Keys the canister writes itself, callers cannot set them on invoices
+const RESERVED_METADATA_KEYS: &[&str] = &[
+    "product_id",
+    "quantity",
+    "order_id",
+    "coupon_id",
+    "invoice_id",
+    "discount_applied",
+    "original_amount",
+    "final_amount",
+    "ledger_created_at",
+];
+
+fn validate_caller_metadata(metadata: &[(String, String)]) -> Result<(), String> {
+    match metadata.iter().find(|(key, _)| RESERVED_METADATA_KEYS.contains(&key.as_str())) {
+        Some((key, _)) => Err(format!("Metadata key '{}' is reserved", key)),
+        None => Ok(()),
+    }
+}
+
+fn set_metadata_value(metadata: &mut Vec<(String, String)>, key: &str, value: String) {
+    if let Some(entry) = metadata.iter_mut().find(|(k, _)| k == key) {
+        entry.1 = value;
+    } else {
+        metadata.push((key.to_string(), value));
+    }
+}
+
+#[ic_cdk::update]
+async fn refund_transaction(
+    transaction_id: String,
+    reason: Option<String>,
+    reopen_invoice: bool,
+) -> Result<RefundRecord, String> {
+    let transaction = TRANSACTIONS.with(|transactions| {
+        transactions.borrow().get(&transaction_id)
+    }).ok_or("Transaction not found")?;
+
+    let remaining = transaction.amount.saturating_sub(refunded_amount(&transaction_id));
+    execute_refund(transaction_id, remaining, reason, reopen_invoice).await
+}
+
+#[ic_cdk::update]
+async fn partial_refund_transaction(
+    transaction_id: String,
+    amount: u64,
+    reason: Option<String>,
+) -> Result<RefundRecord, String> {
+    execute_refund(transaction_id, amount, reason, false).await
+}
+
+async fn execute_refund(
+    transaction_id: String,
+    amount: u64,
+    reason: Option<String>,
+    reopen_invoice: bool,
+) -> Result<RefundRecord, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can refund transactions".to_string());
+    }
+
+    let transaction = TRANSACTIONS.with(|transactions| {
+        transactions.borrow().get(&transaction_id)
+    }).ok_or("Transaction not found")?;
+
+    match transaction.status {
+        TransactionStatus::Completed => {},
+        TransactionStatus::Refunded => return Err("Transaction is already fully refunded".to_string()),
+        _ => return Err("Only completed transactions can be refunded".to_string()),
+    }
+
+    if amount == 0 {
+        return Err("Refund amount must be greater than 0".to_string());
+    }
+
+    let already_refunded = refunded_amount(&transaction_id);
+    let refundable = transaction.amount.saturating_sub(already_refunded);
+    if amount > refundable {
+        return Err(format!("Refund amount exceeds refundable amount of {}", refundable));
+    }
+    let is_full_refund = already_refunded + amount == transaction.amount;
+
+    let token = transaction.token.clone();
+
+    // The merchant covers the ledger fee so the payer gets back exactly `amount`
+    debit_balance(&token.symbol, amount + token.fee)?;
+
+    let refund_id = NEXT_REFUND_ID.with(|id| {
+        let current = *id.borrow().get();
+        id.borrow_mut().set(current + 1).unwrap();
+        format!("refund_{}", current)
+    });
+
+    let invoice_id = metadata_value(&transaction.metadata, "invoice_id");
+
+    let mut refund = RefundRecord {
+        refund_id: refund_id.clone(),
+        transaction_id: transaction_id.clone(),
+        invoice_id: invoice_id.clone(),
+        token_symbol: token.symbol.clone(),
+        amount,
+        fee: token.fee,
+        to: transaction.from,
+        reason,
+        is_full_refund,
+        status: RefundStatus::Pending,
+        block_index: None,
+        created_at: ic_cdk::api::time(),
+        completed_at: None,
+    };
+
+    // Store the pending refund before the ledger call so concurrent refunds see it
+    REFUNDS.with(|refunds| refunds.borrow_mut().insert(refund_id.clone(), refund.clone()));
+
+    let transfer_result = transfer_token(
+        token.canister_id,
+        None,
+        Account {
+            owner: transaction.from,
+            subaccount: None,
+        },
+        amount,
+        token.fee,
+    ).await;
+
+    match transfer_result {
+        Ok(block_index) => {
+            refund.status = RefundStatus::Completed;
+            refund.block_index = Some(block_index);
+            refund.completed_at = Some(ic_cdk::api::time());
+            REFUNDS.with(|refunds| refunds.borrow_mut().insert(refund_id, refund.clone()));
+
+            apply_refund_effects(&transaction, &refund, reopen_invoice);
+            enqueue_webhook_event(WebhookEventType::Refunded, json!({ "refund": refund }));
+            Ok(refund)
+        },
+        Err(err) => {
+            ic_cdk::println!("Refund {} failed: {}", refund_id, err);
+            credit_balance(&token.symbol, amount + token.fee);
+            refund.status = RefundStatus::Failed(err.clone());
+            REFUNDS.with(|refunds| refunds.borrow_mut().insert(refund_id, refund));
+            Err(format!("Refund failed: {}", err))
+        }
+    }
+}
+
+// Update the transaction, invoice, product stats and coupon usage after a completed refund
+fn apply_refund_effects(transaction: &PaymentTransaction, refund: &RefundRecord, reopen_invoice: bool) {
+    let current_time = ic_cdk::api::time();
+    let total_refunded = refunded_amount(&transaction.id);
+
+    if let Some(mut tx) = TRANSACTIONS.with(|transactions| transactions.borrow().get(&transaction.id)) {
+        if refund.is_full_refund {
+            tx.status = TransactionStatus::Refunded;
+        }
+        set_metadata_value(&mut tx.metadata, "refunded_amount", total_refunded.to_string());
+        store_transaction(&tx);
+    }
+
+    let mut sold_lines = Vec::new();
+    if let Some(invoice_id) = &refund.invoice_id {
+        if let Some(mut invoice) = INVOICES.with(|invoices| invoices.borrow().get(invoice_id)) {
+            sold_lines = sold_product_lines(&invoice);
+
+            let refund_status = if refund.is_full_refund { "refunded" } else { "partially_refunded" };
+            set_metadata_value(&mut invoice.metadata, "refund_status", refund_status.to_string());
+            set_metadata_value(&mut invoice.metadata, "refunded_amount", total_refunded.to_string());
+
+            // A reopened invoice can be paid again, e.g. after refunding a payment in the wrong amount.
+            // Its stock is held again, or checked once more when it is paid.
+            if refund.is_full_refund && reopen_invoice {
+                invoice.status = InvoiceStatus::Created;
+                invoice.expires_at = Some(current_time + default_invoice_ttl_nanos());
+                let holder = invoice.payer.unwrap_or(transaction.from);
+                if let Err(err) = reserve_inventory(&invoice.id, invoice_product_items(&invoice), holder, current_time) {
+                    ic_cdk::println!("Stock for reopened invoice {} not held: {}", invoice.id, err);
+                }
+            }
+
+            store_invoice(&invoice);
+        }
+    }
+
+    // Each line gives back its share of the refund, a full refund also takes back its units
+    for line in sold_lines {
+        let amount = (line.amount as u128 * refund.amount as u128 / transaction.amount.max(1) as u128) as u64;
+        reverse_product_sales_stats(
+            &line.product_id,
+            line.variant_sku.as_deref(),
+            &transaction.token.symbol,
+            amount.min(line.amount),
+            line.units,
+            refund.is_full_refund,
+        );
+    }
+
+    if refund.is_full_refund {
+        if let Some(coupon_id) = metadata_value(&transaction.metadata, "coupon_id") {
+            release_coupon_usage(&coupon_id, transaction.from, transaction.timestamp);
+        }
+        if let Some(invoice_id) = &refund.invoice_id {
+            release_invoice_coupon(invoice_id);
+            revoke_invoice_deliveries(invoice_id);
+        }
+    }
+}
+
+#[ic_cdk::query]
+fn get_transaction_refunds(transaction_id: String) -> Result<Vec<RefundRecord>, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+
+    let transaction = TRANSACTIONS.with(|transactions| {
+        transactions.borrow().get(&transaction_id)
+    }).ok_or("Transaction not found")?;
+
+    // Payers can follow refunds of their own payments
+    if caller != owner && caller != transaction.from {
+        return Err("Only the owner or payer can view refunds for this transaction".to_string());
+    }
+
+    Ok(REFUNDS.with(|refunds| {
+        refunds.borrow().iter()
+            .filter(|(_, refund)| refund.transaction_id == transaction_id)
+            .map(|(_, refund)| refund)
+            .collect()
+    }))
+}
+
+#[ic_cdk::query]
+fn list_refunds() -> Vec<RefundRecord> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return vec![];
+    }
+
+    REFUNDS.with(|refunds| {
+        refunds.borrow().iter().map(|(_, refund)| refund).collect()
+    })
+}
+
+// ============================================================================
+// LEDGER RECONCILIATION
+// ============================================================================
+
+const RECONCILIATION_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const MAX_BLOCK_CHECKS_PER_RUN: usize = 100;
+const MAX_RECONCILIATION_REPORTS: u64 = 30;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetTransactionsRequest {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+// Subset of the ICRC-1 ledger `get_transactions` response, unknown fields are skipped on decode
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LedgerAccount {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LedgerTransfer {
+    pub from: LedgerAccount,
+    pub to: LedgerAccount,
+    pub amount: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LedgerTransaction {
+    pub kind: String,
+    pub transfer: Option<LedgerTransfer>,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetTransactionsResponse {
+    pub log_length: Nat,
+    pub first_index: Nat,
+    pub transactions: Vec<LedgerTransaction>,
+}
+
+// Compare what the ledger holds with what the canister believes it holds
+fn reconcile_token(
+    token_symbol: &str,
+    ledger_balance: Result<u64, String>,
+    internal_balance: u64,
+    pending_withdrawals: u64,
+    pending_refunds: u64,
+    retained_fees: u64,
+) -> TokenReconciliation {
+    let accounted = internal_balance as i128 + pending_withdrawals as i128 + pending_refunds as i128 + retained_fees as i128;
+    let (ledger_balance, difference, error) = match ledger_balance {
+        Ok(balance) => {
+            let difference = (balance as i128 - accounted).clamp(i64::MIN as i128, i64::MAX as i128) as i64;
+            (Some(balance), difference, None)
+        },
+        Err(err) => (None, 0, Some(err)),
+    };
+
+    TokenReconciliation {
+        token_symbol: token_symbol.to_string(),
+        ledger_balance,
+        internal_balance,
+        pending_withdrawals,
+        pending_refunds,
+        retained_fees,
+        difference,
+        error,
+    }
+}
+
+// Check that a recorded block moved the transaction's amount into one of our accounts
+fn verify_ledger_block(transaction: &PaymentTransaction, block: Option<&LedgerTransaction>) -> Option<String> {
+    let block = match block {
+        Some(block) => block,
+        None => return Some("Block not found on the ledger (archived or missing)".to_string()),
+    };
+
+    let transfer = match &block.transfer {
+        Some(transfer) => transfer,
+        None => return Some(format!("Block is a {} instead of a transfer", block.kind)),
+    };
+
+    // Payments made before funds were held by the canister went straight to the owner
+    if transfer.to.owner != ic_cdk::id() && transfer.to.owner != transaction.to {
+        return Some(format!("Block pays {} instead of this canister", transfer.to.owner));
+    }
+
+    if transfer.amount != transaction.amount {
+        return Some(format!("Block amount {} does not match transaction amount {}", transfer.amount, transaction.amount));
+    }
+
+    None
+}
+
+async fn get_ledger_block(token_canister_id: Principal, block_index: u64) -> Result<Option<LedgerTransaction>, String> {
+    let request = GetTransactionsRequest {
+        start: Nat::from(block_index),
+        length: Nat::from(1u64),
+    };
+
+    let result: Result<(GetTransactionsResponse,), _> = ic_cdk::call(
+        token_canister_id,
+        "get_transactions",
+        (request,),
+    ).await;
+
+    match result {
+        Ok((response,)) => {
+            // Blocks moved to an archive are not returned inline
+            if response.first_index != block_index {
+                return Ok(None);
+            }
+            Ok(response.transactions.into_iter().next())
+        },
+        Err((rejection_code, msg)) => {
+            Err(format!(
+                "Block query failed: {:?} - {}",
+                rejection_code, msg
+            ))
+        }
+    }
+}
+
+async fn reconcile() -> Result<ReconciliationReport, String> {
+    let _guard = InFlightGuard::acquire("reconciliation".to_string())?;
+
+    let config = CONFIG.with(|c| c.borrow().get().clone());
+    let transactions: Vec<PaymentTransaction> = TRANSACTIONS.with(|t| {
+        t.borrow().iter().map(|(_, tx)| tx).collect()
+    });
+
+    let mut tokens = Vec::new();
+    for token in config.supported_tokens.iter().filter(|t| t.is_active) {
+        let ledger_balance = token_balance_of(
+            token.canister_id,
+            Account {
+                owner: ic_cdk::id(),
+                subaccount: None,
+            },
+        ).await;
+
+        // Snapshot internal state after the await so it matches the ledger reading
+        let internal_balance = get_balance(token.symbol.clone());
+        let pending_withdrawals = WITHDRAWALS.with(|withdrawals| {
+            withdrawals.borrow().iter()
+                .filter(|(_, w)| w.token_symbol == token.symbol && matches!(w.status, WithdrawalStatus::Pending))
+                .map(|(_, w)| w.amount)
+                .sum()
+        });
+        let pending_refunds = REFUNDS.with(|refunds| {
+            refunds.borrow().iter()
+                .filter(|(_, r)| r.token_symbol == token.symbol && matches!(r.status, RefundStatus::Pending))
+                .map(|(_, r)| r.amount + r.fee)
+                .sum()
+        });
+        let retained_fees = pending_platform_fees(&token.symbol) + FEE_SWEEPS.with(|sweeps| {
+            sweeps.borrow().iter()
+                .filter(|(_, sweep)| sweep.token_symbol == token.symbol && matches!(sweep.status, FeeSweepStatus::Pending))
+                .map(|(_, sweep)| sweep.amount)
+                .sum::<u64>()
+        });
+
+        tokens.push(reconcile_token(
+            &token.symbol,
+            ledger_balance,
+            internal_balance,
+            pending_withdrawals,
+            pending_refunds,
+            retained_fees,
+        ));
+    }
+
+    // Verify blocks incrementally, transactions already checked are marked in metadata
+    let unverified: Vec<PaymentTransaction> = transactions.into_iter()
+        .filter(|tx| {
+            tx.block_index.is_some() &&
+            matches!(tx.status, TransactionStatus::Completed | TransactionStatus::Refunded) &&
+            metadata_value(&tx.metadata, "ledger_verified").is_none()
+        })
+        .take(MAX_BLOCK_CHECKS_PER_RUN)
+        .collect();
+
+    let mut block_discrepancies = Vec::new();
+    let mut transactions_checked = 0u32;
+    for transaction in unverified {
+        let block_index = transaction.block_index.unwrap_or_default();
+        let issue = match get_ledger_block(transaction.token.canister_id, block_index).await {
+            Ok(block) => verify_ledger_block(&transaction, block.as_ref()),
+            // Ledger unreachable, retry on the next run without flagging the transaction
+            Err(err) => {
+                ic_cdk::println!("Could not verify {}: {}", transaction.id, err);
+                continue;
+            }
+        };
+        transactions_checked += 1;
+
+        if let Some(mut tx) = TRANSACTIONS.with(|t| t.borrow().get(&transaction.id)) {
+            let verdict = if issue.is_some() { "mismatch" } else { "ok" };
+            set_metadata_value(&mut tx.metadata, "ledger_verified", verdict.to_string());
+            store_transaction(&tx);
+        }
+
+        if let Some(issue) = issue {
+            block_discrepancies.push(BlockDiscrepancy {
+                transaction_id: transaction.id.clone(),
+                block_index,
+                issue,
+            });
+        }
+    }
+
+    let report = ReconciliationReport {
+        generated_at: ic_cdk::api::time(),
+        tokens,
+        block_discrepancies,
+        transactions_checked,
+    };
+
+    RECONCILIATION_REPORTS.with(|reports| {
+        let mut map = reports.borrow_mut();
+        map.insert(report.generated_at, report.clone());
+        while map.len() > MAX_RECONCILIATION_REPORTS {
+            match map.first_key_value() {
+                Some((oldest, _)) => map.remove(&oldest),
+                None => break,
+            };
+        }
+    });
+
+    Ok(report)
+}
+
+#[ic_cdk::update]
+async fn run_reconciliation() -> Result<ReconciliationReport, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can run reconciliation".to_string());
+    }
+
+    reconcile().await
+}
+
+#[ic_cdk::query]
+fn list_reconciliation_reports() -> Vec<ReconciliationReport> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return vec![];
+    }
+
+    // Newest first
+    RECONCILIATION_REPORTS.with(|reports| {
+        reports.borrow().iter().rev().map(|(_, report)| report).collect()
+    })
+}
+
+// ============================================================================
+// ANALYTICS
+// ============================================================================
+
+#[ic_cdk::query]
+fn get_analytics(_from_date: Option<String>, _to_date: Option<String>) -> PaymentAnalytics {
+    let transactions: Vec<PaymentTransaction> = TRANSACTIONS.with(|t| {
+        t.borrow().iter().map(|(_, tx)| tx).collect()
+    });
+
+    let total_transactions = transactions.len() as u64;
+    let completed_transactions = transactions.iter()
+        .filter(|tx| matches!(tx.status, TransactionStatus::Completed))
+        .count() as u64;
+
+    let success_rate = if total_transactions > 0 {
+        completed_transactions as f64 / total_transactions as f64
+    } else {
+        0.0
+    };
+
+    // Calculate volume per token
+    let mut volume_per_token: HashMap<String, u64> = HashMap::new();
+    for tx in &transactions {
+        if matches!(tx.status, TransactionStatus::Completed) {
+            *volume_per_token.entry(tx.token.symbol.clone()).or_insert(0) += tx.amount;
+        }
+    }
+
+    let total_volume: Vec<(String, u64)> = volume_per_token.into_iter().collect();
+
+    PaymentAnalytics {
+        total_transactions,
+        total_volume,
+        success_rate,
+        average_amount: vec![], // Calculate as needed
+        top_tokens: vec![],     // Calculate as needed
+    }
+}
+
+// Oldest first, kept for existing clients; prefer `list_transactions`
+#[ic_cdk::query]
+fn get_transaction_history(limit: u64, offset: u64) -> Vec<PaymentTransaction> {
+    let transaction_ids: Vec<String> = TRANSACTION_INDEX.with(|index| {
+        index.borrow()
+            .iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .map(|(_, id)| id)
+            .collect()
+    });
+
+    TRANSACTIONS.with(|transactions| {
+        let map = transactions.borrow();
+        transaction_ids.iter().filter_map(|id| map.get(id)).collect()
+    })
+}
+
+// ============================================================================
+// TOKEN TRANSFER INTEGRATION
+// ============================================================================
+
+// Transfer arguments for ICRC-1 tokens
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<[u8; 32]>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<[u8; 32]>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferFromArg {
+    pub spender_subaccount: Option<[u8; 32]>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+type TransferResult = Result<Nat, TransferError>; // Block index or error
+
+// Ledger amounts and block indexes are `nat`; everything this canister tracks fits in u64
+fn nat_to_u64(value: Nat) -> u64 {
+    u64::try_from(value.0).unwrap_or(u64::MAX)
+}
+
+// Subaccounts arrive over candid as blobs and must be exactly 32 bytes
+fn parse_subaccount(subaccount: Option<Vec<u8>>) -> Result<Option<[u8; 32]>, String> {
+    match subaccount {
+        None => Ok(None),
+        Some(bytes) => {
+            let array: [u8; 32] = bytes
+                .try_into()
+                .map_err(|_| "Subaccount must be exactly 32 bytes".to_string())?;
+            Ok(Some(array))
+        }
+    }
+}
+
+fn transfer_error_message(transfer_error: TransferError) -> String {
+    match transfer_error {
+        TransferError::BadFee { expected_fee } => {
+            format!("Bad fee: expected {}", expected_fee)
+        },
+        TransferError::BadBurn { min_burn_amount } => {
+            format!("Bad burn: minimum burn amount {}", min_burn_amount)
+        },
+        TransferError::InsufficientFunds { balance } => {
+            format!("Insufficient funds: balance {}", balance)
+        },
+        TransferError::InsufficientAllowance { allowance } => {
+            format!("Insufficient allowance: {}", allowance)
+        },
+        TransferError::TooOld => "Transaction too old".to_string(),
+        TransferError::CreatedInFuture { ledger_time } => {
+            format!("Transaction created in future: ledger time {}", ledger_time)
+        },
+        TransferError::Duplicate { duplicate_of } => {
+            format!("Duplicate transaction: {}", duplicate_of)
+        },
+        TransferError::TemporarilyUnavailable => {
+            "Service temporarily unavailable".to_string()
+        },
+        TransferError::GenericError { error_code, message } => {
+            format!("Generic error {}: {}", error_code, message)
+        },
+    }
+}
+
+// Function to perform transferFrom call to token canister
+async fn transfer_from_token(
+    token_canister_id: Principal,
+    from: Principal,
+    to: Principal,
+    amount: u64,
+    memo: Option<Vec<u8>>,
+    created_at_time: u64,
+) -> Result<u64, String> {
+    let transfer_from_arg = TransferFromArg {
+        spender_subaccount: None,
+        from: Account {
+            owner: from,
+            subaccount: None,
+        },
+        to: Account {
+            owner: to,
+            subaccount: None,
+        },
+        amount: Nat::from(amount),
+        fee: None, // Let the token canister determine the fee
+        memo,
+        created_at_time: Some(created_at_time),
+    };
+
+    // Call the token canister's icrc2_transfer_from method
+    let result: Result<(TransferResult,), _> = ic_cdk::call(
+        token_canister_id,
+        "icrc2_transfer_from",
+        (transfer_from_arg,),
+    ).await;
+
+    match result {
+        Ok((transfer_result,)) => match transfer_result {
+            Ok(block_index) => Ok(nat_to_u64(block_index)),
+            // Same memo and created_at_time as an earlier transfer: that one went through
+            Err(TransferError::Duplicate { duplicate_of }) => Ok(nat_to_u64(duplicate_of)),
+            Err(transfer_error) => Err(transfer_error_message(transfer_error)),
+        },
+        Err((rejection_code, msg)) => {
+            Err(format!(
+                "Transfer call failed: {:?} - {}",
+                rejection_code, msg
+            ))
+        }
+    }
+}
+
+// Function to perform an icrc1_transfer out of one of this canister's accounts
+async fn transfer_token(
+    token_canister_id: Principal,
+    from_subaccount: Option<[u8; 32]>,
+    to: Account,
+    amount: u64,
+    fee: u64,
+) -> Result<u64, String> {
+    let transfer_arg = TransferArg {
+        from_subaccount,
+        to,
+        amount: Nat::from(amount),
+        fee: Some(Nat::from(fee)), // Surface fee changes as BadFee instead of overpaying
+        memo: None,
+        created_at_time: Some(ic_cdk::api::time()),
+    };
+
+    let result: Result<(TransferResult,), _> = ic_cdk::call(
+        token_canister_id,
+        "icrc1_transfer",
+        (transfer_arg,),
+    ).await;
+
+    match result {
+        Ok((transfer_result,)) => match transfer_result {
+            Ok(block_index) => Ok(nat_to_u64(block_index)),
+            Err(transfer_error) => Err(transfer_error_message(transfer_error)),
+        },
+        Err((rejection_code, msg)) => {
+            Err(format!(
+                "Transfer call failed: {:?} - {}",
+                rejection_code, msg
+            ))
+        }
+    }
+}
+
+async fn token_balance_of(token_canister_id: Principal, account: Account) -> Result<u64, String> {
+    let result: Result<(Nat,), _> = ic_cdk::call(
+        token_canister_id,
+        "icrc1_balance_of",
+        (account,),
+    ).await;
+
+    match result {
+        Ok((balance,)) => Ok(nat_to_u64(balance)),
+        Err((rejection_code, msg)) => {
+            Err(format!(
+                "Balance call failed: {:?} - {}",
+                rejection_code, msg
+            ))
+        }
+    }
+}
+
+// ============================================================================
+// ENHANCED ANALYTICS
+// ============================================================================
+
+// Track payment analytics for modal conversion
+fn track_payment_analytics(token_symbol: &str, amount: u64) {
+    // Update modal analytics for successful payment
+    MODAL_ANALYTICS.with(|analytics| {
+        let mut map = analytics.borrow_mut();
+        if let Some(mut modal_analytics) = map.get(&"modal_1".to_string()) {
+            modal_analytics.successful_payments += 1;
+            modal_analytics.revenue_generated += amount;
+            
+            // Recalculate conversion rate
+            if modal_analytics.total_views > 0 {
+                modal_analytics.conversion_rate = 
+                    modal_analytics.successful_payments as f64 / modal_analytics.total_views as f64;
+            }
+            
+            map.insert("modal_1".to_string(), modal_analytics);
+        }
+    });
+}
+
+// Get enhanced analytics with payment method breakdown
+#[ic_cdk::query]
+fn get_enhanced_analytics() -> PaymentAnalytics {
+    let transactions: Vec<PaymentTransaction> = TRANSACTIONS.with(|t| {
+        t.borrow().iter().map(|(_, tx)| tx).collect()
+    });
+
+    let total_transactions = transactions.len() as u64;
+    let completed_transactions = transactions.iter()
+        .filter(|tx| matches!(tx.status, TransactionStatus::Completed))
+        .count() as u64;
+
+    let success_rate = if total_transactions > 0 {
+        completed_transactions as f64 / total_transactions as f64
+    } else {
+        0.0
+    };
+
+    // Calculate volume per token
+    let mut volume_per_token: HashMap<String, u64> = HashMap::new();
+    let mut amount_per_token: HashMap<String, Vec<u64>> = HashMap::new();
+    
+    for tx in &transactions {
+        if matches!(tx.status, TransactionStatus::Completed) {
+            *volume_per_token.entry(tx.token.symbol.clone()).or_insert(0) += tx.amount;
+            amount_per_token.entry(tx.token.symbol.clone())
+                .or_insert_with(Vec::new)
+                .push(tx.amount);
+        }
+    }
+
+    let total_volume: Vec<(String, u64)> = volume_per_token.clone().into_iter().collect();
+    
+    // Calculate average amounts
+    let average_amount: Vec<(String, u64)> = amount_per_token.into_iter()
+        .map(|(token, amounts)| {
+            let avg = if !amounts.is_empty() {
+                amounts.iter().sum::<u64>() / amounts.len() as u64
+            } else {
+                0
+            };
+            (token, avg)
+        })
+        .collect();
+
+    // Get top tokens by volume
+    let mut top_tokens: Vec<(String, u64)> = volume_per_token.into_iter().collect();
+    top_tokens.sort_by(|a, b| b.1.cmp(&a.1));
+    let top_tokens: Vec<String> = top_tokens.into_iter().take(5).map(|(token, _)| token).collect();
+
+    PaymentAnalytics {
+        total_transactions,
+        total_volume,
+        success_rate,
+        average_amount,
+        top_tokens,
+    }
+}
+
+// Get payment method analytics
+#[ic_cdk::query]
+fn get_payment_method_analytics() -> Vec<(String, u64)> {
+    let transactions: Vec<PaymentTransaction> = TRANSACTIONS.with(|t| {
+        t.borrow().iter().map(|(_, tx)| tx).collect()
+    });
+
+    let mut method_counts: HashMap<String, u64> = HashMap::new();
+    
+    for tx in &transactions {
+        if matches!(tx.status, TransactionStatus::Completed) {
+            let method_name = match tx.payment_method {
+                PaymentMethod::Direct => "Direct",
+                PaymentMethod::TransferFrom => "TransferFrom",
+                PaymentMethod::Subscription => "Subscription",
+            };
+            *method_counts.entry(method_name.to_string()).or_insert(0) += 1;
+        }
+    }
+
+    method_counts.into_iter().collect()
+}
+
+// ============================================================================
+// WEBHOOK DELIVERY
+// ============================================================================
+
+// Every replica performs the outcall, so endpoints can receive the same delivery
+// more than once and should deduplicate on the `X-CkPayment-Delivery` header.
+
+const WEBHOOK_RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const WEBHOOK_MAX_ATTEMPTS: usize = 8;
+const WEBHOOK_MAX_PER_RUN: usize = 20;
+const WEBHOOK_MAX_RESPONSE_BYTES: u64 = 16 * 1024;
+const WEBHOOK_OUTCALL_CYCLES: u128 = 400_000_000;
+const WEBHOOK_RETENTION_NANOS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000; // 7 days
+const WEBHOOK_PRUNE_PER_RUN: u64 = 100;
+const PAYMENT_FAILED_WINDOW_NANOS: u64 = 60 * 60 * 1_000_000_000; // 1 hour
+const MAX_PAYMENT_FAILED_EVENTS_PER_WINDOW: usize = 20;
+
+// The caller simply did not fund or approve the payment
+fn is_payer_side_failure(err: &str) -> bool {
+    err.starts_with("Insufficient allowance") || err.starts_with("Insufficient funds")
+}
+
+// Every event costs an outcall per endpoint, so failure events share a budget per window
+fn reserve_payment_failed_event(current_time: u64) -> Result<(), String> {
+    PAYMENT_FAILED_EVENTS.with(|events| {
+        let mut events = events.borrow_mut();
+        events.retain(|queued_at| current_time < queued_at.saturating_add(PAYMENT_FAILED_WINDOW_NANOS));
+        if events.len() >= MAX_PAYMENT_FAILED_EVENTS_PER_WINDOW {
+            return Err("Too many payment failure events".to_string());
+        }
+        events.push(current_time);
+        Ok(())
+    })
+}
+
+fn webhook_queue_key(next_attempt_at: u64, delivery_id: &str) -> String {
+    format!("{:020}:{}", next_attempt_at, delivery_id)
+}
+
+// Every delivery write goes through here so pending deliveries stay queued by their next attempt
+fn store_webhook_delivery(delivery: &WebhookDelivery) {
+    let previous = WEBHOOK_DELIVERIES.with(|deliveries| {
+        deliveries.borrow_mut().insert(delivery.delivery_id.clone(), delivery.clone())
+    });
+    WEBHOOK_QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        if let Some(previous) = previous {
+            queue.remove(&webhook_queue_key(previous.next_attempt_at, &previous.delivery_id));
+        }
+        if matches!(delivery.status, WebhookDeliveryStatus::Pending) {
+            queue.insert(webhook_queue_key(delivery.next_attempt_at, &delivery.delivery_id), delivery.delivery_id.clone());
+        }
+    });
+}
+
+// Queue deliveries left pending before the queue existed
+fn rebuild_webhook_queue() {
+    if WEBHOOK_QUEUE.with(|queue| !queue.borrow().is_empty()) {
+        return;
+    }
+
+    let pending: Vec<WebhookDelivery> = WEBHOOK_DELIVERIES.with(|deliveries| {
+        deliveries.borrow().iter()
+            .filter(|(_, delivery)| matches!(delivery.status, WebhookDeliveryStatus::Pending))
+            .map(|(_, delivery)| delivery)
+            .collect()
+    });
+    for delivery in pending {
+        store_webhook_delivery(&delivery);
+    }
+}
+
+// Drop events older than the retention period, oldest first, once none of their
+// deliveries is pending any more
+fn prune_webhook_history(current_time: u64) {
+    let next_event = NEXT_WEBHOOK_EVENT_ID.with(|id| *id.borrow().get());
+    let mut cursor = WEBHOOK_PRUNE_CURSOR.with(|c| *c.borrow().get());
+    let last = next_event.min(cursor.saturating_add(WEBHOOK_PRUNE_PER_RUN));
+
+    while cursor < last {
+        let event_id = format!("evt_{}", cursor);
+        if let Some(event) = WEBHOOK_EVENTS.with(|events| events.borrow().get(&event_id)) {
+            if current_time < event.created_at.saturating_add(WEBHOOK_RETENTION_NANOS) {
+                break;
+            }
+
+            let deliveries: Vec<WebhookDelivery> = (0..)
+                .map_while(|index| {
+                    WEBHOOK_DELIVERIES.with(|deliveries| deliveries.borrow().get(&format!("{}_{}", event_id, index)))
+                })
+                .collect();
+            if deliveries.iter().any(|delivery| matches!(delivery.status, WebhookDeliveryStatus::Pending)) {
+                break;
+            }
+
+            WEBHOOK_DELIVERIES.with(|map| {
+                let mut map = map.borrow_mut();
+                for delivery in &deliveries {
+                    map.remove(&delivery.delivery_id);
+                }
+            });
+            WEBHOOK_EVENTS.with(|events| events.borrow_mut().remove(&event_id));
+        }
+        cursor += 1;
+    }
+
+    WEBHOOK_PRUNE_CURSOR.with(|c| c.borrow_mut().set(cursor).unwrap());
+}
+
+fn webhook_event_name(event_type: &WebhookEventType) -> &'static str {
+    match event_type {
+        WebhookEventType::InvoicePaid => "invoice.paid",
+        WebhookEventType::PaymentFailed => "payment.failed",
+        WebhookEventType::Refunded => "refund.completed",
+        WebhookEventType::SubscriptionUpdated => "subscription.updated",
+    }
+}
+
+// Exponential backoff starting at one minute and capped at six hours
+fn webhook_retry_delay(attempts: usize) -> u64 {
+    let exponent = attempts.saturating_sub(1).min(16) as u32;
+    let delay_seconds = (60u64 << exponent).min(6 * 60 * 60);
+    delay_seconds * SECONDS_TO_NANOS
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK_SIZE: usize = 64;
+
+    let mut key_block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        key_block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        key_block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(key_block.map(|b| b ^ 0x36));
+    inner.update(message);
+    let inner_hash = inner.finalize();
+
+    let mut outer = Sha256::new();
+    outer.update(key_block.map(|b| b ^ 0x5c));
+    outer.update(inner_hash);
+    outer.finalize().into()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Signature header value: `t=<timestamp>,v1=<hex HMAC-SHA256 of "<timestamp>.<body>">`
+fn webhook_signature(secret: &str, timestamp: u64, body: &str) -> String {
+    let signed_payload = format!("{}.{}", timestamp, body);
+    let mac = hmac_sha256(secret.as_bytes(), signed_payload.as_bytes());
+    format!("t={},v1={}", timestamp, to_hex(&mac))
+}
+
+// https is always accepted, http only while the owner has enabled webhook dev mode
+fn validate_webhook_url(url: &str) -> Result<(), String> {
+    if url.starts_with("https://") {
+        return Ok(());
+    }
+    if url.starts_with("http://") {
+        if WEBHOOK_ALLOW_HTTP.with(|flag| *flag.borrow().get()) {
+            return Ok(());
+        }
+        return Err("Webhook URL must use https (enable webhook dev mode to use http for testing)".to_string());
+    }
+    Err("Webhook URL must start with https://".to_string())
+}
+
+// Endpoints come from the canister configuration and active modal configurations
+fn webhook_urls() -> Vec<String> {
+    let mut urls: Vec<String> = Vec::new();
+
+    if let Some(url) = CONFIG.with(|c| c.borrow().get().webhook.clone()) {
+        urls.push(url);
+    }
+
+    MODAL_CONFIGS.with(|configs| {
+        for (_, modal) in configs.borrow().iter() {
+            if let Some(url) = modal.redirect_urls.webhook_url {
+                if modal.is_active {
+                    urls.push(url);
+                }
+            }
+        }
+    });
+
+    urls.sort();
+    urls.dedup();
+    urls
+}
+
+fn enqueue_webhook_event(event_type: WebhookEventType, data: serde_json::Value) {
+    let urls = webhook_urls();
+    if urls.is_empty() {
+        return;
+    }
+
+    let current_time = ic_cdk::api::time();
+    let event_id = NEXT_WEBHOOK_EVENT_ID.with(|id| {
+        let current = *id.borrow().get();
+        id.borrow_mut().set(current + 1).unwrap();
+        format!("evt_{}", current)
+    });
+
+    let payload = json!({
+        "id": event_id,
+        "type": webhook_event_name(&event_type),
+        "created_at": current_time,
+        "canister_id": ic_cdk::id().to_text(),
+        "data": data,
+    }).to_string();
+
+    let event = WebhookEvent {
+        event_id: event_id.clone(),
+        event_type,
+        payload,
+        created_at: current_time,
+    };
+    WEBHOOK_EVENTS.with(|events| events.borrow_mut().insert(event_id.clone(), event));
+
+    for (index, url) in urls.into_iter().enumerate() {
+        store_webhook_delivery(&WebhookDelivery {
+            delivery_id: format!("{}_{}", event_id, index),
+            event_id: event_id.clone(),
+            url,
+            status: WebhookDeliveryStatus::Pending,
+            attempts: vec![],
+            next_attempt_at: current_time,
+            created_at: current_time,
+            delivered_at: None,
+        });
+    }
+
+    // Deliver right after the current message instead of waiting for the retry timer
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(process_webhook_queue()));
+}
+
+fn enqueue_subscription_event(subscription: &Subscription, previous_status: SubscriptionStatus) {
+    if subscription.status != previous_status {
+        enqueue_webhook_event(
+            WebhookEventType::SubscriptionUpdated,
+            json!({ "subscription": subscription, "previous_status": previous_status }),
+        );
+    }
+}
+
+async fn process_webhook_queue() {
+    let current_time = ic_cdk::api::time();
+
+    let due_deliveries: Vec<String> = WEBHOOK_QUEUE.with(|queue| {
+        queue.borrow()
+            .range(..webhook_queue_key(current_time.saturating_add(1), ""))
+            .map(|(_, delivery_id)| delivery_id)
+            .filter(|delivery_id| !is_in_flight(&format!("webhook:{}", delivery_id)))
+            .take(WEBHOOK_MAX_PER_RUN)
+            .collect()
+    });
+
+    prune_webhook_history(current_time);
+
+    for delivery_id in due_deliveries {
+        if let Err(err) = deliver_webhook(delivery_id.clone()).await {
+            ic_cdk::println!("Webhook delivery {} skipped: {}", delivery_id, err);
+        }
+    }
+}
+
+async fn deliver_webhook(delivery_id: String) -> Result<(), String> {
+    let _guard = InFlightGuard::acquire(format!("webhook:{}", delivery_id))?;
+
+    let mut delivery = WEBHOOK_DELIVERIES.with(|deliveries| deliveries.borrow().get(&delivery_id))
+        .ok_or("Webhook delivery not found")?;
+    let event = WEBHOOK_EVENTS.with(|events| events.borrow().get(&delivery.event_id))
+        .ok_or("Webhook event not found")?;
+
+    let secret = WEBHOOK_SECRET.with(|s| s.borrow().get().clone());
+    let current_time = ic_cdk::api::time();
+
+    // Endpoints stored before dev mode was switched off fail with a recorded error
+    let outcome: Result<u16, String> = if secret.is_empty() {
+        Err("Webhook secret not configured".to_string())
+    } else if let Err(err) = validate_webhook_url(&delivery.url) {
+        Err(err)
+    } else {
+        let request = CanisterHttpRequestArgument {
+            url: delivery.url.clone(),
+            max_response_bytes: Some(WEBHOOK_MAX_RESPONSE_BYTES),
+            method: HttpMethod::POST,
+            headers: vec![
+                HttpHeader { name: "Content-Type".to_string(), value: "application/json".to_string() },
+                HttpHeader { name: "X-CkPayment-Event".to_string(), value: webhook_event_name(&event.event_type).to_string() },
+                HttpHeader { name: "X-CkPayment-Delivery".to_string(), value: delivery.delivery_id.clone() },
+                HttpHeader { name: "X-CkPayment-Signature".to_string(), value: webhook_signature(&secret, current_time, &event.payload) },
+            ],
+            body: Some(event.payload.clone().into_bytes()),
+            transform: Some(TransformContext::from_name("transform_webhook_response".to_string(), vec![])),
+        };
+
+        match http_request(request, WEBHOOK_OUTCALL_CYCLES).await {
+            Ok((response,)) => Ok(nat_to_u64(response.status).min(u16::MAX as u64) as u16),
+            Err((code, msg)) => Err(format!("Outcall failed: {:?} - {}", code, msg)),
+        }
+    };
+
+    let attempt = match &outcome {
+        Ok(status_code) => WebhookAttempt {
+            attempted_at: current_time,
+            status_code: Some(*status_code),
+            error: None,
+        },
+        Err(err) => WebhookAttempt {
+            attempted_at: current_time,
+            status_code: None,
+            error: Some(err.clone()),
+        },
+    };
+    delivery.attempts.push(attempt);
+
+    match outcome {
+        Ok(status_code) if (200..300).contains(&status_code) => {
+            delivery.status = WebhookDeliveryStatus::Delivered;
+            delivery.delivered_at = Some(current_time);
+        },
+        _ if delivery.attempts.len() >= WEBHOOK_MAX_ATTEMPTS => {
+            delivery.status = WebhookDeliveryStatus::Failed;
+        },
+        _ => {
+            delivery.next_attempt_at = current_time + webhook_retry_delay(delivery.attempts.len());
+        },
+    }
+
+    store_webhook_delivery(&delivery);
+    Ok(())
+}
+
+// Replicas must agree on the response, so only the status code is kept
+#[ic_cdk::query]
+fn transform_webhook_response(raw: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: raw.response.status,
+        headers: vec![],
+        body: vec![],
+    }
+}
+
+#[ic_cdk::update]
+fn set_webhook_secret(secret: String) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can set the webhook secret".to_string());
+    }
+
+    if secret.len() < 16 {
+        return Err("Webhook secret must be at least 16 characters".to_string());
+    }
+
+    WEBHOOK_SECRET.with(|s| s.borrow_mut().set(secret).unwrap());
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn set_webhook_dev_mode(allow_http: bool) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can change webhook dev mode".to_string());
+    }
+
+    WEBHOOK_ALLOW_HTTP.with(|flag| flag.borrow_mut().set(allow_http).unwrap());
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_webhook_dev_mode() -> bool {
+    WEBHOOK_ALLOW_HTTP.with(|flag| *flag.borrow().get())
+}
+
+#[ic_cdk::query]
+fn get_webhook_event(event_id: String) -> Result<WebhookEvent, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can view webhook events".to_string());
+    }
+
+    WEBHOOK_EVENTS.with(|events| {
+        events.borrow().get(&event_id)
+            .ok_or("Webhook event not found".to_string())
+    })
+}
+
+#[ic_cdk::query]
+fn list_webhook_deliveries() -> Vec<WebhookDelivery> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return vec![];
+    }
+
+    WEBHOOK_DELIVERIES.with(|deliveries| {
+        deliveries.borrow().iter().map(|(_, delivery)| delivery).collect()
+    })
+}
+
+#[ic_cdk::update]
+async fn resend_webhook_delivery(delivery_id: String) -> Result<WebhookDelivery, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can resend webhooks".to_string());
+    }
+
+    let mut delivery = WEBHOOK_DELIVERIES.with(|deliveries| deliveries.borrow().get(&delivery_id))
+        .ok_or("Webhook delivery not found")?;
+
+    // Keep the attempt log, only the final state is reset
+    delivery.status = WebhookDeliveryStatus::Pending;
+    delivery.next_attempt_at = ic_cdk::api::time();
+    delivery.delivered_at = None;
+    store_webhook_delivery(&delivery);
+
+    deliver_webhook(delivery_id.clone()).await?;
+
+    WEBHOOK_DELIVERIES.with(|deliveries| {
+        deliveries.borrow().get(&delivery_id)
+            .ok_or("Webhook delivery not found".to_string())
+    })
+}
+
+// ============================================================================
+// HEALTH CHECK
+// ============================================================================
+
+#[ic_cdk::query]
+fn health() -> (String, u64, u64) {
+    let status = "healthy".to_string();
+    let version = 1u64;
+    let uptime = ic_cdk::api::time();
+    (status, version, uptime)
+}
+
+// ============================================================================
+// UTILITY METHODS
+// ============================================================================
+
+#[ic_cdk::query]
+fn whoami() -> Principal {
+    ic_cdk::caller()
+}
+
+#[ic_cdk::query]
+fn get_owner() -> Principal {
+    OWNER.with(|o| *o.borrow().get())
+}
+
+#[ic_cdk::update]
+fn admin_update_owner(new_owner: Principal) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let current_owner = OWNER.with(|o| *o.borrow().get());
+    
+    // Only current owner or controllers can change ownership
+    if caller != current_owner {
+        // Check if caller is a controller by attempting to call a management canister function
+        // This is a simple way to verify controller status
+        // In practice, you might want a more sophisticated check
+        
+        // For now, we'll also allow the admin principal (factory owner) to update ownership
+        let admin_principal = "ouuvn-c7hpi-46km4-ywlnr-j2ten-wldfi-xu53v-vth6u-3qtqr-cmbxu-gqe";
+        if caller.to_text() != admin_principal {
+            return Err("Only the current owner or admin can update ownership".to_string());
+        }
+    }
+    
+    if new_owner == Principal::anonymous() {
+        return Err("Cannot set owner to anonymous principal".to_string());
+    }
+    
+    OWNER.with(|o| o.borrow_mut().set(new_owner).unwrap());
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn canister_id() -> Principal {
+    ic_cdk::id()
+}
+
+// ============================================================================
+// MODAL BUILDER METHODS
+// ============================================================================
+
+#[ic_cdk::update]
+fn create_modal_config(config: ModalConfig) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can create modal configurations".to_string());
+    }
+
+    // Validate modal configuration
+    if config.name.is_empty() {
+        return Err("Modal name cannot be empty".to_string());
+    }
+    if config.branding.company_name.is_empty() {
+        return Err("Company name cannot be empty".to_string());
+    }
+    if config.redirect_urls.success_url.is_empty() {
+        return Err("Success URL cannot be empty".to_string());
+    }
+    if config.redirect_urls.cancel_url.is_empty() {
+        return Err("Cancel URL cannot be empty".to_string());
+    }
+    if let Some(url) = &config.redirect_urls.webhook_url {
+        validate_webhook_url(url)?;
+    }
+
+    // Generate modal ID
+    let modal_id = NEXT_MODAL_ID.with(|id| {
+        let current = *id.borrow().get();
+        id.borrow_mut().set(current + 1).unwrap();
+        format!("modal_{}", current)
+    });
+
+    let mut new_config = config;
+    new_config.modal_id = modal_id.clone();
+    new_config.created_at = ic_cdk::api::time();
+    new_config.updated_at = ic_cdk::api::time();
+
+    MODAL_CONFIGS.with(|configs| {
+        configs.borrow_mut().insert(modal_id.clone(), new_config)
+    });
+
+    // Initialize analytics for this modal
+    let analytics = ModalAnalytics {
+        modal_id: modal_id.clone(),
+        total_views: 0,
+        successful_payments: 0,
+        conversion_rate: 0.0,
+        revenue_generated: 0,
+    };
+    
+    MODAL_ANALYTICS.with(|analytics_map| {
+        analytics_map.borrow_mut().insert(modal_id.clone(), analytics)
+    });
+
+    Ok(modal_id)
+}
+
+#[ic_cdk::update]
+fn update_modal_config(modal_id: String, mut config: ModalConfig) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can update modal configurations".to_string());
+    }
+
+    // Validate modal configuration
+    if config.name.is_empty() {
+        return Err("Modal name cannot be empty".to_string());
+    }
+    if config.branding.company_name.is_empty() {
+        return Err("Company name cannot be empty".to_string());
+    }
+    if config.redirect_urls.success_url.is_empty() {
+        return Err("Success URL cannot be empty".to_string());
+    }
+    if config.redirect_urls.cancel_url.is_empty() {
+        return Err("Cancel URL cannot be empty".to_string());
+    }
+    if let Some(url) = &config.redirect_urls.webhook_url {
+        validate_webhook_url(url)?;
+    }
+
+    MODAL_CONFIGS.with(|configs| {
+        let mut map = configs.borrow_mut();
+        let existing_config = map.get(&modal_id)
+            .ok_or("Modal configuration not found")?;
+        
+        // Preserve original creation data and ID
+        config.modal_id = modal_id.clone();
+        config.created_at = existing_config.created_at;
+        config.updated_at = ic_cdk::api::time();
+        
+        map.insert(modal_id, config);
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn get_modal_config(modal_id: String) -> Result<ModalConfig, String> {
+    MODAL_CONFIGS.with(|configs| {
+        configs.borrow().get(&modal_id)
+            .ok_or("Modal configuration not found".to_string())
+    })
+}
+
+#[ic_cdk::query]
+fn list_my_modals() -> Vec<ModalConfig> {
+    MODAL_CONFIGS.with(|configs| {
+        configs.borrow().iter().map(|(_, config)| config).collect()
+    })
+}
+
+#[ic_cdk::update]
+fn delete_modal_config(modal_id: String) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can delete modal configurations".to_string());
+    }
+
+    MODAL_CONFIGS.with(|configs| {
+        let mut map = configs.borrow_mut();
+        if map.remove(&modal_id).is_none() {
+            return Err("Modal configuration not found".to_string());
+        }
+        Ok(())
+    })?;
+
+    // Also remove analytics data
+    MODAL_ANALYTICS.with(|analytics| {
+        analytics.borrow_mut().remove(&modal_id)
+    });
+
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn track_modal_view(modal_id: String) -> Result<(), String> {
+    MODAL_ANALYTICS.with(|analytics| {
+        let mut map = analytics.borrow_mut();
+        if let Some(mut modal_analytics) = map.get(&modal_id) {
+            modal_analytics.total_views += 1;
+            
+            // Recalculate conversion rate
+            if modal_analytics.total_views > 0 {
+                modal_analytics.conversion_rate = 
+                    modal_analytics.successful_payments as f64 / modal_analytics.total_views as f64;
+            }
+            
+            map.insert(modal_id, modal_analytics);
+            Ok(())
+        } else {
+            Err("Modal analytics not found".to_string())
+        }
+    })
+}
+
+#[ic_cdk::query]
+fn get_modal_analytics(modal_id: String) -> Result<ModalAnalytics, String> {
+    MODAL_ANALYTICS.with(|analytics| {
+        analytics.borrow().get(&modal_id)
+            .ok_or("Modal analytics not found".to_string())
+    })
+}
+
+#[ic_cdk::update]
+fn generate_modal_embed_code(modal_id: String) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can generate embed codes".to_string());
+    }
+
+    let config = MODAL_CONFIGS.with(|configs| {
+        configs.borrow().get(&modal_id)
+    }).ok_or("Modal configuration not found")?;
+
+    if !config.is_active {
+        return Err("Cannot generate embed code for inactive modal".to_string());
+    }
+
+    let canister_id = ic_cdk::id().to_text();
+    
+    let embed_code = format!(
+        r#"<script src="https://sdk.ckpayment.io/embed.js"></script>
+<script>
+  ckPayment.createModal({{
+    canisterId: "{}",
+    modalId: "{}",
+    theme: {{
+      primaryColor: "{}",
+      backgroundColor: "{}",
+      textColor: "{}",
+      borderRadius: {},
+      fontFamily: "{}"
+    }},
+    branding: {{
+      companyName: "{}",
+      logoUrl: "{}"
+    }},
+    redirectUrls: {{
+      successUrl: "{}",
+      cancelUrl: "{}"
+    }}
+  }});
+</script>"#,
+        canister_id,
+        modal_id,
+        config.theme.primary_color,
+        config.theme.background_color,
+        config.theme.text_color,
+        config.theme.border_radius,
+        config.theme.font_family,
+        config.branding.company_name,
+        config.branding.logo_url.as_deref().unwrap_or(""),
+        config.redirect_urls.success_url,
+        config.redirect_urls.cancel_url
+    );
+
+    Ok(embed_code)
+}
+
+// ============================================================================
+// DISCOUNT COUPON SYSTEM METHODS
+// ============================================================================
+
+#[ic_cdk::update]
+fn create_coupon(mut coupon: DiscountCoupon) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can create coupons".to_string());
+    }
+
+    // Validate coupon configuration
+    if coupon.code.is_empty() {
+        return Err("Coupon code cannot be empty".to_string());
+    }
+    if coupon.description.is_empty() {
+        return Err("Coupon description cannot be empty".to_string());
+    }
+    
+    if coupon.per_customer_limit == Some(0) {
+        return Err("Per-customer limit must be greater than 0".to_string());
+    }
+    
+    // Validate coupon type
+    match &coupon.coupon_type {
+        CouponType::Percentage(percent) => {
+            if *percent > 100 {
+                return Err("Percentage discount cannot exceed 100%".to_string());
+            }
+        },
+        CouponType::FixedAmount(amount) => {
+            if *amount == 0 {
+                return Err("Fixed discount amount must be greater than 0".to_string());
+            }
+        },
+        CouponType::FreeShipping => {}
+    }
+
+    // Check if coupon code already exists
+    if coupon_id_for_code(&coupon.code).is_some() {
+        return Err("A coupon with this code already exists".to_string());
+    }
+
+    // Generate coupon ID
+    let coupon_id = NEXT_COUPON_ID.with(|id| {
+        let current = *id.borrow().get();
+        id.borrow_mut().set(current + 1).unwrap();
+        format!("coupon_{}", current)
+    });
+
+    // Set coupon metadata
+    coupon.coupon_id = coupon_id.clone();
+    coupon.code = coupon.code.to_uppercase(); // Standardize to uppercase
+    coupon.used_count = 0;
+    coupon.created_at = ic_cdk::api::time();
+    coupon.updated_at = ic_cdk::api::time();
+    coupon.batch_id = None;
+
+    COUPON_CODE_INDEX.with(|index| index.borrow_mut().insert(coupon.code.clone(), coupon_id.clone()));
+    DISCOUNT_COUPONS.with(|coupons| {
+        coupons.borrow_mut().insert(coupon_id.clone(), coupon)
+    });
+
+    Ok(coupon_id)
+}
+
+#[ic_cdk::update]
+fn update_coupon(coupon_id: String, mut updated_coupon: DiscountCoupon) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can update coupons".to_string());
+    }
+
+    // Validate coupon configuration
+    if updated_coupon.code.is_empty() {
+        return Err("Coupon code cannot be empty".to_string());
+    }
+    if updated_coupon.description.is_empty() {
+        return Err("Coupon description cannot be empty".to_string());
+    }
+    
+    if updated_coupon.per_customer_limit == Some(0) {
+        return Err("Per-customer limit must be greater than 0".to_string());
+    }
+    
+    // Validate coupon type
+    match &updated_coupon.coupon_type {
+        CouponType::Percentage(percent) => {
+            if *percent > 100 {
+                return Err("Percentage discount cannot exceed 100%".to_string());
+            }
+        },
+        CouponType::FixedAmount(amount) => {
+            if *amount == 0 {
+                return Err("Fixed discount amount must be greater than 0".to_string());
+            }
+        },
+        CouponType::FreeShipping => {}
+    }
+
+    DISCOUNT_COUPONS.with(|coupons| {
+        let mut map = coupons.borrow_mut();
+        let existing_coupon = map.get(&coupon_id)
+            .ok_or("Coupon not found")?;
+        
+        // Check if the new code conflicts with other coupons (excluding this one)
+        let code_conflict = coupon_id_for_code(&updated_coupon.code)
+            .is_some_and(|id| id != coupon_id);
+        
+        if code_conflict {
+            return Err("A coupon with this code already exists".to_string());
+        }
+
+        // Preserve original metadata
+        updated_coupon.coupon_id = coupon_id.clone();
+        updated_coupon.code = updated_coupon.code.to_uppercase();
+        updated_coupon.used_count = existing_coupon.used_count; // Preserve usage count
+        updated_coupon.created_at = existing_coupon.created_at;
+        updated_coupon.updated_at = ic_cdk::api::time();
+        updated_coupon.batch_id = existing_coupon.batch_id.clone();
+
+        COUPON_CODE_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            index.remove(&existing_coupon.code.to_uppercase());
+            index.insert(updated_coupon.code.clone(), coupon_id.clone());
+        });
+        
+        map.insert(coupon_id, updated_coupon);
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn get_coupon(coupon_id: String) -> Result<DiscountCoupon, String> {
+    DISCOUNT_COUPONS.with(|coupons| {
+        coupons.borrow().get(&coupon_id)
+            .ok_or("Coupon not found".to_string())
+    })
+}
+
+#[ic_cdk::query]
+fn get_coupon_by_code(code: String) -> Result<DiscountCoupon, String> {
+    let coupon_id = coupon_id_for_code(&code).ok_or("Coupon not found")?;
+    DISCOUNT_COUPONS.with(|coupons| {
+        coupons.borrow().get(&coupon_id)
+            .ok_or("Coupon not found".to_string())
+    })
+}
+
+fn coupon_id_for_code(code: &str) -> Option<String> {
+    COUPON_CODE_INDEX.with(|index| index.borrow().get(&code.to_uppercase()))
+}
+
+fn rebuild_coupon_code_index() {
+    let indexed = COUPON_CODE_INDEX.with(|index| index.borrow().len()) ==
+        DISCOUNT_COUPONS.with(|coupons| coupons.borrow().len());
+    if indexed {
+        return;
+    }
+
+    DISCOUNT_COUPONS.with(|coupons| {
+        COUPON_CODE_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            for (coupon_id, coupon) in coupons.borrow().iter() {
+                index.insert(coupon.code.to_uppercase(), coupon_id);
+            }
+        })
+    });
+}
+
+#[ic_cdk::query]
+fn list_my_coupons() -> Vec<DiscountCoupon> {
+    DISCOUNT_COUPONS.with(|coupons| {
+        coupons.borrow().iter().map(|(_, coupon)| coupon).collect()
+    })
+}
+
+#[ic_cdk::query]
+fn list_active_coupons() -> Vec<DiscountCoupon> {
+    let current_time = ic_cdk::api::time();
+    DISCOUNT_COUPONS.with(|coupons| {
+        coupons.borrow().iter()
+            .filter(|(_, coupon)| {
+                // Bulk generated codes are handed out individually, not advertised
+                coupon.batch_id.is_none() &&
+                coupon.is_active && 
+                (coupon.expires_at.is_none() || coupon.expires_at.unwrap() > current_time) &&
+                (coupon.usage_limit.is_none() || coupon.used_count < coupon.usage_limit.unwrap())
+            })
+            .map(|(_, coupon)| coupon)
+            .collect()
+    })
+}
+
+#[ic_cdk::update]
+fn delete_coupon(coupon_id: String) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can delete coupons".to_string());
+    }
+
+    let coupon = DISCOUNT_COUPONS.with(|coupons| coupons.borrow_mut().remove(&coupon_id))
+        .ok_or("Coupon not found")?;
+    COUPON_CODE_INDEX.with(|index| index.borrow_mut().remove(&coupon.code.to_uppercase()));
+
+    // Clean up usage history for this coupon
+    let usage_ids_to_remove: Vec<String> = COUPON_USAGE_HISTORY.with(|usage_history| {
+        usage_history.borrow().iter()
+            .filter(|(_, usage)| usage.coupon_id == coupon_id)
+            .map(|(usage_id, _)| usage_id)
+            .collect()
+    });
+    for usage_id in usage_ids_to_remove {
+        remove_coupon_usage(&usage_id);
+    }
+
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn toggle_coupon_status(coupon_id: String) -> Result<bool, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can toggle coupon status".to_string());
+    }
+
+    DISCOUNT_COUPONS.with(|coupons| {
+        let mut map = coupons.borrow_mut();
+        if let Some(mut coupon) = map.get(&coupon_id) {
+            coupon.is_active = !coupon.is_active;
+            coupon.updated_at = ic_cdk::api::time();
+            let new_status = coupon.is_active;
+            map.insert(coupon_id, coupon);
+            Ok(new_status)
+        } else {
+            Err("Coupon not found".to_string())
+        }
+    })
+}
+
+// A product line of the invoice a coupon is checked against
+struct CouponLine {
+    product_id: String,
+    category: Option<String>,
+    amount: u64,
+}
+
+struct CouponContext {
+    amount: u64, // Amount a percentage or fixed discount is taken from
+    token_symbol: String,
+    shipping: u64,
+    lines: Vec<CouponLine>,
+    customer_uses: u32,
+    first_purchase: bool,
+}
+
+fn coupon_discount(coupon: &DiscountCoupon, context: &CouponContext, current_time: u64) -> Result<u64, String> {
+    if !coupon.is_active {
+        return Err("Coupon is not active".to_string());
+    }
+
+    if let Some(expires_at) = coupon.expires_at {
+        if current_time > expires_at {
+            return Err("Coupon has expired".to_string());
+        }
+    }
+
+    if let Some(usage_limit) = coupon.usage_limit {
+        if coupon.used_count >= usage_limit {
+            return Err("Coupon usage limit reached".to_string());
+        }
+    }
+
+    if let Some(per_customer_limit) = coupon.per_customer_limit {
+        if context.customer_uses >= per_customer_limit {
+            return Err("You have already used this coupon".to_string());
+        }
+    }
+
+    if coupon.first_purchase_only == Some(true) && !context.first_purchase {
+        return Err("Coupon is only valid for a first purchase".to_string());
+    }
+
+    if let Some(minimum_amount) = coupon.minimum_amount {
+        if context.amount < minimum_amount {
+            return Err(format!("Minimum purchase amount of {} required", minimum_amount));
+        }
+    }
+
+    // Check if coupon applies to this token
+    if !coupon.applicable_tokens.is_empty() && !coupon.applicable_tokens.contains(&context.token_symbol) {
+        return Err("Coupon is not applicable to this token".to_string());
+    }
+
+    // Restricted coupons only discount the matching products
+    let products = coupon.applicable_products.as_deref().unwrap_or_default();
+    let categories = coupon.applicable_categories.as_deref().unwrap_or_default();
+    let eligible_amount = if products.is_empty() && categories.is_empty() {
+        context.amount
+    } else {
+        let eligible: u64 = context.lines.iter()
+            .filter(|line| {
+                products.contains(&line.product_id) ||
+                line.category.as_ref().is_some_and(|category| categories.contains(category))
+            })
+            .map(|line| line.amount)
+            .sum();
+        if eligible == 0 {
+            return Err("Coupon does not apply to these products".to_string());
+        }
+        eligible.min(context.amount)
+    };
+
+    // Calculate discount
+    let discount_amount = match coupon.coupon_type {
+        CouponType::Percentage(percent) => {
+            ((eligible_amount as u128 * percent as u128) / 100) as u64
+        },
+        CouponType::FixedAmount(amount) => amount.min(eligible_amount), // Cap discount at the eligible amount
+        CouponType::FreeShipping => {
+            if context.shipping == 0 {
+                return Err("Invoice has no shipping charge".to_string());
+            }
+            context.shipping
+        },
+    };
+
+    Ok(discount_amount)
+}
+
+fn coupon_lines(line_items: &[LineItem]) -> Vec<CouponLine> {
+    line_items.iter()
+        .filter_map(|line| {
+            let product_id = line.product_id.clone()?;
+            let category = PRODUCTS.with(|products| products.borrow().get(&product_id))
+                .and_then(|product| product.category);
+            Some(CouponLine {
+                product_id,
+                category,
+                amount: line_net_amount(line).unwrap_or(0),
+            })
+        })
+        .collect()
+}
+
+// Product lines of an invoice, older product invoices only carry the product in metadata
+fn invoice_coupon_lines(invoice: &PaymentInvoice) -> Vec<CouponLine> {
+    match &invoice.line_items {
+        Some(line_items) => coupon_lines(line_items),
+        None => metadata_value(&invoice.metadata, "product_id")
+            .map(|product_id| vec![CouponLine {
+                product_id,
+                category: metadata_value(&invoice.metadata, "category"),
+                amount: invoice.amount,
+            }])
+            .unwrap_or_default(),
+    }
+}
+
+const COUPON_HOLD_NANOS: u64 = 15 * 60 * 1_000_000_000; // 15 minutes
+
+fn customer_uses_key(coupon_id: &str, customer: Principal) -> String {
+    format!("{}:{}", coupon_id, customer.to_text())
+}
+
+fn customer_coupon_uses(coupon_id: &str, customer: Principal) -> u32 {
+    COUPON_CUSTOMER_USES.with(|uses| uses.borrow().get(&customer_uses_key(coupon_id, customer)).unwrap_or(0))
+}
+
+fn index_coupon_usage(usage: &CouponUsage, is_new: bool) {
+    if is_new {
+        COUPON_CUSTOMER_USES.with(|uses| {
+            let mut map = uses.borrow_mut();
+            let key = customer_uses_key(&usage.coupon_id, usage.user_principal);
+            let count = map.get(&key).unwrap_or(0);
+            map.insert(key, count + 1);
+        });
+    }
+    if usage.status.is_some() {
+        INVOICE_COUPON_USAGE.with(|index| index.borrow_mut().insert(usage.invoice_id.clone(), usage.usage_id.clone()));
+    }
+    match usage.status {
+        Some(CouponUsageStatus::Reserved) => {
+            COUPON_HOLDS.with(|holds| holds.borrow_mut().insert(usage.usage_id.clone(), usage.used_at + COUPON_HOLD_NANOS));
+        },
+        _ => {
+            COUPON_HOLDS.with(|holds| holds.borrow_mut().remove(&usage.usage_id));
+        },
+    }
+}
+
+// Every usage write goes through here so the per-customer counts, the invoice
+// index and the reservation holds stay in step
+fn store_coupon_usage(usage: &CouponUsage) {
+    let previous = COUPON_USAGE_HISTORY.with(|usage_history| {
+        usage_history.borrow_mut().insert(usage.usage_id.clone(), usage.clone())
+    });
+    index_coupon_usage(usage, previous.is_none());
+}
+
+fn remove_coupon_usage(usage_id: &str) -> Option<CouponUsage> {
+    let usage = COUPON_USAGE_HISTORY.with(|usage_history| usage_history.borrow_mut().remove(&usage_id.to_string()))?;
+
+    COUPON_CUSTOMER_USES.with(|uses| {
+        let mut map = uses.borrow_mut();
+        let key = customer_uses_key(&usage.coupon_id, usage.user_principal);
+        match map.get(&key).unwrap_or(0) {
+            0 | 1 => map.remove(&key),
+            count => map.insert(key, count - 1),
+        };
+    });
+    INVOICE_COUPON_USAGE.with(|index| {
+        let mut map = index.borrow_mut();
+        if map.get(&usage.invoice_id).as_deref() == Some(usage_id) {
+            map.remove(&usage.invoice_id);
+        }
+    });
+    COUPON_HOLDS.with(|holds| holds.borrow_mut().remove(&usage_id.to_string()));
+    Some(usage)
+}
+
+// Index usage recorded before the indexes existed
+fn rebuild_coupon_usage_indexes() {
+    let indexed = COUPON_CUSTOMER_USES.with(|uses| !uses.borrow().is_empty());
+    if indexed {
+        return;
+    }
+
+    release_failed_attempt_coupons();
+    COUPON_USAGE_HISTORY.with(|usage_history| {
+        for (_, usage) in usage_history.borrow().iter() {
+            index_coupon_usage(&usage, true);
+        }
+    });
+}
+
+// Before coupons were reserved on invoices, a failed transfer_from attempt already
+// counted a coupon use. Those uses are given back once, when usage is first indexed.
+fn release_failed_attempt_coupons() {
+    let failed = transaction_status_code(&TransactionStatusKind::Failed);
+    let failed_attempts: Vec<PaymentTransaction> = TRANSACTIONS_BY_STATUS.with(|index| {
+        index.borrow().range((failed, 0)..(failed + 1, 0))
+            .filter_map(|(_, transaction_id)| TRANSACTIONS.with(|transactions| transactions.borrow().get(&transaction_id)))
+            .filter(|tx| {
+                metadata_value(&tx.metadata, "coupon_id").is_some() &&
+                metadata_value(&tx.metadata, "coupon_released").is_none()
+            })
+            .collect()
+    });
+
+    for mut tx in failed_attempts {
+        if let Some(coupon_id) = metadata_value(&tx.metadata, "coupon_id") {
+            release_coupon_usage(&coupon_id, tx.from, tx.timestamp);
+        }
+        set_metadata_value(&mut tx.metadata, "coupon_released", "true".to_string());
+        store_transaction(&tx);
+    }
+}
+
+// Reservations only count towards usage limits for a short hold. Lapsed ones are
+// given back unless a payment is in flight or the discount is part of an order's amount.
+fn release_lapsed_coupon_holds(current_time: u64) {
+    let lapsed: Vec<String> = COUPON_HOLDS.with(|holds| {
+        holds.borrow().iter()
+            .filter(|(_, hold_until)| current_time >= *hold_until)
+            .map(|(usage_id, _)| usage_id)
+            .collect()
+    });
+
+    for usage_id in lapsed {
+        let usage = match COUPON_USAGE_HISTORY.with(|usage_history| usage_history.borrow().get(&usage_id)) {
+            Some(usage) => usage,
+            None => {
+                COUPON_HOLDS.with(|holds| holds.borrow_mut().remove(&usage_id));
+                continue;
+            },
+        };
+        if is_in_flight(&format!("invoice:{}", usage.invoice_id)) {
+            continue;
+        }
+
+        let invoice = INVOICES.with(|invoices| invoices.borrow().get(&usage.invoice_id));
+        if invoice.is_some_and(|invoice| metadata_value(&invoice.metadata, "coupon_id").is_some()) {
+            // Released together with the order invoice when it is cancelled or expires
+            COUPON_HOLDS.with(|holds| holds.borrow_mut().remove(&usage_id));
+            continue;
+        }
+        release_invoice_coupon(&usage.invoice_id);
+    }
+}
+
+fn is_first_purchase(customer: Principal) -> bool {
+    let transaction_ids: Vec<String> = TRANSACTIONS_BY_PAYER.with(|index| {
+        index.borrow()
+            .range((customer, 0)..=(customer, u64::MAX))
+            .map(|(_, id)| id)
+            .collect()
+    });
+
+    !transaction_ids.iter().any(|id| {
+        TRANSACTIONS.with(|transactions| transactions.borrow().get(id))
+            .is_some_and(|tx| matches!(tx.status, TransactionStatus::Completed))
+    })
+}
+
+// Checks a coupon for a customer without recording anything
+fn evaluate_coupon(
+    coupon_code: &str,
+    customer: Principal,
+    amount: u64,
+    token_symbol: &str,
+    shipping: u64,
+    lines: Vec<CouponLine>,
+) -> Result<(DiscountCoupon, u64), String> {
+    let coupon = get_coupon_by_code(coupon_code.to_string())?;
+
+    let context = CouponContext {
+        amount,
+        token_symbol: token_symbol.to_string(),
+        shipping,
+        lines,
+        customer_uses: customer_coupon_uses(&coupon.coupon_id, customer),
+        first_purchase: is_first_purchase(customer),
+    };
+    let discount = coupon_discount(&coupon, &context, ic_cdk::api::time())?;
+    Ok((coupon, discount))
+}
+
+fn evaluate_invoice_coupon(
+    coupon_code: &str,
+    invoice: &PaymentInvoice,
+    customer: Principal,
+) -> Result<(DiscountCoupon, u64), String> {
+    let (coupon, discount) = evaluate_coupon(
+        coupon_code,
+        customer,
+        invoice.amount,
+        &invoice.token.symbol,
+        invoice.shipping.unwrap_or(0),
+        invoice_coupon_lines(invoice),
+    )?;
+    Ok((coupon, discount.min(invoice.amount)))
+}
+
+// Counts a use right away, so concurrent reservations cannot exceed the usage limit
+fn reserve_coupon(coupon: &DiscountCoupon, invoice_id: &str, customer: Principal, discount: u64) -> CouponUsage {
+    let current_time = ic_cdk::api::time();
+
+    DISCOUNT_COUPONS.with(|coupons| {
+        let mut map = coupons.borrow_mut();
+        if let Some(mut updated_coupon) = map.get(&coupon.coupon_id) {
+            updated_coupon.used_count += 1;
+            updated_coupon.updated_at = current_time;
+            map.insert(coupon.coupon_id.clone(), updated_coupon);
+        }
+    });
+
+    let usage = CouponUsage {
+        usage_id: format!("usage_{}_{}", coupon.coupon_id, invoice_id),
+        coupon_id: coupon.coupon_id.clone(),
+        user_principal: customer,
+        invoice_id: invoice_id.to_string(),
+        discount_applied: discount,
+        used_at: current_time,
+        status: Some(CouponUsageStatus::Reserved),
+    };
+
+    store_coupon_usage(&usage);
+    usage
+}
+
+fn invoice_coupon_usage(invoice_id: &str) -> Option<CouponUsage> {
+    let usage_id = INVOICE_COUPON_USAGE.with(|index| index.borrow().get(&invoice_id.to_string()))?;
+    COUPON_USAGE_HISTORY.with(|usage_history| usage_history.borrow().get(&usage_id))
+        .filter(|usage| usage.status.is_some())
+}
+
+// What the payer owes, a coupon applied to the invoice before payment lowers it.
+// Order discounts are already part of the invoice amount.
+fn invoice_amount_due(invoice: &PaymentInvoice) -> u64 {
+    if metadata_value(&invoice.metadata, "coupon_id").is_some() {
+        return invoice.amount;
+    }
+    let discount = invoice_coupon_usage(&invoice.id).map_or(0, |usage| usage.discount_applied);
+    invoice.amount.saturating_sub(discount)
+}
+
+fn commit_invoice_coupon(invoice_id: &str) {
+    if let Some(mut usage) = invoice_coupon_usage(invoice_id) {
+        usage.status = Some(CouponUsageStatus::Committed);
+        usage.used_at = ic_cdk::api::time();
+        store_coupon_usage(&usage);
+    }
+}
+
+// Gives the use back, for unpaid invoices that are cancelled or expire and for full refunds
+fn release_invoice_coupon(invoice_id: &str) {
+    if let Some(usage) = invoice_coupon_usage(invoice_id) {
+        remove_coupon_usage(&usage.usage_id);
+
+        DISCOUNT_COUPONS.with(|coupons| {
+            let mut map = coupons.borrow_mut();
+            if let Some(mut coupon) = map.get(&usage.coupon_id) {
+                coupon.used_count = coupon.used_count.saturating_sub(1);
+                coupon.updated_at = ic_cdk::api::time();
+                map.insert(usage.coupon_id.clone(), coupon);
+            }
+        });
+    }
+}
+
+// Reserves a coupon on an unpaid invoice, the discount is taken off when it is paid
+#[ic_cdk::update]
+fn apply_coupon(invoice_id: String, coupon_code: String) -> Result<CouponUsage, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("Anonymous callers cannot use coupons".to_string());
+    }
+
+    let _guard = InFlightGuard::acquire(format!("invoice:{}", invoice_id))?;
+
+    let invoice = INVOICES.with(|invoices| invoices.borrow().get(&invoice_id))
+        .ok_or("Invoice not found")?;
+    if invoice.payer != Some(caller) {
+        return Err("Only the invoice's customer can apply coupons to it".to_string());
+    }
+    if !matches!(invoice.status, InvoiceStatus::Created) {
+        return Err("Coupons can only be applied to unpaid invoices".to_string());
+    }
+    if metadata_value(&invoice.metadata, "coupon_id").is_some() || invoice_coupon_usage(&invoice_id).is_some() {
+        return Err("Invoice already has a coupon applied".to_string());
+    }
+
+    let (coupon, discount) = evaluate_invoice_coupon(&coupon_code, &invoice, caller)?;
+    Ok(reserve_coupon(&coupon, &invoice_id, caller, discount))
+}
+
+#[ic_cdk::update]
+fn remove_coupon(invoice_id: String) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+
+    let _guard = InFlightGuard::acquire(format!("invoice:{}", invoice_id))?;
+
+    let usage = invoice_coupon_usage(&invoice_id).ok_or("Invoice has no coupon applied")?;
+    if caller != owner && caller != usage.user_principal {
+        return Err("Only the owner or the customer who applied the coupon can remove it".to_string());
+    }
+    if usage.status != Some(CouponUsageStatus::Reserved) {
+        return Err("The coupon has already been redeemed".to_string());
+    }
+
+    release_invoice_coupon(&invoice_id);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_invoice_coupon(invoice_id: String) -> Option<CouponUsage> {
+    invoice_coupon_usage(&invoice_id)
+}
+
+// Shows the discount a coupon would give the caller on an invoice, without reserving it
+#[ic_cdk::query]
+fn preview_coupon(invoice_id: String, coupon_code: String) -> Result<u64, String> {
+    let invoice = INVOICES.with(|invoices| invoices.borrow().get(&invoice_id))
+        .ok_or("Invoice not found")?;
+    evaluate_invoice_coupon(&coupon_code, &invoice, ic_cdk::caller()).map(|(_, discount)| discount)
+}
+
+// Give back a coupon use recorded before uses were attached to invoices.
+// Usage records are matched on the payer and the payment time, which is shared
+// by the transaction and the usage recorded during the same call.
+fn release_coupon_usage(coupon_id: &str, user: Principal, used_at: u64) {
+    let usage_id = COUPON_USAGE_HISTORY.with(|usage_history| {
+        usage_history.borrow().iter()
+            .find(|(_, usage)| {
+                usage.status.is_none() &&
+                usage.coupon_id == coupon_id && usage.user_principal == user && usage.used_at == used_at
+            })
+            .map(|(usage_id, _)| usage_id)
+    });
+
+    if let Some(usage_id) = usage_id {
+        remove_coupon_usage(&usage_id);
+
+        DISCOUNT_COUPONS.with(|coupons| {
+            let mut map = coupons.borrow_mut();
+            if let Some(mut coupon) = map.get(&coupon_id.to_string()) {
+                coupon.used_count = coupon.used_count.saturating_sub(1);
+                coupon.updated_at = ic_cdk::api::time();
+                map.insert(coupon_id.to_string(), coupon);
+            }
+        });
+    }
+}
+
+#[ic_cdk::query]
+fn get_coupon_usage_stats(coupon_id: String) -> Result<(u32, Vec<CouponUsage>), String> {
+    let coupon = DISCOUNT_COUPONS.with(|coupons| {
+        coupons.borrow().get(&coupon_id)
+            .ok_or("Coupon not found".to_string())
+    })?;
+
+    let usage_history: Vec<CouponUsage> = COUPON_USAGE_HISTORY.with(|usage_history| {
+        usage_history.borrow().iter()
+            .filter(|(_, usage)| usage.coupon_id == coupon_id)
+            .map(|(_, usage)| usage)
+            .collect()
+    });
+
+    Ok((coupon.used_count, usage_history))
+}
+
+#[ic_cdk::update]
+fn admin_clear_all_coupons() -> Result<u32, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can clear all coupons".to_string());
+    }
+
+    let count = DISCOUNT_COUPONS.with(|coupons| {
+        let coupon_ids: Vec<String> = coupons.borrow().iter().map(|(id, _)| id).collect();
+        let count = coupon_ids.len() as u32;
+        let mut map = coupons.borrow_mut();
+        for id in coupon_ids {
+            map.remove(&id);
+        }
+        count
+    });
+
+    let usage_ids: Vec<String> = COUPON_USAGE_HISTORY.with(|usage_history| {
+        usage_history.borrow().iter().map(|(id, _)| id).collect()
+    });
+    for usage_id in usage_ids {
+        remove_coupon_usage(&usage_id);
+    }
+
+    COUPON_CODE_INDEX.with(|index| {
+        let codes: Vec<String> = index.borrow().iter().map(|(code, _)| code).collect();
+        let mut map = index.borrow_mut();
+        for code in codes {
+            map.remove(&code);
+        }
+    });
+
+    Ok(count)
+}
+
+// ============================================================================
+// BULK COUPON CODES
+// ============================================================================
+
+const MAX_BULK_COUPON_CODES: u32 = 1_000;
+const GENERATED_CODE_LENGTH: usize = 10;
+// No 0/O or 1/I, codes are typed in by hand
+const COUPON_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+fn generated_coupon_code(prefix: &str, seed: &[u8], counter: u64) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(seed);
+    hasher.update(counter.to_be_bytes());
+    let digest = hasher.finalize();
+
+    let suffix: String = digest.iter()
+        .take(GENERATED_CODE_LENGTH)
+        .map(|byte| COUPON_CODE_ALPHABET[*byte as usize % COUPON_CODE_ALPHABET.len()] as char)
+        .collect();
+    format!("{}{}", prefix, suffix)
+}
+
+// Creates `count` single-use copies of a template coupon, each with its own random code
+#[ic_cdk::update]
+async fn generate_coupon_codes(
+    template_coupon_id: String,
+    count: u32,
+    prefix: Option<String>,
+) -> Result<CouponBatch, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can generate coupon codes".to_string());
+    }
+
+    if count == 0 || count > MAX_BULK_COUPON_CODES {
+        return Err(format!("Between 1 and {} codes can be generated at once", MAX_BULK_COUPON_CODES));
+    }
+
+    let prefix = prefix.unwrap_or_default().trim().to_uppercase();
+    if prefix.len() > 16 || !prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err("Prefix can have up to 16 letters, digits or dashes".to_string());
+    }
+
+    if DISCOUNT_COUPONS.with(|coupons| coupons.borrow().get(&template_coupon_id)).is_none() {
+        return Err("Template coupon not found".to_string());
+    }
+
+    let (seed,) = raw_rand().await
+        .map_err(|(code, msg)| format!("Failed to get randomness: {:?} {}", code, msg))?;
+
+    // Re-read after the await, the template may have changed meanwhile
+    let template = DISCOUNT_COUPONS.with(|coupons| coupons.borrow().get(&template_coupon_id))
+        .ok_or("Template coupon not found")?;
+
+    let batch_id = NEXT_COUPON_BATCH_ID.with(|id| {
+        let current = *id.borrow().get();
+        id.borrow_mut().set(current + 1).unwrap();
+        format!("batch_{}", current)
+    });
+
+    let current_time = ic_cdk::api::time();
+    let mut coupon_ids = Vec::with_capacity(count as usize);
+    let mut counter = 0u64;
+
+    while coupon_ids.len() < count as usize {
+        let code = generated_coupon_code(&prefix, &seed, counter);
+        counter += 1;
+        if coupon_id_for_code(&code).is_some() {
+            continue;
+        }
+
+        let coupon_id = NEXT_COUPON_ID.with(|id| {
+            let current = *id.borrow().get();
+            id.borrow_mut().set(current + 1).unwrap();
+            format!("coupon_{}", current)
+        });
+
+        let coupon = DiscountCoupon {
+            coupon_id: coupon_id.clone(),
+            code: code.clone(),
+            usage_limit: Some(1),
+            used_count: 0,
+            per_customer_limit: Some(1),
+            is_active: true,
+            created_at: current_time,
+            updated_at: current_time,
+            batch_id: Some(batch_id.clone()),
+            ..template.clone()
+        };
+
+        COUPON_CODE_INDEX.with(|index| index.borrow_mut().insert(code, coupon_id.clone()));
+        DISCOUNT_COUPONS.with(|coupons| coupons.borrow_mut().insert(coupon_id.clone(), coupon));
+        coupon_ids.push(coupon_id);
+    }
+
+    let batch = CouponBatch {
+        batch_id: batch_id.clone(),
+        template_coupon_id,
+        prefix,
+        coupon_ids,
+        created_at: current_time,
+        deactivated_at: None,
+    };
+
+    COUPON_BATCHES.with(|batches| batches.borrow_mut().insert(batch_id, batch.clone()));
+    Ok(batch)
+}
+
+#[ic_cdk::query]
+fn list_coupon_batches() -> Vec<CouponBatch> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+
+    if caller != owner {
+        return vec![];
+    }
+
+    COUPON_BATCHES.with(|batches| {
+        batches.borrow().iter().map(|(_, batch)| batch).collect()
+    })
+}
+
+// The generated coupons with their codes and redemption state, for distribution
+#[ic_cdk::query]
+fn export_coupon_batch(batch_id: String) -> Result<Vec<DiscountCoupon>, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+
+    if caller != owner {
+        return Err("Only the owner can export coupon codes".to_string());
+    }
+
+    let batch = COUPON_BATCHES.with(|batches| batches.borrow().get(&batch_id))
+        .ok_or("Coupon batch not found")?;
+
+    Ok(DISCOUNT_COUPONS.with(|coupons| {
+        let map = coupons.borrow();
+        batch.coupon_ids.iter()
+            .filter_map(|coupon_id| map.get(coupon_id))
+            .collect()
+    }))
+}
+
+// Returns the number of codes that were still active
+#[ic_cdk::update]
+fn deactivate_coupon_batch(batch_id: String) -> Result<u32, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+
+    if caller != owner {
+        return Err("Only the owner can deactivate coupon codes".to_string());
+    }
+
+    let mut batch = COUPON_BATCHES.with(|batches| batches.borrow().get(&batch_id))
+        .ok_or("Coupon batch not found")?;
+
+    let current_time = ic_cdk::api::time();
+    let mut deactivated = 0u32;
+    DISCOUNT_COUPONS.with(|coupons| {
+        let mut map = coupons.borrow_mut();
+        for coupon_id in &batch.coupon_ids {
+            if let Some(mut coupon) = map.get(coupon_id) {
+                if coupon.is_active {
+                    coupon.is_active = false;
+                    coupon.updated_at = current_time;
+                    map.insert(coupon_id.clone(), coupon);
+                    deactivated += 1;
+                }
+            }
+        }
+    });
+
+    batch.deactivated_at = Some(current_time);
+    COUPON_BATCHES.with(|batches| batches.borrow_mut().insert(batch_id, batch));
+    Ok(deactivated)
+}
+
+// ============================================================================
+// SUBSCRIPTION MANAGEMENT SYSTEM METHODS
+// ============================================================================
+
+// ============================================================================
+// SUBSCRIPTION PLAN MANAGEMENT
+// ============================================================================
+
+#[ic_cdk::update]
+fn create_subscription_plan(mut plan: SubscriptionPlan) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can create subscription plans".to_string());
+    }
+
+    // Validate plan configuration
+    if plan.name.is_empty() {
+        return Err("Plan name cannot be empty".to_string());
+    }
+    if plan.description.is_empty() {
+        return Err("Plan description cannot be empty".to_string());
+    }
+    if plan.price == 0 && plan.usage_pricing.is_none() {
+        return Err("Plan price must be greater than 0".to_string());
+    }
+    if let Some(pricing) = &plan.usage_pricing {
+        validate_usage_pricing(pricing)?;
+    }
+    if plan.token.is_empty() {
+        return Err("Plan token cannot be empty".to_string());
+    }
+    if let Some(policy) = &plan.dunning_policy {
+        validate_dunning_policy(policy)?;
+    }
+    validate_billing_day(&plan)?;
+    if let Some(policy) = &plan.self_service {
+        validate_self_service_policy(policy)?;
+    }
+
+    // Validate that the token is supported
+    let config = CONFIG.with(|c| c.borrow().get().clone());
+    let token_exists = config.supported_tokens
+        .iter()
+        .any(|t| t.symbol == plan.token && t.is_active);
+    
+    if !token_exists {
+        return Err("Token not supported or inactive".to_string());
+    }
+
+    // Generate plan ID
+    let plan_id = NEXT_SUBSCRIPTION_ID.with(|id| {
+        let current = *id.borrow().get();
+        id.borrow_mut().set(current + 1).unwrap();
+        format!("plan_{}", current)
+    });
+
+    // Set plan metadata
+    plan.plan_id = plan_id.clone();
+    plan.created_at = ic_cdk::api::time();
+    plan.updated_at = ic_cdk::api::time();
+
+    SUBSCRIPTION_PLANS.with(|plans| {
+        plans.borrow_mut().insert(plan_id.clone(), plan)
+    });
+
+    Ok(plan_id)
+}
+
+#[ic_cdk::update]
+fn update_subscription_plan(plan_id: String, mut updated_plan: SubscriptionPlan) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can update subscription plans".to_string());
+    }
+
+    // Validate plan configuration
+    if updated_plan.name.is_empty() {
+        return Err("Plan name cannot be empty".to_string());
+    }
+    if updated_plan.description.is_empty() {
+        return Err("Plan description cannot be empty".to_string());
+    }
+    if updated_plan.price == 0 && updated_plan.usage_pricing.is_none() {
+        return Err("Plan price must be greater than 0".to_string());
+    }
+    if let Some(pricing) = &updated_plan.usage_pricing {
+        validate_usage_pricing(pricing)?;
+    }
+    if updated_plan.token.is_empty() {
+        return Err("Plan token cannot be empty".to_string());
+    }
+    if let Some(policy) = &updated_plan.dunning_policy {
+        validate_dunning_policy(policy)?;
+    }
+    validate_billing_day(&updated_plan)?;
+    if let Some(policy) = &updated_plan.self_service {
+        validate_self_service_policy(policy)?;
+    }
+
+    // Validate that the token is supported
+    let config = CONFIG.with(|c| c.borrow().get().clone());
+    let token_exists = config.supported_tokens
+        .iter()
+        .any(|t| t.symbol == updated_plan.token && t.is_active);
+    
+    if !token_exists {
+        return Err("Token not supported or inactive".to_string());
+    }
+
+    SUBSCRIPTION_PLANS.with(|plans| {
+        let mut map = plans.borrow_mut();
+        let existing_plan = map.get(&plan_id)
+            .ok_or("Subscription plan not found")?;
+        
+        // Preserve original metadata
+        updated_plan.plan_id = plan_id.clone();
+        updated_plan.created_at = existing_plan.created_at;
+        updated_plan.updated_at = ic_cdk::api::time();
+        
+        map.insert(plan_id, updated_plan);
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn get_subscription_plan(plan_id: String) -> Result<SubscriptionPlan, String> {
+    SUBSCRIPTION_PLANS.with(|plans| {
+        plans.borrow().get(&plan_id)
+            .ok_or("Subscription plan not found".to_string())
+    })
+}
+
+#[ic_cdk::query]
+fn list_subscription_plans() -> Vec<SubscriptionPlan> {
+    SUBSCRIPTION_PLANS.with(|plans| {
+        plans.borrow().iter().map(|(_, plan)| plan).collect()
+    })
+}
+
+#[ic_cdk::query]
+fn list_active_subscription_plans() -> Vec<SubscriptionPlan> {
+    SUBSCRIPTION_PLANS.with(|plans| {
+        plans.borrow().iter()
+            .filter(|(_, plan)| plan.is_active)
+            .map(|(_, plan)| plan)
+            .collect()
+    })
+}
+
+#[ic_cdk::update]
+fn delete_subscription_plan(plan_id: String) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can delete subscription plans".to_string());
+    }
+
+    // Check if there are any active subscriptions for this plan, paused ones resume on it
+    let has_active_subscriptions = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().iter()
+            .any(|(_, subscription)| {
+                let scheduled = subscription.scheduled_plan_change.as_ref()
+                    .is_some_and(|change| change.to_plan_id == plan_id);
+                (subscription.plan_id == plan_id || scheduled) &&
+                matches!(
+                    subscription.status,
+                    SubscriptionStatus::Active | SubscriptionStatus::PendingPayment | SubscriptionStatus::Paused
+                )
+            })
+    });
+
+    if has_active_subscriptions {
+        return Err("Cannot delete plan with active subscriptions. Cancel subscriptions first.".to_string());
+    }
+
+    SUBSCRIPTION_PLANS.with(|plans| {
+        let mut map = plans.borrow_mut();
+        if map.remove(&plan_id).is_none() {
+            return Err("Subscription plan not found".to_string());
+        }
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn toggle_subscription_plan_status(plan_id: String) -> Result<bool, String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return Err("Only the owner can toggle plan status".to_string());
+    }
+
+    SUBSCRIPTION_PLANS.with(|plans| {
+        let mut map = plans.borrow_mut();
+        if let Some(mut plan) = map.get(&plan_id) {
+            plan.is_active = !plan.is_active;
+            plan.updated_at = ic_cdk::api::time();
+            let new_status = plan.is_active;
+            map.insert(plan_id, plan);
+            Ok(new_status)
+        } else {
+            Err("Subscription plan not found".to_string())
+        }
+    })
+}
+
+// ============================================================================
+// SUBSCRIPTION MANAGEMENT
+// ============================================================================
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Days since 1970-01-01 to a UTC (year, month, day) in the proleptic Gregorian calendar
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153; // March = 0
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = (if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 }) as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = (if month > 2 { month - 3 } else { month + 9 }) as i64;
+    let day_of_year = (153 * shifted_month + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+fn day_of_month(timestamp: u64) -> u8 {
+    civil_from_days((timestamp / NANOS_PER_DAY) as i64).2 as u8
+}
+
+// Move `timestamp` by `months` calendar months onto `anchor_day` (default: its own day),
+// clamped to the last day of shorter months. The UTC time of day is kept.
+fn shift_calendar_months(timestamp: u64, months: i64, anchor_day: Option<u8>) -> u64 {
+    let time_of_day = timestamp % NANOS_PER_DAY;
+    let (year, month, day) = civil_from_days((timestamp / NANOS_PER_DAY) as i64);
+
+    let month_index = year * 12 + month as i64 - 1 + months;
+    let (year, month) = (month_index.div_euclid(12), month_index.rem_euclid(12) as u32 + 1);
+    let day = anchor_day.map_or(day, u32::from).min(days_in_month(year, month));
+
+    days_from_civil(year, month, day).max(0) as u64 * NANOS_PER_DAY + time_of_day
+}
+
+fn interval_months(billing_interval: &BillingInterval) -> Option<i64> {
+    match billing_interval {
+        BillingInterval::Monthly => Some(1),
+        BillingInterval::Quarterly => Some(3),
+        BillingInterval::Yearly => Some(12),
+        _ => None,
+    }
+}
+
+fn validate_billing_day(plan: &SubscriptionPlan) -> Result<(), String> {
+    if let Some(day) = plan.billing_day {
+        if !(1..=31).contains(&day) {
+            return Err("Billing day must be between 1 and 31".to_string());
+        }
+        if interval_months(&plan.billing_interval).is_none() {
+            return Err("A billing day only applies to monthly, quarterly and yearly plans".to_string());
+        }
+    }
+    Ok(())
+}
+
+// Calendar intervals renew on the anchor day. A date off the anchor (e.g. the end of a
+// trial on a plan with a fixed billing day) only runs up to the next anchor day.
+fn calculate_next_billing_date(current_time: u64, billing_interval: &BillingInterval, anchor_day: Option<u8>) -> u64 {
+    match billing_interval {
+        BillingInterval::Daily => current_time + NANOS_PER_DAY,
+        BillingInterval::Weekly => current_time + 7 * NANOS_PER_DAY,
+        BillingInterval::Custom(seconds) => current_time + (seconds * 1_000_000_000),
+        BillingInterval::Monthly | BillingInterval::Quarterly | BillingInterval::Yearly => {
+            let months = interval_months(billing_interval).unwrap_or(1);
+            let anchored = shift_calendar_months(current_time, 0, anchor_day);
+            if anchored == current_time {
+                shift_calendar_months(current_time, months, anchor_day)
+            } else if anchored > current_time {
+                anchored
+            } else {
+                shift_calendar_months(current_time, 1, anchor_day)
+            }
+        },
+    }
+}
+
+// Price of a billing period, pro rata when it was cut short to reach the anchor day
+fn billing_period_price(
+    price: u64,
+    period_start: u64,
+    period_end: u64,
+    billing_interval: &BillingInterval,
+    anchor_day: Option<u8>,
+) -> u64 {
+    let full_period_start = match interval_months(billing_interval) {
+        Some(months) => shift_calendar_months(period_end, -months, anchor_day),
+        None => return price,
+    };
+
+    if full_period_start >= period_start || period_end <= period_start {
+        return price;
+    }
+
+    (price as u128 * (period_end - period_start) as u128 / (period_end - full_period_start) as u128) as u64
+}
+
+#[ic_cdk::update]
+fn create_subscription(plan_id: String, metadata: Vec<(String, String)>) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    let current_time = ic_cdk::api::time();
+    
+    // Get the subscription plan
+    let plan = SUBSCRIPTION_PLANS.with(|plans| {
+        plans.borrow().get(&plan_id)
+    }).ok_or("Subscription plan not found")?;
+
+    if !plan.is_active {
+        return Err("Subscription plan is not active".to_string());
+    }
+
+    // Check if plan has subscription limits
+    if let Some(max_subscriptions) = plan.max_subscriptions {
+        let current_active_count = SUBSCRIPTIONS.with(|subscriptions| {
+            subscriptions.borrow().iter()
+                .filter(|(_, subscription)| {
+                    subscription.plan_id == plan_id &&
+                    matches!(subscription.status, SubscriptionStatus::Active | SubscriptionStatus::PendingPayment)
+                })
+                .count() as u32
+        });
+
+        if current_active_count >= max_subscriptions {
+            return Err("Subscription plan has reached maximum number of subscriptions".to_string());
+        }
+    }
+
+    // Check if user already has an active subscription to this plan
+    let has_existing_subscription = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().iter()
+            .any(|(_, subscription)| {
+                subscription.subscriber == caller &&
+                subscription.plan_id == plan_id &&
+                matches!(subscription.status, SubscriptionStatus::Active | SubscriptionStatus::PendingPayment)
+            })
+    });
+
+    if has_existing_subscription {
+        return Err("User already has an active subscription to this plan".to_string());
+    }
+
+    // Generate subscription ID
+    let subscription_id = NEXT_SUBSCRIPTION_ID.with(|id| {
+        let current = *id.borrow().get();
+        id.borrow_mut().set(current + 1).unwrap();
+        format!("sub_{}", current)
+    });
+
+    // Calculate billing dates
+    let trial_end = plan.trial_period_days.map(|days| {
+        current_time + (days as u64 * 24 * 60 * 60 * 1_000_000_000)
+    });
+
+    // Billing is in advance: trials are charged when they end, everything else right away
+    let (current_period_end, next_billing_date) = match trial_end {
+        Some(trial_end) => (trial_end, trial_end),
+        None => (calculate_next_billing_date(current_time, &plan.billing_interval, plan.billing_day), current_time),
+    };
+
+    // Without a fixed billing day, renewals keep the day of month of the first charge
+    let billing_anchor_day = plan.billing_day.unwrap_or_else(|| day_of_month(next_billing_date));
+
+    let subscription = Subscription {
+        subscription_id: subscription_id.clone(),
+        plan_id: plan_id.clone(),
+        subscriber: caller,
+        status: if trial_end.is_some() {
+            SubscriptionStatus::Active // Free trial is active
+        } else {
+            SubscriptionStatus::PendingPayment // Needs initial payment
+        },
+        current_period_start: current_time,
+        current_period_end,
+        next_billing_date,
+        trial_end,
+        cancelled_at: None,
+        cancel_at_period_end: false,
+        total_payments: 0,
+        payment_failures: 0,
+        next_retry_at: None,
+        metadata,
+        created_at: current_time,
+        updated_at: current_time,
+        proration_credit: None,
+        scheduled_plan_change: None,
+        plan_changes: None,
+        billing_anchor_day: Some(billing_anchor_day),
+        paused_at: None,
+        resume_at: None,
+        paused_by: None,
+        pause_count: None,
+    };
+
+    SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow_mut().insert(subscription_id.clone(), subscription)
+    });
+
+    Ok(subscription_id)
+}
+
+#[ic_cdk::query]
+fn get_subscription(subscription_id: String) -> Result<Subscription, String> {
+    SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().get(&subscription_id)
+            .ok_or("Subscription not found".to_string())
+    })
+}
+
+#[ic_cdk::query]
+fn list_user_subscriptions(user: Principal) -> Vec<Subscription> {
+    SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().iter()
+            .filter(|(_, subscription)| subscription.subscriber == user)
+            .map(|(_, subscription)| subscription)
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn list_my_subscriptions() -> Vec<Subscription> {
+    let caller = ic_cdk::caller();
+    SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().iter()
+            .filter(|(_, subscription)| subscription.subscriber == caller)
+            .map(|(_, subscription)| subscription)
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn list_subscriptions_by_plan(plan_id: String) -> Vec<Subscription> {
+    SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().iter()
+            .filter(|(_, subscription)| subscription.plan_id == plan_id)
+            .map(|(_, subscription)| subscription)
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn list_all_subscriptions() -> Vec<Subscription> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return vec![];
+    }
+
+    SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().iter().map(|(_, subscription)| subscription).collect()
+    })
+}
+
+fn default_self_service_policy() -> SelfServicePolicy {
+    SelfServicePolicy {
+        allow_immediate_cancel: true,
+        allow_pause: true,
+        max_pause_days: None,
+        max_pauses: None,
+    }
+}
+
+fn validate_self_service_policy(policy: &SelfServicePolicy) -> Result<(), String> {
+    if policy.max_pause_days == Some(0) {
+        return Err("Maximum pause length must be at least one day".to_string());
+    }
+    Ok(())
+}
+
+fn self_service_policy(plan_id: &str) -> SelfServicePolicy {
+    SUBSCRIPTION_PLANS.with(|plans| plans.borrow().get(&plan_id.to_string()))
+        .and_then(|plan| plan.self_service)
+        .unwrap_or_else(default_self_service_policy)
+}
+
+// The subscriber acts on their own subscription within the plan's self-service policy,
+// the owner on every subscription without limits. Returns whether the caller is the owner.
+fn authorize_subscription_action(subscription: &Subscription, caller: Principal, action: &str) -> Result<bool, String> {
+    let owner = OWNER.with(|o| *o.borrow().get());
+    if caller == owner {
+        return Ok(true);
+    }
+    if caller == subscription.subscriber {
+        return Ok(false);
+    }
+    Err(format!("Only the subscriber or owner can {} this subscription", action))
+}
+
+// Paused time does not use up a period (or trial) that was already under way
+fn resume_paused_subscription(subscription: &mut Subscription, current_time: u64) {
+    if let Some(paused_at) = subscription.paused_at {
+        let paused_for = current_time.saturating_sub(paused_at);
+        if subscription.next_billing_date > paused_at {
+            subscription.current_period_end = subscription.current_period_end.saturating_add(paused_for);
+            subscription.next_billing_date = subscription.next_billing_date.saturating_add(paused_for);
+        }
+        if let Some(trial_end) = subscription.trial_end.filter(|trial_end| *trial_end > paused_at) {
+            subscription.trial_end = Some(trial_end.saturating_add(paused_for));
+        }
+    }
+
+    subscription.status = SubscriptionStatus::Active;
+    // Resuming starts a fresh dunning cycle for any outstanding charge
+    subscription.payment_failures = 0;
+    subscription.next_retry_at = None;
+    subscription.paused_at = None;
+    subscription.resume_at = None;
+    subscription.paused_by = None;
+    subscription.updated_at = current_time;
+}
+
+#[ic_cdk::update]
+fn cancel_subscription(subscription_id: String, cancel_immediately: bool) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let current_time = ic_cdk::api::time();
+    
+    SUBSCRIPTIONS.with(|subscriptions| {
+        let mut map = subscriptions.borrow_mut();
+        let mut subscription = map.get(&subscription_id)
+            .ok_or("Subscription not found")?;
+        
+        let is_owner = authorize_subscription_action(&subscription, caller, "cancel")?;
+
+        // Check if already cancelled
+        if matches!(subscription.status, SubscriptionStatus::Cancelled) {
+            return Err("Subscription is already cancelled".to_string());
+        }
+
+        if cancel_immediately && !is_owner && !self_service_policy(&subscription.plan_id).allow_immediate_cancel {
+            return Err("This plan only lets subscribers cancel at the end of the billing period".to_string());
+        }
+
+        let previous_status = subscription.status.clone();
+        if cancel_immediately {
+            subscription.status = SubscriptionStatus::Cancelled;
+            subscription.cancelled_at = Some(current_time);
+        } else {
+            // The billing engine ends the subscription once the current period is over
+            subscription.cancel_at_period_end = true;
+        }
+        
+        subscription.updated_at = current_time;
+        enqueue_subscription_event(&subscription, previous_status);
+        map.insert(subscription_id, subscription);
+        Ok(())
+    })
+}
+
+// Subscribers pause within the plan's limits; a pause without `resume_at` lasts until resumed
+// (or the longest allowed pause for subscribers on plans with a limit).
+#[ic_cdk::update]
+fn pause_subscription(subscription_id: String, resume_at: Option<u64>) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let current_time = ic_cdk::api::time();
+    
+    SUBSCRIPTIONS.with(|subscriptions| {
+        let mut map = subscriptions.borrow_mut();
+        let mut subscription = map.get(&subscription_id)
+            .ok_or("Subscription not found")?;
+        
+        let is_owner = authorize_subscription_action(&subscription, caller, "pause")?;
+
+        // Check if can be paused
+        if !matches!(subscription.status, SubscriptionStatus::Active) {
+            return Err("Only active subscriptions can be paused".to_string());
+        }
+
+        if resume_at.is_some_and(|resume_at| resume_at <= current_time) {
+            return Err("Resume date must be in the future".to_string());
+        }
+
+        let resume_at = if is_owner {
+            resume_at
+        } else {
+            let policy = self_service_policy(&subscription.plan_id);
+            if !policy.allow_pause {
+                return Err("This plan does not let subscribers pause".to_string());
+            }
+            if policy.max_pauses.is_some_and(|max| subscription.pause_count.unwrap_or(0) >= max) {
+                return Err("Subscription has reached its pause limit".to_string());
+            }
+
+            match policy.max_pause_days {
+                Some(days) => {
+                    let latest = current_time.saturating_add(days as u64 * NANOS_PER_DAY);
+                    if resume_at.is_some_and(|resume_at| resume_at > latest) {
+                        return Err(format!("Subscriptions can be paused for at most {} days", days));
+                    }
+                    Some(resume_at.unwrap_or(latest))
+                },
+                None => resume_at,
+            }
+        };
+
+        if !is_owner {
+            subscription.pause_count = Some(subscription.pause_count.unwrap_or(0) + 1);
+        }
+
+        subscription.status = SubscriptionStatus::Paused;
+        subscription.paused_at = Some(current_time);
+        subscription.resume_at = resume_at;
+        subscription.paused_by = Some(caller);
+        subscription.updated_at = current_time;
+        enqueue_subscription_event(&subscription, SubscriptionStatus::Active);
+        map.insert(subscription_id, subscription);
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn resume_subscription(subscription_id: String) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let current_time = ic_cdk::api::time();
+    
+    SUBSCRIPTIONS.with(|subscriptions| {
+        let mut map = subscriptions.borrow_mut();
+        let mut subscription = map.get(&subscription_id)
+            .ok_or("Subscription not found")?;
+        
+        let is_owner = authorize_subscription_action(&subscription, caller, "resume")?;
+
+        // Check if can be resumed
+        if !matches!(subscription.status, SubscriptionStatus::Paused) {
+            return Err("Only paused subscriptions can be resumed".to_string());
+        }
+
+        // A pause put in place by the merchant is lifted by the merchant
+        if !is_owner && subscription.paused_by.is_some_and(|paused_by| paused_by != subscription.subscriber) {
+            return Err("Only the owner can resume this subscription".to_string());
+        }
+
+        resume_paused_subscription(&mut subscription, current_time);
+        enqueue_subscription_event(&subscription, SubscriptionStatus::Paused);
+        map.insert(subscription_id, subscription);
+        Ok(())
+    })
+}
+
+// Timer step: lift pauses whose resume date has passed
+fn resume_due_subscriptions(current_time: u64) {
+    let due: Vec<Subscription> = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().iter()
+            .filter(|(_, subscription)| {
+                matches!(subscription.status, SubscriptionStatus::Paused) &&
+                subscription.resume_at.is_some_and(|resume_at| resume_at <= current_time)
+            })
+            .map(|(_, subscription)| subscription)
+            .collect()
+    });
+
+    for mut subscription in due {
+        resume_paused_subscription(&mut subscription, current_time);
+        enqueue_subscription_event(&subscription, SubscriptionStatus::Paused);
+        SUBSCRIPTIONS.with(|subscriptions| {
+            subscriptions.borrow_mut().insert(subscription.subscription_id.clone(), subscription)
+        });
+    }
+}
+
+#[ic_cdk::update]
+fn update_subscription_metadata(subscription_id: String, metadata: Vec<(String, String)>) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let current_time = ic_cdk::api::time();
+    
+    SUBSCRIPTIONS.with(|subscriptions| {
+        let mut map = subscriptions.borrow_mut();
+        let mut subscription = map.get(&subscription_id)
+            .ok_or("Subscription not found")?;
+        
+        authorize_subscription_action(&subscription, caller, "update")?;
+
+        subscription.metadata = metadata;
+        subscription.updated_at = current_time;
+        map.insert(subscription_id, subscription);
+        Ok(())
+    })
+}
+
+// ============================================================================
+// SUBSCRIPTION PAYMENT PROCESSING
+// ============================================================================
+
+// How often the billing engine looks for due subscriptions
+const BILLING_CHECK_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+// Upper bound on charges per run so a single timer execution stays small
+const MAX_CHARGES_PER_RUN: usize = 50;
+
+#[ic_cdk::update]
+async fn process_subscription_payment(subscription_id: String) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    let current_time = ic_cdk::api::time();
+    
+    // Get subscription
+    let subscription = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().get(&subscription_id)
+    }).ok_or("Subscription not found")?;
+
+    // Only the subscriber or owner can trigger a charge outside the billing timer
+    let owner = OWNER.with(|o| *o.borrow().get());
+    if caller != subscription.subscriber && caller != owner {
+        return Err("Only the subscriber or owner can process this payment".to_string());
+    }
+
+    if !matches!(subscription.status, SubscriptionStatus::Active | SubscriptionStatus::PendingPayment) {
+        return Err("Subscription is not billable".to_string());
+    }
+
+    // Ending subscriptions are settled by the billing engine
+    if subscription.cancel_at_period_end {
+        return Err("Subscription ends at the end of the current period".to_string());
+    }
+
+    // Check if payment is due
+    if current_time < subscription.next_billing_date {
+        return Err("Payment is not yet due".to_string());
+    }
+
+    let payment = charge_subscription(subscription_id).await?;
+    match payment.status.as_str() {
+        "paid" => Ok(payment.payment_id),
+        _ => Err(payment.failure_reason.unwrap_or("Subscription payment failed".to_string())),
+    }
+}
+
+// Timer entry point: charge every subscription whose billing date has passed
+async fn run_billing_cycle() {
+    let current_time = ic_cdk::api::time();
+
+    resume_due_subscriptions(current_time);
+    expire_grace_periods(current_time);
+    end_subscriptions_at_period_end(current_time);
+
+    let due_subscriptions: Vec<String> = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().iter()
+            .filter(|(id, subscription)| {
+                matches!(subscription.status, SubscriptionStatus::Active | SubscriptionStatus::PendingPayment) &&
+                subscription.next_retry_at.unwrap_or(subscription.next_billing_date) <= current_time &&
+                !is_in_flight(&format!("subscription:{}", id))
+            })
+            .map(|(id, _)| id)
+            .take(MAX_CHARGES_PER_RUN)
+            .collect()
+    });
+
+    for subscription_id in due_subscriptions {
+        if let Err(err) = charge_subscription(subscription_id.clone()).await {
+            ic_cdk::println!("Billing for {} skipped: {}", subscription_id, err);
+        }
+    }
+}
+
+// Cancel subscriptions set to end with their period once it is over. Those that still
+// owe metered usage stay due and get a final usage-only charge instead.
+fn end_subscriptions_at_period_end(current_time: u64) {
+    let ending: Vec<Subscription> = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().iter()
+            .filter(|(id, subscription)| {
+                matches!(subscription.status, SubscriptionStatus::Active | SubscriptionStatus::PendingPayment) &&
+                subscription.cancel_at_period_end &&
+                subscription.next_billing_date <= current_time &&
+                !is_in_flight(&format!("subscription:{}", id))
+            })
+            .map(|(_, subscription)| subscription)
+            .collect()
+    });
+
+    for mut subscription in ending {
+        let owes_usage = next_subscription_charge(&subscription)
+            .is_ok_and(|charge| charge.upcoming.amount > 0);
+        if owes_usage {
+            continue;
+        }
+
+        let previous_status = subscription.status.clone();
+        subscription.status = SubscriptionStatus::Cancelled;
+        subscription.cancelled_at = Some(current_time);
+        subscription.next_retry_at = None;
+        subscription.updated_at = current_time;
+        enqueue_subscription_event(&subscription, previous_status);
+        SUBSCRIPTIONS.with(|subscriptions| {
+            subscriptions.borrow_mut().insert(subscription.subscription_id.clone(), subscription)
+        });
+    }
+}
+
+// What the next charge of a subscription consists of
+struct NextCharge {
+    plan: SubscriptionPlan, // Plan the charge is billed under
+    scheduled_change: Option<PlanChange>, // Takes effect with this charge
+    usage_records: Vec<UsageRecord>, // Billed with this charge
+    upcoming: UpcomingCharge,
+}
+
+// Subscriptions created before renewals were anchored keep the day of their next renewal,
+// or the plan's fixed billing day, instead of drifting after short months
+fn backfill_billing_anchors() {
+    let unanchored: Vec<Subscription> = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().iter()
+            .filter(|(_, subscription)| subscription.billing_anchor_day.is_none())
+            .map(|(_, subscription)| subscription)
+            .collect()
+    });
+
+    for mut subscription in unanchored {
+        let billing_day = SUBSCRIPTION_PLANS.with(|plans| plans.borrow().get(&subscription.plan_id))
+            .and_then(|plan| plan.billing_day);
+        subscription.billing_anchor_day = Some(billing_day.unwrap_or_else(|| day_of_month(subscription.next_billing_date)));
+        SUBSCRIPTIONS.with(|subscriptions| {
+            subscriptions.borrow_mut().insert(subscription.subscription_id.clone(), subscription)
+        });
+    }
+}
+
+// A subscription whose plan is gone can never be charged again, it is expired
+// instead of staying due and taking a billing slot on every run
+fn expire_unbillable_subscription(mut subscription: Subscription, current_time: u64) {
+    let previous_status = subscription.status.clone();
+    subscription.status = SubscriptionStatus::Expired;
+    subscription.next_retry_at = None;
+    subscription.updated_at = current_time;
+    SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow_mut().insert(subscription.subscription_id.clone(), subscription.clone())
+    });
+    enqueue_subscription_event(&subscription, previous_status);
+}
+
+fn next_subscription_charge(subscription: &Subscription) -> Result<NextCharge, String> {
+    // An ending subscription is not renewed, its final charge only covers usage still owed
+    let ending = subscription.cancel_at_period_end;
+
+    // A change scheduled for the next renewal is billed at the new plan's terms
+    let scheduled = subscription.scheduled_plan_change.clone()
+        .filter(|_| !ending)
+        .and_then(|change| {
+            SUBSCRIPTION_PLANS.with(|plans| plans.borrow().get(&change.to_plan_id))
+                .map(|plan| (change, plan))
+        });
+
+    let current_plan = SUBSCRIPTION_PLANS.with(|plans| plans.borrow().get(&subscription.plan_id));
+    let plan = match &scheduled {
+        Some((_, plan)) => plan.clone(),
+        None => current_plan.clone().ok_or("Subscription plan not found")?,
+    };
+
+    // The charge pays for the period starting at the billing date
+    let period_start = subscription.next_billing_date;
+    let anchor_day = subscription.billing_anchor_day;
+    let (period_end, base_amount) = if ending {
+        (period_start, 0)
+    } else {
+        let period_end = calculate_next_billing_date(period_start, &plan.billing_interval, anchor_day);
+        (period_end, billing_period_price(plan.price, period_start, period_end, &plan.billing_interval, anchor_day))
+    };
+
+    // Usage is billed in arrears, at the pricing of the plan it was recorded under
+    let usage_records = unbilled_usage_records(&subscription.subscription_id, period_start);
+    let current_pricing = current_plan.and_then(|current_plan| current_plan.usage_pricing);
+    let usage = usage_amount(&usage_records, subscription.trial_end, current_pricing.as_ref());
+    let usage_amount = usage.map_or(0, |(_, amount)| amount);
+
+    let credit_applied = subscription.proration_credit.unwrap_or(0).min(base_amount + usage_amount);
+
+    let upcoming = UpcomingCharge {
+        subscription_id: subscription.subscription_id.clone(),
+        plan_id: plan.plan_id.clone(),
+        token: plan.token.clone(),
+        charge_at: subscription.next_retry_at.unwrap_or(subscription.next_billing_date),
+        period_start,
+        period_end,
+        base_amount,
+        usage_quantity: usage.map(|(quantity, _)| quantity),
+        usage_amount: usage.map(|(_, amount)| amount),
+        credit_applied,
+        amount: base_amount + usage_amount - credit_applied,
+        final_charge: ending,
+    };
+
+    Ok(NextCharge {
+        plan,
+        scheduled_change: scheduled.map(|(change, _)| change),
+        usage_records,
+        upcoming,
+    })
+}
+
+#[ic_cdk::query]
+fn get_upcoming_charge(subscription_id: String) -> Result<UpcomingCharge, String> {
+    let subscription = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().get(&subscription_id)
+    }).ok_or("Subscription not found")?;
+
+    authorize_subscription_action(&subscription, ic_cdk::caller(), "view")?;
+
+    if !matches!(subscription.status, SubscriptionStatus::Active | SubscriptionStatus::PendingPayment) {
+        return Err("Subscription has no upcoming charge".to_string());
+    }
+
+    Ok(next_subscription_charge(&subscription)?.upcoming)
+}
+
+// Pull one period's price from the subscriber through their ICRC-2 allowance.
+// Returns Err only when no charge was attempted; ledger failures are recorded
+// on the returned payment.
+async fn charge_subscription(subscription_id: String) -> Result<SubscriptionPayment, String> {
+    let _guard = InFlightGuard::acquire(format!("subscription:{}", subscription_id))?;
+    let current_time = ic_cdk::api::time();
+
+    let subscription = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().get(&subscription_id)
+    }).ok_or("Subscription not found")?;
+
+    let NextCharge { plan, scheduled_change, usage_records, upcoming } = match next_subscription_charge(&subscription) {
+        Ok(next_charge) => next_charge,
+        Err(err) => {
+            expire_unbillable_subscription(subscription, current_time);
+            return Err(err);
+        },
+    };
+
+    let payment_id = format!("pay_{}_{}", subscription_id, current_time);
+    let mut payment = SubscriptionPayment {
+        payment_id: payment_id.clone(),
+        subscription_id: subscription_id.clone(),
+        amount: upcoming.amount,
+        token: plan.token.clone(),
+        billing_period_start: upcoming.period_start,
+        billing_period_end: upcoming.period_end,
+        payment_date: current_time,
+        status: "pending".to_string(),
+        transaction_id: None,
+        failure_reason: None,
+        attempt: Some(subscription.payment_failures + 1),
+        usage_quantity: upcoming.usage_quantity,
+        usage_amount: upcoming.usage_amount,
+    };
+
+    SUBSCRIPTION_PAYMENTS.with(|payments| {
+        payments.borrow_mut().insert(payment_id.clone(), payment.clone())
+    });
+
+    let transfer_result = collect_subscription_funds(&subscription, &plan, &mut payment, current_time).await;
+
+    match &transfer_result {
+        Ok(_) => {
+            payment.status = "paid".to_string();
+            mark_usage_billed(&usage_records, &payment_id);
+        },
+        Err(err) => {
+            ic_cdk::println!("Subscription payment {} failed: {}", payment_id, err);
+            payment.status = "failed".to_string();
+            payment.failure_reason = Some(err.clone());
+        }
+    }
+
+    // Re-read the subscription, it may have been changed while the ledger call was in flight
+    SUBSCRIPTIONS.with(|subscriptions| {
+        let mut map = subscriptions.borrow_mut();
+        if let Some(mut subscription) = map.get(&subscription_id) {
+            let previous_status = subscription.status.clone();
+            let charge = ChargeOutcome {
+                plan: &plan,
+                scheduled_change: scheduled_change.as_ref(),
+                upcoming: &upcoming,
+                paid: transfer_result.is_ok(),
+            };
+            apply_charge_outcome(&mut subscription, &charge, current_time);
+            enqueue_subscription_event(&subscription, previous_status);
+            map.insert(subscription_id.clone(), subscription);
+        }
+    });
+
+    SUBSCRIPTION_PAYMENTS.with(|payments| {
+        payments.borrow_mut().insert(payment_id, payment.clone())
+    });
+
+    if payment.status == "failed" {
+        enqueue_webhook_event(WebhookEventType::PaymentFailed, json!({ "subscription_payment": payment }));
+    }
+
+    Ok(payment)
+}
+
+struct ChargeOutcome<'a> {
+    plan: &'a SubscriptionPlan,
+    scheduled_change: Option<&'a PlanChange>,
+    upcoming: &'a UpcomingCharge,
+    paid: bool,
+}
+
+// Move the subscription on after a charge attempt. A paid renewal starts the next
+// period, a failed one enters dunning and a final charge always ends the subscription.
+fn apply_charge_outcome(subscription: &mut Subscription, charge: &ChargeOutcome, current_time: u64) {
+    let upcoming = charge.upcoming;
+
+    if upcoming.final_charge {
+        // The subscription ends either way; a failed final charge is left to the
+        // merchant through the PaymentFailed event
+        if charge.paid {
+            subscription.total_payments += upcoming.amount;
+        }
+        subscription.status = SubscriptionStatus::Cancelled;
+        subscription.cancelled_at = Some(current_time);
+        subscription.next_retry_at = None;
+    } else if charge.paid {
+        if matches!(subscription.status, SubscriptionStatus::Active | SubscriptionStatus::PendingPayment) {
+            subscription.status = SubscriptionStatus::Active;
+        }
+        subscription.current_period_start = upcoming.period_start;
+        subscription.current_period_end = upcoming.period_end;
+        subscription.next_billing_date = upcoming.period_end;
+        subscription.total_payments += upcoming.amount;
+        subscription.payment_failures = 0; // Reset failure count on successful payment
+        subscription.next_retry_at = None;
+
+        if let Some(change) = charge.scheduled_change {
+            let mut change = change.clone();
+            change.effective_at = upcoming.period_start;
+            subscription.plan_id = change.to_plan_id.clone();
+            subscription.scheduled_plan_change = None;
+            subscription.plan_changes.get_or_insert_with(Vec::new).push(change);
+        }
+    } else {
+        subscription.payment_failures += 1;
+        apply_dunning_policy(subscription, charge.plan, current_time);
+    }
+
+    if charge.paid {
+        subscription.proration_credit = subscription.proration_credit
+            .map(|credit| credit.saturating_sub(upcoming.credit_applied))
+            .filter(|credit| *credit > 0);
+    }
+
+    subscription.updated_at = current_time;
+}
+
+// Pull `payment.amount` from the subscriber and record the ledger transaction on the
+// payment. Nothing is pulled for a zero amount, e.g. when credit covers the whole period.
+async fn collect_subscription_funds(
+    subscription: &Subscription,
+    plan: &SubscriptionPlan,
+    payment: &mut SubscriptionPayment,
+    current_time: u64,
+) -> Result<(), String> {
+    if payment.amount == 0 {
+        return Ok(());
+    }
+
+    let config = CONFIG.with(|c| c.borrow().get().clone());
+    let owner = OWNER.with(|o| *o.borrow().get());
+
+    let token = config.supported_tokens
+        .iter()
+        .find(|t| t.symbol == plan.token && t.is_active)
+        .cloned();
+
+    let transfer_result = match &token {
+        Some(token) => transfer_from_token(
+            token.canister_id,
+            subscription.subscriber,
+            ic_cdk::id(),
+            payment.amount,
+            Some(payment_memo(&payment.payment_id)),
+            current_time,
+        ).await,
+        None => Err("Token not supported or inactive".to_string()),
+    };
+
+    let merchant_fee = (payment.amount * config.merchant_fee as u64) / 10000;
+
+    let (status, block_index) = match &transfer_result {
+        Ok(block_idx) => (TransactionStatus::Completed, Some(*block_idx)),
+        Err(err) => (TransactionStatus::Failed(err.clone()), None),
+    };
+
+    // Link a transaction record when a ledger call was made
+    if let Some(token) = &token {
+        let transaction_id = NEXT_TRANSACTION_ID.with(|id| {
+            let current = *id.borrow().get();
+            id.borrow_mut().set(current + 1).unwrap();
+            format!("tx_{}", current)
+        });
+
+        let transaction = PaymentTransaction {
+            id: transaction_id.clone(),
+            from: subscription.subscriber,
+            to: owner,
+            token: token.clone(),
+            amount: payment.amount,
+            fee: token.fee,
+            merchant_fee,
+            timestamp: current_time,
+            status,
+            metadata: vec![
+                ("subscription_id".to_string(), subscription.subscription_id.clone()),
+                ("subscription_payment_id".to_string(), payment.payment_id.clone()),
+                ("plan_id".to_string(), plan.plan_id.clone()),
+            ],
+            payment_method: PaymentMethod::Subscription,
+            block_index,
+            idempotency_key: None,
+        };
+
+        store_transaction(&transaction);
+        payment.transaction_id = Some(transaction_id);
+    }
+
+    if transfer_result.is_ok() {
+        credit_balance(&plan.token, payment.amount.saturating_sub(merchant_fee));
+        accrue_platform_fee(&plan.token, merchant_fee);
+        track_payment_analytics(&plan.token, payment.amount);
+    }
+
+    transfer_result.map(|_| ())
+}
+
+// ============================================================================
+// SUBSCRIPTION PLAN CHANGES
+// ============================================================================
+
+fn billing_period_length(period_start: u64, billing_interval: &BillingInterval, anchor_day: Option<u8>) -> u64 {
+    calculate_next_billing_date(period_start, billing_interval, anchor_day).saturating_sub(period_start)
+}
+
+// Credit for the unused part of the old plan and cost of the new plan over the rest of
+// the current period. The new plan's price is spread over its own interval length.
+fn prorate_plan_change(
+    old_price: u64,
+    new_price: u64,
+    new_interval_length: u64,
+    period_start: u64,
+    period_end: u64,
+    now: u64,
+) -> (u64, u64) {
+    if now >= period_end || period_end <= period_start || new_interval_length == 0 {
+        return (0, 0);
+    }
+
+    let remaining = (period_end - now.max(period_start)) as u128;
+    let credit = old_price as u128 * remaining / (period_end - period_start) as u128;
+    let charge = (new_price as u128 * remaining / new_interval_length as u128).min(new_price as u128);
+    (credit as u64, charge as u64)
+}
+
+fn validate_plan_change(
+    subscription: &Subscription,
+    caller: Principal,
+    new_plan_id: &str,
+) -> Result<(SubscriptionPlan, SubscriptionPlan), String> {
+    let owner = OWNER.with(|o| *o.borrow().get());
+    if caller != subscription.subscriber && caller != owner {
+        return Err("Only the subscriber or owner can change this subscription's plan".to_string());
+    }
+
+    if !matches!(subscription.status, SubscriptionStatus::Active | SubscriptionStatus::PendingPayment) {
+        return Err("Only active subscriptions can change plans".to_string());
+    }
+    if subscription.cancel_at_period_end {
+        return Err("Subscription is scheduled for cancellation".to_string());
+    }
+    if subscription.payment_failures > 0 {
+        return Err("Settle the outstanding payment before changing plans".to_string());
+    }
+    if subscription.plan_id == new_plan_id {
+        return Err("Subscription is already on this plan".to_string());
+    }
+
+    let current_plan = SUBSCRIPTION_PLANS.with(|plans| plans.borrow().get(&subscription.plan_id))
+        .ok_or("Subscription plan not found")?;
+    let new_plan = SUBSCRIPTION_PLANS.with(|plans| plans.borrow().get(&new_plan_id.to_string()))
+        .ok_or("Subscription plan not found")?;
+
+    if !new_plan.is_active {
+        return Err("Subscription plan is not active".to_string());
+    }
+
+    // Same limits as subscribing to the plan directly
+    let (active_count, already_subscribed) = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().iter()
+            .filter(|(_, other)| {
+                other.plan_id == new_plan_id &&
+                matches!(other.status, SubscriptionStatus::Active | SubscriptionStatus::PendingPayment)
+            })
+            .fold((0u32, false), |(count, subscribed), (_, other)| {
+                (count + 1, subscribed || other.subscriber == subscription.subscriber)
+            })
+    });
+
+    if already_subscribed {
+        return Err("User already has an active subscription to this plan".to_string());
+    }
+    if new_plan.max_subscriptions.is_some_and(|max| active_count >= max) {
+        return Err("Subscription plan has reached maximum number of subscriptions".to_string());
+    }
+
+    Ok((current_plan, new_plan))
+}
+
+fn quote_plan_change(
+    subscription: &Subscription,
+    current_plan: &SubscriptionPlan,
+    new_plan: &SubscriptionPlan,
+    mode: ProrationMode,
+    now: u64,
+) -> PlanChange {
+    // Only a paid period has unused value; trials and unpaid subscriptions just switch
+    let in_trial = subscription.trial_end.is_some_and(|trial_end| now < trial_end);
+    let paid_period = matches!(subscription.status, SubscriptionStatus::Active) && !in_trial;
+
+    let (credit, charge) = if mode == ProrationMode::Immediate && paid_period {
+        prorate_plan_change(
+            current_plan.price,
+            new_plan.price,
+            billing_period_length(
+                subscription.current_period_start,
+                &new_plan.billing_interval,
+                subscription.billing_anchor_day,
+            ),
+            subscription.current_period_start,
+            subscription.current_period_end,
+            now,
+        )
+    } else {
+        (0, 0)
+    };
+
+    let effective_at = match mode {
+        ProrationMode::Immediate => now,
+        ProrationMode::NextRenewal => subscription.next_billing_date,
+    };
+
+    PlanChange {
+        from_plan_id: current_plan.plan_id.clone(),
+        to_plan_id: new_plan.plan_id.clone(),
+        mode,
+        credit,
+        charge,
+        payment_id: None,
+        requested_at: now,
+        effective_at,
+    }
+}
+
+#[ic_cdk::query]
+fn preview_plan_change(
+    subscription_id: String,
+    new_plan_id: String,
+    mode: ProrationMode,
+) -> Result<PlanChange, String> {
+    let subscription = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().get(&subscription_id)
+    }).ok_or("Subscription not found")?;
+
+    let (current_plan, new_plan) = validate_plan_change(&subscription, ic_cdk::caller(), &new_plan_id)?;
+    Ok(quote_plan_change(&subscription, &current_plan, &new_plan, mode, ic_cdk::api::time()))
+}
+
+// Immediate changes charge a positive prorated difference right away and keep a negative
+// one as credit for the next renewals. Scheduled changes take effect with the next charge.
+#[ic_cdk::update]
+async fn change_subscription_plan(
+    subscription_id: String,
+    new_plan_id: String,
+    mode: ProrationMode,
+) -> Result<PlanChange, String> {
+    let caller = ic_cdk::caller();
+    let _guard = InFlightGuard::acquire(format!("subscription:{}", subscription_id))?;
+    let current_time = ic_cdk::api::time();
+
+    let mut subscription = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().get(&subscription_id)
+    }).ok_or("Subscription not found")?;
+
+    let (current_plan, new_plan) = validate_plan_change(&subscription, caller, &new_plan_id)?;
+    let mut change = quote_plan_change(&subscription, &current_plan, &new_plan, mode.clone(), current_time);
+
+    // Credit and unbilled usage are kept in the current plan's token
+    let has_credit = subscription.proration_credit.unwrap_or(0) > 0;
+    let metered = current_plan.usage_pricing.is_some();
+    if new_plan.token != current_plan.token && (mode == ProrationMode::Immediate || has_credit || metered) {
+        return Err("Plans priced in different tokens can only be changed at the next renewal, without outstanding credit or metered usage".to_string());
+    }
+
+    if mode == ProrationMode::NextRenewal {
+        subscription.scheduled_plan_change = Some(change.clone());
+        subscription.updated_at = current_time;
+        SUBSCRIPTIONS.with(|subscriptions| {
+            subscriptions.borrow_mut().insert(subscription_id, subscription)
+        });
+        return Ok(change);
+    }
+
+    let amount_due = change.charge.saturating_sub(change.credit);
+    if amount_due > 0 {
+        let payment_id = format!("pay_{}_{}", subscription_id, current_time);
+        let mut payment = SubscriptionPayment {
+            payment_id: payment_id.clone(),
+            subscription_id: subscription_id.clone(),
+            amount: amount_due,
+            token: new_plan.token.clone(),
+            billing_period_start: current_time,
+            billing_period_end: subscription.current_period_end,
+            payment_date: current_time,
+            status: "pending".to_string(),
+            transaction_id: None,
+            failure_reason: None,
+            attempt: None,
+            usage_quantity: None,
+            usage_amount: None,
+        };
+
+        SUBSCRIPTION_PAYMENTS.with(|payments| {
+            payments.borrow_mut().insert(payment_id.clone(), payment.clone())
+        });
+
+        let transfer_result = collect_subscription_funds(&subscription, &new_plan, &mut payment, current_time).await;
+        match &transfer_result {
+            Ok(_) => payment.status = "paid".to_string(),
+            Err(err) => {
+                payment.status = "failed".to_string();
+                payment.failure_reason = Some(err.clone());
+            }
+        }
+
+        SUBSCRIPTION_PAYMENTS.with(|payments| {
+            payments.borrow_mut().insert(payment_id.clone(), payment)
+        });
+
+        if let Err(err) = transfer_result {
+            return Err(format!("Proration charge failed: {}", err));
+        }
+        change.payment_id = Some(payment_id);
+    }
+
+    // Re-read the subscription, it may have been changed while the ledger call was in flight
+    let subscription = SUBSCRIPTIONS.with(|subscriptions| {
+        let mut map = subscriptions.borrow_mut();
+        let mut subscription = map.get(&subscription_id).ok_or("Subscription not found")?;
+
+        let still_on_plan = subscription.plan_id == change.from_plan_id &&
+            matches!(subscription.status, SubscriptionStatus::Active | SubscriptionStatus::PendingPayment) &&
+            !subscription.cancel_at_period_end;
+        if !still_on_plan {
+            // Keep what was collected as credit rather than switching a subscription that moved on
+            if amount_due > 0 {
+                subscription.proration_credit = Some(subscription.proration_credit.unwrap_or(0) + amount_due);
+                subscription.total_payments += amount_due;
+                subscription.updated_at = current_time;
+                map.insert(subscription_id.clone(), subscription);
+            }
+            return Err("Subscription changed while the plan change was being charged, the charge was kept as credit".to_string());
+        }
+
+        let credit = change.credit.saturating_sub(change.charge);
+        if credit > 0 {
+            subscription.proration_credit = Some(subscription.proration_credit.unwrap_or(0) + credit);
+        }
+        subscription.total_payments += amount_due;
+        subscription.plan_id = change.to_plan_id.clone();
+        subscription.scheduled_plan_change = None;
+        subscription.plan_changes.get_or_insert_with(Vec::new).push(change.clone());
+        subscription.updated_at = current_time;
+
+        map.insert(subscription_id.clone(), subscription.clone());
+        Ok::<Subscription, String>(subscription)
+    })?;
+
+    enqueue_webhook_event(
+        WebhookEventType::SubscriptionUpdated,
+        json!({ "subscription": subscription, "plan_change": change }),
     );
 
-    Ok(embed_code)
+    Ok(change)
+}
+
+#[ic_cdk::update]
+fn cancel_scheduled_plan_change(subscription_id: String) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let _guard = InFlightGuard::acquire(format!("subscription:{}", subscription_id))?;
+    let current_time = ic_cdk::api::time();
+
+    SUBSCRIPTIONS.with(|subscriptions| {
+        let mut map = subscriptions.borrow_mut();
+        let mut subscription = map.get(&subscription_id)
+            .ok_or("Subscription not found")?;
+
+        let owner = OWNER.with(|o| *o.borrow().get());
+        if caller != subscription.subscriber && caller != owner {
+            return Err("Only the subscriber or owner can change this subscription's plan".to_string());
+        }
+
+        if subscription.scheduled_plan_change.take().is_none() {
+            return Err("No plan change is scheduled".to_string());
+        }
+
+        subscription.updated_at = current_time;
+        map.insert(subscription_id, subscription);
+        Ok(())
+    })
 }
 
 // ============================================================================
-// DISCOUNT COUPON SYSTEM METHODS
+// METERED USAGE
 // ============================================================================
 
-#[ic_cdk::update]
-fn create_coupon(mut coupon: DiscountCoupon) -> Result<String, String> {
-    let caller = ic_cdk::caller();
-    let owner = OWNER.with(|o| *o.borrow().get());
-    
-    if caller != owner {
-        return Err("Only the owner can create coupons".to_string());
-    }
+fn validate_usage_pricing(pricing: &UsagePricing) -> Result<(), String> {
+    let tiers = match pricing {
+        UsagePricing::PerUnit { unit_price } => {
+            if *unit_price == 0 {
+                return Err("Usage unit price must be greater than 0".to_string());
+            }
+            return Ok(());
+        },
+        UsagePricing::Graduated(tiers) | UsagePricing::Volume(tiers) => tiers,
+    };
 
-    // Validate coupon configuration
-    if coupon.code.is_empty() {
-        return Err("Coupon code cannot be empty".to_string());
+    if tiers.is_empty() {
+        return Err("Tiered usage pricing needs at least one tier".to_string());
     }
-    if coupon.description.is_empty() {
-        return Err("Coupon description cannot be empty".to_string());
+    if tiers.last().is_some_and(|tier| tier.up_to.is_some()) {
+        return Err("The last usage tier must be unbounded".to_string());
     }
-    
-    // Validate coupon type
-    match &coupon.coupon_type {
-        CouponType::Percentage(percent) => {
-            if *percent > 100 {
-                return Err("Percentage discount cannot exceed 100%".to_string());
-            }
-        },
-        CouponType::FixedAmount(amount) => {
-            if *amount == 0 {
-                return Err("Fixed discount amount must be greater than 0".to_string());
+
+    let bounds: Vec<u64> = tiers[..tiers.len() - 1].iter()
+        .map(|tier| tier.up_to.ok_or("Only the last usage tier can be unbounded"))
+        .collect::<Result<_, _>>()?;
+    if bounds.first() == Some(&0) || bounds.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err("Usage tier bounds must be positive and strictly increasing".to_string());
+    }
+    Ok(())
+}
+
+fn usage_charge(pricing: &UsagePricing, quantity: u64) -> u64 {
+    if quantity == 0 {
+        return 0;
+    }
+
+    match pricing {
+        UsagePricing::PerUnit { unit_price } => quantity.saturating_mul(*unit_price),
+        UsagePricing::Graduated(tiers) => {
+            let mut total = 0u64;
+            let mut lower = 0u64;
+            for tier in tiers {
+                let upper = tier.up_to.unwrap_or(u64::MAX);
+                let units = quantity.min(upper).saturating_sub(lower);
+                total = total
+                    .saturating_add(units.saturating_mul(tier.unit_price))
+                    .saturating_add(tier.flat_fee);
+                if quantity <= upper {
+                    break;
+                }
+                lower = upper;
             }
+            total
         },
-        CouponType::FreeShipping => {}
+        UsagePricing::Volume(tiers) => tiers.iter()
+            .find(|tier| tier.up_to.is_none_or(|up_to| quantity <= up_to))
+            .map_or(0, |tier| quantity.saturating_mul(tier.unit_price).saturating_add(tier.flat_fee)),
     }
+}
 
-    // Check if coupon code already exists
-    let code_exists = DISCOUNT_COUPONS.with(|coupons| {
-        coupons.borrow().iter().any(|(_, existing_coupon)| {
-            existing_coupon.code.to_lowercase() == coupon.code.to_lowercase()
-        })
-    });
+fn usage_record_key(subscription_id: &str, timestamp: u64, record_id: &str) -> String {
+    format!("{}:{:020}:{}", subscription_id, timestamp, record_id)
+}
 
-    if code_exists {
-        return Err("A coupon with this code already exists".to_string());
+fn usage_idempotency_key(subscription_id: &str, idempotency_key: &str) -> String {
+    format!("{}:{}", subscription_id, idempotency_key)
+}
+
+fn store_usage_record(record: &UsageRecord) {
+    let key = usage_record_key(&record.subscription_id, record.timestamp, &record.record_id);
+    if let Some(idempotency_key) = &record.idempotency_key {
+        USAGE_IDEMPOTENCY_INDEX.with(|index| {
+            index.borrow_mut().insert(usage_idempotency_key(&record.subscription_id, idempotency_key), key.clone())
+        });
     }
+    USAGE_RECORDS.with(|records| records.borrow_mut().insert(key, record.clone()));
+}
 
-    // Generate coupon ID
-    let coupon_id = NEXT_COUPON_ID.with(|id| {
-        let current = *id.borrow().get();
-        id.borrow_mut().set(current + 1).unwrap();
-        format!("coupon_{}", current)
-    });
+fn find_usage_record(subscription_id: &str, idempotency_key: &str) -> Option<UsageRecord> {
+    let key = USAGE_IDEMPOTENCY_INDEX.with(|index| {
+        index.borrow().get(&usage_idempotency_key(subscription_id, idempotency_key))
+    })?;
+    USAGE_RECORDS.with(|records| records.borrow().get(&key))
+}
 
-    // Set coupon metadata
-    coupon.coupon_id = coupon_id.clone();
-    coupon.code = coupon.code.to_uppercase(); // Standardize to uppercase
-    coupon.used_count = 0;
-    coupon.created_at = ic_cdk::api::time();
-    coupon.updated_at = ic_cdk::api::time();
+// A subscription's records reported before `before`, oldest first
+fn subscription_usage_records(subscription_id: &str, before: u64) -> Vec<UsageRecord> {
+    USAGE_RECORDS.with(|records| {
+        records.borrow()
+            .range(format!("{}:", subscription_id)..format!("{}:{:020}", subscription_id, before))
+            .map(|(_, record)| record)
+            .collect()
+    })
+}
 
-    DISCOUNT_COUPONS.with(|coupons| {
-        coupons.borrow_mut().insert(coupon_id.clone(), coupon)
+// Records not billed yet that were reported before `before`
+fn unbilled_usage_records(subscription_id: &str, before: u64) -> Vec<UsageRecord> {
+    subscription_usage_records(subscription_id, before).into_iter()
+        .filter(|record| record.payment_id.is_none())
+        .collect()
+}
+
+// Records stored before they were keyed by subscription were keyed by record id
+fn rekey_usage_records() {
+    let legacy: Vec<(String, UsageRecord)> = USAGE_RECORDS.with(|records| {
+        records.borrow().iter()
+            .filter(|(key, record)| *key == record.record_id)
+            .collect()
     });
 
-    Ok(coupon_id)
+    for (key, record) in legacy {
+        USAGE_RECORDS.with(|records| records.borrow_mut().remove(&key));
+        store_usage_record(&record);
+    }
+}
+
+// Usage during a free trial is recorded but not charged
+fn billable_usage_quantity(records: &[UsageRecord], trial_end: Option<u64>) -> u64 {
+    records.iter()
+        .filter(|record| trial_end.is_none_or(|trial_end| record.timestamp >= trial_end))
+        .fold(0u64, |total, record| total.saturating_add(record.quantity))
+}
+
+// Each record is charged at the pricing it was recorded under, so usage from before a plan
+// change is not repriced or forgiven. Records from before pricing was kept on them use
+// `current_pricing`. Returns the billable quantity and its price, None when nothing is metered.
+fn usage_amount(
+    records: &[UsageRecord],
+    trial_end: Option<u64>,
+    current_pricing: Option<&UsagePricing>,
+) -> Option<(u64, u64)> {
+    let mut quantities: Vec<(&UsagePricing, u64)> = Vec::new();
+    for record in records.iter().filter(|record| trial_end.is_none_or(|trial_end| record.timestamp >= trial_end)) {
+        let pricing = match record.usage_pricing.as_ref().or(current_pricing) {
+            Some(pricing) => pricing,
+            None => continue,
+        };
+        match quantities.iter_mut().find(|(other, _)| *other == pricing) {
+            Some((_, quantity)) => *quantity = quantity.saturating_add(record.quantity),
+            None => quantities.push((pricing, record.quantity)),
+        }
+    }
+
+    if quantities.is_empty() && current_pricing.is_none() {
+        return None;
+    }
+    let amount = quantities.iter()
+        .fold(0u64, |total, (pricing, quantity)| total.saturating_add(usage_charge(pricing, *quantity)));
+    Some((billable_usage_quantity(records, trial_end), amount))
+}
+
+fn mark_usage_billed(records: &[UsageRecord], payment_id: &str) {
+    for record in records {
+        let mut record = record.clone();
+        record.payment_id = Some(payment_id.to_string());
+        store_usage_record(&record);
+    }
+}
+
+fn can_report_usage(caller: Principal) -> bool {
+    caller == OWNER.with(|o| *o.borrow().get()) ||
+        USAGE_REPORTERS.with(|reporters| reporters.borrow().contains_key(&caller))
 }
 
 #[ic_cdk::update]
-fn update_coupon(coupon_id: String, mut updated_coupon: DiscountCoupon) -> Result<(), String> {
+fn add_usage_reporter(reporter: Principal) -> Result<(), String> {
     let caller = ic_cdk::caller();
     let owner = OWNER.with(|o| *o.borrow().get());
-    
-    if caller != owner {
-        return Err("Only the owner can update coupons".to_string());
-    }
 
-    // Validate coupon configuration
-    if updated_coupon.code.is_empty() {
-        return Err("Coupon code cannot be empty".to_string());
-    }
-    if updated_coupon.description.is_empty() {
-        return Err("Coupon description cannot be empty".to_string());
+    if caller != owner {
+        return Err("Only the owner can manage usage reporters".to_string());
     }
-    
-    // Validate coupon type
-    match &updated_coupon.coupon_type {
-        CouponType::Percentage(percent) => {
-            if *percent > 100 {
-                return Err("Percentage discount cannot exceed 100%".to_string());
-            }
-        },
-        CouponType::FixedAmount(amount) => {
-            if *amount == 0 {
-                return Err("Fixed discount amount must be greater than 0".to_string());
-            }
-        },
-        CouponType::FreeShipping => {}
+    if reporter == Principal::anonymous() {
+        return Err("The anonymous principal cannot report usage".to_string());
     }
 
-    DISCOUNT_COUPONS.with(|coupons| {
-        let mut map = coupons.borrow_mut();
-        let existing_coupon = map.get(&coupon_id)
-            .ok_or("Coupon not found")?;
-        
-        // Check if the new code conflicts with other coupons (excluding this one)
-        let code_conflict = map.iter().any(|(id, coupon)| {
-            *id != coupon_id && coupon.code.to_lowercase() == updated_coupon.code.to_lowercase()
-        });
-        
-        if code_conflict {
-            return Err("A coupon with this code already exists".to_string());
-        }
-
-        // Preserve original metadata
-        updated_coupon.coupon_id = coupon_id.clone();
-        updated_coupon.code = updated_coupon.code.to_uppercase();
-        updated_coupon.used_count = existing_coupon.used_count; // Preserve usage count
-        updated_coupon.created_at = existing_coupon.created_at;
-        updated_coupon.updated_at = ic_cdk::api::time();
-        
-        map.insert(coupon_id, updated_coupon);
-        Ok(())
-    })
+    USAGE_REPORTERS.with(|reporters| reporters.borrow_mut().insert(reporter, ic_cdk::api::time()));
+    Ok(())
 }
 
-#[ic_cdk::query]
-fn get_coupon(coupon_id: String) -> Result<DiscountCoupon, String> {
-    DISCOUNT_COUPONS.with(|coupons| {
-        coupons.borrow().get(&coupon_id)
-            .ok_or("Coupon not found".to_string())
-    })
-}
+#[ic_cdk::update]
+fn remove_usage_reporter(reporter: Principal) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
 
-#[ic_cdk::query]
-fn get_coupon_by_code(code: String) -> Result<DiscountCoupon, String> {
-    let uppercase_code = code.to_uppercase();
-    DISCOUNT_COUPONS.with(|coupons| {
-        coupons.borrow().iter()
-            .find(|(_, coupon)| coupon.code == uppercase_code)
-            .map(|(_, coupon)| coupon)
-            .ok_or("Coupon not found".to_string())
-    })
-}
+    if caller != owner {
+        return Err("Only the owner can manage usage reporters".to_string());
+    }
 
-#[ic_cdk::query]
-fn list_my_coupons() -> Vec<DiscountCoupon> {
-    DISCOUNT_COUPONS.with(|coupons| {
-        coupons.borrow().iter().map(|(_, coupon)| coupon).collect()
-    })
+    USAGE_REPORTERS.with(|reporters| reporters.borrow_mut().remove(&reporter))
+        .map(|_| ())
+        .ok_or("Usage reporter not found".to_string())
 }
 
 #[ic_cdk::query]
-fn list_active_coupons() -> Vec<DiscountCoupon> {
-    let current_time = ic_cdk::api::time();
-    DISCOUNT_COUPONS.with(|coupons| {
-        coupons.borrow().iter()
-            .filter(|(_, coupon)| {
-                coupon.is_active && 
-                (coupon.expires_at.is_none() || coupon.expires_at.unwrap() > current_time) &&
-                (coupon.usage_limit.is_none() || coupon.used_count < coupon.usage_limit.unwrap())
-            })
-            .map(|(_, coupon)| coupon)
-            .collect()
+fn list_usage_reporters() -> Vec<Principal> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+
+    if caller != owner {
+        return vec![];
+    }
+
+    USAGE_REPORTERS.with(|reporters| {
+        reporters.borrow().iter().map(|(reporter, _)| reporter).collect()
     })
 }
 
 #[ic_cdk::update]
-fn delete_coupon(coupon_id: String) -> Result<(), String> {
+fn report_usage(
+    subscription_id: String,
+    quantity: u64,
+    idempotency_key: Option<String>,
+) -> Result<UsageRecord, String> {
     let caller = ic_cdk::caller();
-    let owner = OWNER.with(|o| *o.borrow().get());
-    
-    if caller != owner {
-        return Err("Only the owner can delete coupons".to_string());
+    if !can_report_usage(caller) {
+        return Err("Only the owner or an authorized usage reporter can report usage".to_string());
     }
 
-    DISCOUNT_COUPONS.with(|coupons| {
-        let mut map = coupons.borrow_mut();
-        if map.remove(&coupon_id).is_none() {
-            return Err("Coupon not found".to_string());
-        }
-        Ok(())
-    })?;
+    if quantity == 0 {
+        return Err("Usage quantity must be greater than 0".to_string());
+    }
 
-    // Clean up usage history for this coupon
-    COUPON_USAGE_HISTORY.with(|usage_history| {
-        let mut map = usage_history.borrow_mut();
-        let usage_ids_to_remove: Vec<String> = map.iter()
-            .filter(|(_, usage)| usage.coupon_id == coupon_id)
-            .map(|(usage_id, _)| usage_id)
-            .collect();
-        
-        for usage_id in usage_ids_to_remove {
-            map.remove(&usage_id);
+    let subscription = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().get(&subscription_id)
+    }).ok_or("Subscription not found")?;
+
+    if !matches!(subscription.status, SubscriptionStatus::Active | SubscriptionStatus::PendingPayment) {
+        return Err("Usage can only be reported for active subscriptions".to_string());
+    }
+
+    let usage_pricing = SUBSCRIPTION_PLANS.with(|plans| plans.borrow().get(&subscription.plan_id))
+        .and_then(|plan| plan.usage_pricing)
+        .ok_or("Subscription plan is not metered")?;
+
+    if let Some(key) = &idempotency_key {
+        if let Some(record) = find_usage_record(&subscription_id, key) {
+            return Ok(record);
         }
+    }
+
+    let record_id = NEXT_USAGE_RECORD_ID.with(|id| {
+        let current = *id.borrow().get();
+        id.borrow_mut().set(current + 1).unwrap();
+        format!("usage_record_{}", current)
     });
 
-    Ok(())
+    let record = UsageRecord {
+        record_id: record_id.clone(),
+        subscription_id,
+        quantity,
+        timestamp: ic_cdk::api::time(),
+        reported_by: caller,
+        idempotency_key,
+        payment_id: None,
+        usage_pricing: Some(usage_pricing),
+    };
+
+    store_usage_record(&record);
+    Ok(record)
 }
 
-#[ic_cdk::update]
-fn toggle_coupon_status(coupon_id: String) -> Result<bool, String> {
+#[ic_cdk::query]
+fn list_usage_records(subscription_id: String, unbilled_only: bool) -> Result<Vec<UsageRecord>, String> {
     let caller = ic_cdk::caller();
-    let owner = OWNER.with(|o| *o.borrow().get());
-    
-    if caller != owner {
-        return Err("Only the owner can toggle coupon status".to_string());
+    let subscription = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().get(&subscription_id)
+    }).ok_or("Subscription not found")?;
+
+    if caller != subscription.subscriber && !can_report_usage(caller) {
+        return Err("Only the subscriber, owner or a usage reporter can view usage".to_string());
     }
 
-    DISCOUNT_COUPONS.with(|coupons| {
-        let mut map = coupons.borrow_mut();
-        if let Some(mut coupon) = map.get(&coupon_id) {
-            coupon.is_active = !coupon.is_active;
-            coupon.updated_at = ic_cdk::api::time();
-            let new_status = coupon.is_active;
-            map.insert(coupon_id, coupon);
-            Ok(new_status)
-        } else {
-            Err("Coupon not found".to_string())
-        }
+    Ok(subscription_usage_records(&subscription_id, u64::MAX).into_iter()
+        .filter(|record| !unbilled_only || record.payment_id.is_none())
+        .collect())
+}
+
+#[ic_cdk::query]
+fn get_usage_summary(subscription_id: String) -> Result<UsageSummary, String> {
+    let caller = ic_cdk::caller();
+    let subscription = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().get(&subscription_id)
+    }).ok_or("Subscription not found")?;
+
+    if caller != subscription.subscriber && !can_report_usage(caller) {
+        return Err("Only the subscriber, owner or a usage reporter can view usage".to_string());
+    }
+
+    let pricing = SUBSCRIPTION_PLANS.with(|plans| plans.borrow().get(&subscription.plan_id))
+        .and_then(|plan| plan.usage_pricing);
+
+    let records = unbilled_usage_records(&subscription_id, u64::MAX);
+    let (quantity, estimated_amount) = usage_amount(&records, subscription.trial_end, pricing.as_ref())
+        .ok_or("Subscription plan is not metered")?;
+
+    Ok(UsageSummary {
+        subscription_id,
+        quantity,
+        estimated_amount,
+        billed_at: subscription.next_billing_date,
     })
 }
 
-#[ic_cdk::update]
-fn validate_and_use_coupon(
-    coupon_code: String, 
-    invoice_amount: u64, 
-    token_symbol: String
-) -> Result<(String, u64), String> {
-    let uppercase_code = coupon_code.to_uppercase();
-    let current_time = ic_cdk::api::time();
-    let caller = ic_cdk::caller();
-    
-    // Find the coupon
-    let coupon = DISCOUNT_COUPONS.with(|coupons| {
-        coupons.borrow().iter()
-            .find(|(_, coupon)| coupon.code == uppercase_code)
-            .map(|(_, coupon)| coupon)
-            .ok_or("Coupon not found".to_string())
-    })?;
+// ============================================================================
+// SUBSCRIPTION DUNNING
+// ============================================================================
 
-    // Validate coupon
-    if !coupon.is_active {
-        return Err("Coupon is not active".to_string());
-    }
+const SECONDS_TO_NANOS: u64 = 1_000_000_000;
 
-    if let Some(expires_at) = coupon.expires_at {
-        if current_time > expires_at {
-            return Err("Coupon has expired".to_string());
-        }
+fn default_dunning_policy() -> DunningPolicy {
+    DunningPolicy {
+        retry_offsets_seconds: vec![
+            24 * 60 * 60,     // 1 day
+            3 * 24 * 60 * 60, // 3 days
+            7 * 24 * 60 * 60, // 7 days
+        ],
+        max_attempts: 4,
+        grace_period_seconds: 3 * 24 * 60 * 60, // 3 days
+        final_action: DunningFinalAction::Cancel,
     }
+}
 
-    if let Some(usage_limit) = coupon.usage_limit {
-        if coupon.used_count >= usage_limit {
-            return Err("Coupon usage limit reached".to_string());
-        }
+fn validate_dunning_policy(policy: &DunningPolicy) -> Result<(), String> {
+    if policy.max_attempts == 0 {
+        return Err("Dunning policy needs at least one attempt".to_string());
+    }
+    if policy.max_attempts > 1 && policy.retry_offsets_seconds.is_empty() {
+        return Err("Dunning policy with retries needs at least one retry offset".to_string());
+    }
+    if policy.retry_offsets_seconds.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err("Dunning retry offsets must be strictly increasing".to_string());
     }
+    Ok(())
+}
 
-    if let Some(minimum_amount) = coupon.minimum_amount {
-        if invoice_amount < minimum_amount {
-            return Err(format!("Minimum purchase amount of {} required", minimum_amount));
-        }
+enum DunningStep {
+    // Try again at `retry_at`; `within_grace` tells whether service should continue meanwhile
+    Retry { retry_at: u64, within_grace: bool },
+    // Every attempt failed, apply the policy's final action
+    Exhausted(DunningFinalAction),
+}
+
+// Decide what happens after the `failures`-th consecutive failed charge for the
+// period that was due at `due_date`. Offsets beyond the configured list reuse the last one.
+fn next_dunning_step(policy: &DunningPolicy, failures: u32, due_date: u64, now: u64) -> DunningStep {
+    if failures >= policy.max_attempts {
+        return DunningStep::Exhausted(policy.final_action.clone());
     }
 
-    // Check if coupon applies to this token
-    if !coupon.applicable_tokens.is_empty() && !coupon.applicable_tokens.contains(&token_symbol) {
-        return Err("Coupon is not applicable to this token".to_string());
+    let offset_index = (failures as usize)
+        .saturating_sub(1)
+        .min(policy.retry_offsets_seconds.len().saturating_sub(1));
+    let offset_seconds = policy.retry_offsets_seconds.get(offset_index).copied().unwrap_or(0);
+
+    let retry_at = due_date.saturating_add(offset_seconds.saturating_mul(SECONDS_TO_NANOS)).max(now);
+    let grace_end = due_date.saturating_add(policy.grace_period_seconds.saturating_mul(SECONDS_TO_NANOS));
+
+    DunningStep::Retry {
+        retry_at,
+        within_grace: now < grace_end,
     }
+}
 
-    // Calculate discount
-    let discount_amount = match coupon.coupon_type {
-        CouponType::Percentage(percent) => {
-            (invoice_amount * percent as u64) / 100
+fn apply_dunning_policy(subscription: &mut Subscription, plan: &SubscriptionPlan, current_time: u64) {
+    let policy = plan.dunning_policy.clone().unwrap_or_else(default_dunning_policy);
+
+    match next_dunning_step(&policy, subscription.payment_failures, subscription.next_billing_date, current_time) {
+        DunningStep::Retry { retry_at, within_grace } => {
+            subscription.next_retry_at = Some(retry_at);
+            if !within_grace && matches!(subscription.status, SubscriptionStatus::Active) {
+                subscription.status = SubscriptionStatus::PendingPayment;
+            }
         },
-        CouponType::FixedAmount(amount) => {
-            if amount > invoice_amount {
-                invoice_amount // Cap discount at invoice amount
-            } else {
-                amount
+        DunningStep::Exhausted(final_action) => {
+            subscription.next_retry_at = None;
+            match final_action {
+                DunningFinalAction::Pause => subscription.status = SubscriptionStatus::Paused,
+                DunningFinalAction::Cancel => {
+                    subscription.status = SubscriptionStatus::Cancelled;
+                    subscription.cancelled_at = Some(current_time);
+                },
+                DunningFinalAction::Expire => subscription.status = SubscriptionStatus::Expired,
             }
         },
-        CouponType::FreeShipping => 0, // This would be handled in shipping calculation
-    };
-
-    // Update coupon usage count
-    DISCOUNT_COUPONS.with(|coupons| {
-        let mut map = coupons.borrow_mut();
-        if let Some(mut updated_coupon) = map.get(&coupon.coupon_id) {
-            updated_coupon.used_count += 1;
-            updated_coupon.updated_at = current_time;
-            map.insert(coupon.coupon_id.clone(), updated_coupon);
-        }
-    });
-
-    // Record usage
-    let usage_id = format!("usage_{}_{}", coupon.coupon_id, current_time);
-    let usage = CouponUsage {
-        usage_id: usage_id.clone(),
-        coupon_id: coupon.coupon_id.clone(),
-        user_principal: caller,
-        invoice_id: format!("pending_{}", current_time), // This would be updated with actual invoice ID
-        discount_applied: discount_amount,
-        used_at: current_time,
-    };
+    }
+}
 
-    COUPON_USAGE_HISTORY.with(|usage_history| {
-        usage_history.borrow_mut().insert(usage_id.clone(), usage)
+// Move active subscriptions with an unpaid period out of their grace period,
+// even when no retry happens to be scheduled at that moment
+fn expire_grace_periods(current_time: u64) {
+    let overdue: Vec<Subscription> = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().iter()
+            .filter(|(_, subscription)| {
+                matches!(subscription.status, SubscriptionStatus::Active) && subscription.payment_failures > 0
+            })
+            .map(|(_, subscription)| subscription)
+            .collect()
     });
 
-    Ok((coupon.coupon_id, discount_amount))
+    for mut subscription in overdue {
+        let policy = SUBSCRIPTION_PLANS.with(|plans| plans.borrow().get(&subscription.plan_id))
+            .and_then(|plan| plan.dunning_policy)
+            .unwrap_or_else(default_dunning_policy);
+
+        let grace_end = subscription.next_billing_date
+            .saturating_add(policy.grace_period_seconds.saturating_mul(SECONDS_TO_NANOS));
+
+        if current_time >= grace_end {
+            subscription.status = SubscriptionStatus::PendingPayment;
+            subscription.updated_at = current_time;
+            enqueue_subscription_event(&subscription, SubscriptionStatus::Active);
+            SUBSCRIPTIONS.with(|subscriptions| {
+                subscriptions.borrow_mut().insert(subscription.subscription_id.clone(), subscription)
+            });
+        }
+    }
 }
 
 #[ic_cdk::query]
-fn get_coupon_usage_stats(coupon_id: String) -> Result<(u32, Vec<CouponUsage>), String> {
-    let coupon = DISCOUNT_COUPONS.with(|coupons| {
-        coupons.borrow().get(&coupon_id)
-            .ok_or("Coupon not found".to_string())
-    })?;
+fn get_subscription_payment(payment_id: String) -> Result<SubscriptionPayment, String> {
+    SUBSCRIPTION_PAYMENTS.with(|payments| {
+        payments.borrow().get(&payment_id)
+            .ok_or("Subscription payment not found".to_string())
+    })
+}
 
-    let usage_history: Vec<CouponUsage> = COUPON_USAGE_HISTORY.with(|usage_history| {
-        usage_history.borrow().iter()
-            .filter(|(_, usage)| usage.coupon_id == coupon_id)
-            .map(|(_, usage)| usage)
+#[ic_cdk::query]
+fn list_subscription_payments(subscription_id: String) -> Vec<SubscriptionPayment> {
+    SUBSCRIPTION_PAYMENTS.with(|payments| {
+        payments.borrow().iter()
+            .filter(|(_, payment)| payment.subscription_id == subscription_id)
+            .map(|(_, payment)| payment)
             .collect()
-    });
-
-    Ok((coupon.used_count, usage_history))
+    })
 }
 
+// ============================================================================
+// SUBSCRIPTION ADMIN METHODS
+// ============================================================================
+
 #[ic_cdk::update]
-fn admin_clear_all_coupons() -> Result<u32, String> {
+fn admin_clear_all_subscriptions() -> Result<u32, String> {
     let caller = ic_cdk::caller();
     let owner = OWNER.with(|o| *o.borrow().get());
     
     if caller != owner {
-        return Err("Only the owner can clear all coupons".to_string());
+        return Err("Only the owner can clear all subscriptions".to_string());
     }
 
-    let count = DISCOUNT_COUPONS.with(|coupons| {
-        let coupon_ids: Vec<String> = coupons.borrow().iter().map(|(id, _)| id).collect();
-        let count = coupon_ids.len() as u32;
-        let mut map = coupons.borrow_mut();
-        for id in coupon_ids {
+    // Clear subscription plans
+    let plan_count = SUBSCRIPTION_PLANS.with(|plans| {
+        let plan_ids: Vec<String> = plans.borrow().iter().map(|(id, _)| id).collect();
+        let count = plan_ids.len() as u32;
+        let mut map = plans.borrow_mut();
+        for id in plan_ids {
             map.remove(&id);
         }
         count
     });
 
-    COUPON_USAGE_HISTORY.with(|usage_history| {
-        let usage_ids: Vec<String> = usage_history.borrow().iter().map(|(id, _)| id).collect();
-        let mut map = usage_history.borrow_mut();
-        for id in usage_ids {
+    // Clear subscriptions
+    let subscription_count = SUBSCRIPTIONS.with(|subscriptions| {
+        let subscription_ids: Vec<String> = subscriptions.borrow().iter().map(|(id, _)| id).collect();
+        let count = subscription_ids.len() as u32;
+        let mut map = subscriptions.borrow_mut();
+        for id in subscription_ids {
             map.remove(&id);
         }
+        count
     });
 
-    Ok(count)
+    // Clear subscription payments
+    SUBSCRIPTION_PAYMENTS.with(|payments| {
+        let payment_ids: Vec<String> = payments.borrow().iter().map(|(id, _)| id).collect();
+        let mut map = payments.borrow_mut();
+        for id in payment_ids {
+            map.remove(&id);
+        }
+    });
+
+    // Clear metered usage
+    USAGE_RECORDS.with(|records| {
+        let record_ids: Vec<String> = records.borrow().iter().map(|(id, _)| id).collect();
+        let mut map = records.borrow_mut();
+        for id in record_ids {
+            map.remove(&id);
+        }
+    });
+    USAGE_IDEMPOTENCY_INDEX.with(|index| {
+        let keys: Vec<String> = index.borrow().iter().map(|(key, _)| key).collect();
+        let mut map = index.borrow_mut();
+        for key in keys {
+            map.remove(&key);
+        }
+    });
+
+    Ok(plan_count + subscription_count)
 }
 
-// ============================================================================
-// SUBSCRIPTION MANAGEMENT SYSTEM METHODS
-// ============================================================================
+#[ic_cdk::query]
+fn get_subscription_stats() -> (u32, u32, u32) {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
+    
+    if caller != owner {
+        return (0, 0, 0);
+    }
+
+    let plan_count = SUBSCRIPTION_PLANS.with(|plans| {
+        plans.borrow().len() as u32
+    });
+
+    let subscription_count = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().len() as u32
+    });
+
+    let active_subscription_count = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().iter()
+            .filter(|(_, subscription)| matches!(subscription.status, SubscriptionStatus::Active))
+            .count() as u32
+    });
+
+    (plan_count, subscription_count, active_subscription_count)
+}
 
 // ============================================================================
-// SUBSCRIPTION PLAN MANAGEMENT
+// PRODUCT MANAGEMENT METHODS
 // ============================================================================
 
 #[ic_cdk::update]
-fn create_subscription_plan(mut plan: SubscriptionPlan) -> Result<String, String> {
+fn create_product(mut product: Product) -> Result<String, String> {
     let caller = ic_cdk::caller();
     let owner = OWNER.with(|o| *o.borrow().get());
     
     if caller != owner {
-        return Err("Only the owner can create subscription plans".to_string());
+        return Err("Only the owner can create products".to_string());
     }
 
-    // Validate plan configuration
-    if plan.name.is_empty() {
-        return Err("Plan name cannot be empty".to_string());
+    // Validate product configuration
+    if product.name.is_empty() {
+        return Err("Product name cannot be empty".to_string());
     }
-    if plan.description.is_empty() {
-        return Err("Plan description cannot be empty".to_string());
+    if product.description.is_empty() {
+        return Err("Product description cannot be empty".to_string());
     }
-    if plan.price == 0 {
-        return Err("Plan price must be greater than 0".to_string());
+    if product.price == 0 {
+        return Err("Product price must be greater than 0".to_string());
     }
-    if plan.token.is_empty() {
-        return Err("Plan token cannot be empty".to_string());
+    if product.token_symbol.is_empty() {
+        return Err("Product token symbol cannot be empty".to_string());
     }
 
     // Validate that the token is supported
     let config = CONFIG.with(|c| c.borrow().get().clone());
     let token_exists = config.supported_tokens
         .iter()
-        .any(|t| t.symbol == plan.token && t.is_active);
+        .any(|t| t.symbol == product.token_symbol && t.is_active);
     
     if !token_exists {
         return Err("Token not supported or inactive".to_string());
     }
+    validate_product_catalog(&product, &active_token_symbols())?;
 
-    // Generate plan ID
-    let plan_id = NEXT_SUBSCRIPTION_ID.with(|id| {
+    // Generate product ID
+    let product_id = NEXT_PRODUCT_ID.with(|id| {
         let current = *id.borrow().get();
         id.borrow_mut().set(current + 1).unwrap();
-        format!("plan_{}", current)
+        format!("product_{}", current)
     });
 
-    // Set plan metadata
-    plan.plan_id = plan_id.clone();
-    plan.created_at = ic_cdk::api::time();
-    plan.updated_at = ic_cdk::api::time();
+    // Set product metadata
+    product.product_id = product_id.clone();
+    product.created_at = ic_cdk::api::time();
+    product.updated_at = ic_cdk::api::time();
+    product.reserved_count = None;
+    for variant in product.variants.iter_mut().flatten() {
+        variant.reserved_count = None;
+    }
+    refresh_stock_status(&mut product);
 
-    SUBSCRIPTION_PLANS.with(|plans| {
-        plans.borrow_mut().insert(plan_id.clone(), plan)
+    // Store the product
+    PRODUCTS.with(|products| {
+        products.borrow_mut().insert(product_id.clone(), product)
     });
 
-    Ok(plan_id)
+    // Initialize sales stats for this product
+    let sales_stats = ProductSalesStats {
+        product_id: product_id.clone(),
+        total_sales: 0,
+        total_revenue: 0,
+        units_sold: 0,
+        last_sale_at: None,
+        revenue_by_token: None,
+        variant_stats: None,
+    };
+    
+    PRODUCT_SALES_STATS.with(|stats| {
+        stats.borrow_mut().insert(product_id.clone(), sales_stats)
+    });
+
+    Ok(product_id)
 }
 
 #[ic_cdk::update]
-fn update_subscription_plan(plan_id: String, mut updated_plan: SubscriptionPlan) -> Result<(), String> {
+fn update_product(product_id: String, mut updated_product: Product) -> Result<(), String> {
     let caller = ic_cdk::caller();
     let owner = OWNER.with(|o| *o.borrow().get());
     
     if caller != owner {
-        return Err("Only the owner can update subscription plans".to_string());
+        return Err("Only the owner can update products".to_string());
     }
 
-    // Validate plan configuration
-    if updated_plan.name.is_empty() {
-        return Err("Plan name cannot be empty".to_string());
+    // Validate product configuration
+    if updated_product.name.is_empty() {
+        return Err("Product name cannot be empty".to_string());
     }
-    if updated_plan.description.is_empty() {
-        return Err("Plan description cannot be empty".to_string());
+    if updated_product.description.is_empty() {
+        return Err("Product description cannot be empty".to_string());
     }
-    if updated_plan.price == 0 {
-        return Err("Plan price must be greater than 0".to_string());
+    if updated_product.price == 0 {
+        return Err("Product price must be greater than 0".to_string());
     }
-    if updated_plan.token.is_empty() {
-        return Err("Plan token cannot be empty".to_string());
+    if updated_product.token_symbol.is_empty() {
+        return Err("Product token symbol cannot be empty".to_string());
     }
 
     // Validate that the token is supported
     let config = CONFIG.with(|c| c.borrow().get().clone());
     let token_exists = config.supported_tokens
         .iter()
-        .any(|t| t.symbol == updated_plan.token && t.is_active);
+        .any(|t| t.symbol == updated_product.token_symbol && t.is_active);
     
     if !token_exists {
         return Err("Token not supported or inactive".to_string());
     }
+    validate_product_catalog(&updated_product, &active_token_symbols())?;
 
-    SUBSCRIPTION_PLANS.with(|plans| {
-        let mut map = plans.borrow_mut();
-        let existing_plan = map.get(&plan_id)
-            .ok_or("Subscription plan not found")?;
+    PRODUCTS.with(|products| {
+        let mut map = products.borrow_mut();
+        let existing_product = map.get(&product_id)
+            .ok_or("Product not found")?;
         
         // Preserve original metadata
-        updated_plan.plan_id = plan_id.clone();
-        updated_plan.created_at = existing_plan.created_at;
-        updated_plan.updated_at = ic_cdk::api::time();
+        updated_product.product_id = product_id.clone();
+        updated_product.created_at = existing_product.created_at;
+        updated_product.updated_at = ic_cdk::api::time();
+        // Reservation counters are owned by the canister, also for variants that are kept
+        updated_product.reserved_count = existing_product.reserved_count;
+        for variant in updated_product.variants.iter_mut().flatten() {
+            variant.reserved_count = existing_product.variants.iter().flatten()
+                .find(|existing| existing.sku == variant.sku)
+                .and_then(|existing| existing.reserved_count);
+        }
+        refresh_stock_status(&mut updated_product);
         
-        map.insert(plan_id, updated_plan);
+        map.insert(product_id, updated_product);
         Ok(())
     })
 }
 
 #[ic_cdk::query]
-fn get_subscription_plan(plan_id: String) -> Result<SubscriptionPlan, String> {
-    SUBSCRIPTION_PLANS.with(|plans| {
-        plans.borrow().get(&plan_id)
-            .ok_or("Subscription plan not found".to_string())
+fn get_product(product_id: String) -> Result<Product, String> {
+    PRODUCTS.with(|products| {
+        products.borrow().get(&product_id)
+            .ok_or("Product not found".to_string())
     })
 }
 
 #[ic_cdk::query]
-fn list_subscription_plans() -> Vec<SubscriptionPlan> {
-    SUBSCRIPTION_PLANS.with(|plans| {
-        plans.borrow().iter().map(|(_, plan)| plan).collect()
+fn list_products() -> Vec<Product> {
+    PRODUCTS.with(|products| {
+        products.borrow().iter().map(|(_, product)| product).collect()
     })
 }
 
 #[ic_cdk::query]
-fn list_active_subscription_plans() -> Vec<SubscriptionPlan> {
-    SUBSCRIPTION_PLANS.with(|plans| {
-        plans.borrow().iter()
-            .filter(|(_, plan)| plan.is_active)
-            .map(|(_, plan)| plan)
+fn list_active_products() -> Vec<Product> {
+    PRODUCTS.with(|products| {
+        products.borrow().iter()
+            .filter(|(_, product)| matches!(product.status, ProductStatus::Active))
+            .map(|(_, product)| product)
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn list_products_by_category(category: String) -> Vec<Product> {
+    PRODUCTS.with(|products| {
+        products.borrow().iter()
+            .filter(|(_, product)| {
+                product.category.as_ref().map_or(false, |cat| cat == &category)
+            })
+            .map(|(_, product)| product)
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn list_products_by_token(token_symbol: String) -> Vec<Product> {
+    PRODUCTS.with(|products| {
+        products.borrow().iter()
+            .filter(|(_, product)| resolve_price(product, None, &token_symbol).is_some())
+            .map(|(_, product)| product)
             .collect()
     })
 }
 
 #[ic_cdk::update]
-fn delete_subscription_plan(plan_id: String) -> Result<(), String> {
+fn delete_product(product_id: String) -> Result<(), String> {
     let caller = ic_cdk::caller();
     let owner = OWNER.with(|o| *o.borrow().get());
     
     if caller != owner {
-        return Err("Only the owner can delete subscription plans".to_string());
-    }
-
-    // Check if there are any active subscriptions for this plan
-    let has_active_subscriptions = SUBSCRIPTIONS.with(|subscriptions| {
-        subscriptions.borrow().iter()
-            .any(|(_, subscription)| {
-                subscription.plan_id == plan_id &&
-                matches!(subscription.status, SubscriptionStatus::Active | SubscriptionStatus::PendingPayment)
-            })
-    });
-
-    if has_active_subscriptions {
-        return Err("Cannot delete plan with active subscriptions. Cancel subscriptions first.".to_string());
+        return Err("Only the owner can delete products".to_string());
     }
 
-    SUBSCRIPTION_PLANS.with(|plans| {
-        let mut map = plans.borrow_mut();
-        if map.remove(&plan_id).is_none() {
-            return Err("Subscription plan not found".to_string());
+    PRODUCTS.with(|products| {
+        let mut map = products.borrow_mut();
+        if map.remove(&product_id).is_none() {
+            return Err("Product not found".to_string());
         }
         Ok(())
-    })
+    })?;
+
+    // Also remove sales statistics
+    PRODUCT_SALES_STATS.with(|stats| {
+        stats.borrow_mut().remove(&product_id)
+    });
+
+    Ok(())
 }
 
 #[ic_cdk::update]
-fn toggle_subscription_plan_status(plan_id: String) -> Result<bool, String> {
+fn toggle_product_status(product_id: String) -> Result<ProductStatus, String> {
     let caller = ic_cdk::caller();
     let owner = OWNER.with(|o| *o.borrow().get());
     
     if caller != owner {
-        return Err("Only the owner can toggle plan status".to_string());
+        return Err("Only the owner can toggle product status".to_string());
     }
 
-    SUBSCRIPTION_PLANS.with(|plans| {
-        let mut map = plans.borrow_mut();
-        if let Some(mut plan) = map.get(&plan_id) {
-            plan.is_active = !plan.is_active;
-            plan.updated_at = ic_cdk::api::time();
-            let new_status = plan.is_active;
-            map.insert(plan_id, plan);
+    PRODUCTS.with(|products| {
+        let mut map = products.borrow_mut();
+        if let Some(mut product) = map.get(&product_id) {
+            product.status = match product.status {
+                ProductStatus::Active => ProductStatus::Inactive,
+                ProductStatus::Inactive => ProductStatus::Active,
+                ProductStatus::OutOfStock => ProductStatus::Active,
+            };
+            product.updated_at = ic_cdk::api::time();
+            let new_status = product.status.clone();
+            map.insert(product_id, product);
             Ok(new_status)
         } else {
-            Err("Subscription plan not found".to_string())
+            Err("Product not found".to_string())
         }
     })
 }
 
-// ============================================================================
-// SUBSCRIPTION MANAGEMENT
-// ============================================================================
-
-fn calculate_next_billing_date(current_time: u64, billing_interval: &BillingInterval) -> u64 {
-    match billing_interval {
-        BillingInterval::Daily => current_time + (24 * 60 * 60 * 1_000_000_000),
-        BillingInterval::Weekly => current_time + (7 * 24 * 60 * 60 * 1_000_000_000),
-        BillingInterval::Monthly => current_time + (30 * 24 * 60 * 60 * 1_000_000_000), // Approximate
-        BillingInterval::Quarterly => current_time + (90 * 24 * 60 * 60 * 1_000_000_000), // Approximate
-        BillingInterval::Yearly => current_time + (365 * 24 * 60 * 60 * 1_000_000_000), // Approximate
-        BillingInterval::Custom(seconds) => current_time + (seconds * 1_000_000_000),
-    }
-}
-
 #[ic_cdk::update]
-fn create_subscription(plan_id: String, metadata: Vec<(String, String)>) -> Result<String, String> {
+fn update_product_inventory(product_id: String, inventory_count: Option<u32>) -> Result<(), String> {
     let caller = ic_cdk::caller();
-    let current_time = ic_cdk::api::time();
+    let owner = OWNER.with(|o| *o.borrow().get());
     
-    // Get the subscription plan
-    let plan = SUBSCRIPTION_PLANS.with(|plans| {
-        plans.borrow().get(&plan_id)
-    }).ok_or("Subscription plan not found")?;
-
-    if !plan.is_active {
-        return Err("Subscription plan is not active".to_string());
-    }
-
-    // Check if plan has subscription limits
-    if let Some(max_subscriptions) = plan.max_subscriptions {
-        let current_active_count = SUBSCRIPTIONS.with(|subscriptions| {
-            subscriptions.borrow().iter()
-                .filter(|(_, subscription)| {
-                    subscription.plan_id == plan_id &&
-                    matches!(subscription.status, SubscriptionStatus::Active | SubscriptionStatus::PendingPayment)
-                })
-                .count() as u32
-        });
-
-        if current_active_count >= max_subscriptions {
-            return Err("Subscription plan has reached maximum number of subscriptions".to_string());
-        }
-    }
-
-    // Check if user already has an active subscription to this plan
-    let has_existing_subscription = SUBSCRIPTIONS.with(|subscriptions| {
-        subscriptions.borrow().iter()
-            .any(|(_, subscription)| {
-                subscription.subscriber == caller &&
-                subscription.plan_id == plan_id &&
-                matches!(subscription.status, SubscriptionStatus::Active | SubscriptionStatus::PendingPayment)
-            })
-    });
-
-    if has_existing_subscription {
-        return Err("User already has an active subscription to this plan".to_string());
+    if caller != owner {
+        return Err("Only the owner can update product inventory".to_string());
     }
 
-    // Generate subscription ID
-    let subscription_id = NEXT_SUBSCRIPTION_ID.with(|id| {
-        let current = *id.borrow().get();
-        id.borrow_mut().set(current + 1).unwrap();
-        format!("sub_{}", current)
-    });
-
-    // Calculate billing dates
-    let trial_end = plan.trial_period_days.map(|days| {
-        current_time + (days as u64 * 24 * 60 * 60 * 1_000_000_000)
-    });
-
-    let next_billing_date = if trial_end.is_some() {
-        trial_end.unwrap()
-    } else {
-        calculate_next_billing_date(current_time, &plan.billing_interval)
-    };
-
-    let current_period_end = calculate_next_billing_date(current_time, &plan.billing_interval);
-
-    let subscription = Subscription {
-        subscription_id: subscription_id.clone(),
-        plan_id: plan_id.clone(),
-        subscriber: caller,
-        status: if trial_end.is_some() {
-            SubscriptionStatus::Active // Free trial is active
+    PRODUCTS.with(|products| {
+        let mut map = products.borrow_mut();
+        if let Some(mut product) = map.get(&product_id) {
+            product.inventory_count = inventory_count;
+            product.updated_at = ic_cdk::api::time();
+            
+            // Update status based on the stock left after reservations
+            refresh_stock_status(&mut product);
+            
+            map.insert(product_id, product);
+            Ok(())
         } else {
-            SubscriptionStatus::PendingPayment // Needs initial payment
-        },
-        current_period_start: current_time,
-        current_period_end,
-        next_billing_date,
-        trial_end,
-        cancelled_at: None,
-        cancel_at_period_end: false,
-        total_payments: 0,
-        payment_failures: 0,
-        metadata,
-        created_at: current_time,
-        updated_at: current_time,
-    };
-
-    SUBSCRIPTIONS.with(|subscriptions| {
-        subscriptions.borrow_mut().insert(subscription_id.clone(), subscription)
-    });
-
-    Ok(subscription_id)
-}
-
-#[ic_cdk::query]
-fn get_subscription(subscription_id: String) -> Result<Subscription, String> {
-    SUBSCRIPTIONS.with(|subscriptions| {
-        subscriptions.borrow().get(&subscription_id)
-            .ok_or("Subscription not found".to_string())
-    })
-}
-
-#[ic_cdk::query]
-fn list_user_subscriptions(user: Principal) -> Vec<Subscription> {
-    SUBSCRIPTIONS.with(|subscriptions| {
-        subscriptions.borrow().iter()
-            .filter(|(_, subscription)| subscription.subscriber == user)
-            .map(|(_, subscription)| subscription)
-            .collect()
-    })
-}
-
-#[ic_cdk::query]
-fn list_my_subscriptions() -> Vec<Subscription> {
-    let caller = ic_cdk::caller();
-    SUBSCRIPTIONS.with(|subscriptions| {
-        subscriptions.borrow().iter()
-            .filter(|(_, subscription)| subscription.subscriber == caller)
-            .map(|(_, subscription)| subscription)
-            .collect()
+            Err("Product not found".to_string())
+        }
     })
 }
-
-#[ic_cdk::query]
-fn list_subscriptions_by_plan(plan_id: String) -> Vec<Subscription> {
-    SUBSCRIPTIONS.with(|subscriptions| {
-        subscriptions.borrow().iter()
-            .filter(|(_, subscription)| subscription.plan_id == plan_id)
-            .map(|(_, subscription)| subscription)
-            .collect()
+
+#[ic_cdk::query]
+fn get_product_sales_stats(product_id: String) -> Result<ProductSalesStats, String> {
+    PRODUCT_SALES_STATS.with(|stats| {
+        stats.borrow().get(&product_id)
+            .ok_or("Product sales statistics not found".to_string())
     })
 }
 
 #[ic_cdk::query]
-fn list_all_subscriptions() -> Vec<Subscription> {
+fn list_all_product_sales_stats() -> Vec<ProductSalesStats> {
     let caller = ic_cdk::caller();
     let owner = OWNER.with(|o| *o.borrow().get());
     
@@ -2389,612 +9346,870 @@ fn list_all_subscriptions() -> Vec<Subscription> {
         return vec![];
     }
 
-    SUBSCRIPTIONS.with(|subscriptions| {
-        subscriptions.borrow().iter().map(|(_, subscription)| subscription).collect()
+    PRODUCT_SALES_STATS.with(|stats| {
+        stats.borrow().iter().map(|(_, stat)| stat).collect()
     })
 }
 
-#[ic_cdk::update]
-fn cancel_subscription(subscription_id: String, cancel_immediately: bool) -> Result<(), String> {
-    let caller = ic_cdk::caller();
-    let current_time = ic_cdk::api::time();
-    
-    SUBSCRIPTIONS.with(|subscriptions| {
-        let mut map = subscriptions.borrow_mut();
-        let mut subscription = map.get(&subscription_id)
-            .ok_or("Subscription not found")?;
+#[ic_cdk::query]
+fn get_product_categories() -> Vec<String> {
+    PRODUCTS.with(|products| {
+        let mut categories: std::collections::HashSet<String> = std::collections::HashSet::new();
         
-        // Check authorization - only subscriber or owner can cancel
-        let owner = OWNER.with(|o| *o.borrow().get());
-        if caller != subscription.subscriber && caller != owner {
-            return Err("Only the subscriber or owner can cancel this subscription".to_string());
-        }
-
-        // Check if already cancelled
-        if matches!(subscription.status, SubscriptionStatus::Cancelled) {
-            return Err("Subscription is already cancelled".to_string());
-        }
-
-        if cancel_immediately {
-            subscription.status = SubscriptionStatus::Cancelled;
-            subscription.cancelled_at = Some(current_time);
-        } else {
-            // Cancel at end of current period
-            subscription.cancel_at_period_end = true;
+        for (_, product) in products.borrow().iter() {
+            if let Some(category) = &product.category {
+                categories.insert(category.clone());
+            }
         }
         
-        subscription.updated_at = current_time;
-        map.insert(subscription_id, subscription);
-        Ok(())
+        categories.into_iter().collect()
     })
 }
 
-#[ic_cdk::update]
-fn pause_subscription(subscription_id: String) -> Result<(), String> {
-    let caller = ic_cdk::caller();
+// Helper function to update product sales stats
+fn update_product_sales_stats(
+    product_id: &str,
+    variant_sku: Option<&str>,
+    token_symbol: &str,
+    sale_amount: u64,
+    units: u32,
+) {
     let current_time = ic_cdk::api::time();
+    let product_id_string = product_id.to_string();
+    let in_product_token = is_product_token(product_id, token_symbol);
     
-    SUBSCRIPTIONS.with(|subscriptions| {
-        let mut map = subscriptions.borrow_mut();
-        let mut subscription = map.get(&subscription_id)
-            .ok_or("Subscription not found")?;
-        
-        // Check authorization - only subscriber or owner can pause
-        let owner = OWNER.with(|o| *o.borrow().get());
-        if caller != subscription.subscriber && caller != owner {
-            return Err("Only the subscriber or owner can pause this subscription".to_string());
+    PRODUCT_SALES_STATS.with(|stats| {
+        let mut map = stats.borrow_mut();
+        if let Some(mut product_stats) = map.get(&product_id_string) {
+            product_stats.total_sales += 1;
+            if in_product_token {
+                product_stats.total_revenue += sale_amount;
+            }
+            product_stats.units_sold += units;
+            product_stats.last_sale_at = Some(current_time);
+            add_token_amount(product_stats.revenue_by_token.get_or_insert_with(Vec::new), token_symbol, sale_amount);
+
+            if let Some(sku) = variant_sku {
+                let variant_stats = product_stats.variant_stats.get_or_insert_with(Vec::new);
+                let index = match variant_stats.iter().position(|stats| stats.variant_sku == sku) {
+                    Some(index) => index,
+                    None => {
+                        variant_stats.push(VariantSalesStats {
+                            variant_sku: sku.to_string(),
+                            total_sales: 0,
+                            units_sold: 0,
+                            revenue_by_token: vec![],
+                        });
+                        variant_stats.len() - 1
+                    },
+                };
+                let entry = &mut variant_stats[index];
+                entry.total_sales += 1;
+                entry.units_sold += units;
+                add_token_amount(&mut entry.revenue_by_token, token_symbol, sale_amount);
+            }
+            
+            map.insert(product_id_string, product_stats);
         }
+    });
+}
 
-        // Check if can be paused
-        if !matches!(subscription.status, SubscriptionStatus::Active) {
-            return Err("Only active subscriptions can be paused".to_string());
+// Whether amounts in this token count towards the product's `total_revenue`
+fn is_product_token(product_id: &str, token_symbol: &str) -> bool {
+    PRODUCTS.with(|products| products.borrow().get(&product_id.to_string()))
+        .is_some_and(|product| product.token_symbol == token_symbol)
+}
+
+fn subtract_token_amount(totals: &mut [(String, u64)], token_symbol: &str, amount: u64) {
+    if let Some((_, total)) = totals.iter_mut().find(|(symbol, _)| symbol == token_symbol) {
+        *total = total.saturating_sub(amount);
+    }
+}
+
+// Helper function to undo sales stats for a refunded product payment
+fn reverse_product_sales_stats(
+    product_id: &str,
+    variant_sku: Option<&str>,
+    token_symbol: &str,
+    refund_amount: u64,
+    units: u32,
+    is_full_refund: bool,
+) {
+    let product_id_string = product_id.to_string();
+    let in_product_token = is_product_token(product_id, token_symbol);
+
+    PRODUCT_SALES_STATS.with(|stats| {
+        let mut map = stats.borrow_mut();
+        if let Some(mut product_stats) = map.get(&product_id_string) {
+            if in_product_token {
+                product_stats.total_revenue = product_stats.total_revenue.saturating_sub(refund_amount);
+            }
+            if let Some(revenue_by_token) = product_stats.revenue_by_token.as_mut() {
+                subtract_token_amount(revenue_by_token, token_symbol, refund_amount);
+            }
+            if is_full_refund {
+                product_stats.total_sales = product_stats.total_sales.saturating_sub(1);
+                product_stats.units_sold = product_stats.units_sold.saturating_sub(units);
+            }
+
+            let variant_entry = variant_sku.and_then(|sku| {
+                product_stats.variant_stats.as_mut()?.iter_mut().find(|stats| stats.variant_sku == sku)
+            });
+            if let Some(entry) = variant_entry {
+                subtract_token_amount(&mut entry.revenue_by_token, token_symbol, refund_amount);
+                if is_full_refund {
+                    entry.total_sales = entry.total_sales.saturating_sub(1);
+                    entry.units_sold = entry.units_sold.saturating_sub(units);
+                }
+            }
+
+            map.insert(product_id_string, product_stats);
         }
+    });
+}
 
-        subscription.status = SubscriptionStatus::Paused;
-        subscription.updated_at = current_time;
-        map.insert(subscription_id, subscription);
-        Ok(())
-    })
+// ============================================================================
+// PRODUCT VARIANTS AND PRICING
+// ============================================================================
+
+// Price of a product or one of its variants in the given token. Variant prices
+// replace the product's prices entirely when set.
+fn resolve_price(product: &Product, variant: Option<&ProductVariant>, token_symbol: &str) -> Option<u64> {
+    if let Some(variant) = variant {
+        if !variant.prices.is_empty() {
+            return variant.prices.iter()
+                .find(|price| price.token_symbol == token_symbol)
+                .map(|price| price.amount);
+        }
+    }
+
+    if product.token_symbol == token_symbol {
+        return Some(product.price);
+    }
+    product.prices.as_ref()?.iter()
+        .find(|price| price.token_symbol == token_symbol)
+        .map(|price| price.amount)
 }
 
-#[ic_cdk::update]
-fn resume_subscription(subscription_id: String) -> Result<(), String> {
-    let caller = ic_cdk::caller();
-    let current_time = ic_cdk::api::time();
-    
-    SUBSCRIPTIONS.with(|subscriptions| {
-        let mut map = subscriptions.borrow_mut();
-        let mut subscription = map.get(&subscription_id)
-            .ok_or("Subscription not found")?;
-        
-        // Check authorization - only subscriber or owner can resume
-        let owner = OWNER.with(|o| *o.borrow().get());
-        if caller != subscription.subscriber && caller != owner {
-            return Err("Only the subscriber or owner can resume this subscription".to_string());
+// Products with variants can only be bought as one of them
+fn find_variant<'a>(product: &'a Product, sku: Option<&str>) -> Result<Option<&'a ProductVariant>, String> {
+    let variants = product.variants.as_ref().filter(|variants| !variants.is_empty());
+    match (variants, sku) {
+        (None, None) => Ok(None),
+        (None, Some(_)) => Err(format!("Product {} has no variants", product.name)),
+        (Some(_), None) => Err(format!("Choose a variant of {}", product.name)),
+        (Some(variants), Some(sku)) => variants.iter()
+            .find(|variant| variant.sku == sku)
+            .map(Some)
+            .ok_or_else(|| format!("Variant {} of {} not found", sku, product.name)),
+    }
+}
+
+fn validate_token_prices(prices: &[TokenPrice], active_tokens: &[String]) -> Result<(), String> {
+    for (index, price) in prices.iter().enumerate() {
+        if price.amount == 0 {
+            return Err(format!("Price in {} must be greater than 0", price.token_symbol));
+        }
+        if !active_tokens.contains(&price.token_symbol) {
+            return Err(format!("Token {} not supported or inactive", price.token_symbol));
         }
+        if prices[..index].iter().any(|other| other.token_symbol == price.token_symbol) {
+            return Err(format!("Duplicate price for {}", price.token_symbol));
+        }
+    }
+    Ok(())
+}
 
-        // Check if can be resumed
-        if !matches!(subscription.status, SubscriptionStatus::Paused) {
-            return Err("Only paused subscriptions can be resumed".to_string());
+fn validate_product_catalog(product: &Product, active_tokens: &[String]) -> Result<(), String> {
+    if let Some(prices) = &product.prices {
+        validate_token_prices(prices, active_tokens)?;
+        if prices.iter().any(|price| price.token_symbol == product.token_symbol) {
+            return Err(format!("{} is already priced by `price`", product.token_symbol));
+        }
+    }
+
+    if let Some(variants) = &product.variants {
+        for (index, variant) in variants.iter().enumerate() {
+            if variant.sku.is_empty() || variant.name.is_empty() {
+                return Err("Variant SKU and name cannot be empty".to_string());
+            }
+            if variants[..index].iter().any(|other| other.sku == variant.sku) {
+                return Err(format!("Duplicate variant SKU {}", variant.sku));
+            }
+            validate_token_prices(&variant.prices, active_tokens)?;
         }
+    }
+    Ok(())
+}
 
-        subscription.status = SubscriptionStatus::Active;
-        subscription.updated_at = current_time;
-        map.insert(subscription_id, subscription);
-        Ok(())
+fn add_token_amount(totals: &mut Vec<(String, u64)>, token_symbol: &str, amount: u64) {
+    match totals.iter_mut().find(|(symbol, _)| symbol == token_symbol) {
+        Some((_, total)) => *total = total.saturating_add(amount),
+        None => totals.push((token_symbol.to_string(), amount)),
+    }
+}
+
+fn active_token_symbols() -> Vec<String> {
+    CONFIG.with(|c| {
+        c.borrow().get().supported_tokens.iter()
+            .filter(|t| t.is_active)
+            .map(|t| t.symbol.clone())
+            .collect()
     })
 }
 
 #[ic_cdk::update]
-fn update_subscription_metadata(subscription_id: String, metadata: Vec<(String, String)>) -> Result<(), String> {
+fn update_variant_inventory(product_id: String, variant_sku: String, inventory_count: Option<u32>) -> Result<(), String> {
     let caller = ic_cdk::caller();
-    let current_time = ic_cdk::api::time();
+    let owner = OWNER.with(|o| *o.borrow().get());
     
-    SUBSCRIPTIONS.with(|subscriptions| {
-        let mut map = subscriptions.borrow_mut();
-        let mut subscription = map.get(&subscription_id)
-            .ok_or("Subscription not found")?;
-        
-        // Check authorization - only subscriber or owner can update
-        let owner = OWNER.with(|o| *o.borrow().get());
-        if caller != subscription.subscriber && caller != owner {
-            return Err("Only the subscriber or owner can update this subscription".to_string());
-        }
+    if caller != owner {
+        return Err("Only the owner can update product inventory".to_string());
+    }
 
-        subscription.metadata = metadata;
-        subscription.updated_at = current_time;
-        map.insert(subscription_id, subscription);
-        Ok(())
-    })
+    let product = PRODUCTS.with(|products| products.borrow().get(&product_id))
+        .ok_or("Product not found")?;
+    find_variant(&product, Some(variant_sku.as_str()))?;
+
+    adjust_product_stock(&product_id, Some(variant_sku.as_str()), |inventory, _| {
+        *inventory = inventory_count;
+    });
+    Ok(())
 }
 
 // ============================================================================
-// SUBSCRIPTION PAYMENT PROCESSING
+// INVENTORY RESERVATIONS
 // ============================================================================
 
-#[ic_cdk::update]
-fn process_subscription_payment(subscription_id: String) -> Result<String, String> {
-    let _caller = ic_cdk::caller();
-    let current_time = ic_cdk::api::time();
-    
-    // Get subscription
-    let mut subscription = SUBSCRIPTIONS.with(|subscriptions| {
-        subscriptions.borrow().get(&subscription_id)
-    }).ok_or("Subscription not found")?;
+// Units that can still be sold, None for products without inventory tracking
+fn available_inventory(product: &Product) -> Option<u32> {
+    product.inventory_count
+        .map(|count| count.saturating_sub(product.reserved_count.unwrap_or(0)))
+}
 
-    // Get plan
-    let plan = SUBSCRIPTION_PLANS.with(|plans| {
-        plans.borrow().get(&subscription.plan_id)
-    }).ok_or("Subscription plan not found")?;
+// Stock of the variant when one is chosen, otherwise of the product itself
+fn stock_available(product: &Product, variant: Option<&ProductVariant>) -> Option<u32> {
+    match variant {
+        Some(variant) => variant.inventory_count
+            .map(|count| count.saturating_sub(variant.reserved_count.unwrap_or(0))),
+        None => available_inventory(product),
+    }
+}
 
-    // Check if payment is due
-    if current_time < subscription.next_billing_date {
-        return Err("Payment is not yet due".to_string());
+// Flips between Active and OutOfStock as stock changes, Inactive is left to the owner
+fn apply_stock_status(status: &mut ProductStatus, available: Option<u32>) {
+    match *status {
+        ProductStatus::Active if available == Some(0) => *status = ProductStatus::OutOfStock,
+        ProductStatus::OutOfStock if available != Some(0) => *status = ProductStatus::Active,
+        _ => {},
     }
+}
 
-    // Generate payment ID
-    let payment_id = format!("pay_{}_{}", subscription_id, current_time);
-    
-    // Create subscription payment record
-    let subscription_payment = SubscriptionPayment {
-        payment_id: payment_id.clone(),
-        subscription_id: subscription_id.clone(),
-        amount: plan.price,
-        token: plan.token.clone(),
-        billing_period_start: subscription.current_period_start,
-        billing_period_end: subscription.current_period_end,
-        payment_date: current_time,
-        status: "pending".to_string(),
-        transaction_id: None,
-        failure_reason: None,
+fn refresh_stock_status(product: &mut Product) {
+    if let Some(variants) = product.variants.as_mut() {
+        for variant in variants.iter_mut() {
+            let available = variant.inventory_count
+                .map(|count| count.saturating_sub(variant.reserved_count.unwrap_or(0)));
+            apply_stock_status(&mut variant.status, available);
+        }
+    }
+
+    // A product with variants is out of stock once none of its variants can be sold
+    let available = match product.variants.as_ref().filter(|variants| !variants.is_empty()) {
+        Some(variants) if variants.iter().any(|v| matches!(v.status, ProductStatus::Active)) => None,
+        Some(_) => Some(0),
+        None => available_inventory(product),
     };
+    apply_stock_status(&mut product.status, available);
+}
 
-    // In a real implementation, you would integrate with actual payment processing here
-    // For this demo, we'll simulate successful payment
-    let mut updated_payment = subscription_payment.clone();
-    updated_payment.status = "paid".to_string();
-    
-    // Update subscription
-    subscription.status = SubscriptionStatus::Active;
-    subscription.current_period_start = subscription.current_period_end;
-    subscription.current_period_end = calculate_next_billing_date(subscription.current_period_end, &plan.billing_interval);
-    subscription.next_billing_date = subscription.current_period_end;
-    subscription.total_payments += plan.price;
-    subscription.payment_failures = 0; // Reset failure count on successful payment
-    subscription.updated_at = current_time;
-    
-    // If subscription was set to cancel at period end, cancel it now
-    if subscription.cancel_at_period_end {
-        subscription.status = SubscriptionStatus::Cancelled;
-        subscription.cancelled_at = Some(current_time);
+// Every unit of a paid invoice is delivered in the same call that records the payment,
+// so the units of catalog products per invoice are capped
+const MAX_PRODUCT_UNITS_PER_INVOICE: u32 = 100;
+
+fn check_invoice_units(line_items: &[LineItem]) -> Result<(), String> {
+    let units = inventory_items(line_items).iter()
+        .fold(0u32, |total, item| total.saturating_add(item.quantity));
+    if units > MAX_PRODUCT_UNITS_PER_INVOICE {
+        return Err(format!("An invoice can hold at most {} product units", MAX_PRODUCT_UNITS_PER_INVOICE));
     }
+    Ok(())
+}
 
-    // Store updated subscription
-    SUBSCRIPTIONS.with(|subscriptions| {
-        subscriptions.borrow_mut().insert(subscription_id.clone(), subscription)
-    });
+// Quantities per product and variant, repeated lines are added up
+fn inventory_items(line_items: &[LineItem]) -> Vec<ReservedItem> {
+    let mut items: Vec<ReservedItem> = Vec::new();
+    for line in line_items {
+        if let Some(product_id) = &line.product_id {
+            match items.iter_mut().find(|item| item.product_id == *product_id && item.variant_sku == line.variant_sku) {
+                Some(item) => item.quantity = item.quantity.saturating_add(line.quantity),
+                None => items.push(ReservedItem {
+                    product_id: product_id.clone(),
+                    variant_sku: line.variant_sku.clone(),
+                    quantity: line.quantity,
+                }),
+            }
+        }
+    }
+    items
+}
 
-    // Store payment record
-    SUBSCRIPTION_PAYMENTS.with(|payments| {
-        payments.borrow_mut().insert(payment_id.clone(), updated_payment)
+// Applies `update` to the (inventory_count, reserved_count) pair of the product or its variant
+fn adjust_product_stock(
+    product_id: &str,
+    variant_sku: Option<&str>,
+    update: impl FnOnce(&mut Option<u32>, &mut Option<u32>),
+) {
+    PRODUCTS.with(|products| {
+        let mut map = products.borrow_mut();
+        if let Some(mut product) = map.get(&product_id.to_string()) {
+            match variant_sku {
+                Some(sku) => {
+                    let variant = product.variants.as_mut()
+                        .and_then(|variants| variants.iter_mut().find(|variant| variant.sku == sku));
+                    match variant {
+                        Some(variant) => update(&mut variant.inventory_count, &mut variant.reserved_count),
+                        None => return,
+                    }
+                },
+                None => update(&mut product.inventory_count, &mut product.reserved_count),
+            }
+            refresh_stock_status(&mut product);
+            product.updated_at = ic_cdk::api::time();
+            map.insert(product_id.to_string(), product);
+        }
     });
+}
 
-    Ok(payment_id)
+fn item_stock_available(item: &ReservedItem) -> Result<Option<u32>, String> {
+    let product = PRODUCTS.with(|products| products.borrow().get(&item.product_id))
+        .ok_or_else(|| format!("Product {} not found", item.product_id))?;
+    let variant = find_variant(&product, item.variant_sku.as_deref())?;
+    Ok(stock_available(&product, variant))
 }
 
-#[ic_cdk::query]
-fn get_subscription_payment(payment_id: String) -> Result<SubscriptionPayment, String> {
-    SUBSCRIPTION_PAYMENTS.with(|payments| {
-        payments.borrow().get(&payment_id)
-            .ok_or("Subscription payment not found".to_string())
+const INVENTORY_HOLD_NANOS: u64 = 15 * 60 * 1_000_000_000; // 15 minutes
+const MAX_HELD_UNITS_PER_CALLER: u32 = 20;
+
+// Units of tracked stock currently held for a principal's unpaid invoices
+fn units_held_by(holder: Principal, current_time: u64) -> u32 {
+    INVENTORY_RESERVATIONS.with(|reservations| {
+        reservations.borrow().iter()
+            .filter(|(_, reservation)| {
+                reservation.holder == Some(holder) &&
+                    reservation.expires_at.is_none_or(|expires_at| current_time < expires_at)
+            })
+            .flat_map(|(_, reservation)| reservation.items)
+            .fold(0u32, |total, item| total.saturating_add(item.quantity))
     })
 }
 
-#[ic_cdk::query]
-fn list_subscription_payments(subscription_id: String) -> Vec<SubscriptionPayment> {
-    SUBSCRIPTION_PAYMENTS.with(|payments| {
-        payments.borrow().iter()
-            .filter(|(_, payment)| payment.subscription_id == subscription_id)
-            .map(|(_, payment)| payment)
-            .collect()
-    })
+// Holds stock for an unpaid invoice. Either every product line is reserved or none is.
+// Holds are short and capped per holder so nobody can take the stock off sale.
+fn reserve_inventory(
+    invoice_id: &str,
+    requested: Vec<ReservedItem>,
+    holder: Principal,
+    current_time: u64,
+) -> Result<(), String> {
+    let mut items = Vec::new();
+    for item in requested {
+        match item_stock_available(&item)? {
+            Some(available) if available < item.quantity => {
+                return Err(format!(
+                    "Insufficient inventory for {}. Available: {}, Requested: {}",
+                    item.product_id, available, item.quantity
+                ));
+            },
+            Some(_) => items.push(item),
+            None => {}, // Untracked stock needs no reservation
+        }
+    }
+
+    if items.is_empty() {
+        return Ok(());
+    }
+
+    let owner = OWNER.with(|o| *o.borrow().get());
+    if holder != owner {
+        let requested_units = items.iter().fold(0u32, |total, item| total.saturating_add(item.quantity));
+        if units_held_by(holder, current_time).saturating_add(requested_units) > MAX_HELD_UNITS_PER_CALLER {
+            return Err(format!(
+                "At most {} units can be held at once, pay or cancel open invoices first",
+                MAX_HELD_UNITS_PER_CALLER
+            ));
+        }
+    }
+
+    for item in &items {
+        adjust_product_stock(&item.product_id, item.variant_sku.as_deref(), |_, reserved| {
+            *reserved = Some(reserved.unwrap_or(0) + item.quantity);
+        });
+    }
+
+    let reservation = InventoryReservation {
+        invoice_id: invoice_id.to_string(),
+        items,
+        created_at: current_time,
+        holder: Some(holder),
+        expires_at: Some(current_time + INVENTORY_HOLD_NANOS),
+    };
+    INVENTORY_RESERVATIONS.with(|reservations| {
+        reservations.borrow_mut().insert(invoice_id.to_string(), reservation)
+    });
+    Ok(())
 }
 
-// ============================================================================
-// SUBSCRIPTION ADMIN METHODS
-// ============================================================================
+// Products sold by an invoice. Only priced line items count, metadata is written by the caller.
+fn invoice_product_items(invoice: &PaymentInvoice) -> Vec<ReservedItem> {
+    invoice.line_items.as_deref().map(inventory_items).unwrap_or_default()
+}
 
-#[ic_cdk::update]
-fn admin_clear_all_subscriptions() -> Result<u32, String> {
-    let caller = ic_cdk::caller();
-    let owner = OWNER.with(|o| *o.borrow().get());
-    
-    if caller != owner {
-        return Err("Only the owner can clear all subscriptions".to_string());
+// Turns the held units of a paid invoice into a stock decrement. Invoices created
+// before reservations existed decrement stock for their line items directly.
+fn commit_inventory(invoice: &PaymentInvoice) {
+    let reservation = INVENTORY_RESERVATIONS.with(|reservations| reservations.borrow_mut().remove(&invoice.id));
+
+    match reservation {
+        Some(reservation) => {
+            for item in reservation.items {
+                adjust_product_stock(&item.product_id, item.variant_sku.as_deref(), |inventory, reserved| {
+                    *reserved = Some(reserved.unwrap_or(0).saturating_sub(item.quantity));
+                    *inventory = inventory.map(|count| count.saturating_sub(item.quantity));
+                });
+            }
+        },
+        None => {
+            for item in invoice_product_items(invoice) {
+                adjust_product_stock(&item.product_id, item.variant_sku.as_deref(), |inventory, _| {
+                    *inventory = inventory.map(|count| count.saturating_sub(item.quantity));
+                });
+            }
+        },
     }
+}
 
-    // Clear subscription plans
-    let plan_count = SUBSCRIPTION_PLANS.with(|plans| {
-        let plan_ids: Vec<String> = plans.borrow().iter().map(|(id, _)| id).collect();
-        let count = plan_ids.len() as u32;
-        let mut map = plans.borrow_mut();
-        for id in plan_ids {
-            map.remove(&id);
-        }
-        count
-    });
+// Renew the hold of an invoice about to be paid, or take a new one if it ran out
+fn hold_invoice_inventory(invoice: &PaymentInvoice, holder: Principal, current_time: u64) -> Result<(), String> {
+    let existing = INVENTORY_RESERVATIONS.with(|reservations| reservations.borrow().get(&invoice.id));
+    match existing {
+        Some(mut reservation) => {
+            reservation.expires_at = Some(current_time + INVENTORY_HOLD_NANOS);
+            INVENTORY_RESERVATIONS.with(|reservations| reservations.borrow_mut().insert(invoice.id.clone(), reservation));
+            Ok(())
+        },
+        None => reserve_inventory(&invoice.id, invoice_product_items(invoice), holder, current_time),
+    }
+}
 
-    // Clear subscriptions
-    let subscription_count = SUBSCRIPTIONS.with(|subscriptions| {
-        let subscription_ids: Vec<String> = subscriptions.borrow().iter().map(|(id, _)| id).collect();
-        let count = subscription_ids.len() as u32;
-        let mut map = subscriptions.borrow_mut();
-        for id in subscription_ids {
-            map.remove(&id);
-        }
-        count
+// Give lapsed holds back to the shelf, invoices with a payment in flight keep theirs
+fn release_expired_reservations(current_time: u64) {
+    let expired: Vec<String> = INVENTORY_RESERVATIONS.with(|reservations| {
+        reservations.borrow().iter()
+            .filter(|(invoice_id, reservation)| {
+                reservation.expires_at.is_some_and(|expires_at| current_time >= expires_at) &&
+                    !is_in_flight(&format!("invoice:{}", invoice_id))
+            })
+            .map(|(invoice_id, _)| invoice_id)
+            .collect()
     });
 
-    // Clear subscription payments
-    SUBSCRIPTION_PAYMENTS.with(|payments| {
-        let payment_ids: Vec<String> = payments.borrow().iter().map(|(id, _)| id).collect();
-        let mut map = payments.borrow_mut();
-        for id in payment_ids {
-            map.remove(&id);
-        }
+    for invoice_id in expired {
+        release_inventory_reservation(&invoice_id);
+    }
+}
+
+fn release_inventory_reservation(invoice_id: &str) {
+    let reservation = INVENTORY_RESERVATIONS.with(|reservations| {
+        reservations.borrow_mut().remove(&invoice_id.to_string())
     });
 
-    Ok(plan_count + subscription_count)
+    if let Some(reservation) = reservation {
+        for item in reservation.items {
+            adjust_product_stock(&item.product_id, item.variant_sku.as_deref(), |_, reserved| {
+                *reserved = Some(reserved.unwrap_or(0).saturating_sub(item.quantity));
+            });
+        }
+    }
 }
 
 #[ic_cdk::query]
-fn get_subscription_stats() -> (u32, u32, u32) {
+fn get_inventory_reservations(product_id: Option<String>) -> Vec<InventoryReservation> {
     let caller = ic_cdk::caller();
     let owner = OWNER.with(|o| *o.borrow().get());
-    
+
     if caller != owner {
-        return (0, 0, 0);
+        return vec![];
     }
 
-    let plan_count = SUBSCRIPTION_PLANS.with(|plans| {
-        plans.borrow().len() as u32
-    });
-
-    let subscription_count = SUBSCRIPTIONS.with(|subscriptions| {
-        subscriptions.borrow().len() as u32
-    });
-
-    let active_subscription_count = SUBSCRIPTIONS.with(|subscriptions| {
-        subscriptions.borrow().iter()
-            .filter(|(_, subscription)| matches!(subscription.status, SubscriptionStatus::Active))
-            .count() as u32
-    });
-
-    (plan_count, subscription_count, active_subscription_count)
+    INVENTORY_RESERVATIONS.with(|reservations| {
+        reservations.borrow().iter()
+            .map(|(_, reservation)| reservation)
+            .filter(|reservation| {
+                product_id.as_ref().is_none_or(|id| reservation.items.iter().any(|item| item.product_id == *id))
+            })
+            .collect()
+    })
 }
 
 // ============================================================================
-// PRODUCT MANAGEMENT METHODS
+// DIGITAL GOODS DELIVERY
 // ============================================================================
 
-#[ic_cdk::update]
-fn create_product(mut product: Product) -> Result<String, String> {
-    let caller = ic_cdk::caller();
-    let owner = OWNER.with(|o| *o.borrow().get());
-    
-    if caller != owner {
-        return Err("Only the owner can create products".to_string());
-    }
+const MAX_LICENSE_KEYS_PER_CALL: usize = 1_000;
 
-    // Validate product configuration
-    if product.name.is_empty() {
-        return Err("Product name cannot be empty".to_string());
-    }
-    if product.description.is_empty() {
-        return Err("Product description cannot be empty".to_string());
+// Unguessable, so the download server can trust whoever presents it
+fn download_token(seed: &[u8], delivery_id: &str, created_at: u64) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(seed);
+    hasher.update(delivery_id.as_bytes());
+    hasher.update(created_at.to_be_bytes());
+    to_hex(&hasher.finalize())
+}
+
+// Drops empty keys and keys already in the pool or repeated in the batch
+fn new_license_keys(pool: &LicenseKeyPool, keys: Vec<String>) -> Vec<String> {
+    let mut added: Vec<String> = Vec::new();
+    for key in keys {
+        let key = key.trim().to_string();
+        if !key.is_empty() && !pool.available.contains(&key) && !added.contains(&key) {
+            added.push(key);
+        }
     }
-    if product.price == 0 {
-        return Err("Product price must be greater than 0".to_string());
+    added
+}
+
+async fn init_delivery_seed() {
+    if !DELIVERY_SEED.with(|seed| seed.borrow().get().is_empty()) {
+        return;
     }
-    if product.token_symbol.is_empty() {
-        return Err("Product token symbol cannot be empty".to_string());
+
+    match raw_rand().await {
+        Ok((bytes,)) => {
+            DELIVERY_SEED.with(|seed| seed.borrow_mut().set(bytes).unwrap());
+            retry_pending_deliveries();
+        },
+        Err((code, msg)) => ic_cdk::println!("Failed to fetch delivery seed: {:?} {}", code, msg),
     }
+}
 
-    // Validate that the token is supported
-    let config = CONFIG.with(|c| c.borrow().get().clone());
-    let token_exists = config.supported_tokens
-        .iter()
-        .any(|t| t.symbol == product.token_symbol && t.is_active);
-    
-    if !token_exists {
-        return Err("Token not supported or inactive".to_string());
+// Fills in the content of a delivery. Returns false while it has to stay pending.
+fn fulfil_delivery(delivery: &mut Delivery, deliverable: &ProductDeliverable) -> bool {
+    let current_time = ic_cdk::api::time();
+
+    let content = match &deliverable.kind {
+        DeliverableKind::LicenseKeys => {
+            let key = LICENSE_KEY_POOLS.with(|pools| {
+                let mut map = pools.borrow_mut();
+                let mut pool = map.get(&delivery.product_id)?;
+                let key = pool.available.pop()?;
+                pool.issued_count += 1;
+                map.insert(delivery.product_id.clone(), pool);
+                Some(key)
+            });
+            match key {
+                Some(key) => DeliveryContent::LicenseKey(key),
+                None => return false,
+            }
+        },
+        DeliverableKind::DownloadToken { base_url, valid_for_seconds } => {
+            let seed = DELIVERY_SEED.with(|seed| seed.borrow().get().clone());
+            if seed.is_empty() {
+                return false;
+            }
+            let token = download_token(&seed, &delivery.delivery_id, delivery.created_at);
+            DOWNLOAD_TOKEN_INDEX.with(|index| index.borrow_mut().insert(token.clone(), delivery.delivery_id.clone()));
+            DeliveryContent::DownloadToken {
+                url: format!("{}?token={}", base_url, token),
+                token,
+                expires_at: current_time.saturating_add(valid_for_seconds.saturating_mul(SECONDS_TO_NANOS)),
+            }
+        },
+        DeliverableKind::EncryptedContent { content_ref, key_ref } => DeliveryContent::EncryptedContent {
+            content_ref: content_ref.clone(),
+            key_ref: key_ref.clone(),
+        },
+    };
+
+    delivery.content = Some(content);
+    delivery.status = DeliveryStatus::Issued;
+    delivery.issued_at = Some(current_time);
+    true
+}
+
+// Called once an invoice is paid, license keys are handed out per unit sold
+fn issue_deliveries(invoice: &PaymentInvoice, buyer: Principal) {
+    let current_time = ic_cdk::api::time();
+
+    for item in invoice_product_items(invoice) {
+        let deliverable = match DELIVERABLES.with(|d| d.borrow().get(&item.product_id)) {
+            Some(deliverable) => deliverable,
+            None => continue,
+        };
+
+        let count = match deliverable.kind {
+            DeliverableKind::LicenseKeys => item.quantity,
+            _ => 1,
+        };
+
+        for _ in 0..count {
+            let delivery_id = NEXT_DELIVERY_ID.with(|id| {
+                let current = *id.borrow().get();
+                id.borrow_mut().set(current + 1).unwrap();
+                format!("dlv_{}", current)
+            });
+
+            let mut delivery = Delivery {
+                delivery_id: delivery_id.clone(),
+                invoice_id: invoice.id.clone(),
+                product_id: item.product_id.clone(),
+                variant_sku: item.variant_sku.clone(),
+                buyer,
+                status: DeliveryStatus::Pending,
+                content: None,
+                instructions: deliverable.instructions.clone(),
+                created_at: current_time,
+                issued_at: None,
+                revoked_at: None,
+            };
+            fulfil_delivery(&mut delivery, &deliverable);
+
+            DELIVERIES.with(|deliveries| deliveries.borrow_mut().insert(delivery_id, delivery));
+        }
     }
+}
 
-    // Generate product ID
-    let product_id = NEXT_PRODUCT_ID.with(|id| {
-        let current = *id.borrow().get();
-        id.borrow_mut().set(current + 1).unwrap();
-        format!("product_{}", current)
+fn retry_pending_deliveries() {
+    let pending: Vec<Delivery> = DELIVERIES.with(|deliveries| {
+        deliveries.borrow().iter()
+            .filter(|(_, delivery)| delivery.status == DeliveryStatus::Pending)
+            .map(|(_, delivery)| delivery)
+            .collect()
     });
 
-    // Set product metadata
-    product.product_id = product_id.clone();
-    product.created_at = ic_cdk::api::time();
-    product.updated_at = ic_cdk::api::time();
+    for mut delivery in pending {
+        let deliverable = match DELIVERABLES.with(|d| d.borrow().get(&delivery.product_id)) {
+            Some(deliverable) => deliverable,
+            None => continue,
+        };
+        if fulfil_delivery(&mut delivery, &deliverable) {
+            DELIVERIES.with(|deliveries| deliveries.borrow_mut().insert(delivery.delivery_id.clone(), delivery));
+        }
+    }
+}
 
-    // Store the product
-    PRODUCTS.with(|products| {
-        products.borrow_mut().insert(product_id.clone(), product)
+// Index download tokens issued before the index existed
+fn rebuild_download_token_index() {
+    if DOWNLOAD_TOKEN_INDEX.with(|index| !index.borrow().is_empty()) {
+        return;
+    }
+
+    DELIVERIES.with(|deliveries| {
+        for (delivery_id, delivery) in deliveries.borrow().iter() {
+            if let Some(DeliveryContent::DownloadToken { token, .. }) = delivery.content {
+                DOWNLOAD_TOKEN_INDEX.with(|index| index.borrow_mut().insert(token, delivery_id));
+            }
+        }
     });
+}
 
-    // Initialize sales stats for this product
-    let sales_stats = ProductSalesStats {
-        product_id: product_id.clone(),
-        total_sales: 0,
-        total_revenue: 0,
-        units_sold: 0,
-        last_sale_at: None,
-    };
-    
-    PRODUCT_SALES_STATS.with(|stats| {
-        stats.borrow_mut().insert(product_id.clone(), sales_stats)
+// Revoked license keys are not put back into the pool, the buyer has seen them
+fn revoke_invoice_deliveries(invoice_id: &str) {
+    let current_time = ic_cdk::api::time();
+    let active: Vec<Delivery> = DELIVERIES.with(|deliveries| {
+        deliveries.borrow().iter()
+            .filter(|(_, delivery)| delivery.invoice_id == invoice_id && delivery.status != DeliveryStatus::Revoked)
+            .map(|(_, delivery)| delivery)
+            .collect()
     });
 
-    Ok(product_id)
+    for mut delivery in active {
+        delivery.status = DeliveryStatus::Revoked;
+        delivery.revoked_at = Some(current_time);
+        DELIVERIES.with(|deliveries| deliveries.borrow_mut().insert(delivery.delivery_id.clone(), delivery));
+    }
 }
 
 #[ic_cdk::update]
-fn update_product(product_id: String, mut updated_product: Product) -> Result<(), String> {
+fn set_product_deliverable(
+    product_id: String,
+    kind: DeliverableKind,
+    instructions: Option<String>,
+) -> Result<ProductDeliverable, String> {
     let caller = ic_cdk::caller();
     let owner = OWNER.with(|o| *o.borrow().get());
     
     if caller != owner {
-        return Err("Only the owner can update products".to_string());
+        return Err("Only the owner can configure product deliverables".to_string());
     }
 
-    // Validate product configuration
-    if updated_product.name.is_empty() {
-        return Err("Product name cannot be empty".to_string());
-    }
-    if updated_product.description.is_empty() {
-        return Err("Product description cannot be empty".to_string());
-    }
-    if updated_product.price == 0 {
-        return Err("Product price must be greater than 0".to_string());
-    }
-    if updated_product.token_symbol.is_empty() {
-        return Err("Product token symbol cannot be empty".to_string());
+    if PRODUCTS.with(|products| products.borrow().get(&product_id)).is_none() {
+        return Err("Product not found".to_string());
     }
 
-    // Validate that the token is supported
-    let config = CONFIG.with(|c| c.borrow().get().clone());
-    let token_exists = config.supported_tokens
-        .iter()
-        .any(|t| t.symbol == updated_product.token_symbol && t.is_active);
-    
-    if !token_exists {
-        return Err("Token not supported or inactive".to_string());
+    match &kind {
+        DeliverableKind::DownloadToken { base_url, valid_for_seconds } => {
+            if !base_url.starts_with("https://") {
+                return Err("Download URL must use HTTPS".to_string());
+            }
+            if *valid_for_seconds == 0 {
+                return Err("Download tokens must be valid for at least one second".to_string());
+            }
+        },
+        DeliverableKind::EncryptedContent { content_ref, key_ref } => {
+            if content_ref.is_empty() || key_ref.is_empty() {
+                return Err("Content and key references cannot be empty".to_string());
+            }
+        },
+        DeliverableKind::LicenseKeys => {},
     }
 
-    PRODUCTS.with(|products| {
-        let mut map = products.borrow_mut();
-        let existing_product = map.get(&product_id)
-            .ok_or("Product not found")?;
-        
-        // Preserve original metadata
-        updated_product.product_id = product_id.clone();
-        updated_product.created_at = existing_product.created_at;
-        updated_product.updated_at = ic_cdk::api::time();
-        
-        map.insert(product_id, updated_product);
-        Ok(())
-    })
-}
-
-#[ic_cdk::query]
-fn get_product(product_id: String) -> Result<Product, String> {
-    PRODUCTS.with(|products| {
-        products.borrow().get(&product_id)
-            .ok_or("Product not found".to_string())
-    })
-}
-
-#[ic_cdk::query]
-fn list_products() -> Vec<Product> {
-    PRODUCTS.with(|products| {
-        products.borrow().iter().map(|(_, product)| product).collect()
-    })
-}
-
-#[ic_cdk::query]
-fn list_active_products() -> Vec<Product> {
-    PRODUCTS.with(|products| {
-        products.borrow().iter()
-            .filter(|(_, product)| matches!(product.status, ProductStatus::Active))
-            .map(|(_, product)| product)
-            .collect()
-    })
-}
+    let current_time = ic_cdk::api::time();
+    let created_at = DELIVERABLES.with(|d| d.borrow().get(&product_id))
+        .map_or(current_time, |existing| existing.created_at);
 
-#[ic_cdk::query]
-fn list_products_by_category(category: String) -> Vec<Product> {
-    PRODUCTS.with(|products| {
-        products.borrow().iter()
-            .filter(|(_, product)| {
-                product.category.as_ref().map_or(false, |cat| cat == &category)
-            })
-            .map(|(_, product)| product)
-            .collect()
-    })
-}
+    let deliverable = ProductDeliverable {
+        product_id: product_id.clone(),
+        kind,
+        instructions,
+        created_at,
+        updated_at: current_time,
+    };
 
-#[ic_cdk::query]
-fn list_products_by_token(token_symbol: String) -> Vec<Product> {
-    PRODUCTS.with(|products| {
-        products.borrow().iter()
-            .filter(|(_, product)| product.token_symbol == token_symbol)
-            .map(|(_, product)| product)
-            .collect()
-    })
+    DELIVERABLES.with(|d| d.borrow_mut().insert(product_id, deliverable.clone()));
+    Ok(deliverable)
 }
 
 #[ic_cdk::update]
-fn delete_product(product_id: String) -> Result<(), String> {
+fn remove_product_deliverable(product_id: String) -> Result<(), String> {
     let caller = ic_cdk::caller();
     let owner = OWNER.with(|o| *o.borrow().get());
     
     if caller != owner {
-        return Err("Only the owner can delete products".to_string());
+        return Err("Only the owner can configure product deliverables".to_string());
     }
 
-    PRODUCTS.with(|products| {
-        let mut map = products.borrow_mut();
-        if map.remove(&product_id).is_none() {
-            return Err("Product not found".to_string());
-        }
-        Ok(())
-    })?;
+    DELIVERABLES.with(|d| d.borrow_mut().remove(&product_id))
+        .map(|_| ())
+        .ok_or("Product has no deliverable".to_string())
+}
 
-    // Also remove sales statistics
-    PRODUCT_SALES_STATS.with(|stats| {
-        stats.borrow_mut().remove(&product_id)
-    });
+#[ic_cdk::query]
+fn get_product_deliverable(product_id: String) -> Option<ProductDeliverable> {
+    let caller = ic_cdk::caller();
+    let owner = OWNER.with(|o| *o.borrow().get());
 
-    Ok(())
+    if caller != owner {
+        return None;
+    }
+    DELIVERABLES.with(|d| d.borrow().get(&product_id))
 }
 
+// Returns the number of keys available for the product afterwards
 #[ic_cdk::update]
-fn toggle_product_status(product_id: String) -> Result<ProductStatus, String> {
+fn add_license_keys(product_id: String, keys: Vec<String>) -> Result<u32, String> {
     let caller = ic_cdk::caller();
     let owner = OWNER.with(|o| *o.borrow().get());
     
     if caller != owner {
-        return Err("Only the owner can toggle product status".to_string());
+        return Err("Only the owner can add license keys".to_string());
     }
 
-    PRODUCTS.with(|products| {
-        let mut map = products.borrow_mut();
-        if let Some(mut product) = map.get(&product_id) {
-            product.status = match product.status {
-                ProductStatus::Active => ProductStatus::Inactive,
-                ProductStatus::Inactive => ProductStatus::Active,
-                ProductStatus::OutOfStock => ProductStatus::Active,
-            };
-            product.updated_at = ic_cdk::api::time();
-            let new_status = product.status.clone();
-            map.insert(product_id, product);
-            Ok(new_status)
-        } else {
-            Err("Product not found".to_string())
-        }
-    })
+    if keys.len() > MAX_LICENSE_KEYS_PER_CALL {
+        return Err(format!("At most {} keys can be added per call", MAX_LICENSE_KEYS_PER_CALL));
+    }
+    if PRODUCTS.with(|products| products.borrow().get(&product_id)).is_none() {
+        return Err("Product not found".to_string());
+    }
+
+    let mut pool = LICENSE_KEY_POOLS.with(|pools| pools.borrow().get(&product_id)).unwrap_or_default();
+    let added = new_license_keys(&pool, keys);
+    pool.available.extend(added);
+    LICENSE_KEY_POOLS.with(|pools| pools.borrow_mut().insert(product_id.clone(), pool));
+
+    // Buyers who paid while the pool was empty get their keys now
+    retry_pending_deliveries();
+
+    let available = LICENSE_KEY_POOLS.with(|pools| pools.borrow().get(&product_id))
+        .map_or(0, |pool| pool.available.len() as u32);
+    Ok(available)
 }
 
-#[ic_cdk::update]
-fn update_product_inventory(product_id: String, inventory_count: Option<u32>) -> Result<(), String> {
+#[ic_cdk::query]
+fn get_license_key_pool(product_id: String) -> Result<(u32, u64), String> {
     let caller = ic_cdk::caller();
     let owner = OWNER.with(|o| *o.borrow().get());
-    
+
     if caller != owner {
-        return Err("Only the owner can update product inventory".to_string());
+        return Err("Only the owner can view license key pools".to_string());
     }
 
-    PRODUCTS.with(|products| {
-        let mut map = products.borrow_mut();
-        if let Some(mut product) = map.get(&product_id) {
-            product.inventory_count = inventory_count;
-            product.updated_at = ic_cdk::api::time();
-            
-            // Update status based on inventory
-            if let Some(count) = inventory_count {
-                if count == 0 && matches!(product.status, ProductStatus::Active) {
-                    product.status = ProductStatus::OutOfStock;
-                } else if count > 0 && matches!(product.status, ProductStatus::OutOfStock) {
-                    product.status = ProductStatus::Active;
-                }
-            }
-            
-            map.insert(product_id, product);
-            Ok(())
-        } else {
-            Err("Product not found".to_string())
-        }
-    })
+    let pool = LICENSE_KEY_POOLS.with(|pools| pools.borrow().get(&product_id)).unwrap_or_default();
+    Ok((pool.available.len() as u32, pool.issued_count))
 }
 
+// Deliveries of the calling buyer, content is only included while issued
 #[ic_cdk::query]
-fn get_product_sales_stats(product_id: String) -> Result<ProductSalesStats, String> {
-    PRODUCT_SALES_STATS.with(|stats| {
-        stats.borrow().get(&product_id)
-            .ok_or("Product sales statistics not found".to_string())
+fn get_my_deliveries(invoice_id: Option<String>) -> Vec<Delivery> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return vec![];
+    }
+
+    DELIVERIES.with(|deliveries| {
+        deliveries.borrow().iter()
+            .map(|(_, delivery)| delivery)
+            .filter(|delivery| delivery.buyer == caller)
+            .filter(|delivery| invoice_id.as_ref().is_none_or(|id| delivery.invoice_id == *id))
+            .map(|mut delivery| {
+                if delivery.status != DeliveryStatus::Issued {
+                    delivery.content = None;
+                }
+                delivery
+            })
+            .collect()
     })
 }
 
 #[ic_cdk::query]
-fn list_all_product_sales_stats() -> Vec<ProductSalesStats> {
+fn list_deliveries(invoice_id: Option<String>) -> Vec<Delivery> {
     let caller = ic_cdk::caller();
     let owner = OWNER.with(|o| *o.borrow().get());
-    
+
     if caller != owner {
         return vec![];
     }
 
-    PRODUCT_SALES_STATS.with(|stats| {
-        stats.borrow().iter().map(|(_, stat)| stat).collect()
+    DELIVERIES.with(|deliveries| {
+        deliveries.borrow().iter()
+            .map(|(_, delivery)| delivery)
+            .filter(|delivery| invoice_id.as_ref().is_none_or(|id| delivery.invoice_id == *id))
+            .collect()
     })
 }
 
+// For download servers: accepts a token only while its delivery is issued and unexpired
 #[ic_cdk::query]
-fn get_product_categories() -> Vec<String> {
-    PRODUCTS.with(|products| {
-        let mut categories: std::collections::HashSet<String> = std::collections::HashSet::new();
-        
-        for (_, product) in products.borrow().iter() {
-            if let Some(category) = &product.category {
-                categories.insert(category.clone());
-            }
-        }
-        
-        categories.into_iter().collect()
-    })
-}
-
-// Helper function to update product sales stats
-fn update_product_sales_stats(product_id: &str, sale_amount: u64) {
+fn verify_download_token(token: String) -> Result<Delivery, String> {
     let current_time = ic_cdk::api::time();
-    let product_id_string = product_id.to_string();
-    
-    PRODUCT_SALES_STATS.with(|stats| {
-        let mut map = stats.borrow_mut();
-        if let Some(mut product_stats) = map.get(&product_id_string) {
-            product_stats.total_sales += 1;
-            product_stats.total_revenue += sale_amount;
-            product_stats.units_sold += 1;
-            product_stats.last_sale_at = Some(current_time);
-            
-            map.insert(product_id_string.clone(), product_stats);
-        }
-    });
-    
-    // Update inventory if applicable
-    PRODUCTS.with(|products| {
-        let mut map = products.borrow_mut();
-        if let Some(mut product) = map.get(&product_id_string) {
-            if let Some(current_inventory) = product.inventory_count {
-                if current_inventory > 0 {
-                    product.inventory_count = Some(current_inventory - 1);
-                    
-                    // Mark as out of stock if inventory reaches 0
-                    if current_inventory == 1 {
-                        product.status = ProductStatus::OutOfStock;
-                    }
-                    
-                    product.updated_at = current_time;
-                    map.insert(product_id_string, product);
-                }
-            }
+
+    let delivery = DOWNLOAD_TOKEN_INDEX.with(|index| index.borrow().get(&token))
+        .and_then(|delivery_id| DELIVERIES.with(|deliveries| deliveries.borrow().get(&delivery_id)))
+        .filter(|delivery| matches!(
+            &delivery.content,
+            Some(DeliveryContent::DownloadToken { token: issued, .. }) if *issued == token
+        ))
+        .ok_or("Invalid download token")?;
+
+    if delivery.status != DeliveryStatus::Issued {
+        return Err("Download has been revoked".to_string());
+    }
+    if let Some(DeliveryContent::DownloadToken { expires_at, .. }) = &delivery.content {
+        if current_time > *expires_at {
+            return Err("Download token expired".to_string());
         }
-    });
+    }
+    Ok(delivery)
 }
 
 // ============================================================================
@@ -3005,8 +10220,13 @@ fn update_product_sales_stats(product_id: &str, sale_amount: u64) {
 fn create_invoice_for_product(
     product_id: String,
     quantity: u32,
-    metadata: Vec<(String, String)>
+    metadata: Vec<(String, String)>,
+    expires_at: Option<u64>,
+    variant_sku: Option<String>,
+    token_symbol: Option<String>, // Defaults to the product's own token
 ) -> Result<PaymentInvoice, String> {
+    let expires_at = invoice_expiry(expires_at)?;
+    validate_caller_metadata(&metadata)?;
     if quantity == 0 {
         return Err("Quantity must be greater than 0".to_string());
     }
@@ -3016,31 +10236,25 @@ fn create_invoice_for_product(
         products.borrow().get(&product_id)
     }).ok_or("Product not found")?;
 
-    // Check if product is active and available
-    if !matches!(product.status, ProductStatus::Active) {
-        return Err("Product is not available for purchase".to_string());
-    }
-
-    // Check inventory if applicable
-    if let Some(inventory) = product.inventory_count {
-        if inventory < quantity {
-            return Err(format!(
-                "Insufficient inventory. Available: {}, Requested: {}", 
-                inventory, quantity
-            ));
-        }
-    }
+    // Status, variant, price and inventory checks, units held by unpaid invoices are not available
+    let token_symbol = token_symbol.unwrap_or_else(|| product.token_symbol.clone());
+    let mut line = LineItem {
+        product_id: Some(product_id.clone()),
+        variant_sku: variant_sku.clone(),
+        description: String::new(),
+        quantity,
+        unit_price: 0,
+        discount: 0,
+        tax_rate_bps: None,
+    };
+    price_product_line(&mut line, &token_symbol)?;
 
     // Get the token configuration for this product
-    let config = CONFIG.with(|c| c.borrow().get().clone());
-    let token = config.supported_tokens
-        .iter()
-        .find(|t| t.symbol == product.token_symbol && t.is_active)
-        .ok_or("Product token not supported or inactive")?
-        .clone();
+    let token = find_active_token(&token_symbol)
+        .map_err(|_| "Product token not supported or inactive".to_string())?;
 
     // Calculate total amount
-    let total_amount = product.price * quantity as u64;
+    let total_amount = line.unit_price.checked_mul(quantity as u64).ok_or("Invoice amount overflow")?;
 
     // Generate invoice ID
     let invoice_id = NEXT_INVOICE_ID.with(|id| {
@@ -3054,24 +10268,41 @@ fn create_invoice_for_product(
     enhanced_metadata.push(("product_id".to_string(), product_id));
     enhanced_metadata.push(("product_name".to_string(), product.name.clone()));
     enhanced_metadata.push(("quantity".to_string(), quantity.to_string()));
-    enhanced_metadata.push(("unit_price".to_string(), product.price.to_string()));
+    enhanced_metadata.push(("unit_price".to_string(), line.unit_price.to_string()));
+    if let Some(sku) = variant_sku {
+        enhanced_metadata.push(("variant_sku".to_string(), sku));
+    }
     if let Some(category) = &product.category {
         enhanced_metadata.push(("category".to_string(), category.clone()));
     }
 
+    let description = format!("{} (Qty: {})", line.description, quantity);
+    let line_items = vec![line];
+    let breakdown = compute_invoice_breakdown(&line_items, None, 0)?;
+    check_invoice_units(&line_items)?;
+    reserve_inventory(&invoice_id, inventory_items(&line_items), ic_cdk::caller(), ic_cdk::api::time())?;
+
     let invoice = PaymentInvoice {
         id: invoice_id.clone(),
         merchant: OWNER.with(|o| *o.borrow().get()),
         amount: total_amount,
         token,
-        description: format!("{} (Qty: {})", product.name, quantity),
+        description,
         metadata: enhanced_metadata,
-        expires_at: Some(ic_cdk::api::time() + (24 * 60 * 60 * 1_000_000_000)), // 24 hours
+        expires_at: Some(expires_at),
         created_at: ic_cdk::api::time(),
         status: InvoiceStatus::Created,
+        deposit_subaccount: Some(invoice_deposit_subaccount(&invoice_id).to_vec()),
+        fiat_amount: None,
+        quote: None,
+        line_items: Some(line_items),
+        tax_rate_bps: None,
+        shipping: None,
+        breakdown: Some(breakdown),
+        payer: expected_invoice_payer(),
     };
 
-    INVOICES.with(|invoices| invoices.borrow_mut().insert(invoice_id, invoice.clone()));
+    store_invoice(&invoice);
     Ok(invoice)
 }
 
@@ -3137,3 +10368,1403 @@ fn get_product_stats() -> (u32, u32) {
 
 // Export candid interface
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 24 * 60 * 60 * SECONDS_TO_NANOS;
+
+    fn create_test_dunning_policy() -> DunningPolicy {
+        DunningPolicy {
+            retry_offsets_seconds: vec![24 * 60 * 60, 3 * 24 * 60 * 60],
+            max_attempts: 3,
+            grace_period_seconds: 2 * 24 * 60 * 60,
+            final_action: DunningFinalAction::Pause,
+        }
+    }
+
+    #[test]
+    fn test_dunning_first_retry_within_grace() {
+        let policy = create_test_dunning_policy();
+        let due = 100 * DAY;
+        match next_dunning_step(&policy, 1, due, due) {
+            DunningStep::Retry { retry_at, within_grace } => {
+                assert_eq!(retry_at, due + DAY);
+                assert!(within_grace);
+            },
+            DunningStep::Exhausted(_) => panic!("expected a retry"),
+        }
+    }
+
+    #[test]
+    fn test_dunning_second_retry_after_grace() {
+        let policy = create_test_dunning_policy();
+        let due = 100 * DAY;
+        match next_dunning_step(&policy, 2, due, due + DAY) {
+            DunningStep::Retry { retry_at, .. } => assert_eq!(retry_at, due + 3 * DAY),
+            DunningStep::Exhausted(_) => panic!("expected a retry"),
+        }
+        match next_dunning_step(&policy, 2, due, due + 2 * DAY) {
+            DunningStep::Retry { within_grace, .. } => assert!(!within_grace),
+            DunningStep::Exhausted(_) => panic!("expected a retry"),
+        }
+    }
+
+    #[test]
+    fn test_dunning_exhausted_after_max_attempts() {
+        let policy = create_test_dunning_policy();
+        assert!(matches!(
+            next_dunning_step(&policy, 3, 0, 0),
+            DunningStep::Exhausted(DunningFinalAction::Pause)
+        ));
+    }
+
+    fn create_test_subscription(period_start: u64, period_end: u64) -> Subscription {
+        Subscription {
+            subscription_id: "sub_1".to_string(),
+            plan_id: "plan_1".to_string(),
+            subscriber: Principal::anonymous(),
+            status: SubscriptionStatus::Paused,
+            current_period_start: period_start,
+            current_period_end: period_end,
+            next_billing_date: period_end,
+            trial_end: None,
+            cancelled_at: None,
+            cancel_at_period_end: false,
+            total_payments: 0,
+            payment_failures: 0,
+            next_retry_at: None,
+            metadata: vec![],
+            created_at: period_start,
+            updated_at: period_start,
+            proration_credit: None,
+            scheduled_plan_change: None,
+            plan_changes: None,
+            billing_anchor_day: None,
+            paused_at: None,
+            resume_at: None,
+            paused_by: None,
+            pause_count: None,
+        }
+    }
+
+    fn create_test_plan(plan_id: &str, price: u64) -> SubscriptionPlan {
+        SubscriptionPlan {
+            plan_id: plan_id.to_string(),
+            name: "Pro".to_string(),
+            description: "Pro plan".to_string(),
+            price,
+            token: "ICP".to_string(),
+            billing_interval: BillingInterval::Monthly,
+            trial_period_days: None,
+            max_subscriptions: None,
+            features: vec![],
+            is_active: true,
+            created_at: 0,
+            updated_at: 0,
+            dunning_policy: None,
+            billing_day: None,
+            usage_pricing: None,
+            self_service: None,
+        }
+    }
+
+    fn charge_test_subscription(subscription: &mut Subscription, paid: bool, now: u64) -> UpcomingCharge {
+        let plan = create_test_plan("plan_1", 3_000);
+        SUBSCRIPTION_PLANS.with(|plans| plans.borrow_mut().insert(plan.plan_id.clone(), plan.clone()));
+
+        let next = next_subscription_charge(subscription).unwrap();
+        let charge = ChargeOutcome {
+            plan: &plan,
+            scheduled_change: next.scheduled_change.as_ref(),
+            upcoming: &next.upcoming,
+            paid,
+        };
+        apply_charge_outcome(subscription, &charge, now);
+        next.upcoming
+    }
+
+    #[test]
+    fn test_paid_renewal_starts_next_period() {
+        let start = utc_date(2024, 1, 15);
+        let mut subscription = create_test_subscription(start, utc_date(2024, 2, 15));
+        subscription.status = SubscriptionStatus::Active;
+        subscription.billing_anchor_day = Some(15);
+
+        let upcoming = charge_test_subscription(&mut subscription, true, utc_date(2024, 2, 15));
+        assert_eq!(upcoming.amount, 3_000);
+        assert!(!upcoming.final_charge);
+        assert_eq!(subscription.status, SubscriptionStatus::Active);
+        assert_eq!(subscription.current_period_start, utc_date(2024, 2, 15));
+        assert_eq!(subscription.next_billing_date, utc_date(2024, 3, 15));
+        assert_eq!(subscription.total_payments, 3_000);
+    }
+
+    #[test]
+    fn test_cancel_at_period_end_is_not_charged_for_next_period() {
+        let mut subscription = create_test_subscription(utc_date(2024, 1, 15), utc_date(2024, 2, 15));
+        subscription.status = SubscriptionStatus::Active;
+        subscription.cancel_at_period_end = true;
+
+        let upcoming = charge_test_subscription(&mut subscription, true, utc_date(2024, 2, 15));
+        assert!(upcoming.final_charge);
+        assert_eq!(upcoming.base_amount, 0);
+        assert_eq!(upcoming.amount, 0);
+        assert_eq!(subscription.status, SubscriptionStatus::Cancelled);
+        assert_eq!(subscription.current_period_end, utc_date(2024, 2, 15));
+        assert_eq!(subscription.total_payments, 0);
+    }
+
+    #[test]
+    fn test_failed_renewal_schedules_retry() {
+        let due = utc_date(2024, 2, 15);
+        let mut subscription = create_test_subscription(utc_date(2024, 1, 15), due);
+        subscription.status = SubscriptionStatus::Active;
+
+        charge_test_subscription(&mut subscription, false, due);
+        assert_eq!(subscription.payment_failures, 1);
+        assert_eq!(subscription.next_retry_at, Some(due + DAY));
+        assert_eq!(subscription.next_billing_date, due);
+    }
+
+    #[test]
+    fn test_resume_extends_paid_period_by_pause_length() {
+        let mut subscription = create_test_subscription(0, 30 * DAY);
+        subscription.paused_at = Some(10 * DAY);
+        subscription.resume_at = Some(15 * DAY);
+
+        resume_paused_subscription(&mut subscription, 15 * DAY);
+        assert_eq!(subscription.status, SubscriptionStatus::Active);
+        assert_eq!(subscription.current_period_end, 35 * DAY);
+        assert_eq!(subscription.next_billing_date, 35 * DAY);
+        assert_eq!(subscription.paused_at, None);
+        assert_eq!(subscription.resume_at, None);
+    }
+
+    #[test]
+    fn test_resume_after_dunning_pause_keeps_due_date() {
+        // Paused by the dunning policy after the period was already due
+        let mut subscription = create_test_subscription(0, 30 * DAY);
+        subscription.payment_failures = 3;
+        subscription.paused_at = Some(40 * DAY);
+
+        resume_paused_subscription(&mut subscription, 50 * DAY);
+        assert_eq!(subscription.next_billing_date, 30 * DAY);
+        assert_eq!(subscription.payment_failures, 0);
+    }
+
+    #[test]
+    fn test_validate_self_service_policy() {
+        let mut policy = default_self_service_policy();
+        assert!(validate_self_service_policy(&policy).is_ok());
+        policy.max_pause_days = Some(0);
+        assert!(validate_self_service_policy(&policy).is_err());
+        policy.max_pause_days = Some(30);
+        assert!(validate_self_service_policy(&policy).is_ok());
+    }
+
+    fn usage_tier(up_to: Option<u64>, unit_price: u64, flat_fee: u64) -> UsageTier {
+        UsageTier { up_to, unit_price, flat_fee }
+    }
+
+    #[test]
+    fn test_usage_charge_per_unit_and_volume() {
+        assert_eq!(usage_charge(&UsagePricing::PerUnit { unit_price: 3 }, 1_000), 3_000);
+        assert_eq!(usage_charge(&UsagePricing::PerUnit { unit_price: 3 }, 0), 0);
+
+        let volume = UsagePricing::Volume(vec![
+            usage_tier(Some(100), 10, 0),
+            usage_tier(Some(1_000), 5, 50),
+            usage_tier(None, 2, 100),
+        ]);
+        assert_eq!(usage_charge(&volume, 100), 1_000);
+        assert_eq!(usage_charge(&volume, 101), 555);
+        assert_eq!(usage_charge(&volume, 5_000), 10_100);
+    }
+
+    #[test]
+    fn test_usage_charge_graduated() {
+        let graduated = UsagePricing::Graduated(vec![
+            usage_tier(Some(100), 10, 0),
+            usage_tier(Some(1_000), 5, 50),
+            usage_tier(None, 2, 100),
+        ]);
+        assert_eq!(usage_charge(&graduated, 50), 500);
+        assert_eq!(usage_charge(&graduated, 100), 1_000);
+        // 100 * 10 + 50 * 5 + 50
+        assert_eq!(usage_charge(&graduated, 150), 1_300);
+        // 100 * 10 + 900 * 5 + 50 + 500 * 2 + 100
+        assert_eq!(usage_charge(&graduated, 1_500), 6_650);
+    }
+
+    #[test]
+    fn test_validate_usage_pricing() {
+        assert!(validate_usage_pricing(&UsagePricing::PerUnit { unit_price: 1 }).is_ok());
+        assert!(validate_usage_pricing(&UsagePricing::PerUnit { unit_price: 0 }).is_err());
+        assert!(validate_usage_pricing(&UsagePricing::Graduated(vec![])).is_err());
+        assert!(validate_usage_pricing(&UsagePricing::Volume(vec![usage_tier(None, 1, 0)])).is_ok());
+        // Last tier must be unbounded
+        assert!(validate_usage_pricing(&UsagePricing::Volume(vec![usage_tier(Some(10), 1, 0)])).is_err());
+        // Bounds must increase
+        assert!(validate_usage_pricing(&UsagePricing::Graduated(vec![
+            usage_tier(Some(10), 2, 0),
+            usage_tier(Some(10), 1, 0),
+            usage_tier(None, 1, 0),
+        ])).is_err());
+        // Only the last tier can be unbounded
+        assert!(validate_usage_pricing(&UsagePricing::Graduated(vec![
+            usage_tier(None, 2, 0),
+            usage_tier(None, 1, 0),
+        ])).is_err());
+    }
+
+    fn utc_date(year: i64, month: u32, day: u32) -> u64 {
+        days_from_civil(year, month, day) as u64 * NANOS_PER_DAY
+    }
+
+    #[test]
+    fn test_civil_date_conversion() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(days_from_civil(2024, 2, 29), 19_782);
+        assert_eq!(civil_from_days(19_722), (2023, 12, 31));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(days_in_month(2024, 2), 29);
+        assert_eq!(days_in_month(2100, 2), 28);
+        assert_eq!(days_in_month(2000, 2), 29);
+    }
+
+    #[test]
+    fn test_monthly_billing_clamps_to_month_end_without_drift() {
+        let anchor = Some(31);
+        let start = utc_date(2024, 1, 31) + 9 * 60 * 60 * SECONDS_TO_NANOS;
+        let feb = calculate_next_billing_date(start, &BillingInterval::Monthly, anchor);
+        assert_eq!(feb, utc_date(2024, 2, 29) + 9 * 60 * 60 * SECONDS_TO_NANOS);
+        let mar = calculate_next_billing_date(feb, &BillingInterval::Monthly, anchor);
+        assert_eq!(mar, utc_date(2024, 3, 31) + 9 * 60 * 60 * SECONDS_TO_NANOS);
+        let jun = calculate_next_billing_date(mar, &BillingInterval::Quarterly, anchor);
+        assert_eq!(jun, utc_date(2024, 6, 30) + 9 * 60 * 60 * SECONDS_TO_NANOS);
+    }
+
+    #[test]
+    fn test_yearly_billing_handles_leap_days() {
+        let anchor = Some(29);
+        let next = calculate_next_billing_date(utc_date(2024, 2, 29), &BillingInterval::Yearly, anchor);
+        assert_eq!(next, utc_date(2025, 2, 28));
+        let next = calculate_next_billing_date(utc_date(2027, 2, 28), &BillingInterval::Yearly, anchor);
+        assert_eq!(next, utc_date(2028, 2, 29));
+    }
+
+    #[test]
+    fn test_fixed_billing_day_aligns_first_period() {
+        let start = utc_date(2024, 4, 11);
+        let next = calculate_next_billing_date(start, &BillingInterval::Monthly, Some(1));
+        assert_eq!(next, utc_date(2024, 5, 1));
+        let next = calculate_next_billing_date(start, &BillingInterval::Monthly, Some(20));
+        assert_eq!(next, utc_date(2024, 4, 20));
+
+        // 20 of the 30 days in the April 1 - May 1 period
+        assert_eq!(billing_period_price(3000, start, utc_date(2024, 5, 1), &BillingInterval::Monthly, Some(1)), 2000);
+        assert_eq!(
+            billing_period_price(3000, utc_date(2024, 5, 1), utc_date(2024, 6, 1), &BillingInterval::Monthly, Some(1)),
+            3000
+        );
+    }
+
+    #[test]
+    fn test_prorate_plan_change_mid_period() {
+        // Halfway through a 30 day period, moving from 300 to 600 per 30 days
+        let (credit, charge) = prorate_plan_change(300, 600, 30 * DAY, 0, 30 * DAY, 15 * DAY);
+        assert_eq!(credit, 150);
+        assert_eq!(charge, 300);
+
+        // Downgrade leaves credit
+        let (credit, charge) = prorate_plan_change(600, 300, 30 * DAY, 0, 30 * DAY, 20 * DAY);
+        assert_eq!(credit, 200);
+        assert_eq!(charge, 100);
+    }
+
+    #[test]
+    fn test_prorate_plan_change_spreads_price_over_new_interval() {
+        // 10 days left on a monthly plan, switching to a yearly plan
+        let (credit, charge) = prorate_plan_change(300, 3650, 365 * DAY, 0, 30 * DAY, 20 * DAY);
+        assert_eq!(credit, 100);
+        assert_eq!(charge, 100);
+    }
+
+    #[test]
+    fn test_prorate_plan_change_outside_period() {
+        assert_eq!(prorate_plan_change(300, 600, 30 * DAY, 0, 30 * DAY, 30 * DAY), (0, 0));
+        assert_eq!(prorate_plan_change(300, 600, 30 * DAY, 10 * DAY, 10 * DAY, 5 * DAY), (0, 0));
+        // Before the period starts the whole period is unused
+        assert_eq!(prorate_plan_change(300, 600, 30 * DAY, 10 * DAY, 40 * DAY, 0), (300, 600));
+    }
+
+    #[test]
+    fn test_invoice_deposit_subaccount_is_stable_and_unique() {
+        assert_eq!(invoice_deposit_subaccount("inv_1"), invoice_deposit_subaccount("inv_1"));
+        assert_ne!(invoice_deposit_subaccount("inv_1"), invoice_deposit_subaccount("inv_2"));
+        assert_ne!(invoice_deposit_subaccount("inv_1"), [0u8; 32]);
+    }
+
+    #[test]
+    fn test_fiat_to_token_amount_rounds_up() {
+        // $10.00 at 8.50 per token with 8 token decimals
+        assert_eq!(fiat_to_token_amount(1_000, 8_500_000_000, 9, 8), Ok(117_647_059));
+        // $1.00 at exactly 1.00 per token with 6 token decimals
+        assert_eq!(fiat_to_token_amount(100, 1_000_000_000, 9, 6), Ok(1_000_000));
+        assert!(fiat_to_token_amount(100, 0, 9, 6).is_err());
+    }
+
+    #[test]
+    fn test_exchange_asset_symbol() {
+        assert_eq!(exchange_asset_symbol("ckBTC"), "BTC");
+        assert_eq!(exchange_asset_symbol("ckUSDC"), "USDC");
+        assert_eq!(exchange_asset_symbol("ICP"), "ICP");
+    }
+
+    #[test]
+    fn test_payment_memo_fits_icrc1_limit() {
+        assert_eq!(payment_memo("tx_1").len(), 32);
+        assert_ne!(payment_memo("tx_1"), payment_memo("tx_2"));
+        assert_ne!(idempotency_index_key(Principal::anonymous(), "a"), idempotency_index_key(Principal::management_canister(), "a"));
+    }
+
+    #[test]
+    fn test_reconcile_token_difference() {
+        let report = reconcile_token("ICP", Ok(1_000), 700, 100, 50, 20);
+        assert_eq!(report.difference, 130);
+
+        let report = reconcile_token("ICP", Ok(800), 700, 100, 50, 20);
+        assert_eq!(report.difference, -70);
+
+        let report = reconcile_token("ICP", Err("unreachable".to_string()), 700, 0, 0, 0);
+        assert_eq!(report.ledger_balance, None);
+        assert_eq!(report.difference, 0);
+        assert!(report.error.is_some());
+    }
+
+    #[test]
+    fn test_balance_credit_and_debit() {
+        credit_balance("ckBTC", 1_000);
+        credit_balance("ckBTC", 500);
+        assert_eq!(BALANCES.with(|b| b.borrow().get(&"ckBTC".to_string())), Some(1_500));
+
+        // An overdraft is rejected and leaves the balance untouched
+        assert!(debit_balance("ckBTC", 1_501).is_err());
+        assert_eq!(BALANCES.with(|b| b.borrow().get(&"ckBTC".to_string())), Some(1_500));
+
+        assert!(debit_balance("ckBTC", 1_500).is_ok());
+        assert_eq!(BALANCES.with(|b| b.borrow().get(&"ckBTC".to_string())), Some(0));
+        assert!(debit_balance("ICP", 1).is_err());
+    }
+
+    fn create_test_refund(refund_id: &str, transaction_id: &str, amount: u64, status: RefundStatus) -> RefundRecord {
+        RefundRecord {
+            refund_id: refund_id.to_string(),
+            transaction_id: transaction_id.to_string(),
+            invoice_id: None,
+            token_symbol: "ICP".to_string(),
+            amount,
+            fee: 10_000,
+            to: Principal::anonymous(),
+            reason: None,
+            is_full_refund: false,
+            status,
+            block_index: None,
+            created_at: 0,
+            completed_at: None,
+        }
+    }
+
+    #[test]
+    fn test_refunded_amount_counts_pending_and_completed() {
+        REFUNDS.with(|refunds| {
+            let mut map = refunds.borrow_mut();
+            for refund in [
+                create_test_refund("refund_1", "tx_1", 300, RefundStatus::Completed),
+                create_test_refund("refund_2", "tx_1", 200, RefundStatus::Pending),
+                create_test_refund("refund_3", "tx_1", 400, RefundStatus::Failed("rejected".to_string())),
+                create_test_refund("refund_4", "tx_2", 100, RefundStatus::Completed),
+            ] {
+                map.insert(refund.refund_id.clone(), refund);
+            }
+        });
+
+        // Failed refunds give the amount back to the refundable total
+        assert_eq!(refunded_amount("tx_1"), 500);
+        assert_eq!(refunded_amount("tx_2"), 100);
+        assert_eq!(refunded_amount("tx_3"), 0);
+    }
+
+    #[test]
+    fn test_payout_due_threshold_and_cadence() {
+        let settings = PayoutSettings::default();
+        assert!(payout_due(&settings, Some(1_000), 1_000, 10, 0));
+        assert!(!payout_due(&settings, Some(1_000), 999, 10, 0));
+        assert!(!payout_due(&settings, None, 1_000_000, 10, 0));
+        assert!(!payout_due(&settings, Some(5), 10, 10, 0));
+
+        let settings = PayoutSettings {
+            cadence_seconds: Some(DAY / SECONDS_TO_NANOS),
+            last_payout_at: Some(0),
+            ..Default::default()
+        };
+        assert!(!payout_due(&settings, None, 500, 10, DAY - 1));
+        assert!(payout_due(&settings, None, 500, 10, DAY));
+    }
+
+    #[test]
+    fn test_fee_sweep_due_batches_small_fees() {
+        assert!(!fee_sweep_due(0, 10));
+        assert!(!fee_sweep_due(999, 10));
+        assert!(fee_sweep_due(1_000, 10));
+        // Zero-fee ledgers still wait for a minimal batch
+        assert!(!fee_sweep_due(99, 0));
+        assert!(fee_sweep_due(100, 0));
+    }
+
+    #[test]
+    fn test_id_sequence_orders_numerically() {
+        assert_eq!(id_sequence("tx_10"), Some(10));
+        assert_eq!(id_sequence("inv_2"), Some(2));
+        assert_eq!(id_sequence("tx_"), None);
+        assert!(id_sequence("tx_10") > id_sequence("tx_9"));
+    }
+
+    #[test]
+    fn test_collect_page_uses_cursor_and_limit() {
+        let candidates: Vec<(u64, String)> = (1..=5).rev().map(|n| (n, format!("tx_{}", n))).collect();
+
+        let (items, next_cursor) = collect_page(candidates.clone(), 2, |id| Some(id.to_string()), |_| true);
+        assert_eq!(items, vec!["tx_5".to_string(), "tx_4".to_string()]);
+        assert_eq!(next_cursor, Some(4));
+
+        let (items, next_cursor) = collect_page(candidates, 10, |id| Some(id.to_string()), |id| id != "tx_3");
+        assert_eq!(items.len(), 4);
+        assert_eq!(next_cursor, None);
+    }
+
+    #[test]
+    fn test_metadata_matches() {
+        let metadata = vec![("order_id".to_string(), "42".to_string())];
+        assert!(metadata_matches(&metadata, &None, &None));
+        assert!(metadata_matches(&metadata, &Some("order_id".to_string()), &None));
+        assert!(metadata_matches(&metadata, &Some("order_id".to_string()), &Some("42".to_string())));
+        assert!(!metadata_matches(&metadata, &Some("order_id".to_string()), &Some("43".to_string())));
+        assert!(!metadata_matches(&metadata, &Some("sku".to_string()), &None));
+    }
+
+    fn create_test_line(unit_price: u64, quantity: u32, discount: u64, tax_rate_bps: Option<u32>) -> LineItem {
+        LineItem {
+            product_id: None,
+            variant_sku: None,
+            description: "Item".to_string(),
+            quantity,
+            unit_price,
+            discount,
+            tax_rate_bps,
+        }
+    }
+
+    #[test]
+    fn test_compute_invoice_breakdown_with_tax_and_shipping() {
+        let lines = vec![
+            create_test_line(1_000, 2, 100, None),      // 1900 net, invoice rate 10% -> 190
+            create_test_line(500, 1, 0, Some(0)),       // Tax exempt line
+            create_test_line(333, 1, 0, Some(2_500)),   // 83.25 tax rounds to 83
+        ];
+
+        let breakdown = compute_invoice_breakdown(&lines, Some(1_000), 250).unwrap();
+        assert_eq!(breakdown.subtotal, 1_900 + 500 + 333);
+        assert_eq!(breakdown.discount_total, 100);
+        assert_eq!(breakdown.tax_total, 190 + 83);
+        assert_eq!(breakdown.shipping, 250);
+        assert_eq!(breakdown.total, 2_733 + 273 + 250);
+        assert_eq!(breakdown.lines[0].gross_amount, 2_000);
+    }
+
+    #[test]
+    fn test_compute_invoice_breakdown_rejects_invalid_lines() {
+        assert!(compute_invoice_breakdown(&[], None, 0).is_err());
+        assert!(compute_invoice_breakdown(&[create_test_line(100, 0, 0, None)], None, 0).is_err());
+        assert!(compute_invoice_breakdown(&[create_test_line(100, 1, 101, None)], None, 0).is_err());
+        assert!(compute_invoice_breakdown(&[create_test_line(100, 1, 0, Some(10_001))], None, 0).is_err());
+        assert!(compute_invoice_breakdown(&[create_test_line(u64::MAX, 2, 0, None)], None, 0).is_err());
+    }
+
+    #[test]
+    fn test_allocate_discount_spreads_over_lines() {
+        let mut lines = vec![
+            create_test_line(300, 1, 0, None),
+            create_test_line(200, 2, 50, None),
+        ];
+
+        assert_eq!(allocate_discount(&mut lines, 500), 500);
+        assert_eq!(lines[0].discount, 300);
+        assert_eq!(lines[1].discount, 250);
+
+        // A discount larger than the order is capped
+        let mut lines = vec![create_test_line(100, 1, 0, None)];
+        assert_eq!(allocate_discount(&mut lines, 250), 100);
+        assert_eq!(line_net_amount(&lines[0]), Ok(0));
+    }
+
+    #[test]
+    fn test_order_transitions() {
+        assert!(order_transition_allowed(&OrderStatus::Pending, &OrderStatus::Paid));
+        assert!(order_transition_allowed(&OrderStatus::Paid, &OrderStatus::Shipped));
+        assert!(order_transition_allowed(&OrderStatus::Shipped, &OrderStatus::Delivered));
+        assert!(!order_transition_allowed(&OrderStatus::Pending, &OrderStatus::Shipped));
+        assert!(!order_transition_allowed(&OrderStatus::Paid, &OrderStatus::Cancelled));
+        assert!(!order_transition_allowed(&OrderStatus::Delivered, &OrderStatus::Shipped));
+    }
+
+    fn create_test_product(inventory_count: Option<u32>, reserved_count: Option<u32>, status: ProductStatus) -> Product {
+        Product {
+            product_id: "product_1".to_string(),
+            name: "Test".to_string(),
+            description: "Test product".to_string(),
+            price: 100,
+            token_symbol: "ICP".to_string(),
+            category: None,
+            image_url: None,
+            status,
+            inventory_count,
+            metadata: vec![],
+            created_at: 0,
+            updated_at: 0,
+            reserved_count,
+            prices: None,
+            variants: None,
+        }
+    }
+
+    fn create_test_variant(sku: &str, prices: Vec<TokenPrice>, inventory_count: Option<u32>) -> ProductVariant {
+        ProductVariant {
+            sku: sku.to_string(),
+            name: sku.to_string(),
+            options: vec![],
+            prices,
+            inventory_count,
+            reserved_count: None,
+            image_url: None,
+            status: ProductStatus::Active,
+        }
+    }
+
+    fn token_price(token_symbol: &str, amount: u64) -> TokenPrice {
+        TokenPrice { token_symbol: token_symbol.to_string(), amount }
+    }
+
+    #[test]
+    fn test_available_inventory_excludes_reservations() {
+        assert_eq!(available_inventory(&create_test_product(None, Some(3), ProductStatus::Active)), None);
+        assert_eq!(available_inventory(&create_test_product(Some(10), None, ProductStatus::Active)), Some(10));
+        assert_eq!(available_inventory(&create_test_product(Some(10), Some(4), ProductStatus::Active)), Some(6));
+        assert_eq!(available_inventory(&create_test_product(Some(2), Some(4), ProductStatus::Active)), Some(0));
+    }
+
+    #[test]
+    fn test_refresh_stock_status() {
+        let mut product = create_test_product(Some(5), Some(5), ProductStatus::Active);
+        refresh_stock_status(&mut product);
+        assert!(matches!(product.status, ProductStatus::OutOfStock));
+
+        product.reserved_count = Some(4);
+        refresh_stock_status(&mut product);
+        assert!(matches!(product.status, ProductStatus::Active));
+
+        // Inactive products stay inactive whatever the stock
+        let mut product = create_test_product(Some(0), None, ProductStatus::Inactive);
+        refresh_stock_status(&mut product);
+        assert!(matches!(product.status, ProductStatus::Inactive));
+    }
+
+    #[test]
+    fn test_inventory_items_merges_product_lines() {
+        let mut first = create_test_line(100, 2, 0, None);
+        first.product_id = Some("product_1".to_string());
+        let mut second = create_test_line(100, 3, 0, None);
+        second.product_id = Some("product_1".to_string());
+        let custom = create_test_line(50, 1, 0, None);
+
+        let mut variant = create_test_line(100, 1, 0, None);
+        variant.product_id = Some("product_1".to_string());
+        variant.variant_sku = Some("L".to_string());
+
+        assert_eq!(inventory_items(&[first, custom, second, variant]), vec![
+            ReservedItem { product_id: "product_1".to_string(), variant_sku: None, quantity: 5 },
+            ReservedItem { product_id: "product_1".to_string(), variant_sku: Some("L".to_string()), quantity: 1 },
+        ]);
+    }
+
+    #[test]
+    fn test_resolve_price_per_token_and_variant() {
+        let mut product = create_test_product(None, None, ProductStatus::Active);
+        product.prices = Some(vec![token_price("ckUSDC", 5_000)]);
+
+        assert_eq!(resolve_price(&product, None, "ICP"), Some(100));
+        assert_eq!(resolve_price(&product, None, "ckUSDC"), Some(5_000));
+        assert_eq!(resolve_price(&product, None, "ckBTC"), None);
+
+        // Variants without prices inherit, variants with prices only sell in those tokens
+        let inherited = create_test_variant("S", vec![], None);
+        let priced = create_test_variant("L", vec![token_price("ckBTC", 7)], None);
+        assert_eq!(resolve_price(&product, Some(&inherited), "ckUSDC"), Some(5_000));
+        assert_eq!(resolve_price(&product, Some(&priced), "ckBTC"), Some(7));
+        assert_eq!(resolve_price(&product, Some(&priced), "ICP"), None);
+    }
+
+    #[test]
+    fn test_find_variant_requires_sku_for_variant_products() {
+        let plain = create_test_product(None, None, ProductStatus::Active);
+        assert!(find_variant(&plain, None).unwrap().is_none());
+        assert!(find_variant(&plain, Some("L")).is_err());
+
+        let mut product = create_test_product(None, None, ProductStatus::Active);
+        product.variants = Some(vec![create_test_variant("L", vec![], Some(3))]);
+        assert!(find_variant(&product, None).is_err());
+        assert!(find_variant(&product, Some("XL")).is_err());
+        assert_eq!(find_variant(&product, Some("L")).unwrap().unwrap().sku, "L");
+    }
+
+    #[test]
+    fn test_validate_product_catalog() {
+        let active = vec!["ICP".to_string(), "ckUSDC".to_string()];
+
+        let mut product = create_test_product(None, None, ProductStatus::Active);
+        product.prices = Some(vec![token_price("ckUSDC", 5_000)]);
+        product.variants = Some(vec![
+            create_test_variant("S", vec![], None),
+            create_test_variant("L", vec![token_price("ICP", 150)], Some(2)),
+        ]);
+        assert!(validate_product_catalog(&product, &active).is_ok());
+
+        product.prices = Some(vec![token_price("ckBTC", 1)]);
+        assert!(validate_product_catalog(&product, &active).is_err());
+
+        product.prices = Some(vec![token_price("ICP", 1)]);
+        assert!(validate_product_catalog(&product, &active).is_err());
+
+        product.prices = None;
+        product.variants = Some(vec![create_test_variant("S", vec![], None), create_test_variant("S", vec![], None)]);
+        assert!(validate_product_catalog(&product, &active).is_err());
+    }
+
+    #[test]
+    fn test_variant_stock_drives_product_status() {
+        let mut product = create_test_product(None, None, ProductStatus::Active);
+        let mut sold_out = create_test_variant("S", vec![], Some(2));
+        sold_out.reserved_count = Some(2);
+        product.variants = Some(vec![sold_out, create_test_variant("L", vec![], Some(0))]);
+
+        refresh_stock_status(&mut product);
+        assert!(matches!(product.status, ProductStatus::OutOfStock));
+
+        product.variants.as_mut().unwrap()[1].inventory_count = Some(4);
+        refresh_stock_status(&mut product);
+        assert!(matches!(product.status, ProductStatus::Active));
+        assert!(matches!(product.variants.as_ref().unwrap()[0].status, ProductStatus::OutOfStock));
+    }
+
+    #[test]
+    fn test_add_token_amount() {
+        let mut totals = vec![];
+        add_token_amount(&mut totals, "ICP", 10);
+        add_token_amount(&mut totals, "ckBTC", 1);
+        add_token_amount(&mut totals, "ICP", 5);
+        assert_eq!(totals, vec![("ICP".to_string(), 15), ("ckBTC".to_string(), 1)]);
+    }
+
+    #[test]
+    fn test_download_token_depends_on_seed_and_delivery() {
+        let token = download_token(b"seed", "dlv_1", 1);
+        assert_eq!(token.len(), 64);
+        assert_eq!(token, download_token(b"seed", "dlv_1", 1));
+        assert_ne!(token, download_token(b"other", "dlv_1", 1));
+        assert_ne!(token, download_token(b"seed", "dlv_2", 1));
+    }
+
+    #[test]
+    fn test_new_license_keys_skips_duplicates() {
+        let pool = LicenseKeyPool {
+            available: vec!["AAAA-1111".to_string()],
+            issued_count: 0,
+        };
+        let keys = vec![
+            "AAAA-1111".to_string(),
+            " BBBB-2222 ".to_string(),
+            "BBBB-2222".to_string(),
+            "".to_string(),
+        ];
+        assert_eq!(new_license_keys(&pool, keys), vec!["BBBB-2222".to_string()]);
+    }
+
+    fn create_test_coupon(coupon_type: CouponType) -> DiscountCoupon {
+        DiscountCoupon {
+            coupon_id: "coupon_1".to_string(),
+            code: "SAVE".to_string(),
+            coupon_type,
+            description: "Test coupon".to_string(),
+            minimum_amount: None,
+            applicable_tokens: vec![],
+            usage_limit: None,
+            used_count: 0,
+            expires_at: None,
+            is_active: true,
+            created_at: 0,
+            updated_at: 0,
+            per_customer_limit: None,
+            first_purchase_only: None,
+            applicable_products: None,
+            applicable_categories: None,
+            batch_id: None,
+        }
+    }
+
+    fn create_coupon_context(amount: u64, lines: Vec<CouponLine>) -> CouponContext {
+        CouponContext {
+            amount,
+            token_symbol: "ICP".to_string(),
+            shipping: 0,
+            lines,
+            customer_uses: 0,
+            first_purchase: false,
+        }
+    }
+
+    fn coupon_line(product_id: &str, category: Option<&str>, amount: u64) -> CouponLine {
+        CouponLine {
+            product_id: product_id.to_string(),
+            category: category.map(|category| category.to_string()),
+            amount,
+        }
+    }
+
+    #[test]
+    fn test_coupon_discount_limits() {
+        let mut coupon = create_test_coupon(CouponType::Percentage(10));
+        let mut context = create_coupon_context(1_000, vec![]);
+        assert_eq!(coupon_discount(&coupon, &context, 0), Ok(100));
+
+        coupon.per_customer_limit = Some(1);
+        context.customer_uses = 1;
+        assert!(coupon_discount(&coupon, &context, 0).is_err());
+
+        coupon.per_customer_limit = None;
+        coupon.first_purchase_only = Some(true);
+        assert!(coupon_discount(&coupon, &context, 0).is_err());
+        context.first_purchase = true;
+        assert_eq!(coupon_discount(&coupon, &context, 0), Ok(100));
+
+        coupon.usage_limit = Some(5);
+        coupon.used_count = 5;
+        assert!(coupon_discount(&coupon, &context, 0).is_err());
+    }
+
+    #[test]
+    fn test_coupon_discount_product_restrictions() {
+        let lines = vec![
+            coupon_line("product_1", Some("Books"), 400),
+            coupon_line("product_2", Some("Music"), 600),
+        ];
+        let context = create_coupon_context(1_100, lines);
+
+        let mut coupon = create_test_coupon(CouponType::Percentage(50));
+        coupon.applicable_products = Some(vec!["product_1".to_string()]);
+        assert_eq!(coupon_discount(&coupon, &context, 0), Ok(200));
+
+        coupon.applicable_products = None;
+        coupon.applicable_categories = Some(vec!["Music".to_string()]);
+        assert_eq!(coupon_discount(&coupon, &context, 0), Ok(300));
+
+        let mut fixed = create_test_coupon(CouponType::FixedAmount(1_000));
+        fixed.applicable_categories = Some(vec!["Books".to_string()]);
+        assert_eq!(coupon_discount(&fixed, &context, 0), Ok(400));
+
+        fixed.applicable_categories = Some(vec!["Games".to_string()]);
+        assert!(coupon_discount(&fixed, &context, 0).is_err());
+    }
+
+    #[test]
+    fn test_free_shipping_coupon_needs_shipping() {
+        let coupon = create_test_coupon(CouponType::FreeShipping);
+        let mut context = create_coupon_context(1_000, vec![]);
+        assert!(coupon_discount(&coupon, &context, 0).is_err());
+
+        context.shipping = 150;
+        assert_eq!(coupon_discount(&coupon, &context, 0), Ok(150));
+    }
+
+    #[test]
+    fn test_generated_coupon_code() {
+        let code = generated_coupon_code("INFL-", b"seed", 0);
+        assert!(code.starts_with("INFL-"));
+        assert_eq!(code.len(), 5 + GENERATED_CODE_LENGTH);
+        assert!(code[5..].bytes().all(|byte| COUPON_CODE_ALPHABET.contains(&byte)));
+
+        assert_eq!(code, generated_coupon_code("INFL-", b"seed", 0));
+        assert_ne!(code, generated_coupon_code("INFL-", b"seed", 1));
+        assert_ne!(code, generated_coupon_code("INFL-", b"other", 0));
+    }
+
+    #[test]
+    fn test_hmac_sha256_rfc4231() {
+        let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(to_hex(&mac), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+
+        let long_key = [0xaau8; 131];
+        let mac = hmac_sha256(&long_key, b"Test Using Larger Than Block-Size Key - Hash Key First");
+        assert_eq!(to_hex(&mac), "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+    }
+
+    #[test]
+    fn test_webhook_retry_delay_backoff() {
+        assert_eq!(webhook_retry_delay(1), 60 * SECONDS_TO_NANOS);
+        assert_eq!(webhook_retry_delay(2), 120 * SECONDS_TO_NANOS);
+        assert_eq!(webhook_retry_delay(30), 6 * 60 * 60 * SECONDS_TO_NANOS);
+    }
+
+    #[test]
+    fn test_http_webhook_urls_require_dev_mode() {
+        assert!(validate_webhook_url("https://example.com/hook").is_ok());
+        assert!(validate_webhook_url("http://localhost:8080/hook").is_err());
+        assert!(validate_webhook_url("ftp://example.com/hook").is_err());
+
+        WEBHOOK_ALLOW_HTTP.with(|flag| flag.borrow_mut().set(true).unwrap());
+        assert!(validate_webhook_url("http://localhost:8080/hook").is_ok());
+        assert!(validate_webhook_url("ftp://example.com/hook").is_err());
+        WEBHOOK_ALLOW_HTTP.with(|flag| flag.borrow_mut().set(false).unwrap());
+    }
+
+    #[test]
+    fn test_validate_dunning_policy() {
+        assert!(validate_dunning_policy(&create_test_dunning_policy()).is_ok());
+        assert!(validate_dunning_policy(&default_dunning_policy()).is_ok());
+
+        let mut policy = create_test_dunning_policy();
+        policy.retry_offsets_seconds = vec![3, 1];
+        assert!(validate_dunning_policy(&policy).is_err());
+
+        policy.max_attempts = 0;
+        assert!(validate_dunning_policy(&policy).is_err());
+    }
+
+    fn create_test_invoice(invoice_id: &str, payer: Option<Principal>) -> PaymentInvoice {
+        PaymentInvoice {
+            id: invoice_id.to_string(),
+            merchant: Principal::anonymous(),
+            amount: 100_000,
+            token: TokenConfig {
+                symbol: "ICP".to_string(),
+                name: "Internet Computer".to_string(),
+                decimals: 8,
+                canister_id: Principal::management_canister(),
+                fee: 10_000,
+                logo: None,
+                is_active: true,
+            },
+            description: "Test invoice".to_string(),
+            metadata: vec![],
+            expires_at: None,
+            created_at: 0,
+            status: InvoiceStatus::Created,
+            deposit_subaccount: None,
+            fiat_amount: None,
+            quote: None,
+            line_items: None,
+            tax_rate_bps: None,
+            shipping: None,
+            breakdown: None,
+            payer,
+        }
+    }
+
+    #[test]
+    fn test_deposit_is_credited_to_expected_payer() {
+        // The owner is anonymous until init runs
+        let owner = Principal::anonymous();
+        let payer = Principal::from_slice(&[1]);
+        let stranger = Principal::from_slice(&[2]);
+
+        let invoice = create_test_invoice("inv_1", Some(payer));
+        assert_eq!(deposit_payer(&invoice, payer), Ok(payer));
+        assert_eq!(deposit_payer(&invoice, owner), Ok(payer));
+        assert!(deposit_payer(&invoice, stranger).is_err());
+
+        let invoice = create_test_invoice("inv_2", None);
+        assert_eq!(deposit_payer(&invoice, owner), Ok(owner));
+        assert!(deposit_payer(&invoice, stranger).is_err());
+    }
+
+    #[test]
+    fn test_public_rate_fetches_are_limited_per_window() {
+        let start = 1_000 * SECONDS_TO_NANOS;
+        for _ in 0..MAX_PUBLIC_RATE_FETCHES_PER_WINDOW {
+            assert!(reserve_public_rate_fetch(start).is_ok());
+        }
+        assert!(reserve_public_rate_fetch(start + 1).is_err());
+        assert!(reserve_public_rate_fetch(start + PUBLIC_RATE_FETCH_WINDOW_NANOS).is_ok());
+    }
+
+    #[test]
+    fn test_cached_exchange_rate_expires() {
+        let pair = ("ICP".to_string(), "USD".to_string());
+        let rate = ExchangeRate {
+            rate: 1_234_000_000,
+            timestamp: 0,
+            metadata: ExchangeRateMetadata { decimals: 9 },
+        };
+        EXCHANGE_RATE_CACHE.with(|cache| cache.borrow_mut().insert(pair.clone(), (0, rate)));
+
+        assert_eq!(cached_exchange_rate(&pair, EXCHANGE_RATE_CACHE_TTL_NANOS - 1).map(|r| r.rate), Some(1_234_000_000));
+        assert!(cached_exchange_rate(&pair, EXCHANGE_RATE_CACHE_TTL_NANOS).is_none());
+        assert!(cached_exchange_rate(&("ICP".to_string(), "EUR".to_string()), 0).is_none());
+    }
+
+    #[test]
+    fn test_invoice_expiry_defaults_to_configured_ttl() {
+        let now = 1_000 * SECONDS_TO_NANOS;
+        assert_eq!(resolve_invoice_expiry(None, now), Ok(now + DAY));
+
+        INVOICE_TTL_SECONDS.with(|ttl| ttl.borrow_mut().set(60 * 60).unwrap());
+        assert_eq!(resolve_invoice_expiry(None, now), Ok(now + 60 * 60 * SECONDS_TO_NANOS));
+        INVOICE_TTL_SECONDS.with(|ttl| ttl.borrow_mut().set(DEFAULT_INVOICE_TTL_SECONDS).unwrap());
+
+        assert_eq!(resolve_invoice_expiry(Some(now + 5), now), Ok(now + 5));
+        assert!(resolve_invoice_expiry(Some(now), now).is_err());
+        assert!(resolve_invoice_expiry(Some(now + 2 * 365 * DAY), now).is_err());
+    }
+
+    #[test]
+    fn test_expire_overdue_invoices_only_expires_open_invoices() {
+        let mut overdue = create_test_invoice("inv_1", None);
+        overdue.expires_at = Some(100);
+        let mut paid = create_test_invoice("inv_2", None);
+        paid.expires_at = Some(100);
+        paid.status = InvoiceStatus::Paid;
+        let mut open = create_test_invoice("inv_3", None);
+        open.expires_at = Some(1_000);
+        let mut in_flight = create_test_invoice("inv_4", None);
+        in_flight.expires_at = Some(100);
+
+        assert!(is_invoice_overdue(&overdue, 101));
+        assert!(!is_invoice_overdue(&overdue, 100));
+        assert!(!is_invoice_overdue(&paid, 101));
+
+        for invoice in [overdue, paid, open, in_flight] {
+            store_invoice(&invoice);
+        }
+
+        let _guard = InFlightGuard::acquire("invoice:inv_4".to_string()).unwrap();
+        expire_overdue_invoices(500);
+
+        let status = |id: &str| INVOICES.with(|invoices| invoices.borrow().get(&id.to_string())).unwrap().status;
+        assert!(matches!(status("inv_1"), InvoiceStatus::Expired));
+        assert!(matches!(status("inv_2"), InvoiceStatus::Paid));
+        assert!(matches!(status("inv_3"), InvoiceStatus::Created));
+        assert!(matches!(status("inv_4"), InvoiceStatus::Created));
+        // A short batch means every open invoice was seen, the next run starts over
+        assert_eq!(EXPIRY_SCAN_CURSOR.with(|cursor| *cursor.borrow()), 0);
+    }
+
+    #[test]
+    fn test_invoice_indexes_follow_status_changes() {
+        let payer = Principal::from_slice(&[1]);
+        for (invoice_id, created_at, symbol) in [("inv_1", 10, "ICP"), ("inv_2", 20, "ckBTC"), ("inv_3", 30, "ICP")] {
+            let mut invoice = create_test_invoice(invoice_id, if invoice_id == "inv_2" { Some(payer) } else { None });
+            invoice.created_at = created_at;
+            invoice.token.symbol = symbol.to_string();
+            store_invoice(&invoice);
+        }
+
+        let ids = |filter: InvoiceFilter, cursor: Option<u64>| -> Vec<String> {
+            invoice_candidates(&filter, cursor).into_iter().map(|(_, id)| id).collect()
+        };
+        let by_status = |status: InvoiceStatus| InvoiceFilter { status: Some(status), ..Default::default() };
+
+        assert_eq!(ids(by_status(InvoiceStatus::Created), None), vec!["inv_3", "inv_2", "inv_1"]);
+        assert_eq!(ids(InvoiceFilter { payer: Some(payer), ..Default::default() }, None), vec!["inv_2"]);
+        assert_eq!(ids(InvoiceFilter { token_symbol: Some("ICP".to_string()), ..Default::default() }, None), vec!["inv_3", "inv_1"]);
+        assert_eq!(ids(InvoiceFilter { from_time: Some(15), ..Default::default() }, None), vec!["inv_3", "inv_2"]);
+        assert_eq!(ids(InvoiceFilter { to_time: Some(30), ..Default::default() }, Some(3)), vec!["inv_2", "inv_1"]);
+
+        let mut paid = INVOICES.with(|invoices| invoices.borrow().get(&"inv_2".to_string())).unwrap();
+        paid.status = InvoiceStatus::Paid;
+        store_invoice(&paid);
+
+        assert_eq!(ids(by_status(InvoiceStatus::Created), None), vec!["inv_3", "inv_1"]);
+        assert_eq!(ids(by_status(InvoiceStatus::Paid), None), vec!["inv_2"]);
+        // Overdue invoices are still indexed as created and checked when loaded
+        assert_eq!(ids(by_status(InvoiceStatus::Expired), Some(3)), vec!["inv_1"]);
+    }
+
+    #[test]
+    fn test_catalog_lines_ignore_caller_discounts() {
+        let mut product = create_test_product(None, None, ProductStatus::Active);
+        product.product_id = "product_test".to_string();
+        PRODUCTS.with(|products| products.borrow_mut().insert(product.product_id.clone(), product));
+
+        let mut line = create_test_line(1, 5, 499, Some(0));
+        line.product_id = Some("product_test".to_string());
+        price_product_line(&mut line, "ICP").unwrap();
+
+        assert_eq!(line.unit_price, 100);
+        assert_eq!(line.discount, 0);
+        assert_eq!(line.tax_rate_bps, None);
+        assert_eq!(line_net_amount(&line), Ok(500));
+    }
+
+    #[test]
+    fn test_refund_reverses_every_product_line_by_quantity() {
+        let mut invoice = create_test_invoice("inv_1", None);
+        let mut first = create_test_line(100, 3, 0, None);
+        first.product_id = Some("product_a".to_string());
+        let mut second = create_test_line(50, 2, 0, None);
+        second.product_id = Some("product_b".to_string());
+        invoice.line_items = Some(vec![first, second, create_test_line(10, 1, 0, None)]);
+
+        let lines = sold_product_lines(&invoice);
+        assert_eq!(lines.len(), 2);
+        assert_eq!((lines[0].amount, lines[0].units), (300, 3));
+        assert_eq!((lines[1].amount, lines[1].units), (100, 2));
+
+        let mut product = create_test_product(None, None, ProductStatus::Active);
+        product.product_id = "product_a".to_string();
+        PRODUCTS.with(|products| products.borrow_mut().insert(product.product_id.clone(), product));
+        PRODUCT_SALES_STATS.with(|stats| {
+            stats.borrow_mut().insert("product_a".to_string(), ProductSalesStats {
+                product_id: "product_a".to_string(),
+                total_sales: 2,
+                total_revenue: 600,
+                units_sold: 6,
+                last_sale_at: None,
+                revenue_by_token: Some(vec![("ICP".to_string(), 600), ("ckBTC".to_string(), 50)]),
+                variant_stats: Some(vec![VariantSalesStats {
+                    variant_sku: "red".to_string(),
+                    total_sales: 2,
+                    units_sold: 6,
+                    revenue_by_token: vec![("ICP".to_string(), 600)],
+                }]),
+            })
+        });
+        reverse_product_sales_stats("product_a", Some("red"), "ICP", 300, 3, true);
+        // Other tokens leave the product-token total alone
+        reverse_product_sales_stats("product_a", None, "ckBTC", 20, 1, false);
+
+        let stats = PRODUCT_SALES_STATS.with(|stats| stats.borrow().get(&"product_a".to_string())).unwrap();
+        assert_eq!((stats.total_sales, stats.total_revenue, stats.units_sold), (1, 300, 3));
+        assert_eq!(stats.revenue_by_token, Some(vec![("ICP".to_string(), 300), ("ckBTC".to_string(), 30)]));
+        let variant = &stats.variant_stats.unwrap()[0];
+        assert_eq!((variant.total_sales, variant.units_sold), (1, 3));
+        assert_eq!(variant.revenue_by_token, vec![("ICP".to_string(), 300)]);
+    }
+
+    #[test]
+    fn test_units_held_by_ignores_lapsed_holds() {
+        let holder = Principal::from_slice(&[1]);
+        let reservation = |invoice_id: &str, holder: Principal, quantity: u32, expires_at: Option<u64>| InventoryReservation {
+            invoice_id: invoice_id.to_string(),
+            items: vec![ReservedItem { product_id: "product_1".to_string(), variant_sku: None, quantity }],
+            created_at: 0,
+            holder: Some(holder),
+            expires_at,
+        };
+
+        INVENTORY_RESERVATIONS.with(|reservations| {
+            let mut map = reservations.borrow_mut();
+            map.insert("inv_1".to_string(), reservation("inv_1", holder, 5, Some(100)));
+            map.insert("inv_2".to_string(), reservation("inv_2", holder, 7, Some(200)));
+            map.insert("inv_3".to_string(), reservation("inv_3", Principal::from_slice(&[2]), 9, Some(200)));
+        });
+
+        assert_eq!(units_held_by(holder, 50), 12);
+        assert_eq!(units_held_by(holder, 100), 7);
+        assert_eq!(units_held_by(holder, 200), 0);
+    }
+
+    #[test]
+    fn test_download_token_index_backfills_issued_tokens() {
+        let token = download_token(b"seed", "dlv_1", 1);
+        let delivery = Delivery {
+            delivery_id: "dlv_1".to_string(),
+            invoice_id: "inv_1".to_string(),
+            product_id: "product_1".to_string(),
+            variant_sku: None,
+            buyer: Principal::anonymous(),
+            status: DeliveryStatus::Issued,
+            content: Some(DeliveryContent::DownloadToken {
+                url: format!("https://example.com/download?token={}", token),
+                token: token.clone(),
+                expires_at: 100,
+            }),
+            instructions: None,
+            created_at: 1,
+            issued_at: Some(1),
+            revoked_at: None,
+        };
+        DELIVERIES.with(|deliveries| deliveries.borrow_mut().insert(delivery.delivery_id.clone(), delivery));
+
+        rebuild_download_token_index();
+        assert_eq!(DOWNLOAD_TOKEN_INDEX.with(|index| index.borrow().get(&token)), Some("dlv_1".to_string()));
+    }
+
+    #[test]
+    fn test_lapsed_coupon_holds_are_given_back() {
+        let customer = Principal::from_slice(&[1]);
+        let usage = |usage_id: &str, invoice_id: &str, status: CouponUsageStatus| CouponUsage {
+            usage_id: usage_id.to_string(),
+            coupon_id: "coupon_held".to_string(),
+            user_principal: customer,
+            invoice_id: invoice_id.to_string(),
+            discount_applied: 1_000,
+            used_at: 0,
+            status: Some(status),
+        };
+        store_coupon_usage(&usage("usage_held_1", "invoice_held_1", CouponUsageStatus::Reserved));
+        store_coupon_usage(&usage("usage_held_2", "invoice_held_2", CouponUsageStatus::Committed));
+
+        assert_eq!(customer_coupon_uses("coupon_held", customer), 2);
+        assert_eq!(invoice_coupon_usage("invoice_held_1").unwrap().usage_id, "usage_held_1");
+        assert_eq!(invoice_coupon_usage("invoice_held_2").unwrap().usage_id, "usage_held_2");
+
+        release_lapsed_coupon_holds(COUPON_HOLD_NANOS - 1);
+        assert!(invoice_coupon_usage("invoice_held_1").is_some());
+
+        // Only the reservation lapses, the redeemed use keeps counting
+        release_lapsed_coupon_holds(COUPON_HOLD_NANOS);
+        assert!(invoice_coupon_usage("invoice_held_1").is_none());
+        assert!(invoice_coupon_usage("invoice_held_2").is_some());
+        assert_eq!(customer_coupon_uses("coupon_held", customer), 1);
+
+        remove_coupon_usage("usage_held_2");
+        assert_eq!(customer_coupon_uses("coupon_held", customer), 0);
+        assert!(invoice_coupon_usage("invoice_held_2").is_none());
+    }
+
+    #[test]
+    fn test_billing_anchors_are_backfilled_from_next_renewal() {
+        let renewal = days_from_civil(2024, 1, 31) as u64 * NANOS_PER_DAY;
+        let subscription = create_test_subscription(renewal - 31 * DAY, renewal);
+        SUBSCRIPTIONS.with(|subscriptions| {
+            subscriptions.borrow_mut().insert(subscription.subscription_id.clone(), subscription)
+        });
+
+        backfill_billing_anchors();
+
+        let subscription = SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow().get(&"sub_1".to_string())).unwrap();
+        assert_eq!(subscription.billing_anchor_day, Some(31));
+        assert_eq!(
+            calculate_next_billing_date(
+                calculate_next_billing_date(renewal, &BillingInterval::Monthly, subscription.billing_anchor_day),
+                &BillingInterval::Monthly,
+                subscription.billing_anchor_day,
+            ),
+            days_from_civil(2024, 3, 31) as u64 * NANOS_PER_DAY
+        );
+    }
+
+    #[test]
+    fn test_usage_records_are_read_per_subscription_in_order() {
+        let usage_record = |record_id: &str, subscription_id: &str, timestamp: u64, key: Option<&str>| UsageRecord {
+            record_id: record_id.to_string(),
+            subscription_id: subscription_id.to_string(),
+            quantity: 1,
+            timestamp,
+            reported_by: Principal::anonymous(),
+            idempotency_key: key.map(str::to_string),
+            payment_id: None,
+            usage_pricing: None,
+        };
+        store_usage_record(&usage_record("usage_record_1", "sub_1", 30, None));
+        store_usage_record(&usage_record("usage_record_2", "sub_1", 10, Some("batch_1")));
+        store_usage_record(&usage_record("usage_record_3", "sub_10", 20, None));
+        // Stored under its record id, as before records were keyed by subscription
+        let legacy = usage_record("usage_record_4", "sub_1", 20, Some("batch_2"));
+        USAGE_RECORDS.with(|records| records.borrow_mut().insert(legacy.record_id.clone(), legacy));
+
+        rekey_usage_records();
+
+        let ids = |records: Vec<UsageRecord>| records.into_iter().map(|record| record.record_id).collect::<Vec<_>>();
+        assert_eq!(ids(unbilled_usage_records("sub_1", u64::MAX)), vec!["usage_record_2", "usage_record_4", "usage_record_1"]);
+        assert_eq!(ids(unbilled_usage_records("sub_1", 30)), vec!["usage_record_2", "usage_record_4"]);
+        assert_eq!(find_usage_record("sub_1", "batch_2").unwrap().record_id, "usage_record_4");
+        assert!(find_usage_record("sub_10", "batch_1").is_none());
+
+        mark_usage_billed(&unbilled_usage_records("sub_1", 20), "pay_1");
+        assert_eq!(ids(unbilled_usage_records("sub_1", u64::MAX)), vec!["usage_record_4", "usage_record_1"]);
+        assert_eq!(find_usage_record("sub_1", "batch_1").unwrap().payment_id, Some("pay_1".to_string()));
+    }
+
+    #[test]
+    fn test_usage_is_charged_at_the_pricing_it_was_recorded_under() {
+        let usage_record = |quantity: u64, timestamp: u64, usage_pricing: Option<UsagePricing>| UsageRecord {
+            record_id: format!("usage_record_{}", timestamp),
+            subscription_id: "sub_1".to_string(),
+            quantity,
+            timestamp,
+            reported_by: Principal::anonymous(),
+            idempotency_key: None,
+            payment_id: None,
+            usage_pricing,
+        };
+        let old_pricing = UsagePricing::PerUnit { unit_price: 10 };
+        let new_pricing = UsagePricing::PerUnit { unit_price: 3 };
+        let records = vec![
+            usage_record(5, 1, Some(old_pricing.clone())),
+            usage_record(5, 2, Some(old_pricing)),
+            usage_record(2, 3, None),
+        ];
+
+        // Moved to the new metered plan: earlier usage keeps the old unit price
+        assert_eq!(usage_amount(&records, None, Some(&new_pricing)), Some((12, 106)));
+        // Moved to a plan without metering: recorded usage is still owed
+        assert_eq!(usage_amount(&records, None, None), Some((12, 100)));
+        assert_eq!(usage_amount(&records, Some(2), None), Some((7, 50)));
+        assert_eq!(usage_amount(&[], None, None), None);
+    }
+
+    #[test]
+    fn test_invoice_metadata_does_not_sell_products() {
+        let mut invoice = create_test_invoice("inv_1", None);
+        invoice.metadata = vec![
+            ("product_id".to_string(), "product_1".to_string()),
+            ("quantity".to_string(), "1000".to_string()),
+        ];
+
+        assert!(invoice_product_items(&invoice).is_empty());
+        assert!(sold_product_lines(&invoice).is_empty());
+        assert!(validate_caller_metadata(&invoice.metadata).is_err());
+        assert!(validate_caller_metadata(&[("note".to_string(), "gift".to_string())]).is_ok());
+    }
+
+    #[test]
+    fn test_invoice_metadata_does_not_move_stock() {
+        let product = create_test_product(Some(5), None, ProductStatus::Active);
+        PRODUCTS.with(|products| products.borrow_mut().insert(product.product_id.clone(), product));
+
+        let mut invoice = create_test_invoice("inv_1", None);
+        invoice.metadata = vec![
+            ("product_id".to_string(), "product_1".to_string()),
+            ("quantity".to_string(), "5".to_string()),
+        ];
+
+        hold_invoice_inventory(&invoice, Principal::from_slice(&[1]), 0).unwrap();
+        assert!(INVENTORY_RESERVATIONS.with(|reservations| reservations.borrow().get(&invoice.id)).is_none());
+
+        commit_inventory(&invoice);
+        let product = PRODUCTS.with(|products| products.borrow().get(&"product_1".to_string())).unwrap();
+        assert_eq!(product.inventory_count, Some(5));
+        assert!(matches!(product.status, ProductStatus::Active));
+    }
+
+    #[test]
+    fn test_invoice_only_moves_its_own_order() {
+        let order = Order {
+            order_id: "order_1".to_string(),
+            customer: Principal::from_slice(&[1]),
+            items: vec![],
+            invoice_id: "inv_1".to_string(),
+            token_symbol: "ICP".to_string(),
+            coupon_id: None,
+            discount_applied: 0,
+            total_amount: 100_000,
+            status: OrderStatus::Pending,
+            tracking_reference: None,
+            metadata: vec![],
+            created_at: 0,
+            updated_at: 0,
+        };
+        ORDERS.with(|orders| orders.borrow_mut().insert(order.order_id.clone(), order));
+
+        // Another customer's invoice pointing at the order
+        let mut invoice = create_test_invoice("inv_2", Some(Principal::from_slice(&[2])));
+        invoice.metadata = vec![("order_id".to_string(), "order_1".to_string())];
+        update_order_for_invoice(&invoice, OrderStatus::Cancelled);
+
+        let order = ORDERS.with(|orders| orders.borrow().get(&"order_1".to_string())).unwrap();
+        assert_eq!(order.status, OrderStatus::Pending);
+        assert!(validate_caller_metadata(&invoice.metadata).is_err());
+    }
+
+    #[test]
+    fn test_product_units_per_invoice_are_capped() {
+        let mut first = create_test_line(100, MAX_PRODUCT_UNITS_PER_INVOICE - 1, 0, None);
+        first.product_id = Some("product_1".to_string());
+        let mut second = create_test_line(100, 1, 0, None);
+        second.product_id = Some("product_1".to_string());
+        let custom = create_test_line(10, 1_000, 0, None);
+
+        let mut lines = vec![first, second, custom];
+        assert!(check_invoice_units(&lines).is_ok());
+        lines[1].quantity = 2;
+        assert!(check_invoice_units(&lines).is_err());
+    }
+
+    #[test]
+    fn test_webhook_queue_and_pruning() {
+        let event = |event_id: &str| WebhookEvent {
+            event_id: event_id.to_string(),
+            event_type: WebhookEventType::PaymentFailed,
+            payload: "{}".to_string(),
+            created_at: 0,
+        };
+        let delivery = |event_id: &str, status: WebhookDeliveryStatus| WebhookDelivery {
+            delivery_id: format!("{}_0", event_id),
+            event_id: event_id.to_string(),
+            url: "https://example.com/hook".to_string(),
+            status,
+            attempts: vec![],
+            next_attempt_at: 10,
+            created_at: 0,
+            delivered_at: None,
+        };
+        for event_id in ["evt_1", "evt_2"] {
+            WEBHOOK_EVENTS.with(|events| events.borrow_mut().insert(event_id.to_string(), event(event_id)));
+        }
+        NEXT_WEBHOOK_EVENT_ID.with(|id| id.borrow_mut().set(3).unwrap());
+        store_webhook_delivery(&delivery("evt_1", WebhookDeliveryStatus::Pending));
+        store_webhook_delivery(&delivery("evt_2", WebhookDeliveryStatus::Pending));
+        assert_eq!(WEBHOOK_QUEUE.with(|queue| queue.borrow().len()), 2);
+
+        store_webhook_delivery(&delivery("evt_1", WebhookDeliveryStatus::Delivered));
+        assert_eq!(WEBHOOK_QUEUE.with(|queue| queue.borrow().len()), 1);
+
+        // Nothing is pruned within the retention period, or while a delivery is pending
+        prune_webhook_history(WEBHOOK_RETENTION_NANOS - 1);
+        assert!(WEBHOOK_EVENTS.with(|events| events.borrow().contains_key(&"evt_1".to_string())));
+        prune_webhook_history(WEBHOOK_RETENTION_NANOS);
+        assert!(!WEBHOOK_EVENTS.with(|events| events.borrow().contains_key(&"evt_1".to_string())));
+        assert!(!WEBHOOK_DELIVERIES.with(|deliveries| deliveries.borrow().contains_key(&"evt_1_0".to_string())));
+        assert!(WEBHOOK_EVENTS.with(|events| events.borrow().contains_key(&"evt_2".to_string())));
+        assert_eq!(WEBHOOK_PRUNE_CURSOR.with(|c| *c.borrow().get()), 2);
+    }
+
+    #[test]
+    fn test_payment_failed_events_share_a_budget() {
+        assert!(is_payer_side_failure("Insufficient allowance: 0"));
+        assert!(!is_payer_side_failure("Service temporarily unavailable"));
+
+        for _ in 0..MAX_PAYMENT_FAILED_EVENTS_PER_WINDOW {
+            assert!(reserve_payment_failed_event(0).is_ok());
+        }
+        assert!(reserve_payment_failed_event(0).is_err());
+        assert!(reserve_payment_failed_event(PAYMENT_FAILED_WINDOW_NANOS).is_ok());
+    }
+
+    #[test]
+    fn test_subscription_without_plan_is_expired() {
+        let mut subscription = create_test_subscription(0, 30 * DAY);
+        subscription.status = SubscriptionStatus::PendingPayment;
+        subscription.next_retry_at = Some(31 * DAY);
+        assert!(next_subscription_charge(&subscription).is_err());
+
+        expire_unbillable_subscription(subscription, 31 * DAY);
+
+        let subscription = SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow().get(&"sub_1".to_string())).unwrap();
+        assert_eq!(subscription.status, SubscriptionStatus::Expired);
+        assert_eq!(subscription.next_retry_at, None);
+    }
+}